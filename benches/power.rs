@@ -0,0 +1,18 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use rocoder::power;
+
+fn audio_power(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let mut group = c.benchmark_group("audio_power");
+    for len in [1 << 10, 1 << 14] {
+        let samples: Vec<f32> = (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(len), &samples, |b, samples| {
+            b.iter(|| power::audio_power(black_box(samples)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, audio_power);
+criterion_main!(benches);
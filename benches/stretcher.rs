@@ -0,0 +1,86 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam_channel::unbounded;
+use rand::Rng;
+use rocoder::audio::AudioSpec;
+use rocoder::signal_flow::node::Processor;
+use rocoder::stretch_engine::StretchEngine;
+use rocoder::stretcher::Stretcher;
+use rocoder::stretcher_processor::StretcherProcessor;
+use rocoder::windows;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+
+fn noise(len: usize) -> Vec<f32> {
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| rng.gen_range(-1.0..1.0)).collect()
+}
+
+/// A stretcher with one second of noise to work through, followed by silence
+fn stretcher(channels: u16, window_len: usize, factor: f32) -> Stretcher {
+    let (tx, rx) = unbounded();
+    tx.send(noise(SAMPLE_RATE as usize)).unwrap();
+    Stretcher::new(
+        AudioSpec {
+            channels,
+            sample_rate: SAMPLE_RATE,
+        },
+        rx,
+        factor,
+        1.0,
+        1,
+        windows::hanning(window_len),
+        Duration::from_secs(1),
+        vec![],
+    )
+}
+
+fn next_window(c: &mut Criterion) {
+    let mut group = c.benchmark_group("next_window");
+    for window_len in [1 << 10, 1 << 12, 1 << 14] {
+        for factor in [1.0, 4.0, 16.0] {
+            let mut stretcher = stretcher(1, window_len, factor);
+            group.bench_with_input(
+                BenchmarkId::new(format!("factor {}", factor), window_len),
+                &window_len,
+                |b, _| b.iter(|| stretcher.next_window()),
+            );
+        }
+    }
+    group.finish();
+}
+
+/// Stretch one second of every channel on as many workers as channels
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render_one_second");
+    group.sample_size(10);
+    for channels in [1, 2, 4] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(channels),
+            &channels,
+            |b, &channels| {
+                b.iter(|| {
+                    let stretchers = (0..channels)
+                        .map(|_| {
+                            Box::new(stretcher(channels, 1 << 12, 4.0)) as Box<dyn StretchEngine>
+                        })
+                        .collect();
+                    let (processor, bus) = StretcherProcessor::new(stretchers, None);
+                    let (errors, _) = unbounded();
+                    let (_ctrl, handle) = processor
+                        .with_workers(channels as usize)
+                        .start(Arc::new(AtomicBool::new(false)), errors);
+                    // Windows arrive one per channel in turn
+                    while bus.channels.iter().all(|output| output.recv().is_ok()) {}
+                    handle.join().unwrap();
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, next_window, render);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+/*
+ * C bindings for rocoder's stretcher, built with the `capi` feature, e.g.
+ *
+ *     cargo rustc --release --lib --features capi --crate-type cdylib
+ *
+ * Kept in step with src/capi.rs by hand; a test there checks that every
+ * function is declared.
+ */
+
+#ifndef ROCODER_H
+#define ROCODER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Compare with rocoder_abi_version() to catch a mismatched library */
+#define ROCODER_ABI_VERSION 1
+
+#define ROCODER_OK 0
+/* A null pointer, zero channels or sample rate, an unknown engine, an option
+ * out of range, or an output buffer of the wrong size */
+#define ROCODER_ERROR_INVALID_ARGUMENT -1
+/* Stretching failed unexpectedly */
+#define ROCODER_ERROR_INTERNAL -2
+
+#define ROCODER_ENGINE_VOCODER 0
+#define ROCODER_ENGINE_GRANULAR 1
+#define ROCODER_ENGINE_WSOLA 2
+/* WSOLA for factors from 0.5 to 2, and the vocoder otherwise */
+#define ROCODER_ENGINE_AUTO 3
+
+typedef struct RocoderOptions {
+    /* How many times longer the output is, where below 1 compresses */
+    float factor;
+    /* From -128 to 127, but not 0; negative numbers divide the pitch */
+    int32_t pitch_multiple;
+    float amplitude;
+    /* One of the ROCODER_ENGINE_* constants */
+    uint32_t engine;
+    /* The vocoder's window length */
+    uint32_t window_len;
+    /* The granular engine's grain length, jitter from 0 to 1, and how many
+     * grains overlap, at least 1 */
+    float grain_seconds;
+    float grain_jitter;
+    float grain_density;
+    /* Only used if seeded isn't 0 */
+    uint64_t seed;
+    uint32_t seeded;
+} RocoderOptions;
+
+uint32_t rocoder_abi_version(void);
+
+/* The same defaults as the command line */
+RocoderOptions rocoder_default_options(void);
+
+/* How many frames rocoder_stretch writes for `frames` of input */
+size_t rocoder_stretched_frames(size_t frames, float factor);
+
+/*
+ * Stretch `frames` frames of interleaved audio from `input` into `output`,
+ * which must hold exactly rocoder_stretched_frames(frames, options->factor)
+ * frames of `channels` samples. Blocks until done, and returns ROCODER_OK or
+ * an error code. The caller owns both buffers.
+ */
+int32_t rocoder_stretch(const float *input,
+                        size_t frames,
+                        uint16_t channels,
+                        uint32_t sample_rate,
+                        const RocoderOptions *options,
+                        float *output,
+                        size_t output_frames);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ROCODER_H */
@@ -1,9 +1,14 @@
+use crate::markers::Marker;
 use crate::math;
-use anyhow::Result;
-use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crate::metrics::{self, Counter};
+use anyhow::{anyhow, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TrySendError};
 use num_traits::Num;
-use std::ops::MulAssign;
-use std::time::Duration;
+use std::fmt;
+use std::ops::{Deref, MulAssign};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub trait Sample: Sized + Num + Copy + MulAssign + Send + 'static {
     fn from_i8(n: i8) -> Self;
@@ -28,7 +33,7 @@ impl Sample for f32 {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct AudioSpec {
     /// Number of audio channels (e.g. 2 for stereo)
     pub channels: u16,
@@ -36,30 +41,198 @@ pub struct AudioSpec {
     pub sample_rate: u32,
 }
 
-#[derive(Debug)]
+impl FromStr for AudioSpec {
+    type Err = anyhow::Error;
+
+    /// Parse `<sample rate>:<channels>`, e.g. `44100:2`
+    fn from_str(s: &str) -> Result<AudioSpec> {
+        let invalid = || anyhow!("invalid spec {:?}, expected <sample rate>:<channels>", s);
+        let (sample_rate, channels) = s.split_once(':').ok_or_else(invalid)?;
+        let sample_rate: u32 = sample_rate.parse().map_err(|_| invalid())?;
+        let channels: u16 = channels.parse().map_err(|_| invalid())?;
+        if sample_rate == 0 || channels == 0 {
+            return Err(invalid());
+        }
+        Ok(AudioSpec {
+            channels,
+            sample_rate,
+        })
+    }
+}
+
+/// Why an edit to an `Audio` couldn't be made
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioError {
+    /// The audio has no channels to edit
+    EmptyAudio,
+    /// A range of samples that doesn't fit in the audio
+    RangeOutOfBounds {
+        start: usize,
+        end: usize,
+        len: usize,
+    },
+    /// Audio with a different spec from the audio it was combined with
+    SpecMismatch {
+        expected: AudioSpec,
+        found: AudioSpec,
+    },
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::EmptyAudio => write!(f, "the audio has no channels"),
+            AudioError::RangeOutOfBounds { start, end, len } => write!(
+                f,
+                "samples {}..{} are out of bounds of audio {} samples long",
+                start, end, len
+            ),
+            AudioError::SpecMismatch { expected, found } => write!(
+                f,
+                "expected audio of {} channels at {} Hz, got {} channels at {} Hz",
+                expected.channels, expected.sample_rate, found.channels, found.sample_rate
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+#[derive(Debug, Clone)]
 pub struct Audio {
     pub data: Vec<Vec<f32>>,
     pub spec: AudioSpec,
+    /// Labelled points and regions, kept in place when the audio is clipped
+    pub markers: Vec<Marker>,
 }
 
 impl Audio {
     pub fn from_spec(spec: &AudioSpec) -> Audio {
         let data = (0..spec.channels).map(|_| Vec::new()).collect();
-        Audio { data, spec: *spec }
+        Audio {
+            data,
+            spec: *spec,
+            markers: vec![],
+        }
+    }
+
+    /// Split interleaved `samples` into channels, dropping any partial frame
+    /// at the end
+    pub fn from_interleaved(spec: &AudioSpec, samples: &[f32]) -> Audio {
+        let mut audio = Audio::from_spec(spec);
+        let channels = spec.channels as usize;
+        for frame in samples.chunks_exact(channels) {
+            for (channel, sample) in audio.data.iter_mut().zip(frame) {
+                channel.push(*sample);
+            }
+        }
+        audio
+    }
+
+    /// Every channel's samples, a frame at a time
+    pub fn interleaved(&self) -> Vec<f32> {
+        let len = self.data.first().map_or(0, |channel| channel.len());
+        (0..len)
+            .flat_map(|i| self.data.iter().map(move |channel| channel[i]))
+            .collect()
     }
 
     pub fn duration(&self) -> Duration {
         Duration::from_nanos(
-            ((self.data[0].len() as f32 / self.spec.sample_rate as f32) * 1_000_000_000.0) as u64,
+            ((self.len() as f32 / self.spec.sample_rate as f32) * 1_000_000_000.0) as u64,
         )
     }
 
-    pub fn clip_in_place(&mut self, start_offset: Option<Duration>, duration: Option<Duration>) {
-        let start_sample_pos = self.resolve_start_sample_pos(start_offset);
-        let end_sample_pos = self.resolve_end_sample_pos(start_sample_pos, duration);
+    /// Samples in each channel, or 0 if there are no channels
+    pub fn len(&self) -> usize {
+        self.data.first().map_or(0, |channel| channel.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Like `len`, but an error if there are no channels to edit
+    fn editable_len(&self) -> Result<usize, AudioError> {
+        self.data
+            .first()
+            .map(|channel| channel.len())
+            .ok_or(AudioError::EmptyAudio)
+    }
+
+    /// Keep only `duration` from `start_offset`, or to the end if
+    /// `duration` is `None`
+    pub fn clip_in_place(
+        &mut self,
+        start_offset: Option<Duration>,
+        duration: Option<Duration>,
+    ) -> Result<(), AudioError> {
+        let (start, end) = self.resolve_range(start_offset, duration)?;
+        self.clip_to_samples(start, end)
+    }
+
+    /// Keep only samples `start..end`
+    pub(crate) fn clip_to_samples(&mut self, start: usize, end: usize) -> Result<(), AudioError> {
+        self.check_bounds(start, end)?;
         for channel in self.data.iter_mut() {
-            *channel = channel[start_sample_pos..end_sample_pos].to_vec();
+            channel.truncate(end);
+            channel.drain(..start);
         }
+        self.markers = self
+            .markers
+            .iter()
+            .filter_map(|marker| marker.clipped(start, end))
+            .collect();
+        Ok(())
+    }
+
+    /// Move the audio behind an `Arc`, so it can be handed to any number of
+    /// consumers without copying it
+    pub fn shared(self) -> SharedAudio {
+        SharedAudio(Arc::new(self))
+    }
+
+    /// A view of all of the audio
+    pub fn as_slice(&self) -> AudioSlice<'_> {
+        AudioSlice {
+            spec: self.spec,
+            channels: &self.data,
+            markers: &self.markers,
+            start: 0,
+            end: self.len(),
+        }
+    }
+
+    /// A view of `duration` from `start_offset`, picked out like
+    /// `clip_in_place` but without copying or changing anything
+    pub fn slice(
+        &self,
+        start_offset: Option<Duration>,
+        duration: Option<Duration>,
+    ) -> Result<AudioSlice<'_>, AudioError> {
+        let (start, end) = self.resolve_range(start_offset, duration)?;
+        self.as_slice().slice(start, end)
+    }
+
+    /// Add `other` to the end, moving its markers to match
+    pub fn append(&mut self, other: &Audio) -> Result<(), AudioError> {
+        if other.spec != self.spec {
+            return Err(AudioError::SpecMismatch {
+                expected: self.spec,
+                found: other.spec,
+            });
+        }
+        let offset = self.len();
+        for (channel, appended) in self.data.iter_mut().zip(&other.data) {
+            channel.extend_from_slice(appended);
+        }
+        self.markers
+            .extend(other.markers.iter().map(|marker| Marker {
+                start: marker.start + offset,
+                end: marker.end.map(|end| end + offset),
+                ..marker.clone()
+            }));
+        Ok(())
     }
 
     pub fn amplify_in_place(&mut self, factor: f32) {
@@ -74,15 +247,28 @@ impl Audio {
         self.data.rotate_right(1);
     }
 
-    pub fn fade_in(&mut self, start: Duration, dur: Duration) {
+    /// Flip the sign of every sample in a channel
+    pub fn invert_polarity(&mut self, channel: usize) {
+        match self.data.get_mut(channel) {
+            Some(samples) => {
+                for sample in samples.iter_mut() {
+                    *sample = -*sample;
+                }
+            }
+            None => warn!(
+                "Cannot invert polarity of missing channel {}, ignoring.",
+                channel
+            ),
+        }
+    }
+
+    pub fn fade_in(&mut self, start: Duration, dur: Duration) -> Result<(), AudioError> {
         self.fade_in_at_sample(self.duration_to_sample(start), self.duration_to_sample(dur))
     }
 
-    fn fade_in_at_sample(&mut self, start: usize, dur: usize) {
-        if start + dur > self.data[0].len() {
-            warn!("Fade in parameters out of bounds, ignoring.");
-            return;
-        }
+    /// Silence everything before `start`, then fade in over `dur` samples
+    pub(crate) fn fade_in_at_sample(&mut self, start: usize, dur: usize) -> Result<(), AudioError> {
+        self.check_range(start, dur)?;
         for channel in self.data.iter_mut() {
             for i in 0..start {
                 channel[i] = 0.0;
@@ -91,17 +277,20 @@ impl Audio {
                 channel[start + p] *= math::sqrt_interp(0.0, 1.0, p as f32 / dur as f32)
             }
         }
+        Ok(())
     }
 
-    pub fn fade_out(&mut self, start: Duration, dur: Duration) {
+    pub fn fade_out(&mut self, start: Duration, dur: Duration) -> Result<(), AudioError> {
         self.fade_out_at_sample(self.duration_to_sample(start), self.duration_to_sample(dur))
     }
 
-    fn fade_out_at_sample(&mut self, start: usize, dur: usize) {
-        if start + dur > self.data[0].len() {
-            warn!("Fade out parameters out of bounds, ignoring.");
-            return;
-        }
+    /// Fade out over `dur` samples from `start`, then silence the rest
+    pub(crate) fn fade_out_at_sample(
+        &mut self,
+        start: usize,
+        dur: usize,
+    ) -> Result<(), AudioError> {
+        self.check_range(start, dur)?;
         for channel in self.data.iter_mut() {
             for i in start + dur..channel.len() {
                 channel[i] = 0.0;
@@ -110,6 +299,42 @@ impl Audio {
                 channel[start + p] *= math::sqrt_interp(1.0, 0.0, p as f32 / dur as f32)
             }
         }
+        Ok(())
+    }
+
+    /// Whether `dur` samples from `start` fit in the audio
+    fn check_range(&self, start: usize, dur: usize) -> Result<(), AudioError> {
+        let len = self.editable_len()?;
+        let end = start.saturating_add(dur);
+        if end > len {
+            return Err(AudioError::RangeOutOfBounds { start, end, len });
+        }
+        Ok(())
+    }
+
+    /// The samples `start_offset` and `duration` cover, which must lie
+    /// within the audio
+    fn resolve_range(
+        &self,
+        start_offset: Option<Duration>,
+        duration: Option<Duration>,
+    ) -> Result<(usize, usize), AudioError> {
+        let len = self.editable_len()?;
+        let start = self.resolve_start_sample_pos(start_offset);
+        let end = match duration {
+            Some(_) => self.resolve_end_sample_pos(start, duration),
+            None => len.max(start),
+        };
+        self.check_bounds(start, end)?;
+        Ok((start, end))
+    }
+
+    fn check_bounds(&self, start: usize, end: usize) -> Result<(), AudioError> {
+        let len = self.editable_len()?;
+        if start > end || end > len {
+            return Err(AudioError::RangeOutOfBounds { start, end, len });
+        }
+        Ok(())
     }
 
     fn resolve_start_sample_pos(&self, start_offset: Option<Duration>) -> usize {
@@ -123,9 +348,9 @@ impl Audio {
         match duration {
             Some(dur) => {
                 let dur_in_samples = (dur.as_secs_f64() * self.spec.sample_rate as f64) as usize;
-                start_sample_pos + dur_in_samples
+                start_sample_pos.saturating_add(dur_in_samples)
             }
-            None => self.data.get(0).unwrap().len(),
+            None => self.len(),
         }
     }
 
@@ -138,15 +363,367 @@ impl Audio {
     }
 }
 
+/// A borrowed view of part of an `Audio`, for reading without copying
+/// its channels
+///
+/// Copy only what's needed out of it, e.g. with `to_audio`, or hand it
+/// to something that reads samples, like `stretch` or `AudioWriter::write_slice`.
+#[derive(Debug, Clone, Copy)]
+pub struct AudioSlice<'a, T = f32> {
+    pub spec: AudioSpec,
+    channels: &'a [Vec<T>],
+    markers: &'a [Marker],
+    start: usize,
+    end: usize,
+}
+
+impl<'a, T> AudioSlice<'a, T> {
+    /// Samples in each channel of the view
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The viewed samples of channel `index`, if there is one
+    pub fn channel(&self, index: usize) -> Option<&'a [T]> {
+        self.channels
+            .get(index)
+            .map(|channel| &channel[self.start..self.end])
+    }
+
+    /// The viewed samples of every channel, in order
+    pub fn channels(&self) -> impl Iterator<Item = &'a [T]> + 'a {
+        let (start, end) = (self.start, self.end);
+        self.channels
+            .iter()
+            .map(move |channel| &channel[start..end])
+    }
+
+    /// The markers within the view, positioned from its start
+    pub fn markers(&self) -> Vec<Marker> {
+        self.markers
+            .iter()
+            .filter_map(|marker| marker.clipped(self.start, self.end))
+            .collect()
+    }
+
+    /// A narrower view of samples `start..end` of this one
+    pub fn slice(&self, start: usize, end: usize) -> Result<AudioSlice<'a, T>, AudioError> {
+        if self.channels.is_empty() {
+            return Err(AudioError::EmptyAudio);
+        }
+        if start > end || end > self.len() {
+            return Err(AudioError::RangeOutOfBounds {
+                start,
+                end,
+                len: self.len(),
+            });
+        }
+        Ok(AudioSlice {
+            start: self.start + start,
+            end: self.start + end,
+            ..*self
+        })
+    }
+}
+
+impl<'a> AudioSlice<'a> {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.len() as f64 / self.spec.sample_rate as f64)
+    }
+
+    /// Copy the view into audio of its own
+    pub fn to_audio(&self) -> Audio {
+        Audio {
+            data: self.channels().map(|channel| channel.to_vec()).collect(),
+            spec: self.spec,
+            markers: self.markers(),
+        }
+    }
+}
+
+impl<'a> From<&'a Audio> for AudioSlice<'a> {
+    fn from(audio: &'a Audio) -> Self {
+        audio.as_slice()
+    }
+}
+
+/// Immutable audio that's cheap to clone, where every clone reads the same
+/// samples, from `Audio::shared`
+///
+/// It derefs to `Audio` for reading, and slices like one.
+#[derive(Debug, Clone)]
+pub struct SharedAudio(Arc<Audio>);
+
+impl SharedAudio {
+    /// Whether both read the same samples, rather than equal ones
+    pub fn ptr_eq(&self, other: &SharedAudio) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// The audio, which is only copied if another clone still reads it
+    pub fn into_audio(self) -> Audio {
+        Arc::try_unwrap(self.0).unwrap_or_else(|audio| (*audio).clone())
+    }
+}
+
+impl Deref for SharedAudio {
+    type Target = Audio;
+
+    fn deref(&self) -> &Audio {
+        &self.0
+    }
+}
+
+impl From<Audio> for SharedAudio {
+    fn from(audio: Audio) -> Self {
+        audio.shared()
+    }
+}
+
+impl<'a> From<&'a SharedAudio> for AudioSlice<'a> {
+    fn from(audio: &'a SharedAudio) -> Self {
+        audio.as_slice()
+    }
+}
+
+/// Marks when a frame of a bus was produced, so consumers can tell how long
+/// it took to reach them
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timestamp {
+    /// Which frame of the bus, counting from its first
+    pub sample: u64,
+    pub at: Instant,
+}
+
+impl Timestamp {
+    /// The same moment on a bus whose frames are `offset` further along,
+    /// unless that's before the bus started
+    pub fn shifted(self, offset: i64) -> Option<Timestamp> {
+        Some(Timestamp {
+            sample: self.sample.checked_add_signed(offset)?,
+            at: self.at,
+        })
+    }
+}
+
+/// How many timestamps a bus holds for its consumer; any more are dropped,
+/// since every one is worth as much as the next
+const TIMESTAMP_QUEUE: usize = 64;
+
 #[derive(Debug)]
 pub struct AudioBus {
     pub spec: AudioSpec,
     pub channels: Vec<Receiver<Vec<f32>>>,
     pub expected_total_samples: Option<usize>,
+    /// Timestamps for some of the bus's frames, in order, if its producer
+    /// keeps them
+    pub timestamps: Option<Receiver<Timestamp>>,
 }
 
 const INTO_AUDIO_DRAIN_TIMEOUT: Duration = Duration::from_millis(5);
 
+/// What a bounded bus does with a chunk when its consumer has fallen behind
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Overflow {
+    /// Wait for the consumer, holding the producer back with it
+    Block,
+    /// Discard the oldest queued chunk to make room, so the consumer always
+    /// gets the latest audio
+    ///
+    /// The bus keeps a receiver of its own to discard with, so its producer
+    /// never sees the consumer go and has to be shut down instead.
+    DropOldest,
+    /// Discard the chunk being sent, keeping what's already queued
+    DropNewest,
+}
+
+impl FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Overflow> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            _ => Err(anyhow!(
+                "unknown overflow policy {:?}, expected block, drop-oldest or drop-newest",
+                s
+            )),
+        }
+    }
+}
+
+/// How much a processor's output bus holds before its `Overflow` kicks in
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BusPolicy {
+    /// Chunks per channel that haven't been received yet, or `None` for no
+    /// limit
+    pub capacity: Option<usize>,
+    pub overflow: Overflow,
+}
+
+impl BusPolicy {
+    pub const UNBOUNDED: BusPolicy = BusPolicy {
+        capacity: None,
+        overflow: Overflow::Block,
+    };
+}
+
+impl Default for BusPolicy {
+    fn default() -> Self {
+        BusPolicy::UNBOUNDED
+    }
+}
+
+/// The sending end of a bus made by `AudioBus::bounded`
+///
+/// Whenever a channel drops a chunk, the others drop one too, so the
+/// channels stay the same length. Only `send_frame` keeps them in step as
+/// well: with `Overflow::DropNewest` it drops a frame whole, while chunks
+/// passed to `send` one channel at a time may drop one frame's chunk on
+/// one channel and the next frame's on another.
+#[derive(Debug)]
+pub struct BusSender {
+    senders: Vec<Sender<Vec<f32>>>,
+    /// Only kept to discard the oldest chunks with `Overflow::DropOldest`
+    receivers: Vec<Receiver<Vec<f32>>>,
+    overflow: Overflow,
+    /// Chunks each channel still has to drop to match the others
+    owed: Vec<usize>,
+    dropped_chunks: usize,
+    dropped: Counter,
+    timestamps: Sender<Timestamp>,
+    frames_sent: u64,
+}
+
+impl BusSender {
+    /// How many frames have gone out on the bus, which is also the frame
+    /// the next timestamp for new audio should name
+    pub fn frames_sent(&self) -> u64 {
+        self.frames_sent
+    }
+
+    /// Pass `timestamp` on to the consumer, unless it has too many waiting
+    pub fn send_timestamp(&mut self, timestamp: Timestamp) {
+        let _ = self.timestamps.try_send(timestamp);
+    }
+
+    /// Pass on `timestamps` from an input whose frames this bus sends
+    /// `offset` frames further along
+    pub fn forward_timestamps(&mut self, timestamps: &[Timestamp], offset: i64) {
+        for timestamp in timestamps {
+            if let Some(timestamp) = timestamp.shifted(offset) {
+                self.send_timestamp(timestamp);
+            }
+        }
+    }
+
+    /// Send `chunk` on `channel`, returning false once the bus's receivers
+    /// have gone
+    pub fn send(&mut self, channel: usize, chunk: Vec<f32>) -> bool {
+        if self.owed[channel] > 0 {
+            self.owed[channel] -= 1;
+            self.count_dropped();
+            return true;
+        }
+        let len = chunk.len() as u64;
+        if channel == 0 {
+            self.frames_sent += len;
+        }
+        let mut chunk = chunk;
+        loop {
+            match self.senders[channel].try_send(chunk) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(full)) => match self.overflow {
+                    Overflow::Block => return self.senders[channel].send(full).is_ok(),
+                    Overflow::DropNewest => {
+                        if channel == 0 {
+                            self.frames_sent -= len;
+                        }
+                        self.count_dropped();
+                        self.owe_all_but(channel);
+                        return true;
+                    }
+                    Overflow::DropOldest => {
+                        chunk = full;
+                        // The consumer may have made room in the meantime
+                        if self.discard_oldest(channel) {
+                            for i in (0..self.receivers.len()).filter(|i| *i != channel) {
+                                if !self.discard_oldest(i) {
+                                    self.owed[i] += 1;
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Send one chunk per channel, returning false once the bus's receivers
+    /// have gone
+    pub fn send_frame(&mut self, chunks: Vec<Vec<f32>>) -> bool {
+        // Only this end sends, so a channel with room now still has it when
+        // its chunk goes
+        if self.overflow == Overflow::DropNewest
+            && self.owed.iter().all(|owed| *owed == 0)
+            && self.senders.iter().any(|sender| sender.is_full())
+        {
+            for _ in &chunks {
+                self.count_dropped();
+            }
+            return true;
+        }
+        chunks
+            .into_iter()
+            .enumerate()
+            .all(|(channel, chunk)| self.send(channel, chunk))
+    }
+
+    /// Drop the oldest chunk queued on `channel`, if there is one
+    fn discard_oldest(&mut self, channel: usize) -> bool {
+        match self.receivers[channel].try_recv() {
+            Ok(oldest) => {
+                if channel == 0 {
+                    self.frames_sent -= oldest.len() as u64;
+                }
+                self.count_dropped();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn owe_all_but(&mut self, channel: usize) {
+        for (i, owed) in self.owed.iter_mut().enumerate() {
+            if i != channel {
+                *owed += 1;
+            }
+        }
+    }
+
+    fn count_dropped(&mut self) {
+        if self.dropped_chunks == 0 {
+            warn!("a bus's consumer is falling behind, dropping audio");
+        }
+        self.dropped_chunks += 1;
+        self.dropped.inc();
+    }
+}
+
+impl Drop for BusSender {
+    fn drop(&mut self) {
+        if self.dropped_chunks > 0 {
+            warn!("dropped {} chunks for a slow consumer", self.dropped_chunks);
+        }
+    }
+}
+
 impl AudioBus {
     /// quick and dirty collapse into audio
     pub fn into_audio(self) -> Audio {
@@ -168,6 +745,7 @@ impl AudioBus {
         Audio {
             spec: self.spec,
             data: out,
+            markers: vec![],
         }
     }
 
@@ -187,6 +765,7 @@ impl AudioBus {
             spec,
             expected_total_samples,
             channels,
+            timestamps: None,
         }
     }
 
@@ -206,11 +785,62 @@ impl AudioBus {
                 spec,
                 expected_total_samples,
                 channels: receivers,
+                timestamps: None,
             },
             senders,
         )
     }
 
+    /// A bus that holds what `policy` allows, and its sender
+    pub fn bounded(
+        spec: AudioSpec,
+        expected_total_samples: Option<usize>,
+        policy: BusPolicy,
+    ) -> (Self, BusSender) {
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..spec.channels)
+            .map(|_| match policy.capacity {
+                Some(capacity) => bounded(capacity),
+                None => unbounded(),
+            })
+            .unzip();
+        let (timestamps, timestamp_rx) = bounded(TIMESTAMP_QUEUE);
+        let sender = BusSender {
+            senders,
+            receivers: if policy.overflow == Overflow::DropOldest {
+                receivers.clone()
+            } else {
+                vec![]
+            },
+            overflow: policy.overflow,
+            owed: vec![0; spec.channels as usize],
+            dropped_chunks: 0,
+            dropped: metrics::registry().counter(
+                "rocoder_bus_dropped_chunks_total",
+                &[],
+                "Chunks dropped from bounded buses whose consumers fell behind",
+            ),
+            timestamps,
+            frames_sent: 0,
+        };
+        (
+            AudioBus {
+                spec,
+                expected_total_samples,
+                channels: receivers,
+                timestamps: Some(timestamp_rx),
+            },
+            sender,
+        )
+    }
+
+    /// The timestamps that have arrived since the last call
+    pub fn take_timestamps(&self) -> Vec<Timestamp> {
+        self.timestamps
+            .iter()
+            .flat_map(Receiver::try_iter)
+            .collect()
+    }
+
     pub fn collect_chunk(&mut self) -> Result<Audio> {
         let mut chunk = Vec::with_capacity(self.spec.channels as usize);
         for channel_rx in &self.channels {
@@ -219,6 +849,7 @@ impl AudioBus {
         Ok(Audio {
             spec: self.spec,
             data: chunk,
+            markers: vec![],
         })
     }
 }
@@ -227,6 +858,33 @@ impl AudioBus {
 mod test {
     use super::*;
     use crate::test_utils::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec: AudioSpec = "44100:2".parse().unwrap();
+        assert_eq!(
+            spec,
+            AudioSpec {
+                channels: 2,
+                sample_rate: 44100
+            }
+        );
+        for invalid in ["44100", "44100:0", "stereo:2", "0:1", ""] {
+            assert!(invalid.parse::<AudioSpec>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn interleaving_round_trips() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 8000,
+        };
+        let audio = Audio::from_interleaved(&spec, &[0.1, 0.2, 0.3, 0.4, 0.5]);
+        assert_eq!(audio.data, vec![vec![0.1, 0.3], vec![0.2, 0.4]]);
+        assert_eq!(audio.interleaved(), vec![0.1, 0.2, 0.3, 0.4]);
+    }
 
     #[test]
     fn test_duration() {
@@ -237,7 +895,7 @@ mod test {
     #[test]
     fn test_clip_in_place_both_args_none() {
         let mut audio = generate_audio(0.0, 5, 2, 2);
-        audio.clip_in_place(None, None);
+        audio.clip_in_place(None, None).unwrap();
         assert_eq!(audio.data.get(0).unwrap().len(), 5);
         assert_eq!(audio.data.get(1).unwrap().len(), 5);
     }
@@ -245,7 +903,9 @@ mod test {
     #[test]
     fn test_clip_in_place_only_start_offset_given() {
         let mut audio = generate_audio(0.0, 5, 2, 2);
-        audio.clip_in_place(Some(Duration::from_millis(500)), None);
+        audio
+            .clip_in_place(Some(Duration::from_millis(500)), None)
+            .unwrap();
         assert_eq!(audio.data.get(0).unwrap().len(), 4);
         assert_eq!(audio.data.get(1).unwrap().len(), 4);
     }
@@ -253,7 +913,9 @@ mod test {
     #[test]
     fn test_clip_in_place_only_duration_given() {
         let mut audio = generate_audio(0.0, 5, 2, 2);
-        audio.clip_in_place(None, Some(Duration::from_millis(500)));
+        audio
+            .clip_in_place(None, Some(Duration::from_millis(500)))
+            .unwrap();
         assert_eq!(audio.data.get(0).unwrap().len(), 1);
         assert_eq!(audio.data.get(1).unwrap().len(), 1);
     }
@@ -261,14 +923,96 @@ mod test {
     #[test]
     fn test_clip_in_place_both_given() {
         let mut audio = generate_audio(0.0, 5, 2, 2);
-        audio.clip_in_place(
-            Some(Duration::from_millis(500)),
-            Some(Duration::from_millis(1000)),
-        );
+        audio
+            .clip_in_place(
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_millis(1000)),
+            )
+            .unwrap();
         assert_eq!(audio.data.get(0).unwrap().len(), 2);
         assert_eq!(audio.data.get(1).unwrap().len(), 2);
     }
 
+    #[test]
+    fn edits_of_audio_without_channels_fail() {
+        let mut audio = generate_audio(0.0, 5, 0, 2);
+        assert_eq!(audio.clip_in_place(None, None), Err(AudioError::EmptyAudio));
+        assert_eq!(audio.fade_in_at_sample(0, 0), Err(AudioError::EmptyAudio));
+        assert_eq!(audio.duration(), Duration::ZERO);
+    }
+
+    #[test]
+    fn append_moves_markers_and_checks_the_spec() {
+        let mut audio = generate_audio(0.0, 5, 2, 2);
+        let mut other = generate_audio(1.0, 3, 2, 2);
+        other.markers = vec![Marker::region("tail", 1, 2)];
+        audio.append(&other).unwrap();
+        assert_eq!(audio.data[1], vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(audio.markers, vec![Marker::region("tail", 6, 7)]);
+
+        let mono = generate_audio(1.0, 3, 1, 2);
+        assert_eq!(
+            audio.append(&mono),
+            Err(AudioError::SpecMismatch {
+                expected: audio.spec,
+                found: mono.spec,
+            })
+        );
+        assert_eq!(audio.len(), 8);
+    }
+
+    #[test]
+    fn slices_view_a_range_without_copying() {
+        let mut audio = generate_audio(0.0, 5, 2, 2);
+        audio.data[0] = vec![0.0, 1.0, 2.0, 3.0, 4.0];
+        audio.markers = vec![Marker::point("three", 3), Marker::point("four", 4)];
+        let slice = audio
+            .slice(
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert_eq!(slice.len(), 2);
+        assert_eq!(slice.channel(0), Some(&[1.0, 2.0][..]));
+        assert_eq!(
+            slice.channel(0).unwrap().as_ptr(),
+            audio.data[0][1..].as_ptr()
+        );
+        assert_eq!(slice.channel(2), None);
+
+        let narrower = slice.slice(1, 2).unwrap();
+        assert_eq!(narrower.channel(0), Some(&[2.0][..]));
+        assert!(slice.slice(1, 3).is_err());
+
+        let mut clipped = audio.clone();
+        clipped
+            .clip_in_place(Some(Duration::from_millis(1000)), None)
+            .unwrap();
+        let copied = audio
+            .slice(Some(Duration::from_millis(1000)), None)
+            .unwrap()
+            .to_audio();
+        assert_eq!(copied.data, clipped.data);
+        assert_eq!(copied.markers, clipped.markers);
+    }
+
+    #[test]
+    fn shared_audio_is_not_copied_until_owned() {
+        let audio = generate_audio(0.5, 100, 2, 44100);
+        let shared = audio.clone().shared();
+        let other = shared.clone();
+        assert!(shared.ptr_eq(&other));
+        assert_eq!(shared.data[0].as_ptr(), other.data[0].as_ptr());
+        assert_eq!(other.slice(None, None).unwrap().to_audio().data, audio.data);
+
+        let copied = other.into_audio();
+        assert_ne!(copied.data[0].as_ptr(), shared.data[0].as_ptr());
+        let ptr = shared.data[0].as_ptr();
+        let owned = shared.into_audio();
+        assert_eq!(owned.data[0].as_ptr(), ptr);
+        assert_eq!(owned.data, copied.data);
+    }
+
     #[test]
     fn test_amplify_in_place() {
         let mut audio = generate_audio(5.0, 2, 2, 44100);
@@ -286,10 +1030,27 @@ mod test {
         assert_almost_eq_by_element(audio.data[1].clone(), vec![6.0, 5.0]);
     }
 
+    #[test]
+    fn test_invert_polarity() {
+        let mut audio = generate_audio(0.5, 2, 2, 44100);
+        audio.data[1][0] = -0.25;
+        audio.invert_polarity(1);
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![0.5, 0.5]);
+        assert_almost_eq_by_element(audio.data[1].clone(), vec![0.25, -0.5]);
+    }
+
+    #[test]
+    fn test_invert_polarity_missing_channel_is_ignored() {
+        let mut audio = generate_audio(0.5, 2, 2, 44100);
+        audio.invert_polarity(2);
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![0.5, 0.5]);
+        assert_almost_eq_by_element(audio.data[1].clone(), vec![0.5, 0.5]);
+    }
+
     #[test]
     fn test_fade_in_at_sample() {
         let mut audio = generate_audio(1.0, 10, 2, 44100);
-        audio.fade_in_at_sample(3, 4);
+        audio.fade_in_at_sample(3, 4).unwrap();
         assert_almost_eq_by_element(
             audio.data[0].clone(),
             vec![
@@ -302,7 +1063,7 @@ mod test {
     #[test]
     fn test_fade_out_at_sample() {
         let mut audio = generate_audio(1.0, 10, 2, 44100);
-        audio.fade_out_at_sample(3, 4);
+        audio.fade_out_at_sample(3, 4).unwrap();
         assert_almost_eq_by_element(
             audio.data[0].clone(),
             vec![
@@ -323,4 +1084,141 @@ mod test {
         let audio = generate_audio(1.0, 10, 2, 44100);
         assert_eq!(audio.sample_to_duration(44100), Duration::from_secs(1));
     }
+
+    #[test]
+    fn bounded_buses_drop_whole_frames() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let policy = |overflow| BusPolicy {
+            capacity: Some(2),
+            overflow,
+        };
+        let (bus, mut sender) = AudioBus::bounded(spec, None, policy(Overflow::DropNewest));
+        for i in 0..4 {
+            assert!(sender.send_frame(vec![vec![i as f32], vec![-i as f32]]));
+        }
+        drop(sender);
+        let audio = bus.into_audio();
+        assert_eq!(audio.data, vec![vec![0.0, 1.0], vec![0.0, -1.0]]);
+
+        let (bus, mut sender) = AudioBus::bounded(spec, None, policy(Overflow::DropOldest));
+        for i in 0..4 {
+            assert!(sender.send_frame(vec![vec![i as f32], vec![-i as f32]]));
+        }
+        drop(sender);
+        let audio = bus.into_audio();
+        assert_eq!(audio.data, vec![vec![2.0, 3.0], vec![-2.0, -3.0]]);
+    }
+
+    #[test]
+    fn dropping_the_newest_keeps_channels_in_step() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let policy = BusPolicy {
+            capacity: Some(2),
+            overflow: Overflow::DropNewest,
+        };
+        let (bus, mut sender) = AudioBus::bounded(spec, None, policy);
+        let frame = |i: usize| vec![vec![i as f32], vec![-(i as f32)]];
+        let mut received = vec![vec![], vec![]];
+        assert!(sender.send_frame(frame(0)));
+        assert!(sender.send_frame(frame(1)));
+        // Only the first channel has room for frame 2
+        received[0].extend(bus.channels[0].recv().unwrap());
+        assert!(sender.send_frame(frame(2)));
+        received[1].extend(bus.channels[1].recv().unwrap());
+        assert!(sender.send_frame(frame(3)));
+        drop(sender);
+        for (channel, received) in bus.channels.iter().zip(received.iter_mut()) {
+            received.extend(channel.iter().flatten());
+        }
+        assert_eq!(received, vec![vec![0.0, 1.0, 3.0], vec![0.0, -1.0, -3.0]]);
+    }
+
+    /// Up to four channels of up to a tenth of a second at 8 kHz
+    fn arbitrary_audio() -> impl Strategy<Value = Audio> {
+        (1u16..=4, 0usize..800).prop_flat_map(|(channels, len)| {
+            prop::collection::vec(prop::collection::vec(-1.0f32..1.0, len), channels as usize)
+                .prop_map(move |data| Audio {
+                    data,
+                    spec: AudioSpec {
+                        channels,
+                        sample_rate: 8000,
+                    },
+                    markers: vec![],
+                })
+        })
+    }
+
+    /// Up to twice the longest arbitrary audio
+    fn arbitrary_duration() -> impl Strategy<Value = Duration> {
+        (0u64..200_000).prop_map(Duration::from_micros)
+    }
+
+    proptest! {
+        #[test]
+        fn clip_in_place_keeps_the_range_or_fails_untouched(
+            mut audio in arbitrary_audio(),
+            start in prop::option::of(arbitrary_duration()),
+            duration in prop::option::of(arbitrary_duration()),
+        ) {
+            let original = audio.clone();
+            let len = original.len();
+            let start_pos = original.resolve_start_sample_pos(start);
+            let end_pos = match duration {
+                Some(_) => original.resolve_end_sample_pos(start_pos, duration),
+                None => len.max(start_pos),
+            };
+            match audio.clip_in_place(start, duration) {
+                Ok(()) => {
+                    prop_assert!(end_pos <= len);
+                    prop_assert_eq!(audio.data.len(), original.data.len());
+                    for (clipped, channel) in audio.data.iter().zip(&original.data) {
+                        prop_assert_eq!(&clipped[..], &channel[start_pos..end_pos]);
+                    }
+                }
+                Err(e) => {
+                    prop_assert!(end_pos > len);
+                    prop_assert_eq!(
+                        e,
+                        AudioError::RangeOutOfBounds { start: start_pos, end: end_pos, len }
+                    );
+                    prop_assert_eq!(audio.data, original.data);
+                }
+            }
+        }
+
+        #[test]
+        fn fades_keep_the_length_and_never_amplify(
+            audio in arbitrary_audio(),
+            start in arbitrary_duration(),
+            dur in arbitrary_duration(),
+        ) {
+            let fits = audio.duration_to_sample(start) + audio.duration_to_sample(dur) <= audio.len();
+            let mut faded_in = audio.clone();
+            prop_assert_eq!(faded_in.fade_in(start, dur).is_ok(), fits);
+            let mut faded_out = audio.clone();
+            prop_assert_eq!(faded_out.fade_out(start, dur).is_ok(), fits);
+            for faded in [faded_in, faded_out] {
+                prop_assert_eq!(faded.data.len(), audio.data.len());
+                for (faded, channel) in faded.data.iter().zip(&audio.data) {
+                    prop_assert_eq!(faded.len(), channel.len());
+                    for (faded, sample) in faded.iter().zip(channel) {
+                        prop_assert!(faded.is_finite());
+                        prop_assert!(faded.abs() <= sample.abs());
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn interleaving_round_trips_any_audio(audio in arbitrary_audio()) {
+            let round_tripped = Audio::from_interleaved(&audio.spec, &audio.interleaved());
+            prop_assert_eq!(round_tripped.data, audio.data);
+        }
+    }
 }
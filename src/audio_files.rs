@@ -1,12 +1,14 @@
-use crate::audio::{Audio, AudioSpec, Sample};
-use anyhow::{anyhow, Result};
+use crate::audio::{Audio, AudioSlice, AudioSpec, Sample};
+use anyhow::{anyhow, bail, Result};
 use hound;
+#[cfg(feature = "native")]
 use minimp3;
 use std::collections::HashSet;
 use std::fs;
 use std::io::{self, Read, Seek, Write};
 use std::iter::FromIterator;
 use std::marker::Sized;
+use std::str::FromStr;
 
 pub trait AudioReader<R>: Iterator<Item = f32>
 where
@@ -45,6 +47,7 @@ where
         Audio {
             data: channels,
             spec: self.spec(),
+            markers: vec![],
         }
     }
 }
@@ -79,6 +82,17 @@ where
         }
         Ok(())
     }
+
+    /// Write the samples `audio` views, interleaved, without copying them first
+    fn write_slice(&mut self, audio: AudioSlice) -> Result<()> {
+        let channels: Vec<&[f32]> = audio.channels().collect();
+        for i in 0..audio.len() {
+            for channel in &channels {
+                self.write(channel[i])?;
+            }
+        }
+        Ok(())
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -236,6 +250,106 @@ impl WavWriter<io::BufWriter<fs::File>> {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// How headerless interleaved samples are encoded, named as ffmpeg and sox name them
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RawFormat {
+    F32le,
+    S16le,
+}
+
+impl FromStr for RawFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<RawFormat> {
+        match s {
+            "f32le" => Ok(RawFormat::F32le),
+            "s16le" => Ok(RawFormat::S16le),
+            _ => bail!("unknown raw sample format {:?}, expected f32le or s16le", s),
+        }
+    }
+}
+
+impl RawFormat {
+    fn sample_len(self) -> usize {
+        match self {
+            RawFormat::F32le => 4,
+            RawFormat::S16le => 2,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            RawFormat::F32le => f32::from_le_bytes(bytes.try_into().unwrap()),
+            RawFormat::S16le => f32::from_i16(i16::from_le_bytes(bytes.try_into().unwrap())),
+        }
+    }
+
+    /// Write one sample, clipping it to full scale if the format is an integer one
+    pub fn encode<W: Write>(self, sample: f32, writer: &mut W) -> io::Result<()> {
+        match self {
+            RawFormat::F32le => writer.write_all(&sample.to_le_bytes()),
+            RawFormat::S16le => {
+                let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                writer.write_all(&sample.to_le_bytes())
+            }
+        }
+    }
+}
+
+/// Reads headerless interleaved samples, e.g. piped from `ffmpeg -f f32le`
+///
+/// With no header, the spec and format have to be given up front.
+pub struct RawReader<R> {
+    pub spec: AudioSpec,
+    format: RawFormat,
+    underlier: R,
+}
+
+impl<R> RawReader<R>
+where
+    R: Read,
+{
+    pub fn new(reader: R, spec: AudioSpec, format: RawFormat) -> Self {
+        RawReader {
+            spec,
+            format,
+            underlier: reader,
+        }
+    }
+
+    /// Read until the end of the stream, dropping any incomplete frame at the end
+    pub fn read_all(&mut self) -> Result<Audio> {
+        let mut bytes = vec![];
+        self.underlier.read_to_end(&mut bytes)?;
+        let num_channels = self.spec.channels as usize;
+        let frame_len = self.format.sample_len() * num_channels;
+        let frames = bytes.chunks_exact(frame_len);
+        if !frames.remainder().is_empty() {
+            warn!(
+                "Ignoring {} bytes of raw input that don't make a whole frame",
+                frames.remainder().len()
+            );
+        }
+        let mut channels = vec![Vec::with_capacity(bytes.len() / frame_len); num_channels];
+        for frame in frames {
+            for (channel, sample) in channels
+                .iter_mut()
+                .zip(frame.chunks_exact(self.format.sample_len()))
+            {
+                channel.push(self.format.decode(sample));
+            }
+        }
+        Ok(Audio {
+            data: channels,
+            spec: self.spec,
+            markers: vec![],
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "native")]
 pub struct Mp3Reader<R> {
     pub spec: AudioSpec,
     underlier: minimp3::Decoder<R>,
@@ -243,6 +357,7 @@ pub struct Mp3Reader<R> {
     buffer_i: usize,
 }
 
+#[cfg(feature = "native")]
 impl<R> AudioReader<R> for Mp3Reader<R>
 where
     R: Read,
@@ -282,6 +397,7 @@ where
     }
 }
 
+#[cfg(feature = "native")]
 impl Mp3Reader<io::BufReader<fs::File>> {
     pub fn open(path: &str) -> Result<Self> {
         let file = fs::File::open(path)?;
@@ -290,6 +406,7 @@ impl Mp3Reader<io::BufReader<fs::File>> {
     }
 }
 
+#[cfg(feature = "native")]
 impl<R> Mp3Reader<R>
 where
     R: Read,
@@ -313,6 +430,7 @@ where
     }
 }
 
+#[cfg(feature = "native")]
 impl<R> Iterator for Mp3Reader<R>
 where
     R: Read,
@@ -323,3 +441,31 @@ where
         self.next_i16_sample().map(f32::from_i16)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn raw_samples_round_trip() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 8000,
+        };
+        let samples = [0.5, -0.25, 1.5, 0.0];
+        for format in [RawFormat::F32le, RawFormat::S16le] {
+            let mut bytes = vec![];
+            for sample in samples {
+                format.encode(sample, &mut bytes).unwrap();
+            }
+            // A stray byte from a truncated pipe is dropped
+            bytes.push(0);
+            let audio = RawReader::new(&bytes[..], spec, format).read_all().unwrap();
+            assert_eq!(audio.spec, spec);
+            let clipped = if format == RawFormat::S16le { 1.0 } else { 1.5 };
+            assert_almost_eq_by_element(audio.data[0].clone(), vec![0.5, clipped]);
+            assert_almost_eq_by_element(audio.data[1].clone(), vec![-0.25, 0.0]);
+        }
+    }
+}
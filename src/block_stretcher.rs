@@ -0,0 +1,349 @@
+//! Stretching live audio a block at a time from a host's audio callback, as
+//! an audio plugin has to
+//!
+//! The host hands `process` a block of each channel and gets back the same
+//! length of stretched audio. The stretching itself happens on a
+//! `StretcherProcessor`'s thread, with the same engines as the command line,
+//! and `process` only copies samples in and out of ring buffers, so it never
+//! locks, allocates or waits on the stretch.
+//!
+//! Stretching live input by more than 1 falls further and further behind it,
+//! so the input the stretch hasn't reached yet is queued without limit, as it
+//! is when the command line records from an input device. Freezing holds the
+//! current sound for as long as it's frozen.
+//!
+//! The output lags the input by a fixed `latency`, which hosts can report
+//! for their own delay compensation. The dry input can be mixed back in
+//! under the stretch, delayed by the same amount so the two stay in step
+//! when only the pitch is shifted.
+
+use crate::audio::{Audio, AudioBus, AudioSpec};
+use crate::ring_buffer::{ring_buffer, RingConsumer, RingProducer};
+use crate::runtime_setup;
+use crate::signal_flow::node::Node;
+use crate::slices;
+use crate::smoothing::SmoothedParam;
+use crate::stretch::{self, StretchOptions};
+use crate::stretch_engine::StretchEngine;
+use crate::stretcher_processor::{StretcherProcessor, StretcherProcessorControlMessage};
+use anyhow::Result;
+use crossbeam_channel::{Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+/// How much input can wait for the feeder thread before blocks are dropped
+const INPUT_QUEUE: Duration = Duration::from_millis(100);
+/// How much stretched output is kept ready for `process`, which is also the
+/// latency it adds beyond the stretch itself
+const OUTPUT_QUEUE: Duration = Duration::from_millis(20);
+const FEED_BLOCK_FRAMES: usize = 64;
+const FEED_POLL: Duration = Duration::from_millis(1);
+const DRY_LEVEL_RAMP: Duration = Duration::from_millis(50);
+
+/// Stretches audio handed over a block at a time, e.g. by a plugin host
+pub struct BlockStretcher {
+    spec: AudioSpec,
+    node: Node<StretcherProcessor, StretcherProcessorControlMessage>,
+    input: RingProducer,
+    output: RingConsumer,
+    /// Interleaved frames on their way in or out, sized for the largest block
+    scratch: Vec<f32>,
+    /// Frames by which the output lags the input
+    latency: usize,
+    /// Each channel's input, delayed by `latency`
+    dry: Vec<VecDeque<f32>>,
+    dry_level: SmoothedParam,
+}
+
+impl BlockStretcher {
+    /// Stretch each channel with its engine in `engines`, whose input is read
+    /// from the matching channel in `inputs`, in blocks of up to
+    /// `max_block_frames`
+    pub fn new(
+        engines: Vec<Box<dyn StretchEngine>>,
+        inputs: Vec<Sender<Vec<f32>>>,
+        max_block_frames: usize,
+    ) -> BlockStretcher {
+        assert_eq!(engines.len(), inputs.len());
+        let spec = engines[0].spec();
+        let n_channels = spec.channels as usize;
+        let block_len = max_block_frames * n_channels;
+        let frames =
+            |duration: Duration| (duration.as_secs_f32() * spec.sample_rate as f32) as usize;
+        let queue_len = |duration: Duration| (frames(duration) * n_channels).max(block_len * 2);
+        // The engines wait for their first window, input only arrives a block
+        // at a time, and the output queue keeps the stretch ahead of `process`
+        let latency = engines
+            .iter()
+            .map(|engine| engine.latency())
+            .max()
+            .unwrap_or(0)
+            + max_block_frames
+            + frames(OUTPUT_QUEUE);
+        let (input_tx, input_rx) = ring_buffer(queue_len(INPUT_QUEUE));
+        let (mut output_tx, output_rx) =
+            ring_buffer(queue_len(OUTPUT_QUEUE) + latency * n_channels);
+        // Silence to play while the stretch catches up, which fixes the lag
+        // at exactly `latency` for as long as it keeps up
+        output_tx.push(&vec![0.0; latency * n_channels]);
+        let (processor, bus) = StretcherProcessor::new(engines, None);
+        thread::spawn(move || feed(input_rx, inputs, bus, output_tx));
+        let mut dry = VecDeque::with_capacity(latency + max_block_frames);
+        dry.resize(latency, 0.0);
+        BlockStretcher {
+            spec,
+            node: Node::new(processor),
+            input: input_tx,
+            output: output_rx,
+            scratch: vec![0.0; block_len],
+            latency,
+            dry: vec![dry; n_channels],
+            dry_level: SmoothedParam::new(0.0).with_ramp(frames(DRY_LEVEL_RAMP)),
+        }
+    }
+
+    /// Stretch audio of `spec` with the same engines `stretch` would use
+    pub fn from_options(
+        spec: AudioSpec,
+        options: &StretchOptions,
+        max_block_frames: usize,
+    ) -> Result<BlockStretcher> {
+        options.check()?;
+        let seed = options.seed.unwrap_or_else(rand::random);
+        let buffer_dur = Duration::from_secs_f32(max_block_frames as f32 / spec.sample_rate as f32);
+        let (engines, inputs) = (0..spec.channels as u64)
+            .map(|i| stretch::build_engine(spec, options, seed.wrapping_add(i), buffer_dur))
+            .unzip();
+        Ok(BlockStretcher::new(engines, inputs, max_block_frames))
+    }
+
+    pub fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    /// How many frames the output lags the input, as a plugin reports to its
+    /// host
+    ///
+    /// This is fixed when the block stretcher is made. If the stretch ever
+    /// falls behind, the output slips further back and stays there.
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Take in a block of each channel in `channels`, and replace it with
+    /// the same length of stretched audio, returning how many frames of it
+    /// were ready
+    ///
+    /// Frames that weren't ready because the stretch fell behind are left
+    /// silent, but for any dry signal.
+    pub fn process(&mut self, channels: &mut [&mut [f32]]) -> usize {
+        let n_channels = self.spec.channels as usize;
+        assert_eq!(channels.len(), n_channels);
+        let frames = channels[0].len();
+        let scratch = &mut self.scratch[..frames * n_channels];
+        for (i, frame) in scratch.chunks_exact_mut(n_channels).enumerate() {
+            for (sample, channel) in frame.iter_mut().zip(channels.iter()) {
+                *sample = channel[i];
+            }
+        }
+        self.input.push(scratch);
+        for (dry, channel) in self.dry.iter_mut().zip(channels.iter()) {
+            dry.extend(channel.iter());
+        }
+        let ready = self.output.pop(scratch) / n_channels;
+        for (i, frame) in scratch[..ready * n_channels]
+            .chunks_exact(n_channels)
+            .enumerate()
+        {
+            for (sample, channel) in frame.iter().zip(channels.iter_mut()) {
+                channel[i] = *sample;
+            }
+        }
+        for channel in channels.iter_mut() {
+            slices::zero_slice(&mut channel[ready..]);
+        }
+        if self.dry_level.is_settled() && self.dry_level.current() == 0.0 {
+            for dry in &mut self.dry {
+                dry.drain(..frames);
+            }
+        } else {
+            for i in 0..frames {
+                let level = self.dry_level.next_value();
+                for (dry, channel) in self.dry.iter_mut().zip(channels.iter_mut()) {
+                    channel[i] += dry.pop_front().unwrap() * level;
+                }
+            }
+        }
+        ready
+    }
+
+    /// Mix the input in under the stretch at `level`, delayed by `latency`,
+    /// where 0, the default, leaves it out
+    pub fn set_dry_level(&mut self, level: f32) {
+        self.dry_level.set_target(level);
+    }
+
+    pub fn set_factor(&self, factor: f32) -> Result<()> {
+        self.node
+            .send_control_message(StretcherProcessorControlMessage::SetFactor { factor })
+    }
+
+    /// Hold the current sound, or let the stretch move on again
+    pub fn set_frozen(&self, frozen: bool) -> Result<()> {
+        self.node
+            .send_control_message(StretcherProcessorControlMessage::SetFrozen { frozen })
+    }
+
+    pub fn set_kernel_param(&self, name: &str, value: f32) -> Result<()> {
+        self.node
+            .send_control_message(StretcherProcessorControlMessage::SetKernelParam {
+                name: name.to_string(),
+                value,
+            })
+    }
+
+    pub fn set_kernel_stage_enabled(&self, stage: usize, enabled: bool) -> Result<()> {
+        self.node
+            .send_control_message(StretcherProcessorControlMessage::SetKernelStageEnabled {
+                stage,
+                enabled,
+            })
+    }
+}
+
+/// Pass input from `input` on to the engines' `inputs`, and windows from
+/// `windows` on to `output`, until the block stretcher is dropped
+///
+/// Returning drops the engines' inputs and the processor's outputs, which
+/// stops the processor too.
+fn feed(
+    mut input: RingConsumer,
+    inputs: Vec<Sender<Vec<f32>>>,
+    windows: AudioBus,
+    mut output: RingProducer,
+) {
+    runtime_setup::pin_processing_thread();
+    let n_channels = windows.spec.channels as usize;
+    let mut block = vec![0.0; FEED_BLOCK_FRAMES * n_channels];
+    let mut pending: Vec<VecDeque<f32>> = vec![VecDeque::new(); n_channels];
+    while !input.is_abandoned() && !output.is_abandoned() {
+        let mut idle = true;
+        loop {
+            let len = input.len().min(block.len());
+            // Whole frames only, so the channels stay aligned
+            let len = len - len % n_channels;
+            if len == 0 {
+                break;
+            }
+            input.pop(&mut block[..len]);
+            let audio = Audio::from_interleaved(&windows.spec, &block[..len]);
+            for (tx, channel) in inputs.iter().zip(audio.data) {
+                if tx.send(channel).is_err() {
+                    return;
+                }
+            }
+            idle = false;
+        }
+        for (pending, rx) in pending.iter_mut().zip(&windows.channels) {
+            if pending.is_empty() {
+                match rx.try_recv() {
+                    Ok(window) => pending.extend(window),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+        }
+        let frames = pending
+            .iter()
+            .map(|pending| pending.len())
+            .min()
+            .unwrap_or(0)
+            .min(output.free_len() / n_channels)
+            .min(FEED_BLOCK_FRAMES);
+        if frames > 0 {
+            for frame in block[..frames * n_channels].chunks_exact_mut(n_channels) {
+                for (sample, pending) in frame.iter_mut().zip(pending.iter_mut()) {
+                    *sample = pending.pop_front().unwrap();
+                }
+            }
+            output.push(&block[..frames * n_channels]);
+            idle = false;
+        }
+        if idle {
+            thread::sleep(FEED_POLL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stretch_engine::EngineKind;
+    use std::time::Instant;
+
+    #[test]
+    fn stretches_blocks_as_they_come() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 8000,
+        };
+        let options = StretchOptions {
+            factor: 2.0,
+            engine: EngineKind::Wsola,
+            seed: Some(1),
+            ..StretchOptions::default()
+        };
+        let mut stretcher = BlockStretcher::from_options(spec, &options, 128).unwrap();
+        let latency = stretcher.latency();
+        let mut output = vec![];
+        let started = Instant::now();
+        while output.len() < latency + 4000 && started.elapsed() < Duration::from_secs(10) {
+            let mut left = [0.5; 128];
+            let mut right = [-0.5; 128];
+            let ready = stretcher.process(&mut [&mut left, &mut right]);
+            assert!(left[ready..].iter().all(|sample| *sample == 0.0));
+            output.extend(
+                left[..ready]
+                    .iter()
+                    .copied()
+                    .zip(right[..ready].iter().copied()),
+            );
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert!(output.len() >= latency + 4000);
+        assert!(output[..latency]
+            .iter()
+            .all(|(left, right)| *left == 0.0 && *right == 0.0));
+        // Past the fade in of the first frame, the channels come through apart
+        for (left, right) in &output[latency + 1000..] {
+            assert!((left - 0.5).abs() < 0.01 && (right + 0.5).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn dry_input_is_delayed_by_the_latency() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 8000,
+        };
+        // Silence the stretch, leaving only the dry signal
+        let options = StretchOptions {
+            amplitude: 0.0,
+            engine: EngineKind::Wsola,
+            ..StretchOptions::default()
+        };
+        let mut stretcher = BlockStretcher::from_options(spec, &options, 64).unwrap();
+        stretcher.set_dry_level(1.0);
+        let latency = stretcher.latency();
+        let input: Vec<f32> = (0..latency + 1600).map(|i| i as f32).collect();
+        let mut output = vec![];
+        for block in input.chunks(64) {
+            let mut block = block.to_vec();
+            stretcher.process(&mut [&mut block]);
+            output.extend(block);
+        }
+        // Once the level has ramped up over 50 ms
+        assert_eq!(output[latency + 400..], input[400..1600]);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::kernel::{FrameInfo, Kernel};
+use anyhow::{bail, Result};
+use rustfft::num_complex::Complex32;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// Frequency kernels that ship with the rocoder, selectable by name
+///
+/// Each only edits the positive frequencies and mirrors them onto the
+/// negative ones, and reads its settings from kernel params.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BuiltinKernel {
+    /// Keep only bins that are the loudest among their neighbours
+    Thin,
+    /// Smear each bin's magnitude over time
+    Blur,
+    /// Boost the harmonics of the loudest bin
+    Harmonic,
+    /// Flatten the spectrum into bands of noise, removing pitch
+    Whisper,
+    /// Move every bin up or down by a fixed frequency
+    Shift,
+    /// Average each bin's magnitude over the last few windows
+    Smear,
+}
+
+impl BuiltinKernel {
+    pub const ALL: [BuiltinKernel; 6] = [
+        BuiltinKernel::Thin,
+        BuiltinKernel::Blur,
+        BuiltinKernel::Harmonic,
+        BuiltinKernel::Whisper,
+        BuiltinKernel::Shift,
+        BuiltinKernel::Smear,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinKernel::Thin => "thin",
+            BuiltinKernel::Blur => "blur",
+            BuiltinKernel::Harmonic => "harmonic",
+            BuiltinKernel::Whisper => "whisper",
+            BuiltinKernel::Shift => "shift",
+            BuiltinKernel::Smear => "smear",
+        }
+    }
+
+    pub fn create(self) -> Box<dyn Kernel> {
+        match self {
+            BuiltinKernel::Thin => Box::new(Thin),
+            BuiltinKernel::Blur => Box::new(Blur { magnitudes: vec![] }),
+            BuiltinKernel::Harmonic => Box::new(Harmonic),
+            BuiltinKernel::Whisper => Box::new(Whisper),
+            BuiltinKernel::Shift => Box::new(Shift),
+            BuiltinKernel::Smear => Box::new(Smear {
+                history: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl FromStr for BuiltinKernel {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<BuiltinKernel> {
+        match BuiltinKernel::ALL
+            .iter()
+            .find(|kernel| kernel.name() == name)
+        {
+            Some(kernel) => Ok(*kernel),
+            None => bail!(
+                "unknown kernel {:?}, expected one of: {}",
+                name,
+                BuiltinKernel::ALL.map(|kernel| kernel.name()).join(", ")
+            ),
+        }
+    }
+}
+
+struct Thin;
+
+impl Kernel for Thin {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let radius = info.param("thin_radius").unwrap_or(4.0).max(1.0) as usize;
+        let magnitudes = positive_magnitudes(bins);
+        for (i, bin) in bins[..magnitudes.len()].iter_mut().enumerate() {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(magnitudes.len());
+            if magnitudes[start..end].iter().any(|m| *m > magnitudes[i]) {
+                *bin = Complex32::new(0.0, 0.0);
+            }
+        }
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+struct Blur {
+    magnitudes: Vec<f32>,
+}
+
+impl Kernel for Blur {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let amount = info.param("blur_amount").unwrap_or(0.9).clamp(0.0, 1.0);
+        let magnitudes = positive_magnitudes(bins);
+        if self.magnitudes.len() != magnitudes.len() {
+            self.magnitudes = magnitudes.clone();
+        }
+        for (i, magnitude) in magnitudes.iter().enumerate() {
+            let blurred = self.magnitudes[i] * amount + magnitude * (1.0 - amount);
+            self.magnitudes[i] = blurred;
+            set_magnitude(&mut bins[i], blurred);
+        }
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+/// Unlike `Blur`, which decays forever, this averages a fixed number of
+/// windows evenly, so a sound is smeared for a set time and then lets go.
+/// The stretcher randomizes every bin's phase afterwards, so the averaged
+/// magnitudes come out as a wash rather than an echo.
+struct Smear {
+    /// The positive magnitudes of the most recent windows, newest last
+    history: VecDeque<Vec<f32>>,
+}
+
+impl Kernel for Smear {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let amount = info.param("smear_amount").unwrap_or(0.5).clamp(0.0, 1.0);
+        let frames = info.param("smear_frames").unwrap_or(8.0).max(1.0) as usize;
+        let magnitudes = positive_magnitudes(bins);
+        if self
+            .history
+            .front()
+            .is_some_and(|frame| frame.len() != magnitudes.len())
+        {
+            self.history.clear();
+        }
+        while self.history.len() >= frames {
+            self.history.pop_front();
+        }
+        self.history.push_back(magnitudes.clone());
+        for (i, magnitude) in magnitudes.iter().enumerate() {
+            let mean =
+                self.history.iter().map(|frame| frame[i]).sum::<f32>() / self.history.len() as f32;
+            set_magnitude(&mut bins[i], mean * amount + magnitude * (1.0 - amount));
+        }
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+struct Harmonic;
+
+impl Kernel for Harmonic {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let gain = info.param("harmonic_gain").unwrap_or(2.0);
+        let magnitudes = positive_magnitudes(bins);
+        // skip DC, which isn't a pitch
+        let fundamental = (1..magnitudes.len())
+            .max_by(|a, b| magnitudes[*a].total_cmp(&magnitudes[*b]))
+            .filter(|i| magnitudes[*i] > 0.0);
+        if let Some(fundamental) = fundamental {
+            // boost the bins either side of each harmonic too, since they rarely land exactly
+            let mut boosted = vec![false; magnitudes.len()];
+            for harmonic in (fundamental..magnitudes.len()).step_by(fundamental) {
+                let end = (harmonic + 2).min(magnitudes.len());
+                boosted[harmonic - 1..end].fill(true);
+            }
+            for (bin, boosted) in bins.iter_mut().zip(boosted) {
+                if boosted {
+                    *bin *= gain;
+                }
+            }
+        }
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+struct Whisper;
+
+impl Kernel for Whisper {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let band_hz = info.param("whisper_band_hz").unwrap_or(200.0);
+        let radius = hz_to_bins(band_hz / 2.0, info).unsigned_abs().max(1);
+        let magnitudes = positive_magnitudes(bins);
+        let mut sums = vec![0.0; magnitudes.len() + 1];
+        for (i, magnitude) in magnitudes.iter().enumerate() {
+            sums[i + 1] = sums[i] + magnitude;
+        }
+        for (i, bin) in bins[..magnitudes.len()].iter_mut().enumerate() {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(magnitudes.len());
+            set_magnitude(bin, (sums[end] - sums[start]) / (end - start) as f32);
+        }
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+struct Shift;
+
+impl Kernel for Shift {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let offset = hz_to_bins(info.param("shift_hz").unwrap_or(100.0), info);
+        let n_positive = positive_len(bins);
+        let shifted: Vec<Complex32> = (0..n_positive as isize)
+            .map(|i| match usize::try_from(i - offset) {
+                Ok(source) if source < n_positive => bins[source],
+                _ => Complex32::new(0.0, 0.0),
+            })
+            .collect();
+        bins[..n_positive].copy_from_slice(&shifted);
+        mirror_positive_frequencies(bins);
+        Ok(())
+    }
+}
+
+/// Number of bins from DC up to and including the Nyquist frequency
+fn positive_len(bins: &[Complex32]) -> usize {
+    bins.len() / 2 + 1
+}
+
+fn positive_magnitudes(bins: &[Complex32]) -> Vec<f32> {
+    bins[..positive_len(bins)]
+        .iter()
+        .map(|c| c.norm())
+        .collect()
+}
+
+/// Overwrite the negative frequencies with the conjugates of the positive ones
+fn mirror_positive_frequencies(bins: &mut [Complex32]) {
+    let len = bins.len();
+    for i in 1..len.div_ceil(2) {
+        bins[len - i] = bins[i].conj();
+    }
+}
+
+fn set_magnitude(bin: &mut Complex32, magnitude: f32) {
+    *bin = Complex32::from_polar(magnitude, bin.arg());
+}
+
+fn hz_to_bins(hz: f32, info: FrameInfo<'_>) -> isize {
+    (hz * info.window_len as f32 / info.sample_rate as f32).round() as isize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kernel::KernelParam;
+    use crate::test_utils::*;
+
+    fn info(params: &[KernelParam]) -> FrameInfo<'_> {
+        FrameInfo {
+            sample_rate: 800,
+            window_len: 8,
+            channel: 0,
+            elapsed_ms: 0,
+            params,
+        }
+    }
+
+    /// Build a full spectrum from its positive magnitudes
+    fn spectrum(positive: &[f32]) -> Vec<Complex32> {
+        let mut bins: Vec<Complex32> = positive.iter().map(|m| Complex32::new(*m, 0.0)).collect();
+        bins.resize((positive.len() - 1) * 2, Complex32::new(0.0, 0.0));
+        mirror_positive_frequencies(&mut bins);
+        bins
+    }
+
+    fn run(kernel: BuiltinKernel, params: &[KernelParam], positive: &[f32]) -> Vec<f32> {
+        let mut bins = spectrum(positive);
+        kernel.create().apply(&mut bins, info(params)).unwrap();
+        bins.iter().map(|c| c.norm()).collect()
+    }
+
+    #[test]
+    fn parse_names() {
+        for kernel in BuiltinKernel::ALL {
+            assert_eq!(kernel.name().parse::<BuiltinKernel>().unwrap(), kernel);
+        }
+        assert!("nope".parse::<BuiltinKernel>().is_err());
+    }
+
+    #[test]
+    fn mirror_keeps_conjugate_symmetry() {
+        let mut bins = vec![Complex32::new(1.0, 2.0); 4];
+        mirror_positive_frequencies(&mut bins);
+        assert_eq!(bins[3], Complex32::new(1.0, -2.0));
+        assert_eq!(bins[2], Complex32::new(1.0, 2.0));
+    }
+
+    #[test]
+    fn thin_keeps_peaks() {
+        let params = [KernelParam::new("thin_radius", 1.0)];
+        assert_almost_eq_by_element(
+            run(BuiltinKernel::Thin, &params, &[0.0, 3.0, 1.0, 2.0, 1.0]),
+            vec![0.0, 3.0, 0.0, 2.0, 0.0, 2.0, 0.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn blur_smears_over_time() {
+        let params = [KernelParam::new("blur_amount", 0.5)];
+        let mut kernel = BuiltinKernel::Blur.create();
+        let mut bins = spectrum(&[2.0, 2.0, 2.0, 2.0, 2.0]);
+        kernel.apply(&mut bins, info(&params)).unwrap();
+        let mut bins = spectrum(&[0.0, 4.0, 0.0, 0.0, 0.0]);
+        kernel.apply(&mut bins, info(&params)).unwrap();
+        assert_almost_eq_by_element(
+            bins.iter().map(|c| c.norm()).collect(),
+            vec![1.0, 3.0, 1.0, 1.0, 1.0, 1.0, 1.0, 3.0],
+        );
+    }
+
+    #[test]
+    fn smear_averages_recent_windows() {
+        let params = [
+            KernelParam::new("smear_amount", 1.0),
+            KernelParam::new("smear_frames", 2.0),
+        ];
+        let mut kernel = BuiltinKernel::Smear.create();
+        let mut smeared = vec![];
+        for loud in [4.0, 0.0, 0.0] {
+            let mut bins = spectrum(&[0.0, loud, 0.0, 0.0, 0.0]);
+            kernel.apply(&mut bins, info(&params)).unwrap();
+            smeared.push(bins[1].norm());
+        }
+        // The loud window is held for as many windows as are averaged, then gone
+        assert_almost_eq_by_element(smeared, vec![4.0, 2.0, 0.0]);
+
+        let params = [
+            KernelParam::new("smear_amount", 0.5),
+            KernelParam::new("smear_frames", 2.0),
+        ];
+        let mut kernel = BuiltinKernel::Smear.create();
+        let mut bins = spectrum(&[0.0, 4.0, 0.0, 0.0, 0.0]);
+        kernel.apply(&mut bins, info(&params)).unwrap();
+        let mut bins = spectrum(&[0.0, 0.0, 0.0, 0.0, 0.0]);
+        kernel.apply(&mut bins, info(&params)).unwrap();
+        assert_almost_eq(bins[1].norm(), 1.0);
+        assert_almost_eq(bins[7].norm(), 1.0);
+    }
+
+    #[test]
+    fn harmonic_boosts_multiples_of_loudest_bin() {
+        let mut positive = vec![1.0; 9];
+        positive[2] = 4.0;
+        let mut bins = spectrum(&positive);
+        BuiltinKernel::Harmonic
+            .create()
+            .apply(&mut bins, info(&[]))
+            .unwrap();
+        let magnitudes: Vec<f32> = bins.iter().take(9).map(|c| c.norm()).collect();
+        assert_almost_eq_by_element(
+            magnitudes,
+            vec![1.0, 2.0, 8.0, 2.0, 2.0, 2.0, 2.0, 2.0, 2.0],
+        );
+    }
+
+    #[test]
+    fn whisper_averages_bands() {
+        let params = [KernelParam::new("whisper_band_hz", 200.0)];
+        assert_almost_eq_by_element(
+            run(BuiltinKernel::Whisper, &params, &[0.0, 3.0, 0.0, 0.0, 6.0]),
+            vec![1.5, 1.0, 1.0, 2.0, 3.0, 2.0, 1.0, 1.0],
+        );
+    }
+
+    #[test]
+    fn shift_moves_bins() {
+        let params = [KernelParam::new("shift_hz", 100.0)];
+        assert_almost_eq_by_element(
+            run(BuiltinKernel::Shift, &params, &[1.0, 2.0, 3.0, 4.0, 5.0]),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 3.0, 2.0, 1.0],
+        );
+        let params = [KernelParam::new("shift_hz", -100.0)];
+        assert_almost_eq_by_element(
+            run(BuiltinKernel::Shift, &params, &[1.0, 2.0, 3.0, 4.0, 5.0]),
+            vec![2.0, 3.0, 4.0, 5.0, 0.0, 5.0, 4.0, 3.0],
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! C bindings for `stretch`, so C and C++ hosts, Python through ctypes and
+//! game engines can stretch audio without going through Rust
+//!
+//! Enabled by the `capi` feature. `include/rocoder.h` declares everything
+//! here for C; build a shared or static library with e.g.
+//! `cargo rustc --release --lib --features capi --crate-type cdylib`.
+//!
+//! Samples are interleaved, and the caller owns every buffer, so nothing
+//! allocated on one side of the boundary is freed on the other.
+
+use crate::audio::{Audio, AudioSpec};
+use crate::stretch::{stretch, StretchOptions};
+use crate::stretch_engine::EngineKind;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::time::Duration;
+
+/// Bumped whenever `RocoderOptions` or a function's signature changes
+pub const CAPI_ABI_VERSION: u32 = 1;
+
+pub const ROCODER_OK: i32 = 0;
+/// A null pointer, zero channels or sample rate, an unknown engine, an
+/// option out of range, or an output buffer of the wrong size
+pub const ROCODER_ERROR_INVALID_ARGUMENT: i32 = -1;
+/// Stretching failed unexpectedly
+pub const ROCODER_ERROR_INTERNAL: i32 = -2;
+
+pub const ROCODER_ENGINE_VOCODER: u32 = 0;
+pub const ROCODER_ENGINE_GRANULAR: u32 = 1;
+pub const ROCODER_ENGINE_WSOLA: u32 = 2;
+pub const ROCODER_ENGINE_AUTO: u32 = 3;
+
+/// `StretchOptions` for C, with plain types throughout
+///
+/// Any change to this layout must bump `CAPI_ABI_VERSION` and be copied to
+/// `include/rocoder.h`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct RocoderOptions {
+    pub factor: f32,
+    /// From -128 to 127, but not 0
+    pub pitch_multiple: i32,
+    pub amplitude: f32,
+    /// One of the `ROCODER_ENGINE_*` constants
+    pub engine: u32,
+    pub window_len: u32,
+    pub grain_seconds: f32,
+    pub grain_jitter: f32,
+    pub grain_density: f32,
+    /// Only used if `seeded` isn't 0
+    pub seed: u64,
+    pub seeded: u32,
+}
+
+impl From<&StretchOptions> for RocoderOptions {
+    fn from(options: &StretchOptions) -> Self {
+        RocoderOptions {
+            factor: options.factor,
+            pitch_multiple: options.pitch_multiple as i32,
+            amplitude: options.amplitude,
+            engine: match options.engine {
+                EngineKind::Vocoder => ROCODER_ENGINE_VOCODER,
+                EngineKind::Granular => ROCODER_ENGINE_GRANULAR,
+                EngineKind::Wsola => ROCODER_ENGINE_WSOLA,
+                EngineKind::Auto => ROCODER_ENGINE_AUTO,
+            },
+            window_len: options.window_len as u32,
+            grain_seconds: options.grain.as_secs_f32(),
+            grain_jitter: options.grain_jitter,
+            grain_density: options.grain_density,
+            seed: options.seed.unwrap_or(0),
+            seeded: options.seed.is_some() as u32,
+        }
+    }
+}
+
+impl RocoderOptions {
+    /// The options as `stretch` takes them, if they're all in range
+    fn to_stretch_options(self) -> Option<StretchOptions> {
+        let engine = match self.engine {
+            ROCODER_ENGINE_VOCODER => EngineKind::Vocoder,
+            ROCODER_ENGINE_GRANULAR => EngineKind::Granular,
+            ROCODER_ENGINE_WSOLA => EngineKind::Wsola,
+            ROCODER_ENGINE_AUTO => EngineKind::Auto,
+            _ => return None,
+        };
+        let options = StretchOptions {
+            factor: self.factor,
+            pitch_multiple: i8::try_from(self.pitch_multiple).ok()?,
+            amplitude: self.amplitude,
+            engine,
+            window_len: self.window_len as usize,
+            grain: Duration::try_from_secs_f32(self.grain_seconds).ok()?,
+            grain_jitter: self.grain_jitter,
+            grain_density: self.grain_density,
+            seed: (self.seeded != 0).then_some(self.seed),
+        };
+        options.check().is_ok().then_some(options)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rocoder_abi_version() -> u32 {
+    CAPI_ABI_VERSION
+}
+
+/// The same defaults as the command line
+#[no_mangle]
+pub extern "C" fn rocoder_default_options() -> RocoderOptions {
+    RocoderOptions::from(&StretchOptions::default())
+}
+
+/// How many frames `rocoder_stretch` writes for `frames` of input
+#[no_mangle]
+pub extern "C" fn rocoder_stretched_frames(frames: usize, factor: f32) -> usize {
+    (frames as f64 * factor as f64).round() as usize
+}
+
+/// Stretch `frames` frames of interleaved audio from `input` into `output`,
+/// which must hold exactly `rocoder_stretched_frames(frames, factor)`
+/// frames, returning `ROCODER_OK` or an error code
+///
+/// # Safety
+///
+/// `input` must point to `frames * channels` floats, `output` to
+/// `output_frames * channels` floats, and `options` to a `RocoderOptions`.
+#[no_mangle]
+pub unsafe extern "C" fn rocoder_stretch(
+    input: *const f32,
+    frames: usize,
+    channels: u16,
+    sample_rate: u32,
+    options: *const RocoderOptions,
+    output: *mut f32,
+    output_frames: usize,
+) -> i32 {
+    if input.is_null() || output.is_null() || options.is_null() {
+        return ROCODER_ERROR_INVALID_ARGUMENT;
+    }
+    let options = match (*options).to_stretch_options() {
+        Some(options) => options,
+        None => return ROCODER_ERROR_INVALID_ARGUMENT,
+    };
+    if channels == 0
+        || sample_rate == 0
+        || output_frames != rocoder_stretched_frames(frames, options.factor)
+    {
+        return ROCODER_ERROR_INVALID_ARGUMENT;
+    }
+    let input = slice::from_raw_parts(input, frames * channels as usize);
+    let output = slice::from_raw_parts_mut(output, output_frames * channels as usize);
+    // Unwinding into C is undefined, so panics become an error code
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let spec = AudioSpec {
+            channels,
+            sample_rate,
+        };
+        stretch(&Audio::from_interleaved(&spec, input), &options)
+            .map(|stretched| output.copy_from_slice(&stretched.interleaved()))
+    }));
+    match result {
+        Ok(Ok(())) => ROCODER_OK,
+        // Only options that fail their checks are turned away
+        Ok(Err(_)) => ROCODER_ERROR_INVALID_ARGUMENT,
+        Err(_) => ROCODER_ERROR_INTERNAL,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn stretches_interleaved_audio() {
+        let input: Vec<f32> = (0..8000).flat_map(|_| [0.5, -0.5]).collect();
+        let mut options = rocoder_default_options();
+        options.factor = 1.5;
+        options.engine = ROCODER_ENGINE_WSOLA;
+        let output_frames = rocoder_stretched_frames(8000, options.factor);
+        assert_eq!(output_frames, 12000);
+        let mut output = vec![0.0; output_frames * 2];
+        let result = unsafe {
+            rocoder_stretch(
+                input.as_ptr(),
+                8000,
+                2,
+                8000,
+                &options,
+                output.as_mut_ptr(),
+                output_frames,
+            )
+        };
+        assert_eq!(result, ROCODER_OK);
+        // The channels stay apart
+        assert!((output[4000] - 0.5).abs() < 0.01);
+        assert!((output[4001] + 0.5).abs() < 0.01);
+
+        let stretch_into = |options: &RocoderOptions, output: &mut [f32], frames| unsafe {
+            rocoder_stretch(
+                input.as_ptr(),
+                8000,
+                2,
+                8000,
+                options,
+                output.as_mut_ptr(),
+                frames,
+            )
+        };
+        assert_eq!(
+            stretch_into(&options, &mut output, output_frames - 1),
+            ROCODER_ERROR_INVALID_ARGUMENT
+        );
+        options.pitch_multiple = 0;
+        assert_eq!(
+            stretch_into(&options, &mut output, output_frames),
+            ROCODER_ERROR_INVALID_ARGUMENT
+        );
+        let null =
+            unsafe { rocoder_stretch(ptr::null(), 0, 2, 8000, &options, output.as_mut_ptr(), 0) };
+        assert_eq!(null, ROCODER_ERROR_INVALID_ARGUMENT);
+    }
+
+    #[test]
+    fn header_declares_every_function() {
+        let header = include_str!("../include/rocoder.h");
+        let source = include_str!("capi.rs");
+        let functions: Vec<&str> = source
+            .lines()
+            .filter_map(|line| line.split(" extern \"C\" fn ").nth(1))
+            .filter_map(|rest| rest.split('(').next())
+            .filter(|name| name.starts_with("rocoder_"))
+            .collect();
+        assert_eq!(functions.len(), 4);
+        for function in functions {
+            assert!(header.contains(&format!(" {}(", function)), "{}", function);
+        }
+        assert!(header.contains(&format!("ROCODER_ABI_VERSION {}", CAPI_ABI_VERSION)));
+    }
+}
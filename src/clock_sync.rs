@@ -0,0 +1,370 @@
+//! Starting and stopping several rocoders together, e.g. one per room of an
+//! installation
+//!
+//! One instance leads and the others follow. Each follower estimates the
+//! offset between its clock and the leader's from the round trip of a few
+//! pings, NTP style. The leader then sends triggers stamped with when they
+//! should take effect on its own clock, which followers convert to theirs.
+//! Everything goes over UDP, and triggers are sent a few times over in case
+//! some are lost.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Pings each follower sends when joining, keeping the one with the quickest round trip
+const PINGS: usize = 8;
+const PONG_TIMEOUT: Duration = Duration::from_millis(500);
+/// Copies of each trigger sent, spaced out a little so one burst of loss doesn't drop them all
+const TRIGGER_REPEATS: usize = 3;
+const TRIGGER_REPEAT_SPACING: Duration = Duration::from_millis(5);
+const FOLLOWER_POLL: Duration = Duration::from_millis(50);
+const MAX_PACKET_LEN: usize = 64;
+
+/// What the leader asks its followers to do
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    /// Start playing
+    Start,
+    /// Fade out and quit
+    Stop,
+}
+
+/// Times are microseconds since the Unix epoch on the sender's clock
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Packet {
+    Ping {
+        sent: i64,
+    },
+    Pong {
+        ping_sent: i64,
+        received: i64,
+        sent: i64,
+    },
+    Trigger {
+        seq: u32,
+        at: i64,
+        trigger: Trigger,
+    },
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Packet::Ping { sent } => {
+                buf.push(0);
+                buf.extend(sent.to_le_bytes());
+            }
+            Packet::Pong {
+                ping_sent,
+                received,
+                sent,
+            } => {
+                buf.push(1);
+                buf.extend(ping_sent.to_le_bytes());
+                buf.extend(received.to_le_bytes());
+                buf.extend(sent.to_le_bytes());
+            }
+            Packet::Trigger { seq, at, trigger } => {
+                buf.push(2);
+                buf.extend(seq.to_le_bytes());
+                buf.extend(at.to_le_bytes());
+                buf.push(match trigger {
+                    Trigger::Start => 0,
+                    Trigger::Stop => 1,
+                });
+            }
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Packet> {
+        let i64_at = |pos: usize| -> Result<i64> {
+            match buf.get(pos..pos + 8) {
+                Some(bytes) => Ok(i64::from_le_bytes(bytes.try_into().unwrap())),
+                None => bail!("packet too short"),
+            }
+        };
+        match buf.first() {
+            Some(0) => Ok(Packet::Ping { sent: i64_at(1)? }),
+            Some(1) => Ok(Packet::Pong {
+                ping_sent: i64_at(1)?,
+                received: i64_at(9)?,
+                sent: i64_at(17)?,
+            }),
+            Some(2) => {
+                let seq = match buf.get(1..5) {
+                    Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+                    None => bail!("packet too short"),
+                };
+                let trigger = match buf.get(13) {
+                    Some(0) => Trigger::Start,
+                    Some(1) => Trigger::Stop,
+                    other => bail!("unknown trigger {:?}", other),
+                };
+                Ok(Packet::Trigger {
+                    seq,
+                    at: i64_at(5)?,
+                    trigger,
+                })
+            }
+            other => bail!("unknown packet type {:?}", other),
+        }
+    }
+}
+
+fn now_micros() -> i64 {
+    to_micros(SystemTime::now())
+}
+
+fn to_micros(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+fn from_micros(micros: i64) -> SystemTime {
+    if micros >= 0 {
+        UNIX_EPOCH + Duration::from_micros(micros as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_micros(micros.unsigned_abs())
+    }
+}
+
+/// Sleep until `time` on this machine's clock, returning at once if it has passed
+pub fn sleep_until(time: SystemTime) {
+    if let Ok(remaining) = time.duration_since(SystemTime::now()) {
+        thread::sleep(remaining);
+    }
+}
+
+/// Answers followers' pings and sends them triggers
+pub struct Leader {
+    socket: UdpSocket,
+    followers: Arc<Mutex<HashSet<SocketAddr>>>,
+    next_seq: u32,
+}
+
+impl Leader {
+    pub fn bind(addr: SocketAddr) -> Result<Leader> {
+        let socket =
+            UdpSocket::bind(addr).with_context(|| format!("failed to listen on {}", addr))?;
+        let followers = Arc::new(Mutex::new(HashSet::new()));
+        let responder = socket.try_clone()?;
+        let joined = Arc::clone(&followers);
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET_LEN];
+            loop {
+                let (len, from) = match responder.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!("Sync leader stopped answering pings: {}", e);
+                        return;
+                    }
+                };
+                let received = now_micros();
+                match Packet::decode(&buf[..len]) {
+                    Ok(Packet::Ping { sent }) => {
+                        let pong = Packet::Pong {
+                            ping_sent: sent,
+                            received,
+                            sent: now_micros(),
+                        };
+                        if let Err(e) = responder.send_to(&pong.encode(), from) {
+                            warn!("Failed to answer a ping from {}: {}", from, e);
+                        } else if joined.lock().unwrap().insert(from) {
+                            info!("Follower {} joined", from);
+                        }
+                    }
+                    Ok(packet) => warn!("Ignoring unexpected {:?} from {}", packet, from),
+                    Err(e) => warn!("Ignoring sync packet from {}: {}", from, e),
+                }
+            }
+        });
+        Ok(Leader {
+            socket,
+            followers,
+            next_seq: 0,
+        })
+    }
+
+    pub fn followers(&self) -> usize {
+        self.followers.lock().unwrap().len()
+    }
+
+    /// Block until at least `count` followers have joined
+    pub fn wait_for_followers(&self, count: usize) {
+        while self.followers() < count {
+            thread::sleep(FOLLOWER_POLL);
+        }
+    }
+
+    /// Tell every follower that has joined to act on `trigger` at `at` on this machine's clock
+    pub fn send(&mut self, trigger: Trigger, at: SystemTime) -> Result<()> {
+        let packet = Packet::Trigger {
+            seq: self.next_seq,
+            at: to_micros(at),
+            trigger,
+        }
+        .encode();
+        self.next_seq += 1;
+        let followers: Vec<SocketAddr> = self.followers.lock().unwrap().iter().copied().collect();
+        for repeat in 0..TRIGGER_REPEATS {
+            if repeat > 0 {
+                thread::sleep(TRIGGER_REPEAT_SPACING);
+            }
+            for follower in &followers {
+                self.socket.send_to(&packet, follower)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Receives triggers from a leader, on a clock estimated to match the leader's
+pub struct Follower {
+    socket: UdpSocket,
+    /// Microseconds to add to this machine's clock to get the leader's
+    offset: i64,
+    last_seq: Option<u32>,
+}
+
+impl Follower {
+    /// Join the leader at `leader` and estimate how far its clock is from ours
+    pub fn join(leader: SocketAddr) -> Result<Follower> {
+        let local: SocketAddr = if leader.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(leader)?;
+        socket.set_read_timeout(Some(PONG_TIMEOUT))?;
+        let mut best: Option<(i64, i64)> = None;
+        let mut buf = [0; MAX_PACKET_LEN];
+        for _ in 0..PINGS {
+            let sent = now_micros();
+            socket.send(&Packet::Ping { sent }.encode())?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let returned = now_micros();
+            if let Ok(Packet::Pong {
+                ping_sent,
+                received,
+                sent: replied,
+            }) = Packet::decode(&buf[..len])
+            {
+                // A late pong to an earlier ping would skew the estimate
+                if ping_sent != sent {
+                    continue;
+                }
+                let round_trip = (returned - sent) - (replied - received);
+                let offset = ((received - sent) + (replied - returned)) / 2;
+                if best.is_none_or(|(best_round_trip, _)| round_trip < best_round_trip) {
+                    best = Some((round_trip, offset));
+                }
+            }
+        }
+        let (round_trip, offset) = match best {
+            Some(best) => best,
+            None => bail!("no answer from a sync leader at {}", leader),
+        };
+        info!(
+            "Joined sync leader {}, clock offset {:.1} ms, round trip {:.1} ms",
+            leader,
+            offset as f64 / 1000.0,
+            round_trip as f64 / 1000.0
+        );
+        socket.set_read_timeout(None)?;
+        Ok(Follower {
+            socket,
+            offset,
+            last_seq: None,
+        })
+    }
+
+    /// Wait for the next trigger, returning when it should take effect on this machine's clock
+    pub fn next_trigger(&mut self) -> Result<(Trigger, SystemTime)> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            match Packet::decode(&buf[..len]) {
+                Ok(Packet::Trigger { seq, at, trigger }) => {
+                    // Skip the repeats of triggers already seen
+                    if self.last_seq.is_some_and(|last| seq <= last) {
+                        continue;
+                    }
+                    self.last_seq = Some(seq);
+                    return Ok((trigger, from_micros(at - self.offset)));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Ignoring sync packet: {}", e),
+            }
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        for packet in [
+            Packet::Ping { sent: -5 },
+            Packet::Pong {
+                ping_sent: 1,
+                received: 2,
+                sent: 3,
+            },
+            Packet::Trigger {
+                seq: 7,
+                at: 1_700_000_000_000_000,
+                trigger: Trigger::Stop,
+            },
+        ] {
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+        assert!(Packet::decode(&[1, 2, 3]).is_err());
+        assert!(Packet::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn followers_get_triggers_on_their_own_clock() {
+        let mut leader = Leader::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = leader.socket.local_addr().unwrap();
+        let mut follower = Follower::join(addr).unwrap();
+        // Both ends share a clock here, so the offset is only measurement error
+        assert!(follower.offset.abs() < 10_000, "{}", follower.offset);
+        leader.wait_for_followers(1);
+
+        let start_at = SystemTime::now() + Duration::from_millis(100);
+        leader.send(Trigger::Start, start_at).unwrap();
+        leader.send(Trigger::Stop, start_at).unwrap();
+        let (trigger, at) = follower.next_trigger().unwrap();
+        assert_eq!(trigger, Trigger::Start);
+        let error = match at.duration_since(start_at) {
+            Ok(late) => late,
+            Err(e) => e.duration(),
+        };
+        assert!(error < Duration::from_millis(10), "{:?}", error);
+        // The Start repeats are skipped
+        assert_eq!(follower.next_trigger().unwrap().0, Trigger::Stop);
+    }
+}
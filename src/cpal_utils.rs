@@ -1,43 +1,301 @@
-use anyhow::{bail, Result};
+use crate::virtual_audio::VirtualAudio;
+use anyhow::{anyhow, bail, Result};
+use cpal::traits::{DeviceTrait, HostTrait};
 use cpal::{
-    self, SampleFormat, SampleRate, StreamConfig, SupportedInputConfigs, SupportedOutputConfigs,
+    self, BufferSize, Device, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize,
+    SupportedInputConfigs, SupportedOutputConfigs, SupportedStreamConfigRange,
 };
 
-// I'm sure there's a way to make this generic, but..
+/// The audio system devices are opened through
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Backend {
+    /// cpal's default host for the platform, e.g. ALSA on Linux
+    #[default]
+    Default,
+    /// A device of the platform's default host, chosen by name
+    Device { name: String },
+    /// A JACK client with one port per channel; needs the `jack` feature
+    Jack { client_name: String },
+    /// An ASIO driver, or the first one if `driver` is `None`; needs Windows
+    /// and the `asio` feature
+    Asio { driver: Option<String> },
+    /// In-memory devices with scripted input and captured output, for tests
+    Virtual(VirtualAudio),
+}
+
+impl Backend {
+    pub fn output_device(&self) -> Result<Device> {
+        match self {
+            Backend::Default => cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow!("no default output device")),
+            Backend::Device { name } => {
+                find_device(cpal::default_host().output_devices()?, name, "output")
+            }
+            #[cfg(feature = "jack")]
+            Backend::Jack { client_name } => cpal::platform::JackHost::new()
+                .map_err(|_| anyhow!("JACK is unavailable"))?
+                .output_device_with_name(client_name)
+                .map(Device::from)
+                .ok_or_else(|| anyhow!("failed to connect to the JACK server")),
+            #[cfg(not(feature = "jack"))]
+            Backend::Jack { .. } => bail!(NO_JACK),
+            #[cfg(all(feature = "asio", target_os = "windows"))]
+            Backend::Asio { driver } => {
+                let host = asio_host()?;
+                match driver {
+                    Some(name) => find_device(host.output_devices()?, name, "ASIO output"),
+                    None => host
+                        .default_output_device()
+                        .ok_or_else(|| anyhow!("no ASIO driver has outputs")),
+                }
+            }
+            #[cfg(not(all(feature = "asio", target_os = "windows")))]
+            Backend::Asio { .. } => bail!(NO_ASIO),
+            Backend::Virtual(_) => bail!(NO_DEVICE),
+        }
+    }
+
+    pub fn input_device(&self) -> Result<Device> {
+        match self {
+            Backend::Default => cpal::default_host()
+                .default_input_device()
+                .ok_or_else(|| anyhow!("no default input device")),
+            Backend::Device { name } => {
+                find_device(cpal::default_host().input_devices()?, name, "input")
+            }
+            #[cfg(feature = "jack")]
+            Backend::Jack { client_name } => cpal::platform::JackHost::new()
+                .map_err(|_| anyhow!("JACK is unavailable"))?
+                .input_device_with_name(client_name)
+                .map(Device::from)
+                .ok_or_else(|| anyhow!("failed to connect to the JACK server")),
+            #[cfg(not(feature = "jack"))]
+            Backend::Jack { .. } => bail!(NO_JACK),
+            #[cfg(all(feature = "asio", target_os = "windows"))]
+            Backend::Asio { driver } => {
+                let host = asio_host()?;
+                match driver {
+                    Some(name) => find_device(host.input_devices()?, name, "ASIO input"),
+                    None => host
+                        .default_input_device()
+                        .ok_or_else(|| anyhow!("no ASIO driver has inputs")),
+                }
+            }
+            #[cfg(not(all(feature = "asio", target_os = "windows")))]
+            Backend::Asio { .. } => bail!(NO_ASIO),
+            Backend::Virtual(_) => bail!(NO_DEVICE),
+        }
+    }
+}
+
+#[cfg(all(feature = "asio", target_os = "windows"))]
+fn asio_host() -> Result<cpal::Host> {
+    cpal::host_from_id(cpal::HostId::Asio)
+        .map_err(|_| anyhow!("ASIO is unavailable; is an ASIO driver installed?"))
+}
+
+fn find_device(
+    devices: impl Iterator<Item = Device>,
+    name: &str,
+    direction: &str,
+) -> Result<Device> {
+    let mut names = vec![];
+    for device in devices {
+        match device.name() {
+            Ok(device_name) if device_name == name => return Ok(device),
+            Ok(device_name) => names.push(device_name),
+            Err(_) => {}
+        }
+    }
+    bail!(
+        "no {} device named {:?}; the {} devices are: {}",
+        direction,
+        name,
+        direction,
+        names.join(", ")
+    );
+}
+
+const NO_DEVICE: &str = "the virtual backend has no devices to open";
+
+#[cfg(not(feature = "jack"))]
+const NO_JACK: &str = "this build of rocoder doesn't support JACK; rebuild it with --features jack";
+
+#[cfg(not(all(feature = "asio", target_os = "windows")))]
+const NO_ASIO: &str =
+    "this build of rocoder doesn't support ASIO; it needs Windows and a build with --features asio";
+
 pub fn find_input_stream_config(
     supported_configs: SupportedInputConfigs,
     channels: u16,
     sample_rate: u32,
 ) -> Result<StreamConfig> {
-    let cpal_sample_rate = SampleRate(sample_rate);
+    find_stream_config(supported_configs, channels, sample_rate, "input")
+}
+
+/// How big an output buffer to ask the device for
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum BufferRequest {
+    /// Whatever the device chooses
+    #[default]
+    Default,
+    /// About this many frames, clamped to what the device supports
+    Frames(u32),
+    /// The smallest the device supports, for the lowest latency
+    Smallest,
+}
+
+/// Pick the 32-bit float output config with the given channel count whose
+/// sample rate is nearest `sample_rate`, with the requested buffer size
+///
+/// The caller must resample if the chosen rate isn't `sample_rate`.
+pub fn negotiate_output_stream_config(
+    supported_configs: SupportedOutputConfigs,
+    channels: u16,
+    sample_rate: u32,
+    buffer: BufferRequest,
+) -> Result<StreamConfig> {
+    let mut offered = vec![];
+    let mut best: Option<(u32, SupportedStreamConfigRange)> = None;
     for supported_config in supported_configs {
         if supported_config.sample_format() != SampleFormat::F32
             || supported_config.channels() != channels
-            || supported_config.min_sample_rate() > cpal_sample_rate
-            || supported_config.max_sample_rate() < cpal_sample_rate
         {
+            offered.push(describe_config(&supported_config));
             continue;
         }
-        return Ok(supported_config.with_sample_rate(cpal_sample_rate).into());
+        let rate = sample_rate.clamp(
+            supported_config.min_sample_rate().0,
+            supported_config.max_sample_rate().0,
+        );
+        if best.as_ref().is_none_or(|(best_rate, _)| {
+            best_rate.abs_diff(sample_rate) > rate.abs_diff(sample_rate)
+        }) {
+            best = Some((rate, supported_config));
+        }
     }
-    bail!("Failed to find matching stream config.");
+    let (rate, supported_config) = match best {
+        Some(best) => best,
+        None => bail!(
+            "the output device can't play {} channels of 32-bit float audio; it supports: {}",
+            channels,
+            offered.join(", ")
+        ),
+    };
+    let buffer_size = match (buffer, supported_config.buffer_size()) {
+        (BufferRequest::Default, _) => BufferSize::Default,
+        (BufferRequest::Frames(frames), SupportedBufferSize::Range { min, max }) => {
+            let clamped = frames.clamp(*min, *max);
+            if clamped != frames {
+                warn!(
+                    "The output device needs a buffer of {} to {} frames, using {}",
+                    min, max, clamped
+                );
+            }
+            BufferSize::Fixed(clamped)
+        }
+        (BufferRequest::Frames(frames), SupportedBufferSize::Unknown) => BufferSize::Fixed(frames),
+        (BufferRequest::Smallest, SupportedBufferSize::Range { min, .. }) => {
+            BufferSize::Fixed(*min)
+        }
+        (BufferRequest::Smallest, SupportedBufferSize::Unknown) => {
+            warn!("The output device doesn't report its buffer sizes, using its default");
+            BufferSize::Default
+        }
+    };
+    Ok(StreamConfig {
+        channels,
+        sample_rate: SampleRate(rate),
+        buffer_size,
+    })
 }
 
-pub fn find_output_stream_config(
-    supported_configs: SupportedOutputConfigs,
+/// Open the output device and negotiate a config for it as playback would,
+/// without starting a stream, describing what was chosen
+pub fn probe_output(
+    backend: &Backend,
     channels: u16,
     sample_rate: u32,
+    buffer: BufferRequest,
+) -> Result<String> {
+    let device = backend.output_device()?;
+    let config = negotiate_output_stream_config(
+        device.supported_output_configs()?,
+        channels,
+        sample_rate,
+        buffer,
+    )?;
+    Ok(format!(
+        "\"{}\", {} channels at {} Hz",
+        device.name()?,
+        config.channels,
+        config.sample_rate.0
+    ))
+}
+
+/// Open the input device and find the config recording would use, without
+/// starting a stream
+pub fn probe_input(backend: &Backend, channels: u16, sample_rate: u32) -> Result<String> {
+    let device = backend.input_device()?;
+    let config =
+        find_input_stream_config(device.supported_input_configs()?, channels, sample_rate)?;
+    Ok(format!(
+        "\"{}\", {} channels at {} Hz",
+        device.name()?,
+        config.channels,
+        config.sample_rate.0
+    ))
+}
+
+/// Pick a 32-bit float config with the given channels and sample rate, or
+/// explain what the device offers instead
+fn find_stream_config(
+    supported_configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    channels: u16,
+    sample_rate: u32,
+    direction: &str,
 ) -> Result<StreamConfig> {
     let cpal_sample_rate = SampleRate(sample_rate);
+    let mut offered = vec![];
     for supported_config in supported_configs {
         if supported_config.sample_format() != SampleFormat::F32
             || supported_config.channels() != channels
             || supported_config.min_sample_rate() > cpal_sample_rate
             || supported_config.max_sample_rate() < cpal_sample_rate
         {
+            offered.push(describe_config(&supported_config));
             continue;
         }
         return Ok(supported_config.with_sample_rate(cpal_sample_rate).into());
     }
-    bail!("Failed to find matching stream config.");
+    bail!(
+        "the {} device doesn't support {} channels of 32-bit float audio at {} Hz; it supports: {}",
+        direction,
+        channels,
+        sample_rate,
+        if offered.is_empty() {
+            "nothing".to_string()
+        } else {
+            offered.join(", ")
+        }
+    );
+}
+
+fn describe_config(config: &SupportedStreamConfigRange) -> String {
+    let rates = if config.min_sample_rate() == config.max_sample_rate() {
+        format!("{} Hz", config.min_sample_rate().0)
+    } else {
+        format!(
+            "{}-{} Hz",
+            config.min_sample_rate().0,
+            config.max_sample_rate().0
+        )
+    };
+    format!(
+        "{} channels of {:?} at {}",
+        config.channels(),
+        config.sample_format(),
+        rates
+    )
 }
@@ -0,0 +1,153 @@
+//! Cue sheets, which split a source into segments stretched with their own settings
+//!
+//! Each line gives a segment's start and end in the source, its stretch
+//! factor and optionally its pitch multiple, e.g.
+//!
+//! ```text
+//! # start  end    factor  pitch
+//! 0        0:12   8
+//! 0:12     0:30   20      -2
+//! ```
+//!
+//! Times are in `hh:mm:ss.ss` like everywhere else, and `#` starts a comment.
+
+use crate::duration_parser::parse_duration;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    pub start: Duration,
+    pub end: Duration,
+    pub factor: f32,
+    pub pitch_multiple: i8,
+}
+
+impl Cue {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueSheet {
+    pub cues: Vec<Cue>,
+}
+
+fn parse_cue(line: &str) -> Result<Cue> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if !(3..=4).contains(&fields.len()) {
+        bail!("expected start, end, factor and optionally pitch");
+    }
+    let start = parse_duration(fields[0]).context("invalid start")?;
+    let end = parse_duration(fields[1]).context("invalid end")?;
+    if end <= start {
+        bail!("the segment ends before it starts");
+    }
+    let factor: f32 = fields[2].parse().context("invalid factor")?;
+    if !(factor > 0.0 && factor.is_finite()) {
+        bail!("the factor must be positive");
+    }
+    let pitch_multiple: i8 = match fields.get(3) {
+        Some(pitch) => pitch.parse().context("invalid pitch multiple")?,
+        None => 1,
+    };
+    if pitch_multiple == 0 {
+        bail!("the pitch multiple can't be 0");
+    }
+    Ok(Cue {
+        start,
+        end,
+        factor,
+        pitch_multiple,
+    })
+}
+
+impl CueSheet {
+    pub fn from_file(path: &Path) -> Result<CueSheet> {
+        let sheet = fs::read_to_string(path)
+            .with_context(|| format!("failed to read cue sheet {:?}", path))?;
+        CueSheet::parse(&sheet).with_context(|| format!("invalid cue sheet {:?}", path))
+    }
+
+    pub fn parse(sheet: &str) -> Result<CueSheet> {
+        let mut cues = vec![];
+        for (i, line) in sheet.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            cues.push(parse_cue(line).with_context(|| format!("line {}: {:?}", i + 1, line))?);
+        }
+        if cues.is_empty() {
+            bail!("there are no cues");
+        }
+        Ok(CueSheet { cues })
+    }
+
+    /// Fail if any cue runs past the end of a source `duration` long
+    pub fn check_fits(&self, duration: Duration) -> Result<()> {
+        match self.cues.iter().find(|cue| cue.end > duration) {
+            Some(cue) => Err(anyhow!(
+                "a cue ends at {:?}, after the source's {:?}",
+                cue.end,
+                duration
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_cues_with_comments_and_default_pitch() {
+        let sheet = CueSheet::parse(
+            "# intro\n\
+             0 0:12 8\n\
+             \n\
+             0:12   0:30.5 20 -2 # the rest\n",
+        )
+        .unwrap();
+        assert_eq!(
+            sheet.cues,
+            vec![
+                Cue {
+                    start: Duration::ZERO,
+                    end: Duration::from_secs(12),
+                    factor: 8.0,
+                    pitch_multiple: 1,
+                },
+                Cue {
+                    start: Duration::from_secs(12),
+                    end: Duration::from_millis(30500),
+                    factor: 20.0,
+                    pitch_multiple: -2,
+                },
+            ]
+        );
+        assert!(sheet.check_fits(Duration::from_secs(31)).is_ok());
+        assert!(sheet.check_fits(Duration::from_secs(30)).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_cues() {
+        for invalid in [
+            "",
+            "# nothing\n",
+            "0 10",
+            "0 10 8 1 extra",
+            "10 5 8",
+            "0 10 0",
+            "0 10 -4",
+            "0 10 8 0",
+            "0 ten 8",
+        ] {
+            assert!(CueSheet::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Spectral subtraction, for taking steady background noise such as air
+//! conditioning out of a recording before it's stretched
+//!
+//! A noise profile is the average spectrum of a stretch of audio with
+//! nothing but the noise in it. Each window of the audio then has the
+//! profile, scaled up by the over-subtraction factor, taken off its
+//! magnitudes. No bin is cut below the floor, a fraction of where it
+//! started, which keeps the leftover noise from turning into chirps.
+
+use crate::audio::{Audio, AudioSlice};
+use crate::fft;
+use anyhow::{bail, Result};
+
+/// About 46 ms at 44.1 kHz
+pub const DEFAULT_WINDOW_LEN: usize = 2048;
+
+/// The average magnitude of each bin of the noise, for each channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseProfile {
+    window_len: usize,
+    channels: Vec<Vec<f32>>,
+}
+
+impl NoiseProfile {
+    /// Profile `noise`, which should hold only the noise to be removed
+    pub fn capture<'a>(
+        noise: impl Into<AudioSlice<'a>>,
+        window_len: usize,
+    ) -> Result<NoiseProfile> {
+        let noise = noise.into();
+        if noise.len() < window_len {
+            bail!(
+                "the noise sample is shorter than a {} sample window",
+                window_len
+            );
+        }
+        let n_positive = window_len / 2 + 1;
+        let channels = noise
+            .channels()
+            .map(|channel| {
+                let hop = fft::stft_hop(window_len);
+                // Leave out the padded windows at either end, which only
+                // partly cover the noise
+                let frames: Vec<_> = fft::stft(channel, window_len)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(t, _)| t * hop >= window_len && t * hop <= channel.len())
+                    .map(|(_, bins)| bins)
+                    .collect();
+                let mut sums = vec![0.0; n_positive];
+                for bins in &frames {
+                    for (sum, bin) in sums.iter_mut().zip(bins) {
+                        *sum += bin.norm();
+                    }
+                }
+                sums.iter().map(|sum| sum / frames.len() as f32).collect()
+            })
+            .collect();
+        Ok(NoiseProfile {
+            window_len,
+            channels,
+        })
+    }
+}
+
+/// How hard spectral subtraction cuts
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Subtraction {
+    /// How many times the noise profile is taken off each window, where
+    /// more than 1 also clears noise that is louder than average
+    pub over_subtraction: f32,
+    /// The least of each bin that's kept, as a fraction of its magnitude
+    pub floor: f32,
+}
+
+impl Default for Subtraction {
+    fn default() -> Self {
+        Subtraction {
+            over_subtraction: 2.0,
+            floor: 0.05,
+        }
+    }
+}
+
+/// Take the noise in `profile` out of every channel of `audio`
+pub fn subtract(audio: &mut Audio, profile: &NoiseProfile, subtraction: Subtraction) -> Result<()> {
+    if profile.channels.len() != audio.data.len() {
+        bail!(
+            "the noise profile has {} channels, but the audio has {}",
+            profile.channels.len(),
+            audio.data.len()
+        );
+    }
+    let window_len = profile.window_len;
+    for (channel, noise) in audio.data.iter_mut().zip(&profile.channels) {
+        let mut frames = fft::stft(channel, window_len);
+        for bins in frames.iter_mut() {
+            for (bin, noise) in bins.iter_mut().zip(noise) {
+                let magnitude = bin.norm();
+                if magnitude > 0.0 {
+                    let cleaned = (magnitude - noise * subtraction.over_subtraction)
+                        .max(magnitude * subtraction.floor);
+                    *bin *= cleaned / magnitude;
+                }
+            }
+        }
+        *channel = fft::istft(frames, window_len, channel.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn steady_noise_is_removed_and_tones_kept() {
+        let sample_rate = 8000;
+        let len = sample_rate as usize * 2;
+        let mut rng = StdRng::seed_from_u64(7);
+        let noise: Vec<f32> = (0..len).map(|_| rng.gen_range(-0.05..0.05)).collect();
+        let tone: Vec<f32> = (0..len)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 500.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate,
+        };
+        let calibration = Audio {
+            spec,
+            data: vec![noise[..len / 2].to_vec()],
+            markers: vec![],
+        };
+        let profile = NoiseProfile::capture(&calibration, 512).unwrap();
+
+        let mut noisy = Audio {
+            spec,
+            data: vec![tone.iter().zip(&noise).map(|(t, n)| t + n).collect()],
+            markers: vec![],
+        };
+        subtract(&mut noisy, &profile, Subtraction::default()).unwrap();
+        let residual: Vec<f32> = noisy.data[0]
+            .iter()
+            .zip(&tone)
+            .map(|(d, t)| d - t)
+            .collect();
+        assert_eq!(noisy.data[0].len(), len);
+        assert!(energy(&residual) < energy(&noise) / 4.0);
+        assert!(energy(&noisy.data[0]) > 0.9 * energy(&tone));
+
+        let mut stereo = Audio {
+            spec: AudioSpec {
+                channels: 2,
+                sample_rate,
+            },
+            data: vec![tone.clone(), tone],
+            markers: vec![],
+        };
+        assert!(subtract(&mut stereo, &profile, Subtraction::default()).is_err());
+        assert!(NoiseProfile::capture(&calibration, len).is_err());
+    }
+}
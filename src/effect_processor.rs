@@ -0,0 +1,129 @@
+use crate::audio::{AudioBus, BusPolicy, BusSender};
+use crate::effects::TimeDomainEffect;
+use crate::metrics::{self, Counter};
+use crate::runtime_setup;
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum EffectProcessorControlMessage {
+    Shutdown,
+    /// Pass audio through untouched while bypassed
+    SetBypass {
+        enabled: bool,
+    },
+}
+
+impl ControlMessage for EffectProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        EffectProcessorControlMessage::Shutdown
+    }
+}
+
+/// Runs a time-domain effect over every channel of a bus
+pub struct EffectProcessor {
+    effect: Box<dyn TimeDomainEffect>,
+    input: AudioBus,
+    output: BusSender,
+    bypass: bool,
+    busy: Counter,
+}
+
+impl EffectProcessor {
+    pub fn new(
+        input: AudioBus,
+        effect: Box<dyn TimeDomainEffect>,
+        policy: BusPolicy,
+    ) -> (EffectProcessor, AudioBus) {
+        let (output, sender) = AudioBus::bounded(input.spec, input.expected_total_samples, policy);
+        (
+            EffectProcessor {
+                effect,
+                input,
+                output: sender,
+                bypass: false,
+                busy: metrics::busy_counter("effect"),
+            },
+            output,
+        )
+    }
+}
+
+impl Processor<EffectProcessorControlMessage> for EffectProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<EffectProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            runtime_setup::pin_processing_thread();
+            let mut open_channels = self.input.channels.len();
+            'outer: while open_channels > 0 {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                open_channels = 0;
+                for (i, channel) in self.input.channels.iter().enumerate() {
+                    let mut chunk = match channel.recv_timeout(INPUT_POLL) {
+                        Ok(chunk) => chunk,
+                        Err(RecvTimeoutError::Timeout) => {
+                            open_channels += 1;
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => continue,
+                    };
+                    open_channels += 1;
+                    if !self.bypass {
+                        let started = Instant::now();
+                        self.effect.process(i, &mut chunk);
+                        self.busy.add(started.elapsed().as_secs_f64());
+                    }
+                    if !self.output.send(i, chunk) {
+                        info!("effect output disconnected, stopping");
+                        break 'outer;
+                    }
+                }
+                self.output.forward_timestamps(
+                    &self.input.take_timestamps(),
+                    self.effect.latency() as i64,
+                );
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<EffectProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                EffectProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                EffectProcessorControlMessage::SetBypass { enabled } => {
+                    self.bypass = enabled;
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.effect.latency()
+    }
+}
@@ -0,0 +1,166 @@
+use crate::audio::AudioSpec;
+use std::f32;
+use std::time::Duration;
+
+pub const DEFAULT_DELAY_FEEDBACK: f32 = 0.4;
+pub const DEFAULT_DELAY_MIX: f32 = 0.3;
+pub const DEFAULT_TREMOLO_DEPTH: f32 = 0.5;
+
+/// An effect applied to audio samples after resynthesis, one channel chunk at a time
+///
+/// Chunks of each channel arrive in order, but channels may be interleaved
+/// in any order, so effects with memory should keep it per channel.
+pub trait TimeDomainEffect: Send + 'static {
+    fn process(&mut self, channel: usize, samples: &mut [f32]);
+
+    /// How many frames the effect delays its input by
+    fn latency(&self) -> usize {
+        0
+    }
+}
+
+/// Feedback delay
+pub struct Delay {
+    lines: Vec<DelayLine>,
+    feedback: f32,
+    mix: f32,
+}
+
+struct DelayLine {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl Delay {
+    /// `feedback` is how much of each echo feeds into the next, and `mix` is the
+    /// wet level from 0 (dry only) to 1 (echoes only)
+    pub fn new(spec: &AudioSpec, time: Duration, feedback: f32, mix: f32) -> Delay {
+        let len = ((time.as_secs_f32() * spec.sample_rate as f32) as usize).max(1);
+        Delay {
+            lines: (0..spec.channels)
+                .map(|_| DelayLine {
+                    buffer: vec![0.0; len],
+                    pos: 0,
+                })
+                .collect(),
+            feedback,
+            mix,
+        }
+    }
+}
+
+impl TimeDomainEffect for Delay {
+    fn process(&mut self, channel: usize, samples: &mut [f32]) {
+        let line = &mut self.lines[channel];
+        for sample in samples.iter_mut() {
+            let delayed = line.buffer[line.pos];
+            line.buffer[line.pos] = *sample + delayed * self.feedback;
+            line.pos = (line.pos + 1) % line.buffer.len();
+            *sample = *sample * (1.0 - self.mix) + delayed * self.mix;
+        }
+    }
+}
+
+/// One-pole lowpass filter
+pub struct Lowpass {
+    coefficient: f32,
+    previous: Vec<f32>,
+}
+
+impl Lowpass {
+    pub fn new(spec: &AudioSpec, cutoff_hz: f32) -> Lowpass {
+        Lowpass {
+            coefficient: 1.0 - (-2.0 * f32::consts::PI * cutoff_hz / spec.sample_rate as f32).exp(),
+            previous: vec![0.0; spec.channels as usize],
+        }
+    }
+}
+
+impl TimeDomainEffect for Lowpass {
+    fn process(&mut self, channel: usize, samples: &mut [f32]) {
+        let previous = &mut self.previous[channel];
+        for sample in samples.iter_mut() {
+            *previous += (*sample - *previous) * self.coefficient;
+            *sample = *previous;
+        }
+    }
+}
+
+/// Amplitude modulation by a sine wave
+pub struct Tremolo {
+    phase_step: f32,
+    depth: f32,
+    /// Per channel, so channels stay in step however their chunks interleave
+    phases: Vec<f32>,
+}
+
+impl Tremolo {
+    /// `depth` from 0 (no effect) to 1 (fully silent at each trough)
+    pub fn new(spec: &AudioSpec, rate_hz: f32, depth: f32) -> Tremolo {
+        Tremolo {
+            phase_step: 2.0 * f32::consts::PI * rate_hz / spec.sample_rate as f32,
+            depth,
+            phases: vec![0.0; spec.channels as usize],
+        }
+    }
+}
+
+impl TimeDomainEffect for Tremolo {
+    fn process(&mut self, channel: usize, samples: &mut [f32]) {
+        let phase = &mut self.phases[channel];
+        for sample in samples.iter_mut() {
+            *sample *= 1.0 - self.depth * (0.5 - 0.5 * phase.cos());
+            *phase = (*phase + self.phase_step) % (2.0 * f32::consts::PI);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    const SPEC: AudioSpec = AudioSpec {
+        channels: 2,
+        sample_rate: 10,
+    };
+
+    #[test]
+    fn delay_echoes() {
+        let mut delay = Delay::new(&SPEC, Duration::from_millis(200), 0.5, 1.0);
+        let mut samples = vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        delay.process(0, &mut samples);
+        assert_almost_eq_by_element(samples, vec![0.0, 0.0, 1.0, 0.0, 0.5, 0.0, 0.25]);
+    }
+
+    #[test]
+    fn delay_keeps_channels_separate() {
+        let mut delay = Delay::new(&SPEC, Duration::from_millis(100), 0.0, 0.5);
+        let mut left = vec![1.0];
+        delay.process(0, &mut left);
+        let mut right = vec![0.0, 0.0];
+        delay.process(1, &mut right);
+        assert_almost_eq_by_element(right, vec![0.0, 0.0]);
+        let mut left = vec![0.0];
+        delay.process(0, &mut left);
+        assert_almost_eq_by_element(left, vec![0.5]);
+    }
+
+    #[test]
+    fn lowpass_smooths_steps() {
+        let mut lowpass = Lowpass::new(&SPEC, 1.0);
+        let mut samples = vec![1.0; 20];
+        lowpass.process(0, &mut samples);
+        assert!(samples[0] > 0.0 && samples[0] < 0.5);
+        assert!(samples.windows(2).all(|w| w[1] > w[0]));
+        assert!(samples[19] > 0.99);
+    }
+
+    #[test]
+    fn tremolo_modulates_amplitude() {
+        let mut tremolo = Tremolo::new(&SPEC, 2.5, 1.0);
+        let mut samples = vec![1.0; 5];
+        tremolo.process(0, &mut samples);
+        assert_almost_eq_by_element(samples, vec![1.0, 0.5, 0.0, 0.5, 1.0]);
+    }
+}
@@ -1,109 +1,573 @@
+#[cfg(feature = "native")]
 use crate::hotswapper;
-use crossbeam_channel::Receiver;
-use libloading::{Library, Symbol};
-use rand::Rng;
+use crate::kernel::{FrameInfo, Kernel, KernelParam, KernelSource};
+use crate::math;
+use crate::simd;
+use crate::spectral_eq::SpectralEq;
+use crate::windows;
+use anyhow::{bail, Result};
+use crossbeam_channel::{unbounded, Receiver};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rustfft::num_complex::Complex32;
 use rustfft::{Fft, FftPlanner};
 use std::f32;
-use std::panic;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 const TWO_PI: f32 = f32::consts::PI;
+pub const DEFAULT_KERNEL_CROSSFADE: Duration = Duration::from_millis(100);
 
 pub struct ReFFT {
     forward_fft: Arc<dyn Fft<f32>>,
     inverse_fft: Arc<dyn Fft<f32>>,
     window_len: usize,
     window: Vec<f32>,
-    kernel_recv: Option<Receiver<Library>>,
-    kernels: Vec<Library>,
+    sample_rate: u32,
+    channel: u32,
+    /// When kernels first saw a frame, so stretching without kernels never
+    /// reads the clock, which wasm32 doesn't have
+    started_at: Option<Instant>,
+    kernel_stages: Vec<KernelStage>,
+    kernel_params: Vec<(String, f32)>,
+    kernel_crossfade: Duration,
+    /// Buffers kept between frames so resynthesis doesn't allocate them each time
+    bins: Vec<Complex32>,
+    magnitudes: Vec<f32>,
+    scratch: Vec<Complex32>,
+    /// Picks the random phases of resynthesized bins
+    rng: StdRng,
+    spectrum_tap: Option<SpectrumTap>,
+    /// What to scale each bin's magnitude by, or empty to leave them be
+    eq_gains: Vec<f32>,
+    /// Picks an offset for each bin's phase, and how far it may stray
+    phase_offsets: Option<(StdRng, f32)>,
+}
+
+/// The bin magnitudes of the latest analysis frame, after the frequency
+/// kernels, shared with whoever is drawing them
+#[derive(Debug, Clone, Default)]
+pub struct SpectrumTap(Arc<Mutex<Vec<f32>>>);
+
+impl SpectrumTap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The positive frequency magnitudes of the frame published since the
+    /// last take, if there was one
+    pub fn take(&self) -> Option<Vec<f32>> {
+        let magnitudes = std::mem::take(&mut *self.0.lock().unwrap());
+        (!magnitudes.is_empty()).then_some(magnitudes)
+    }
+
+    fn publish(&self, magnitudes: &[f32]) {
+        // Drop the frame rather than wait on a reader
+        if let Ok(mut latest) = self.0.try_lock() {
+            latest.clear();
+            latest.extend_from_slice(&magnitudes[..magnitudes.len() / 2]);
+        }
+    }
+}
+
+/// Plan forward and inverse FFTs of this length, reusing the plans of any
+/// earlier ReFFT of the same length, since planning is slow
+fn plan_ffts(window_len: usize) -> (Arc<dyn Fft<f32>>, Arc<dyn Fft<f32>>) {
+    static PLANNER: OnceLock<Mutex<FftPlanner<f32>>> = OnceLock::new();
+    let mut planner = PLANNER
+        .get_or_init(|| Mutex::new(FftPlanner::new()))
+        .lock()
+        .unwrap();
+    (
+        planner.plan_fft_forward(window_len),
+        planner.plan_fft_inverse(window_len),
+    )
 }
 
 impl ReFFT {
-    pub fn new(window: Vec<f32>, kernel_src: Option<PathBuf>) -> ReFFT {
+    /// `kernel_srcs` are chained in order, each stage processing the previous stage's output
+    pub fn new(window: Vec<f32>, sample_rate: u32, kernel_srcs: Vec<KernelSource>) -> ReFFT {
         let window_len = window.len();
-        let mut planner = FftPlanner::new();
-        let forward_fft = planner.plan_fft_forward(window_len);
-        let inverse_fft = planner.plan_fft_inverse(window_len);
+        let (forward_fft, inverse_fft) = plan_ffts(window_len);
+        let scratch_len = forward_fft
+            .get_inplace_scratch_len()
+            .max(inverse_fft.get_inplace_scratch_len());
         // TODO maybe need to block on the initial compilation?
-        let kernel_recv = kernel_src.map(|src| hotswapper::hotswap(src).unwrap());
+        let kernel_stages = kernel_srcs
+            .into_iter()
+            .map(|src| match src {
+                #[cfg(feature = "native")]
+                KernelSource::File(path) => KernelStage::new(hotswapper::hotswap(path).unwrap()),
+                KernelSource::Builtin(builtin) => {
+                    let (tx, rx) = unbounded();
+                    tx.send(builtin.create()).unwrap();
+                    KernelStage::new(rx)
+                }
+            })
+            .collect();
         ReFFT {
             forward_fft,
             inverse_fft,
             window_len,
             window,
-            kernel_recv,
-            kernels: vec![],
+            sample_rate,
+            channel: 0,
+            started_at: None,
+            kernel_stages,
+            kernel_params: vec![],
+            kernel_crossfade: DEFAULT_KERNEL_CROSSFADE,
+            bins: Vec::with_capacity(window_len),
+            magnitudes: vec![0.0; window_len],
+            scratch: vec![Complex32::new(0.0, 0.0); scratch_len],
+            rng: StdRng::from_entropy(),
+            spectrum_tap: None,
+            eq_gains: vec![],
+            phase_offsets: None,
+        }
+    }
+
+    /// Set how long a newly loaded kernel takes to fade in over the previous one
+    pub fn set_kernel_crossfade(&mut self, crossfade: Duration) {
+        self.kernel_crossfade = crossfade;
+    }
+
+    /// Pick phases from a fixed seed, so the same input resynthesizes the same way every run
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Offset each bin's phase from the one picked by the seed, by up to
+    /// `intensity` of a half turn either way, drawing the offsets from
+    /// `offset_seed`
+    ///
+    /// Channels given the same seed and different offset seeds drift apart
+    /// as `intensity` goes from 0, where their phases match, to 1, where
+    /// they're unrelated.
+    pub fn set_decorrelation(&mut self, intensity: f32, offset_seed: u64) {
+        self.phase_offsets = Some((StdRng::seed_from_u64(offset_seed), intensity));
+    }
+
+    /// Shape every frame's magnitudes with `eq`, after the kernels
+    ///
+    /// `frequency_scale` is how far the output will be pitch shifted, as in
+    /// `SpectralEq::bin_gains`.
+    pub fn set_spectral_eq(&mut self, eq: &SpectralEq, frequency_scale: f32) {
+        self.eq_gains = if eq.is_empty() {
+            vec![]
+        } else {
+            eq.bin_gains(self.window_len, self.sample_rate, frequency_scale)
+        };
+    }
+
+    /// Publish each frame's magnitudes to `tap`
+    pub fn set_spectrum_tap(&mut self, tap: SpectrumTap) {
+        self.spectrum_tap = Some(tap);
+    }
+
+    /// Set the channel index passed to frequency kernels
+    pub fn set_channel(&mut self, channel: u32) {
+        self.channel = channel;
+    }
+
+    /// Set a named parameter passed to every kernel stage, replacing any previous value
+    pub fn set_kernel_param(&mut self, name: &str, value: f32) {
+        match self.kernel_params.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.kernel_params.push((name.to_string(), value)),
+        }
+    }
+
+    /// Enable or bypass one stage of the kernel chain
+    pub fn set_kernel_stage_enabled(&mut self, stage: usize, enabled: bool) -> Result<()> {
+        match self.kernel_stages.get_mut(stage) {
+            Some(kernel_stage) => {
+                kernel_stage.enabled = enabled;
+                Ok(())
+            }
+            None => bail!(
+                "no kernel stage {}, there are only {}",
+                stage,
+                self.kernel_stages.len()
+            ),
         }
     }
 
     pub fn resynth(&mut self, samples: &[f32]) -> Vec<f32> {
         let mut fft_result = self.forward_fft(samples);
-        if self.kernel_recv.is_some() {
-            fft_result = self.apply_kernel_to_fft_result(fft_result);
+        if !self.kernel_stages.is_empty() {
+            let params: Vec<KernelParam> = self
+                .kernel_params
+                .iter()
+                .map(|(name, value)| KernelParam::new(name, *value))
+                .collect();
+            let started_at = *self.started_at.get_or_insert_with(Instant::now);
+            let info = FrameInfo {
+                sample_rate: self.sample_rate,
+                window_len: self.window_len as u32,
+                channel: self.channel,
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+                params: &params,
+            };
+            for stage in self.kernel_stages.iter_mut() {
+                fft_result = stage.apply(fft_result, info, self.kernel_crossfade);
+            }
         }
         self.resynth_from_fft_result(fft_result)
     }
 
-    fn forward_fft(&self, samples: &[f32]) -> Vec<Complex32> {
-        let mut buf: Vec<Complex32> = samples
-            .iter()
-            .zip(&self.window)
-            .map(|(s, w)| Complex32::new(s * w, 0.0))
-            .collect();
-        if buf.len() < self.window_len {
-            buf.extend(vec![Complex32::new(0.0, 0.0); self.window_len - buf.len()]);
-        }
-        self.forward_fft.process(&mut buf);
+    fn forward_fft(&mut self, samples: &[f32]) -> Vec<Complex32> {
+        // Reuse the previous frame's bins, which the caller handed back
+        let mut buf = std::mem::take(&mut self.bins);
+        buf.clear();
+        buf.resize(self.window_len, Complex32::new(0.0, 0.0));
+        let len = samples.len().min(self.window_len);
+        simd::window_into_complex(&samples[..len], &self.window[..len], &mut buf[..len]);
+        self.forward_fft
+            .process_with_scratch(&mut buf, &mut self.scratch);
         buf
     }
 
-    fn resynth_from_fft_result(&self, fft_result: Vec<Complex32>) -> Vec<f32> {
-        let mut rng = rand::thread_rng();
-        let mut buf: Vec<Complex32> = fft_result
-            .iter()
-            .map(|c| Complex32::new(0.0, rng.gen_range(0.0..TWO_PI)).exp() * c.norm())
-            .collect();
-        self.inverse_fft.process(&mut buf);
-        buf.iter()
-            .zip(&self.window)
-            .map(|(c, w)| (c.re / self.window_len as f32) * w)
-            .collect()
+    fn resynth_from_fft_result(&mut self, mut buf: Vec<Complex32>) -> Vec<f32> {
+        self.magnitudes.resize(buf.len(), 0.0);
+        simd::magnitudes(&buf, &mut self.magnitudes);
+        if !self.eq_gains.is_empty() {
+            for (magnitude, gain) in self.magnitudes.iter_mut().zip(&self.eq_gains) {
+                *magnitude *= gain;
+            }
+        }
+        if let Some(tap) = &self.spectrum_tap {
+            tap.publish(&self.magnitudes);
+        }
+        for (bin, magnitude) in buf.iter_mut().zip(&self.magnitudes) {
+            let mut phase = self.rng.gen_range(0.0..TWO_PI);
+            if let Some((offsets, intensity)) = &mut self.phase_offsets {
+                phase += *intensity * offsets.gen_range(-f32::consts::PI..f32::consts::PI);
+            }
+            *bin = Complex32::from_polar(*magnitude, phase);
+        }
+        self.inverse_fft
+            .process_with_scratch(&mut buf, &mut self.scratch);
+        let mut samples = vec![0.0; self.window_len];
+        simd::windowed_real_parts(
+            &buf,
+            &self.window,
+            1.0 / self.window_len as f32,
+            &mut samples,
+        );
+        self.bins = buf;
+        samples
+    }
+}
+
+/// How many analysis windows of `stft` overlap each sample
+const STFT_OVERLAP: usize = 4;
+
+/// How far apart the windows of `stft` start
+pub fn stft_hop(window_len: usize) -> usize {
+    (window_len / STFT_OVERLAP).max(1)
+}
+
+/// The spectra of overlapping Hann windows of `samples`, a quarter window
+/// apart, for offline processing that `istft` then undoes
+///
+/// The samples are padded with a window of silence on each side, so every
+/// sample is covered by the same number of windows.
+pub fn stft(samples: &[f32], window_len: usize) -> Vec<Vec<Complex32>> {
+    let (forward, _) = plan_ffts(window_len);
+    let window = windows::hanning(window_len);
+    let mut padded = vec![0.0; window_len];
+    padded.extend_from_slice(samples);
+    padded.resize(samples.len() + 2 * window_len, 0.0);
+    (0..=padded.len() - window_len)
+        .step_by(stft_hop(window_len))
+        .map(|start| {
+            let mut bins: Vec<Complex32> = padded[start..start + window_len]
+                .iter()
+                .zip(&window)
+                .map(|(sample, w)| Complex32::new(sample * w, 0.0))
+                .collect();
+            forward.process(&mut bins);
+            bins
+        })
+        .collect()
+}
+
+/// Overlap-add spectra from `stft` back into the `len` samples they came from
+///
+/// Only the positive frequencies are read; the negative ones are taken to
+/// be their conjugates, so callers need only edit the first half.
+pub fn istft(frames: Vec<Vec<Complex32>>, window_len: usize, len: usize) -> Vec<f32> {
+    let (_, inverse) = plan_ffts(window_len);
+    let window = windows::hanning(window_len);
+    let hop = stft_hop(window_len);
+    let mut out = vec![0.0; len + 2 * window_len];
+    let mut window_sums = vec![0.0; out.len()];
+    for (t, mut bins) in frames.into_iter().enumerate() {
+        for k in 1..window_len.div_ceil(2) {
+            bins[window_len - k] = bins[k].conj();
+        }
+        inverse.process(&mut bins);
+        let start = t * hop;
+        for (j, w) in window.iter().enumerate() {
+            out[start + j] += bins[j].re * w / window_len as f32;
+            window_sums[start + j] += w * w;
+        }
+    }
+    out.iter()
+        .zip(&window_sums)
+        .skip(window_len)
+        .take(len)
+        .map(|(sample, sum)| if *sum > 1e-6 { sample / sum } else { 0.0 })
+        .collect()
+}
+
+/// One hot-swappable kernel in a chain
+///
+/// Older versions of the kernel are kept to fall back on if the newest fails.
+struct KernelStage {
+    kernel_recv: Receiver<Box<dyn Kernel>>,
+    kernels: Vec<Box<dyn Kernel>>,
+    enabled: bool,
+    swapped_at: Option<Instant>,
+}
+
+impl KernelStage {
+    fn new(kernel_recv: Receiver<Box<dyn Kernel>>) -> KernelStage {
+        KernelStage {
+            kernel_recv,
+            kernels: vec![],
+            enabled: true,
+            swapped_at: None,
+        }
     }
 
-    fn apply_kernel_to_fft_result(&mut self, fft_result: Vec<Complex32>) -> Vec<Complex32> {
-        // use catch_unwind to make sure we dont use the new lib if its call panics
-        if let Ok(lib) = self.kernel_recv.as_ref().unwrap().try_recv() {
+    fn apply(
+        &mut self,
+        fft_result: Vec<Complex32>,
+        info: FrameInfo<'_>,
+        crossfade: Duration,
+    ) -> Vec<Complex32> {
+        if let Ok(kernel) = self.kernel_recv.try_recv() {
             info!("Got new kernel");
-            self.kernels.push(lib);
+            self.kernels.push(kernel);
+            self.swapped_at = Some(Instant::now());
         }
-        let maybe_lib = self.kernels.last();
-        if maybe_lib.is_none() {
+        if !self.enabled {
             return fft_result;
         }
-        let kernel_input = fft_result.iter().map(|c| (c.re, c.im)).collect();
-        let lib = maybe_lib.unwrap();
-        match panic::catch_unwind(move || {
-            let time_ms = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as usize;
-            let symbol: Symbol<fn(usize, Vec<(f32, f32)>) -> Vec<(f32, f32)>> =
-                unsafe { lib.get(b"apply\0").unwrap() };
-            let kernel_output = symbol(time_ms, kernel_input);
-            kernel_output
-                .iter()
-                .map(|c| Complex32 { re: c.0, im: c.1 })
-                .collect()
-        }) {
-            Ok(applied) => applied,
-            Err(_) => {
-                warn!("kernel panicked, retrying with last or noop.");
-                self.kernels.pop();
-                self.apply_kernel_to_fft_result(fft_result)
+        let applied = self.apply_newest_kernel(&fft_result, info);
+        match self.crossfade_progress(crossfade) {
+            Some(progress) => {
+                // Blend from the previous kernel, or from the untouched bins if this is the first
+                let previous = match self.kernels.len() {
+                    0 | 1 => Ok(fft_result),
+                    n => run_kernel(&mut self.kernels[n - 2], &fft_result, info),
+                };
+                match previous {
+                    Ok(previous) => crossfade_bins(&previous, &applied, progress),
+                    Err(_) => applied,
+                }
             }
+            None => applied,
         }
     }
+
+    /// Run the newest kernel, falling back to older ones if it fails
+    fn apply_newest_kernel(
+        &mut self,
+        fft_result: &[Complex32],
+        info: FrameInfo<'_>,
+    ) -> Vec<Complex32> {
+        loop {
+            let kernel = match self.kernels.last_mut() {
+                Some(kernel) => kernel,
+                None => return fft_result.to_vec(),
+            };
+            match run_kernel(kernel, fft_result, info) {
+                Ok(applied) => return applied,
+                Err(_) => {
+                    warn!("kernel failed, retrying with last or noop.");
+                    self.kernels.pop();
+                    self.swapped_at = None;
+                }
+            }
+        }
+    }
+
+    /// How far through the crossfade from the previous kernel we are, from 0 to 1
+    fn crossfade_progress(&mut self, crossfade: Duration) -> Option<f32> {
+        let elapsed = self.swapped_at?.elapsed();
+        if elapsed >= crossfade {
+            self.swapped_at = None;
+            return None;
+        }
+        Some(elapsed.as_secs_f32() / crossfade.as_secs_f32())
+    }
+}
+
+fn run_kernel(
+    kernel: &mut Box<dyn Kernel>,
+    fft_result: &[Complex32],
+    info: FrameInfo<'_>,
+) -> Result<Vec<Complex32>> {
+    let mut bins = fft_result.to_vec();
+    kernel.apply(&mut bins, info)?;
+    Ok(bins)
+}
+
+/// Blend bin magnitudes from `from` to `to`, keeping the phases of `to`
+///
+/// Magnitudes are blended rather than complex values so that bins with
+/// different phases don't cancel out partway through.
+fn crossfade_bins(from: &[Complex32], to: &[Complex32], progress: f32) -> Vec<Complex32> {
+    from.iter()
+        .zip(to)
+        .map(|(from, to)| {
+            Complex32::from_polar(math::lerp(from.norm(), to.norm(), progress), to.arg())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "native")]
+    use crate::kernel::DylibKernel;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_crossfade_bins() {
+        let from = vec![Complex32::new(2.0, 0.0), Complex32::new(0.0, -4.0)];
+        let to = vec![Complex32::new(0.0, 1.0), Complex32::new(0.0, 0.0)];
+        let result = crossfade_bins(&from, &to, 0.25);
+        assert_almost_eq(result[0].norm(), 1.75);
+        assert_almost_eq(result[0].arg(), to[0].arg());
+        assert_almost_eq(result[1].norm(), 3.0);
+    }
+
+    #[cfg(feature = "native")]
+    fn stage_running(src: &str) -> KernelStage {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let kernel = DylibKernel::load(compile_test_kernel(src)).unwrap();
+        tx.send(Box::new(kernel) as Box<dyn Kernel>).unwrap();
+        KernelStage::new(rx)
+    }
+
+    #[cfg(feature = "native")]
+    fn run_chain(stages: &mut [KernelStage], bins: Vec<Complex32>) -> Vec<f32> {
+        let info = FrameInfo {
+            sample_rate: 44100,
+            window_len: bins.len() as u32,
+            channel: 0,
+            elapsed_ms: 0,
+            params: &[],
+        };
+        stages
+            .iter_mut()
+            .fold(bins, |bins, stage| stage.apply(bins, info, Duration::ZERO))
+            .iter()
+            .map(|c| c.re)
+            .collect()
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn kernel_stages_chain_in_order() {
+        let add_one = "
+            #[no_mangle]
+            pub extern \"C\" fn process(frame: &mut KernelFrame) -> bool {
+                let bins = unsafe { std::slice::from_raw_parts_mut(frame.bins, frame.bins_len) };
+                bins.iter_mut().for_each(|bin| bin[0] += 1.0);
+                true
+            }
+        ";
+        let double = "
+            #[no_mangle]
+            pub extern \"C\" fn process(frame: &mut KernelFrame) -> bool {
+                let bins = unsafe { std::slice::from_raw_parts_mut(frame.bins, frame.bins_len) };
+                bins.iter_mut().for_each(|bin| bin[0] *= 2.0);
+                true
+            }
+        ";
+        let mut stages = vec![stage_running(add_one), stage_running(double)];
+        let bins = vec![Complex32::new(1.0, 0.0), Complex32::new(2.0, 0.0)];
+        assert_almost_eq_by_element(run_chain(&mut stages, bins.clone()), vec![4.0, 6.0]);
+
+        stages[0].enabled = false;
+        assert_almost_eq_by_element(run_chain(&mut stages, bins), vec![2.0, 4.0]);
+    }
+
+    #[test]
+    fn set_kernel_param_replaces_value() {
+        let mut re_fft = ReFFT::new(vec![1.0; 4], 44100, vec![]);
+        re_fft.set_kernel_param("cutoff", 1.0);
+        re_fft.set_kernel_param("gain", 2.0);
+        re_fft.set_kernel_param("cutoff", 3.0);
+        assert_eq!(
+            re_fft.kernel_params,
+            vec![("cutoff".to_string(), 3.0), ("gain".to_string(), 2.0)]
+        );
+    }
+
+    #[test]
+    fn re_ffts_of_the_same_length_share_plans() {
+        let first = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+        let second = ReFFT::new(vec![0.5; 16], 48000, vec![]);
+        assert!(Arc::ptr_eq(&first.forward_fft, &second.forward_fft));
+        assert!(Arc::ptr_eq(&first.inverse_fft, &second.inverse_fft));
+    }
+
+    #[test]
+    fn seeded_re_ffts_resynthesize_identically() {
+        let samples: Vec<f32> = (0..16).map(|i| (i as f32 * 0.7).sin()).collect();
+        let mut first = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+        let mut second = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+        first.set_seed(7);
+        second.set_seed(7);
+        for _ in 0..3 {
+            assert_eq!(first.resynth(&samples), second.resynth(&samples));
+        }
+    }
+
+    #[test]
+    fn decorrelation_sets_how_far_phases_drift() {
+        let samples: Vec<f32> = (0..16).map(|i| (i as f32 * 0.7).sin()).collect();
+        let channels = |intensity| {
+            let mut left = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+            let mut right = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+            left.set_seed(7);
+            right.set_seed(7);
+            left.set_decorrelation(intensity, 8);
+            right.set_decorrelation(intensity, 9);
+            (left.resynth(&samples), right.resynth(&samples))
+        };
+        let (left, right) = channels(0.0);
+        assert_eq!(left, right);
+        let (left, right) = channels(1.0);
+        assert_ne!(left, right);
+    }
+
+    #[test]
+    fn spectrum_tap_takes_the_latest_frame() {
+        let tap = SpectrumTap::new();
+        let mut re_fft = ReFFT::new(vec![1.0; 16], 44100, vec![]);
+        re_fft.set_spectrum_tap(tap.clone());
+        assert_eq!(tap.take(), None);
+        // Two cycles per window puts all the energy in bin 2
+        let samples: Vec<f32> = (0..16)
+            .map(|i| (i as f32 * f32::consts::PI / 4.0).cos())
+            .collect();
+        re_fft.resynth(&samples);
+        let magnitudes = tap.take().unwrap();
+        assert_eq!(magnitudes.len(), 8);
+        assert_almost_eq(magnitudes[2], 8.0);
+        assert_almost_eq(magnitudes[5], 0.0);
+        assert_eq!(tap.take(), None);
+    }
+
+    #[test]
+    fn set_missing_kernel_stage_enabled_fails() {
+        let mut re_fft = ReFFT::new(vec![1.0; 4], 44100, vec![]);
+        assert!(re_fft.set_kernel_stage_enabled(0, false).is_err());
+    }
 }
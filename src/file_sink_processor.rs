@@ -0,0 +1,230 @@
+use crate::audio::AudioBus;
+use crate::audio_files::{AudioWriter, RawFormat, WavWriter};
+use crate::pcm_stream::{StreamAddr, StreamWriter};
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use anyhow::{Context, Result};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileSinkTarget {
+    /// A 32-bit float .wav file
+    Wav(PathBuf),
+    /// Headerless interleaved samples on stdout, e.g. for piping into ffmpeg or sox
+    Stdout(RawFormat),
+    /// Another rocoder receiving over the network or a Unix socket
+    Stream(StreamAddr),
+}
+
+impl FileSinkTarget {
+    /// Parse a command line path, where `-` means stdout in `raw_format`, and
+    /// `tcp://` or `unix://` addresses mean a stream
+    pub fn from_path(path: PathBuf, raw_format: RawFormat) -> Self {
+        if path.to_str() == Some("-") {
+            FileSinkTarget::Stdout(raw_format)
+        } else if let Some(addr) = path.to_str().and_then(StreamAddr::parse) {
+            FileSinkTarget::Stream(addr)
+        } else {
+            FileSinkTarget::Wav(path)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FileSinkProcessorControlMessage {
+    /// Finish writing what has been received so far and close the file
+    Shutdown,
+}
+
+impl ControlMessage for FileSinkProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        FileSinkProcessorControlMessage::Shutdown
+    }
+}
+
+enum SampleWriter {
+    Wav(WavWriter<io::BufWriter<fs::File>>),
+    Stdout(io::BufWriter<io::Stdout>, RawFormat),
+    Stream(StreamWriter),
+}
+
+impl SampleWriter {
+    fn write(&mut self, sample: f32) -> Result<()> {
+        match self {
+            SampleWriter::Wav(writer) => writer.write(sample),
+            SampleWriter::Stdout(writer, format) => Ok(format.encode(sample, writer)?),
+            SampleWriter::Stream(writer) => {
+                writer.write(sample);
+                Ok(())
+            }
+        }
+    }
+
+    /// Mark the end of a batch of frames, which streams send as one block
+    fn end_block(&mut self) -> Result<()> {
+        match self {
+            SampleWriter::Stream(writer) => writer.end_block(),
+            _ => Ok(()),
+        }
+    }
+
+    fn finalize(self) -> Result<()> {
+        match self {
+            SampleWriter::Wav(writer) => writer.finalize(),
+            SampleWriter::Stdout(mut writer, _) => Ok(writer.flush()?),
+            SampleWriter::Stream(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Writes every sample arriving on a bus to a file, stdout or a stream as it arrives
+pub struct FileSinkProcessor {
+    input: AudioBus,
+    writer: SampleWriter,
+    /// Samples received on each channel but not yet written, since channels
+    /// can arrive in different chunk sizes and frames are written interleaved
+    pending: Vec<VecDeque<f32>>,
+    frames_written: Arc<AtomicUsize>,
+}
+
+impl FileSinkProcessor {
+    /// Open `target` for writing; the file is created or the stream connected immediately
+    pub fn new(input: AudioBus, target: FileSinkTarget) -> Result<FileSinkProcessor> {
+        let writer = match target {
+            FileSinkTarget::Wav(path) => SampleWriter::Wav(
+                WavWriter::open(path.to_str().unwrap(), input.spec)
+                    .with_context(|| format!("failed to create {:?}", path))?,
+            ),
+            FileSinkTarget::Stdout(format) => {
+                SampleWriter::Stdout(io::BufWriter::new(io::stdout()), format)
+            }
+            FileSinkTarget::Stream(addr) => SampleWriter::Stream(
+                StreamWriter::connect(&addr, input.spec)
+                    .with_context(|| format!("failed to connect to {}", addr))?,
+            ),
+        };
+        Ok(FileSinkProcessor {
+            pending: vec![VecDeque::new(); input.channels.len()],
+            input,
+            writer,
+            frames_written: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Count the frames written so far in `frames_written`, e.g. to show progress
+    pub fn with_progress(mut self, frames_written: Arc<AtomicUsize>) -> Self {
+        self.frames_written = frames_written;
+        self
+    }
+
+    fn write_complete_frames(&mut self) -> Result<()> {
+        let frames = self.pending.iter().map(|c| c.len()).min().unwrap_or(0);
+        for _ in 0..frames {
+            for channel in self.pending.iter_mut() {
+                self.writer.write(channel.pop_front().unwrap())?;
+            }
+        }
+        self.writer.end_block()?;
+        self.frames_written.fetch_add(frames, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn run(&mut self, ctrl_rx: &Receiver<FileSinkProcessorControlMessage>) -> Result<()> {
+        let mut open_channels = self.input.channels.len();
+        while open_channels > 0 {
+            if let ProcessorState::Finished = self.handle_control_messages(ctrl_rx)? {
+                break;
+            }
+            open_channels = 0;
+            for (i, channel) in self.input.channels.iter().enumerate() {
+                match channel.recv_timeout(INPUT_POLL) {
+                    Ok(chunk) => {
+                        open_channels += 1;
+                        self.pending[i].extend(chunk);
+                    }
+                    Err(RecvTimeoutError::Timeout) => open_channels += 1,
+                    Err(RecvTimeoutError::Disconnected) => {}
+                }
+            }
+            self.write_complete_frames()?;
+        }
+        Ok(())
+    }
+}
+
+impl Processor<FileSinkProcessorControlMessage> for FileSinkProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<FileSinkProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let result = self.run(&ctrl_rx).and_then(|_| self.writer.finalize());
+            if let Err(e) = result {
+                let _ = errors.send(NodeError::Failed(e));
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<FileSinkProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                FileSinkProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::{Audio, AudioSpec};
+    use crate::audio_files::{AudioReader, WavReader};
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    #[test]
+    fn writes_interleaved_wav() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let (bus, senders) = AudioBus::from_spec(spec, None);
+        let path = std::env::temp_dir().join("rocoder_file_sink_test.wav");
+        let frames_written = Arc::new(AtomicUsize::new(0));
+        let node = Node::new(
+            FileSinkProcessor::new(bus, FileSinkTarget::Wav(path.clone()))
+                .unwrap()
+                .with_progress(Arc::clone(&frames_written)),
+        );
+        // uneven chunks across channels are written as whole frames
+        senders[0].send(vec![1.0, 2.0, 3.0]).unwrap();
+        senders[1].send(vec![-1.0]).unwrap();
+        senders[1].send(vec![-2.0, -3.0]).unwrap();
+        drop(senders);
+        node.join().unwrap();
+        assert_eq!(frames_written.load(Ordering::Relaxed), 3);
+        let audio: Audio = WavReader::open(path.to_str().unwrap()).unwrap().read_all();
+        assert_eq!(audio.spec, spec);
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![1.0, 2.0, 3.0]);
+        assert_almost_eq_by_element(audio.data[1].clone(), vec![-1.0, -2.0, -3.0]);
+        let _ = fs::remove_file(path);
+    }
+}
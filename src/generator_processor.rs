@@ -0,0 +1,149 @@
+use crate::audio::{AudioBus, AudioSpec};
+use crate::generators::{Generator, Waveform};
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use anyhow::Result;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const CHUNK_LEN: usize = 1024;
+/// Chunks queued per channel before generating blocks, which keeps a
+/// generator feeding live playback from running far ahead of it
+const CHUNK_QUEUE: usize = 8;
+
+#[derive(Debug)]
+pub enum GeneratorProcessorControlMessage {
+    Shutdown,
+    SetWaveform { waveform: Waveform },
+    SetAmplitude { amplitude: f32 },
+}
+
+impl ControlMessage for GeneratorProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        GeneratorProcessorControlMessage::Shutdown
+    }
+}
+
+/// A source node emitting a test signal on every channel of a bus
+pub struct GeneratorProcessor {
+    generator: Generator,
+    /// Samples per channel left to generate, or `None` to run until shut down
+    remaining: Option<usize>,
+    outputs: Vec<Sender<Vec<f32>>>,
+}
+
+impl GeneratorProcessor {
+    pub fn new(
+        spec: AudioSpec,
+        waveform: Waveform,
+        amplitude: f32,
+        duration: Option<Duration>,
+    ) -> (GeneratorProcessor, AudioBus) {
+        let remaining =
+            duration.map(|duration| (duration.as_secs_f32() * spec.sample_rate as f32) as usize);
+        let (outputs, receivers) = (0..spec.channels).map(|_| bounded(CHUNK_QUEUE)).unzip();
+        (
+            GeneratorProcessor {
+                generator: Generator::new(waveform, amplitude, spec.sample_rate),
+                remaining,
+                outputs,
+            },
+            AudioBus {
+                spec,
+                channels: receivers,
+                expected_total_samples: remaining,
+                timestamps: None,
+            },
+        )
+    }
+}
+
+impl Processor<GeneratorProcessorControlMessage> for GeneratorProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<GeneratorProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            while self.remaining != Some(0) {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                let len = self
+                    .remaining
+                    .map_or(CHUNK_LEN, |remaining| remaining.min(CHUNK_LEN));
+                let mut chunk = vec![0.0; len];
+                self.generator.fill(&mut chunk);
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining -= len;
+                }
+                if !self
+                    .outputs
+                    .iter()
+                    .all(|output| output.send(chunk.clone()).is_ok())
+                {
+                    info!("generator output disconnected, stopping");
+                    break;
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<GeneratorProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                GeneratorProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                GeneratorProcessorControlMessage::SetWaveform { waveform } => {
+                    self.generator.set_waveform(waveform);
+                    Ok(ProcessorState::Running)
+                }
+                GeneratorProcessorControlMessage::SetAmplitude { amplitude } => {
+                    self.generator.set_amplitude(amplitude);
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signal_flow::node::Node;
+
+    #[test]
+    fn generates_for_the_given_duration() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let (generator, output) = GeneratorProcessor::new(
+            spec,
+            Waveform::Sine { freq: 440.0 },
+            0.5,
+            Some(Duration::from_millis(100)),
+        );
+        assert_eq!(output.expected_total_samples, Some(4410));
+        let node = Node::new(generator);
+        let audio = output.into_audio();
+        node.join().unwrap();
+        assert_eq!(audio.data[0].len(), 4410);
+        assert_eq!(audio.data[0], audio.data[1]);
+        assert!(audio.data[0].iter().all(|sample| sample.abs() <= 0.5));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::audio::{Audio, AudioSpec};
+use anyhow::{anyhow, bail, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const CLICK_FREQ: f32 = 1000.0;
+const CLICK_LEN: Duration = Duration::from_millis(10);
+
+/// A test signal; parsed from e.g. `sine:440`, `noise`, `pink` or `click:120`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Waveform {
+    Sine {
+        freq: f32,
+    },
+    WhiteNoise,
+    PinkNoise,
+    /// A short decaying beep at the start of every beat
+    Click {
+        bpm: f32,
+    },
+}
+
+impl FromStr for Waveform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Waveform> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s, None),
+        };
+        let number = |what: &str| -> Result<f32> {
+            let arg =
+                arg.ok_or_else(|| anyhow!("waveform {:?} needs a {} after a colon", name, what))?;
+            match arg.parse::<f32>() {
+                Ok(value) if value > 0.0 => Ok(value),
+                _ => bail!("invalid {} {:?} for {}", what, arg, name),
+            }
+        };
+        match name {
+            "sine" => Ok(Waveform::Sine {
+                freq: number("frequency")?,
+            }),
+            "noise" => Ok(Waveform::WhiteNoise),
+            "pink" => Ok(Waveform::PinkNoise),
+            "click" => Ok(Waveform::Click {
+                bpm: number("tempo")?,
+            }),
+            _ => bail!(
+                "unknown waveform {:?}, expected one of: sine:<hz>, noise, pink, click:<bpm>",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Waveform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Waveform::Sine { freq } => write!(f, "sine:{}", freq),
+            Waveform::WhiteNoise => write!(f, "noise"),
+            Waveform::PinkNoise => write!(f, "pink"),
+            Waveform::Click { bpm } => write!(f, "click:{}", bpm),
+        }
+    }
+}
+
+/// Produces a mono test signal one block at a time
+pub struct Generator {
+    waveform: Waveform,
+    amplitude: f32,
+    sample_rate: u32,
+    /// Samples generated so far
+    position: u64,
+    rng: StdRng,
+    /// Filter state for pink noise
+    pink: [f32; 7],
+}
+
+impl Generator {
+    pub fn new(waveform: Waveform, amplitude: f32, sample_rate: u32) -> Self {
+        Generator {
+            waveform,
+            amplitude,
+            sample_rate,
+            position: 0,
+            rng: StdRng::from_entropy(),
+            pink: [0.0; 7],
+        }
+    }
+
+    /// Make the noise waveforms reproducible from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    pub fn set_amplitude(&mut self, amplitude: f32) {
+        self.amplitude = amplitude;
+    }
+
+    /// Overwrite `out` with the next samples of the signal
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample() * self.amplitude;
+            self.position += 1;
+        }
+    }
+
+    /// Render `duration` of the signal onto every channel of `spec`
+    pub fn render(&mut self, spec: &AudioSpec, duration: Duration) -> Audio {
+        let mut channel = vec![0.0; (duration.as_secs_f32() * spec.sample_rate as f32) as usize];
+        self.fill(&mut channel);
+        Audio {
+            data: vec![channel; spec.channels as usize],
+            spec: *spec,
+            markers: vec![],
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let t = self.position as f32 / self.sample_rate as f32;
+        match self.waveform {
+            Waveform::Sine { freq } => {
+                // Wrap the phase per cycle so long runs don't lose precision
+                let cycle_len = self.sample_rate as f64 / freq as f64;
+                let phase = (self.position as f64 % cycle_len) / cycle_len;
+                (2.0 * PI * phase as f32).sin()
+            }
+            Waveform::WhiteNoise => self.rng.gen_range(-1.0..1.0),
+            Waveform::PinkNoise => {
+                // Paul Kellet's refined pink noise filter
+                let white = self.rng.gen_range(-1.0..1.0);
+                let b = &mut self.pink;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.153852;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                // Roughly normalize to the same peak level as the other waveforms
+                pink * 0.11
+            }
+            Waveform::Click { bpm } => {
+                let since_beat = t % (60.0 / bpm);
+                let click_len = CLICK_LEN.as_secs_f32();
+                if since_beat < click_len {
+                    (2.0 * PI * CLICK_FREQ * since_beat).sin() * (1.0 - since_beat / click_len)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn parses_waveforms() {
+        assert_eq!(
+            "sine:440".parse::<Waveform>().unwrap(),
+            Waveform::Sine { freq: 440.0 }
+        );
+        assert_eq!("pink".parse::<Waveform>().unwrap(), Waveform::PinkNoise);
+        assert_eq!(
+            "click:120".parse::<Waveform>().unwrap(),
+            Waveform::Click { bpm: 120.0 }
+        );
+        assert_eq!(Waveform::Click { bpm: 120.0 }.to_string(), "click:120");
+        assert!("sine".parse::<Waveform>().is_err());
+        assert!("sine:-1".parse::<Waveform>().is_err());
+        assert!("saw:440".parse::<Waveform>().is_err());
+    }
+
+    #[test]
+    fn sine_continues_across_blocks() {
+        let mut generator = Generator::new(Waveform::Sine { freq: 1.0 }, 0.5, 4);
+        let mut first = vec![0.0; 2];
+        let mut second = vec![0.0; 3];
+        generator.fill(&mut first);
+        generator.fill(&mut second);
+        assert_almost_eq_by_element(first, vec![0.0, 0.5]);
+        assert_almost_eq_by_element(second, vec![0.0, -0.5, 0.0]);
+    }
+
+    #[test]
+    fn clicks_on_every_beat_and_noise_is_not_silent() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 8000,
+        };
+        let click = Generator::new(Waveform::Click { bpm: 600.0 }, 1.0, spec.sample_rate)
+            .render(&spec, Duration::from_millis(300));
+        assert_eq!(click.data.len(), 2);
+        for beat in [0, 800, 1600] {
+            assert!(click.data[0][beat + 1].abs() > 0.1);
+            assert_almost_eq(click.data[0][beat + 400], 0.0);
+        }
+        for waveform in [Waveform::WhiteNoise, Waveform::PinkNoise] {
+            let noise = Generator::new(waveform, 0.5, spec.sample_rate)
+                .render(&spec, Duration::from_millis(100));
+            assert!(noise.data[0].iter().any(|sample| sample.abs() > 0.01));
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Golden-file regression tests for the stretch engines
+//!
+//! Each case stretches a short generated fixture with a fixed seed and window,
+//! summarizes the result as its length and the average level in a set of
+//! log-spaced frequency bands, and compares that with the reference stored in
+//! `testdata/golden`. Levels may drift by `TOLERANCE_DB`, so floating point
+//! differences between platforms pass but a change to how a stretch sounds
+//! doesn't.
+//!
+//! After a change that's meant to alter the sound, rewrite the references with
+//!
+//! ```text
+//! ROCODER_BLESS_GOLDEN=1 cargo test golden
+//! ```
+//!
+//! and check the differences in review.
+
+use crate::audio::{Audio, AudioSpec};
+use crate::generators::{Generator, Waveform};
+use crate::stretch::{stretch, StretchOptions};
+use crate::stretch_engine::EngineKind;
+use crate::windows;
+use rustfft::num_complex::Complex32;
+use rustfft::FftPlanner;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const SPEC: AudioSpec = AudioSpec {
+    channels: 1,
+    sample_rate: 22050,
+};
+const FIXTURE_DURATION: Duration = Duration::from_millis(500);
+const SEED: u64 = 1;
+const ANALYSIS_WINDOW: usize = 2048;
+const BANDS: usize = 24;
+const LOWEST_BAND_HZ: f32 = 40.0;
+/// Quieter bands are clamped to this, so noise floors don't have to match
+const FLOOR_DB: f32 = -90.0;
+const TOLERANCE_DB: f32 = 1.5;
+const BLESS_VAR: &str = "ROCODER_BLESS_GOLDEN";
+
+fn fixture(waveform: Waveform) -> Audio {
+    let mut generator = Generator::new(waveform, 0.5, SPEC.sample_rate);
+    generator.set_seed(SEED);
+    generator.render(&SPEC, FIXTURE_DURATION)
+}
+
+/// The average level in dB of each of `BANDS` log-spaced bands from
+/// `LOWEST_BAND_HZ` to Nyquist, over Hann-windowed frames overlapping by half
+fn band_levels(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    let fft = FftPlanner::new().plan_fft_forward(ANALYSIS_WINDOW);
+    let window = windows::hanning(ANALYSIS_WINDOW);
+    let bin_hz = sample_rate as f32 / ANALYSIS_WINDOW as f32;
+    let nyquist = sample_rate as f32 / 2.0;
+    let band_of = |bin: usize| {
+        let hz = bin as f32 * bin_hz;
+        if hz < LOWEST_BAND_HZ {
+            return None;
+        }
+        let position = (hz / LOWEST_BAND_HZ).ln() / (nyquist / LOWEST_BAND_HZ).ln();
+        Some(((position * BANDS as f32) as usize).min(BANDS - 1))
+    };
+    let mut power = [0.0f64; BANDS];
+    let mut bins = [0usize; BANDS];
+    let mut buf = vec![Complex32::default(); ANALYSIS_WINDOW];
+    for frame in samples
+        .windows(ANALYSIS_WINDOW)
+        .step_by(ANALYSIS_WINDOW / 2)
+    {
+        for ((out, sample), gain) in buf.iter_mut().zip(frame).zip(&window) {
+            *out = Complex32::new(sample * gain, 0.0);
+        }
+        fft.process(&mut buf);
+        for (bin, value) in buf[..ANALYSIS_WINDOW / 2].iter().enumerate() {
+            if let Some(band) = band_of(bin) {
+                power[band] += value.norm_sqr() as f64;
+                bins[band] += 1;
+            }
+        }
+    }
+    power
+        .iter()
+        .zip(&bins)
+        .map(|(power, bins)| match bins {
+            0 => FLOOR_DB,
+            _ => (10.0 * (power / *bins as f64).log10() as f32).max(FLOOR_DB),
+        })
+        .collect()
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/golden")
+        .join(format!("{}.txt", name))
+}
+
+fn render_reference(name: &str, len: usize, levels: &[f32]) -> String {
+    let levels: Vec<String> = levels.iter().map(|db| format!("{:.2}", db)).collect();
+    format!(
+        "# {}: length, then band levels in dB. Regenerate with {}=1 cargo test golden\n{}\n{}\n",
+        name,
+        BLESS_VAR,
+        len,
+        levels.join(" ")
+    )
+}
+
+fn parse_reference(reference: &str) -> (usize, Vec<f32>) {
+    let mut lines = reference.lines().filter(|line| !line.starts_with('#'));
+    let len = lines.next().unwrap().trim().parse().unwrap();
+    let levels = lines
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .map(|db| db.parse().unwrap())
+        .collect();
+    (len, levels)
+}
+
+/// Stretch `waveform` with `options` and compare it with the reference `name`
+fn check_golden(name: &str, waveform: Waveform, options: StretchOptions) {
+    let options = StretchOptions {
+        seed: Some(SEED),
+        ..options
+    };
+    let stretched = stretch(&fixture(waveform), &options).unwrap();
+    let len = stretched.data[0].len();
+    let levels = band_levels(&stretched.data[0], SPEC.sample_rate);
+    let path = reference_path(name);
+    if std::env::var_os(BLESS_VAR).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, render_reference(name, len, &levels)).unwrap();
+        return;
+    }
+    let reference = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "failed to read {:?} ({}); create it with {}=1",
+            path, e, BLESS_VAR
+        )
+    });
+    let (reference_len, reference_levels) = parse_reference(&reference);
+    assert_eq!(len, reference_len, "{} changed length", name);
+    for (band, (level, reference_level)) in levels.iter().zip(&reference_levels).enumerate() {
+        assert!(
+            (level - reference_level).abs() <= TOLERANCE_DB,
+            "{}: band {} is {:.2} dB, but the reference is {:.2} dB",
+            name,
+            band,
+            level,
+            reference_level
+        );
+    }
+}
+
+fn options(engine: EngineKind, factor: f32) -> StretchOptions {
+    StretchOptions {
+        engine,
+        factor,
+        window_len: 2048,
+        grain: Duration::from_millis(50),
+        ..StretchOptions::default()
+    }
+}
+
+#[test]
+fn golden_vocoder_sine() {
+    check_golden(
+        "vocoder_sine",
+        Waveform::Sine { freq: 440.0 },
+        options(EngineKind::Vocoder, 4.0),
+    );
+}
+
+#[test]
+fn golden_vocoder_pink_noise() {
+    check_golden(
+        "vocoder_pink_noise",
+        Waveform::PinkNoise,
+        options(EngineKind::Vocoder, 2.0),
+    );
+}
+
+#[test]
+fn golden_vocoder_pitch_shift() {
+    check_golden(
+        "vocoder_pitch_shift",
+        Waveform::Sine { freq: 440.0 },
+        StretchOptions {
+            pitch_multiple: 2,
+            ..options(EngineKind::Vocoder, 2.0)
+        },
+    );
+}
+
+#[test]
+fn golden_granular_click() {
+    check_golden(
+        "granular_click",
+        Waveform::Click { bpm: 240.0 },
+        options(EngineKind::Granular, 3.0),
+    );
+}
+
+#[test]
+fn golden_wsola_sine() {
+    check_golden(
+        "wsola_sine",
+        Waveform::Sine { freq: 440.0 },
+        options(EngineKind::Wsola, 1.5),
+    );
+}
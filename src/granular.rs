@@ -0,0 +1,248 @@
+//! Granular stretching, an alternative to the phase vocoder
+//!
+//! Short windowed grains are read from the input and overlap-added into the
+//! output. Grains are laid down at a fixed rate while their read position
+//! creeps through the input at a fraction of it, so sounds last longer
+//! without changing pitch. There's no spectrum involved, so transients and
+//! noisy material keep more of their grit than through the vocoder, at the
+//! cost of a graininess of its own.
+
+use crate::audio::AudioSpec;
+use crate::math::lerp;
+use crate::slices::SliceDeque;
+use crate::stretch_engine::{EngineInput, StretchEngine};
+use crossbeam_channel::Receiver;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/// The shape of the grains
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GrainParams {
+    /// How long each grain is, in samples
+    pub len: usize,
+    /// How far ahead of the read position a grain may start, as a fraction of its length
+    pub jitter: f32,
+    /// How many grains overlap at any moment
+    pub density: f32,
+}
+
+/// Granular stretcher for one channel of audio
+pub struct GranularStretcher {
+    spec: AudioSpec,
+    input: EngineInput,
+    /// Where the next grain is read from in `input`, between samples
+    /// for small steps
+    read_pos: f64,
+    output_buf: SliceDeque<f32>,
+    /// Where the next grain is added in `output_buf`
+    write_pos: usize,
+    grain: GrainParams,
+    window: Vec<f32>,
+    /// Output samples between grain starts
+    output_step: usize,
+    /// Input samples between grain starts
+    input_step: f64,
+    /// Input samples read per output sample, which shifts the pitch
+    read_rate: f64,
+    /// Evens out the level however many grains overlap
+    gain: f32,
+    amplitude: f32,
+    factor: f32,
+    rng: StdRng,
+    frozen: bool,
+    done: bool,
+}
+
+impl GranularStretcher {
+    pub fn new(
+        spec: AudioSpec,
+        input: Receiver<Vec<f32>>,
+        factor: f32,
+        amplitude: f32,
+        pitch_multiple: i8,
+        grain: GrainParams,
+        buffer_dur: Duration,
+    ) -> GranularStretcher {
+        assert!(pitch_multiple != 0);
+        assert!(grain.len > 1 && grain.density > 0.0);
+        let read_rate = if pitch_multiple < 0 {
+            1.0 / pitch_multiple.abs() as f64
+        } else {
+            pitch_multiple as f64
+        };
+        // A periodic Hann window, whose overlaps sum to a flat line
+        let window: Vec<f32> = (0..grain.len)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / grain.len as f32).cos())
+            .collect();
+        let output_step = ((grain.len as f32 / grain.density).round() as usize).max(1);
+        let gain = output_step as f32 / window.iter().sum::<f32>();
+        let mut stretcher = GranularStretcher {
+            spec,
+            input: EngineInput::new(input, buffer_dur),
+            read_pos: 0.0,
+            output_buf: SliceDeque::new(),
+            write_pos: 0,
+            grain,
+            window,
+            output_step,
+            input_step: 0.0,
+            read_rate,
+            gain,
+            amplitude,
+            factor,
+            rng: StdRng::from_entropy(),
+            frozen: false,
+            done: false,
+        };
+        stretcher.set_factor(factor);
+        stretcher
+    }
+
+    /// Make this stretcher's grain positions reproducible from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Add the grain at the read position to the output, and move both on
+    fn add_grain(&mut self) {
+        let span = self.grain.len as f64 * self.read_rate;
+        let jitter = self.grain.jitter as f64 * span;
+        let start = self.read_pos
+            + if jitter > 0.0 {
+                self.rng.gen_range(0.0..jitter)
+            } else {
+                0.0
+            };
+        // Grains reach past the read position, so the input carries on as
+        // silence until it reaches the end
+        self.input
+            .ensure_available(start.ceil() as usize + span.ceil() as usize + 1);
+        let end = self.write_pos + self.grain.len;
+        if self.output_buf.len() < end {
+            self.output_buf.resize(end, 0.0);
+        }
+        let scale = self.gain * self.amplitude;
+        for (i, window) in self.window.iter().enumerate() {
+            let pos = start + i as f64 * self.read_rate;
+            let index = pos as usize;
+            let sample = lerp(self.input[index], self.input[index + 1], pos.fract() as f32);
+            self.output_buf[self.write_pos + i] += sample * window * scale;
+        }
+        self.write_pos += self.output_step;
+        if !self.frozen {
+            self.read_pos += self.input_step;
+            let consumed = self.read_pos.floor();
+            // Compressing steps further than a grain, past input not read yet
+            self.input.consume(consumed as usize);
+            self.read_pos -= consumed;
+            if let Some(left) = self.input.samples_left() {
+                self.done = self.read_pos >= left as f64;
+            }
+        }
+    }
+}
+
+impl StretchEngine for GranularStretcher {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    /// A grain's length of output
+    fn next_window(&mut self) -> Vec<f32> {
+        // Samples before the write position have had every grain they'll get
+        while self.write_pos < self.grain.len {
+            self.add_grain();
+        }
+        let window = self.output_buf[..self.grain.len].to_vec();
+        self.output_buf
+            .truncate_front(self.output_buf.len() - self.grain.len);
+        self.write_pos -= self.grain.len;
+        window
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn channel_bound(&self) -> usize {
+        self.input
+            .channel_bound(self.grain.len, self.spec.sample_rate)
+    }
+
+    fn latency(&self) -> usize {
+        // The last grain of the window can start anywhere in its jitter
+        let span = self.grain.len as f64 * self.read_rate;
+        let steps = self.grain.len.div_ceil(self.output_step) - 1;
+        (steps as f64 * self.input_step + span * (1.0 + self.grain.jitter as f64)).ceil() as usize
+            + 1
+    }
+
+    fn factor(&self) -> f32 {
+        self.factor
+    }
+
+    fn set_factor(&mut self, factor: f32) {
+        self.factor = factor;
+        self.input_step = self.output_step as f64 / factor as f64;
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crossbeam_channel::unbounded;
+
+    fn stretch(input: Vec<f32>, factor: f32, pitch_multiple: i8) -> Vec<f32> {
+        let (tx, rx) = unbounded();
+        tx.send(input).unwrap();
+        drop(tx);
+        let mut stretcher = GranularStretcher::new(
+            AudioSpec {
+                channels: 1,
+                sample_rate: 44100,
+            },
+            rx,
+            factor,
+            1.0,
+            pitch_multiple,
+            GrainParams {
+                len: 256,
+                jitter: 0.5,
+                density: 4.0,
+            },
+            Duration::from_secs(1),
+        );
+        stretcher.set_seed(3);
+        let mut output = vec![];
+        while !stretcher.is_done() {
+            output.extend(stretcher.next_window());
+        }
+        output
+    }
+
+    #[test]
+    fn grains_stretch_without_changing_the_level() {
+        let output = stretch(vec![0.5; 4410], 4.0, 1);
+        let len = output.len() as f32;
+        assert!((len / (4410.0 * 4.0) - 1.0).abs() < 0.05, "{}", len);
+        // Away from the fade in at the start, and the end, where grains
+        // reach past the input into silence
+        for sample in &output[512..output.len() - 2048] {
+            assert!((sample - 0.5).abs() < 0.01, "{}", sample);
+        }
+
+        // Compressing skips most of the input between grains
+        let compressed = stretch(vec![0.5; 44100], 0.1, 1);
+        assert!((compressed.len() as f32 - 4410.0).abs() <= 256.0);
+
+        // Pitching grains up reads each one faster, but not the input as a whole
+        let pitched = stretch(vec![0.5; 4410], 4.0, 2);
+        assert!((pitched.len() as f32 / len - 1.0).abs() < 0.05);
+    }
+}
@@ -1,8 +1,13 @@
+use crate::kernel::{DylibKernel, Kernel};
+#[cfg(feature = "scripting")]
+use crate::script_kernel::ScriptKernel;
 use anyhow::{bail, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use fwatch::{BasicTarget, Transition, Watcher};
 use libloading::{Library, Symbol};
 use std::ffi::CString;
+#[cfg(feature = "scripting")]
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::thread;
@@ -11,10 +16,13 @@ use tempfile;
 
 const WATCHER_POLL_DUR: Duration = Duration::from_millis(100);
 
-pub fn hotswap(path: PathBuf) -> Result<Receiver<Library>> {
-    let (sender, receiver) = unbounded::<Library>();
+/// Load the kernel at `path`, then reload it whenever the file changes
+///
+/// `.rhai` files are interpreted as scripts, anything else is compiled as rust.
+pub fn hotswap(path: PathBuf) -> Result<Receiver<Box<dyn Kernel>>> {
+    let (sender, receiver) = unbounded::<Box<dyn Kernel>>();
 
-    attempt_lib_update(&path, &sender);
+    attempt_kernel_update(&path, &sender);
 
     let mut watcher: Watcher<BasicTarget> = Watcher::new();
     watcher.add_target(BasicTarget::new(&path));
@@ -22,7 +30,7 @@ pub fn hotswap(path: PathBuf) -> Result<Receiver<Library>> {
     thread::spawn(move || loop {
         for event in watcher.watch() {
             match event {
-                Transition::Modified => attempt_lib_update(&path, &sender),
+                Transition::Modified => attempt_kernel_update(&path, &sender),
                 _ => {}
             }
         }
@@ -32,24 +40,33 @@ pub fn hotswap(path: PathBuf) -> Result<Receiver<Library>> {
     Ok(receiver)
 }
 
-fn attempt_lib_update(src_path: &Path, lib_sender: &Sender<Library>) {
-    let library = match compile(&src_path) {
-        Ok(lib) => lib,
-        Err(_e) => {
-            warn!("Failed to compile library for file {:?}", &src_path);
+fn attempt_kernel_update(src_path: &Path, kernel_sender: &Sender<Box<dyn Kernel>>) {
+    let kernel = match load_kernel(src_path) {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            warn!("Failed to load kernel for file {:?}: {}", &src_path, e);
             return;
         }
     };
-    match lib_sender.send(library) {
+    match kernel_sender.send(kernel) {
         Ok(_) => (),
         Err(_) => trace!(
-            "Failed to send library down channel for file {:?}",
+            "Failed to send kernel down channel for file {:?}",
             &src_path
         ),
     }
 }
 
-fn compile(path: &Path) -> Result<Library> {
+/// Compile or interpret the kernel at `path` once, without watching it
+pub fn load_kernel(path: &Path) -> Result<Box<dyn Kernel>> {
+    #[cfg(feature = "scripting")]
+    if path.extension().is_some_and(|ext| ext == "rhai") {
+        return Ok(Box::new(ScriptKernel::new(&fs::read_to_string(path)?)?));
+    }
+    Ok(Box::new(DylibKernel::load(compile(path)?)?))
+}
+
+pub(crate) fn compile(path: &Path) -> Result<Library> {
     if cfg!(target_os = "windows") {
         // this definitely _can_ be done, but the code would be different here
         // and I don't have a windows machine to develop on
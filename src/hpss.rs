@@ -0,0 +1,121 @@
+//! Harmonic/percussive source separation, as described by Derry FitzGerald
+//! in "Harmonic/Percussive Separation using Median Filtering" (2010)
+//!
+//! Sustained sounds make horizontal lines in a spectrogram, and hits make
+//! vertical ones. Median filtering each bin across time keeps the former,
+//! filtering each window across frequency keeps the latter, and the two
+//! filtered spectrograms become soft masks that split the audio in two.
+//! The masks add up to one, so the two parts sum back to the input.
+
+use crate::audio::Audio;
+use crate::fft;
+
+/// About 46 ms at 44.1 kHz, short enough to keep hits apart
+pub const DEFAULT_WINDOW_LEN: usize = 2048;
+/// How many windows or bins each median filter spans
+const MEDIAN_LEN: usize = 17;
+
+fn median(values: &mut [f32]) -> f32 {
+    let middle = values.len() / 2;
+    *values
+        .select_nth_unstable_by(middle, |a, b| a.total_cmp(b))
+        .1
+}
+
+/// Split `audio` into its harmonic and percussive parts, analysing it in
+/// windows of `window_len` samples
+pub fn separate(audio: &Audio, window_len: usize) -> (Audio, Audio) {
+    let mut harmonic = Audio::from_spec(&audio.spec);
+    let mut percussive = Audio::from_spec(&audio.spec);
+    harmonic.markers = audio.markers.clone();
+    for (i, channel) in audio.data.iter().enumerate() {
+        let (h, p) = separate_channel(channel, window_len);
+        harmonic.data[i] = h;
+        percussive.data[i] = p;
+    }
+    (harmonic, percussive)
+}
+
+fn separate_channel(samples: &[f32], window_len: usize) -> (Vec<f32>, Vec<f32>) {
+    let frames = fft::stft(samples, window_len);
+    let n_positive = window_len / 2 + 1;
+    let magnitudes: Vec<Vec<f32>> = frames
+        .iter()
+        .map(|bins| bins[..n_positive].iter().map(|bin| bin.norm()).collect())
+        .collect();
+    let radius = MEDIAN_LEN / 2;
+    let mut scratch = Vec::with_capacity(MEDIAN_LEN);
+    let mut median_across = |values: &mut dyn Iterator<Item = f32>| {
+        scratch.clear();
+        scratch.extend(values);
+        median(&mut scratch)
+    };
+
+    let mut percussive_frames = frames.clone();
+    let mut harmonic_frames = frames;
+    for t in 0..harmonic_frames.len() {
+        let first = t.saturating_sub(radius);
+        let last = (t + radius + 1).min(magnitudes.len());
+        for k in 0..n_positive {
+            let across_time =
+                median_across(&mut magnitudes[first..last].iter().map(|frame| frame[k]));
+            let low = k.saturating_sub(radius);
+            let high = (k + radius + 1).min(n_positive);
+            let across_frequency = median_across(&mut magnitudes[t][low..high].iter().copied());
+            let (h, p) = (
+                across_time * across_time,
+                across_frequency * across_frequency,
+            );
+            let harmonic_share = if h + p > 0.0 { h / (h + p) } else { 0.5 };
+            harmonic_frames[t][k] *= harmonic_share;
+            percussive_frames[t][k] *= 1.0 - harmonic_share;
+        }
+    }
+    (
+        fft::istft(harmonic_frames, window_len, samples.len()),
+        fft::istft(percussive_frames, window_len, samples.len()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn sines_are_harmonic_and_clicks_are_percussive() {
+        let sample_rate = 8000;
+        let len = sample_rate as usize * 2;
+        let sine: Vec<f32> = (0..len)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 8000.0).sin())
+            .collect();
+        let mut clicks = vec![0.0; len];
+        for click in (1000..len).step_by(3000) {
+            clicks[click] = 1.0;
+        }
+        let audio = Audio {
+            spec: AudioSpec {
+                channels: 2,
+                sample_rate,
+            },
+            data: vec![sine.clone(), clicks.clone()],
+            markers: vec![],
+        };
+        let (harmonic, percussive) = separate(&audio, 512);
+        assert_eq!(harmonic.data[0].len(), len);
+        assert!(energy(&harmonic.data[0]) > 20.0 * energy(&percussive.data[0]));
+        assert!(energy(&percussive.data[1]) > 5.0 * energy(&harmonic.data[1]));
+
+        // The parts sum back to the input
+        for (i, input) in audio.data.iter().enumerate() {
+            for (j, sample) in input.iter().enumerate() {
+                let sum = harmonic.data[i][j] + percussive.data[i][j];
+                assert!((sum - sample).abs() < 1e-3, "{} vs {}", sum, sample);
+            }
+        }
+    }
+}
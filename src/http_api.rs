@@ -0,0 +1,194 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Limits how long one slow client can hold up the others
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(body: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    pub fn text(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", body),
+        }
+    }
+}
+
+/// Serve HTTP on `addr`, answering every request with `handler`
+///
+/// This is a deliberately tiny HTTP/1.1 server for monitoring and control:
+/// one request per connection, handled one connection at a time.
+pub fn serve<F>(addr: SocketAddr, handler: F) -> Result<()>
+where
+    F: Fn(&Request) -> Response + Send + 'static,
+{
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("failed to listen on {}", addr))?;
+    info!("Serving the HTTP API on http://{}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("HTTP connection failed: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = handle_connection(stream, &handler) {
+                debug!("HTTP request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection<F>(mut stream: TcpStream, handler: &F) -> Result<()>
+where
+    F: Fn(&Request) -> Response,
+{
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let response = match read_request(&mut BufReader::new(&stream)) {
+        Ok(request) => handler(&request),
+        Err(e) => Response::text(400, &e.to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    Ok(())
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => bail!("malformed request line"),
+    };
+    let mut content_len = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            bail!("connection closed in headers");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("invalid Content-Length"))?;
+            }
+        }
+    }
+    if content_len > MAX_BODY_LEN {
+        bail!("request body too large");
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8(body).context("request body isn't UTF-8")?,
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+
+/// Quote a string for JSON output
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn reads_requests_with_bodies() {
+        let raw = "POST /stretch/factor HTTP/1.1\r\nHost: x\r\ncontent-length: 3\r\n\r\n2.5";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "POST".to_string(),
+                path: "/stretch/factor".to_string(),
+                body: "2.5".to_string(),
+            }
+        );
+        assert!(read_request(&mut "\r\n\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET / HTTP/1.1\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn serves_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        serve(addr, |request| Response::json(json_string(&request.path))).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /st\"atus HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n\"/st\\\"atus\""));
+    }
+}
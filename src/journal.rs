@@ -0,0 +1,136 @@
+use crate::http_api::json_string;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A value recorded with a journal event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Number(value as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::Number(value) if value.is_finite() => value.to_string(),
+            Value::Number(_) => "null".to_string(),
+            Value::String(value) => json_string(value),
+            Value::Bool(value) => value.to_string(),
+        }
+    }
+}
+
+/// An append-only log of what happened during a run, one JSON object per line
+///
+/// Every line has `time`, in seconds since the Unix epoch, and `event`, plus
+/// the event's own fields. Lines are written through as they happen, so the
+/// journal survives the process being killed.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Journal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open journal {:?}", path))?;
+        Ok(Journal { file })
+    }
+
+    pub fn record(&mut self, event: &str, fields: &[(&str, Value)]) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        writeln!(self.file, "{}", event_json(time, event, fields))?;
+        Ok(())
+    }
+}
+
+fn event_json(time: f64, event: &str, fields: &[(&str, Value)]) -> String {
+    let mut json = format!("{{\"time\":{:.3},\"event\":{}", time, json_string(event));
+    for (name, value) in fields {
+        json.push_str(&format!(",{}:{}", json_string(name), value.to_json()));
+    }
+    json.push('}');
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn appends_one_object_per_event() {
+        assert_eq!(
+            event_json(
+                12.5,
+                "start",
+                &[
+                    ("input", "a \"b\".wav".into()),
+                    ("factor", 4.0f32.into()),
+                    ("ab", false.into()),
+                    ("peak", f32::NAN.into())
+                ]
+            ),
+            r#"{"time":12.500,"event":"start","input":"a \"b\".wav","factor":4,"ab":false,"peak":null}"#
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        Journal::open(&path).unwrap().record("start", &[]).unwrap();
+        Journal::open(&path)
+            .unwrap()
+            .record("finish", &[("played_secs", 1.5f64.into())])
+            .unwrap();
+        let lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with(r#""event":"finish","played_secs":1.5}"#));
+    }
+}
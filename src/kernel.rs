@@ -0,0 +1,408 @@
+use crate::builtin_kernels::BuiltinKernel;
+use anyhow::{bail, Result};
+#[cfg(feature = "native")]
+use libloading::Library;
+use rustfft::num_complex::Complex32;
+use std::ffi::c_void;
+#[cfg(feature = "native")]
+use std::path::PathBuf;
+#[cfg(feature = "native")]
+use std::ptr;
+
+/// Bumped whenever `KernelFrame` or the kernel entry points change
+pub const KERNEL_ABI_VERSION: u32 = 3;
+
+#[cfg(feature = "native")]
+const ABI_VERSION_SYMBOL: &[u8] = b"ROCODER_KERNEL_ABI_VERSION\0";
+#[cfg(feature = "native")]
+const PROCESS_SYMBOL: &[u8] = b"process\0";
+#[cfg(feature = "native")]
+const INIT_SYMBOL: &[u8] = b"init\0";
+#[cfg(feature = "native")]
+const TEARDOWN_SYMBOL: &[u8] = b"teardown\0";
+
+/// One analysis window, as handed to a frequency kernel's `process` function
+///
+/// Kernels are compiled on their own, so they must declare an identical
+/// `#[repr(C)]` struct; the README has a copy to paste. Any change to this
+/// layout must bump `KERNEL_ABI_VERSION`.
+#[repr(C)]
+pub struct KernelFrame {
+    /// Frequency bins as `[re, im]` pairs, modified in place by the kernel
+    pub bins: *mut [f32; 2],
+    pub bins_len: usize,
+    pub sample_rate: u32,
+    pub window_len: u32,
+    /// Index of the audio channel this frame belongs to
+    pub channel: u32,
+    /// Milliseconds since the stretcher started
+    pub elapsed_ms: u64,
+    /// Named parameters set from the command line or control messages
+    pub params: *const KernelParam,
+    pub params_len: usize,
+    /// Whatever the kernel's `init` returned, or null if it has none
+    pub state: *mut c_void,
+}
+
+/// A named value a kernel can read to expose a knob without being recompiled
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct KernelParam {
+    /// UTF-8 name, not nul terminated
+    pub name: *const u8,
+    pub name_len: usize,
+    pub value: f32,
+}
+
+impl KernelParam {
+    /// The returned param borrows `name`, which must outlive it
+    pub fn new(name: &str, value: f32) -> KernelParam {
+        KernelParam {
+            name: name.as_ptr(),
+            name_len: name.len(),
+            value,
+        }
+    }
+}
+
+/// Everything in a `KernelFrame` besides its bins
+#[derive(Debug, Copy, Clone)]
+pub struct FrameInfo<'a> {
+    pub sample_rate: u32,
+    pub window_len: u32,
+    pub channel: u32,
+    pub elapsed_ms: u64,
+    pub params: &'a [KernelParam],
+}
+
+impl FrameInfo<'_> {
+    /// Named parameters as `(name, value)` pairs
+    pub fn params(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.params.iter().filter_map(|param| {
+            // params are only built by KernelParam::new from names that outlive the frame
+            let name = unsafe { std::slice::from_raw_parts(param.name, param.name_len) };
+            Some((std::str::from_utf8(name).ok()?, param.value))
+        })
+    }
+
+    /// Look up a named parameter
+    pub fn param(&self, name: &str) -> Option<f32> {
+        self.params().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+}
+
+/// Something that transforms the frequency bins of each analysis window
+pub trait Kernel: Send {
+    /// Run the kernel over `bins` in place
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()>;
+}
+
+/// Where a stage of the kernel chain gets its kernel from
+#[derive(Debug, Clone)]
+pub enum KernelSource {
+    /// A rust or script file, reloaded whenever it changes
+    #[cfg(feature = "native")]
+    File(PathBuf),
+    Builtin(BuiltinKernel),
+}
+
+/// Returns false if the kernel failed, e.g. by catching a panic
+///
+/// Kernels link their own copy of std, so a panic unwinding out of `process`
+/// can't be caught here and would abort the whole process.
+#[cfg(feature = "native")]
+type ProcessFn = unsafe extern "C" fn(*mut KernelFrame) -> bool;
+/// Takes the sample rate, window length and channel, and returns the kernel's state
+#[cfg(feature = "native")]
+type InitFn = unsafe extern "C" fn(u32, u32, u32) -> *mut c_void;
+#[cfg(feature = "native")]
+type TeardownFn = unsafe extern "C" fn(*mut c_void);
+
+/// A compiled kernel library whose ABI version has been checked
+///
+/// Kernels may optionally export `init` and `teardown` functions to keep state
+/// between frames. `init` is called before the first frame, and `teardown`
+/// when the kernel is dropped, so a reloaded kernel always starts fresh.
+#[cfg(feature = "native")]
+pub struct DylibKernel {
+    process: ProcessFn,
+    init: Option<InitFn>,
+    teardown: Option<TeardownFn>,
+    state: Option<*mut c_void>,
+    // the functions above point into this library, so it must be dropped last
+    _library: Library,
+}
+
+// `state` is owned by this kernel and only ever passed back into it
+#[cfg(feature = "native")]
+unsafe impl Send for DylibKernel {}
+
+#[cfg(feature = "native")]
+impl DylibKernel {
+    pub fn load(library: Library) -> Result<DylibKernel> {
+        let version = match unsafe { library.get::<*const u32>(ABI_VERSION_SYMBOL) } {
+            Ok(symbol) => unsafe { **symbol },
+            Err(_) => bail!(
+                "kernel does not export ROCODER_KERNEL_ABI_VERSION; kernels using the old \
+                 `apply(usize, Vec<(f32, f32)>)` signature must be ported to `process` (see README)"
+            ),
+        };
+        if version != KERNEL_ABI_VERSION {
+            bail!(
+                "kernel was written for ABI version {} but this rocoder uses version {}",
+                version,
+                KERNEL_ABI_VERSION
+            );
+        }
+        let process = match unsafe { library.get::<ProcessFn>(PROCESS_SYMBOL) } {
+            Ok(symbol) => *symbol,
+            Err(_) => bail!("kernel does not export a `process` function"),
+        };
+        let init = unsafe { library.get::<InitFn>(INIT_SYMBOL) }
+            .ok()
+            .map(|symbol| *symbol);
+        let teardown = unsafe { library.get::<TeardownFn>(TEARDOWN_SYMBOL) }
+            .ok()
+            .map(|symbol| *symbol);
+        Ok(DylibKernel {
+            process,
+            init,
+            teardown,
+            state: None,
+            _library: library,
+        })
+    }
+}
+
+#[cfg(feature = "native")]
+impl Kernel for DylibKernel {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let init = self.init;
+        let state = *self.state.get_or_insert_with(|| match init {
+            Some(init) => unsafe { init(info.sample_rate, info.window_len, info.channel) },
+            None => ptr::null_mut(),
+        });
+        let mut frame = KernelFrame {
+            // Complex32 is repr(C) with `re` then `im`, so it has the same layout as [f32; 2]
+            bins: bins.as_mut_ptr() as *mut [f32; 2],
+            bins_len: bins.len(),
+            sample_rate: info.sample_rate,
+            window_len: info.window_len,
+            channel: info.channel,
+            elapsed_ms: info.elapsed_ms,
+            params: info.params.as_ptr(),
+            params_len: info.params.len(),
+            state,
+        };
+        if !unsafe { (self.process)(&mut frame) } {
+            bail!("kernel reported a failure");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "native")]
+impl Drop for DylibKernel {
+    fn drop(&mut self) {
+        if let (Some(teardown), Some(state)) = (self.teardown, self.state) {
+            unsafe { teardown(state) };
+        }
+    }
+}
+
+/// Parse a kernel parameter given as `name=value`
+pub fn parse_param(param: &str) -> Result<(String, f32)> {
+    match param.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.trim().parse()?)),
+        _ => bail!("expected a kernel parameter as name=value, got {:?}", param),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "native")]
+    use crate::test_utils::*;
+
+    fn info() -> FrameInfo<'static> {
+        FrameInfo {
+            sample_rate: 44100,
+            window_len: 2,
+            channel: 1,
+            elapsed_ms: 0,
+            params: &[],
+        }
+    }
+
+    #[test]
+    fn frame_info_param_lookup() {
+        let params = vec![
+            KernelParam::new("cutoff", 0.5),
+            KernelParam::new("gain", 2.0),
+        ];
+        let info = FrameInfo {
+            params: &params,
+            ..info()
+        };
+        assert_eq!(info.param("gain"), Some(2.0));
+        assert_eq!(info.param("missing"), None);
+    }
+
+    #[test]
+    fn parse_valid_param() {
+        assert_eq!(
+            parse_param("cutoff=0.5").unwrap(),
+            ("cutoff".to_string(), 0.5)
+        );
+        assert_eq!(parse_param("gain=-3").unwrap(), ("gain".to_string(), -3.0));
+    }
+
+    #[test]
+    fn parse_invalid_param() {
+        assert!(parse_param("cutoff").is_err());
+        assert!(parse_param("=1").is_err());
+        assert!(parse_param("cutoff=loud").is_err());
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn load_and_apply_kernel() {
+        let lib = compile_test_kernel(
+            "
+            #[no_mangle]
+            pub extern \"C\" fn process(frame: &mut KernelFrame) -> bool {
+                let bins = unsafe { std::slice::from_raw_parts_mut(frame.bins, frame.bins_len) };
+                for bin in bins.iter_mut() {
+                    bin[0] *= frame.channel as f32 + 1.0;
+                    bin[1] = -bin[1];
+                }
+                true
+            }
+            ",
+        );
+        let mut kernel = DylibKernel::load(lib).unwrap();
+        let mut bins = vec![Complex32::new(1.0, 2.0), Complex32::new(-3.0, 0.5)];
+        kernel.apply(&mut bins, info()).unwrap();
+        assert_almost_eq_by_element(
+            bins.iter().flat_map(|c| vec![c.re, c.im]).collect(),
+            vec![2.0, -2.0, -6.0, -0.5],
+        );
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn kernel_reads_params() {
+        let lib = compile_test_kernel(
+            "
+            #[no_mangle]
+            pub extern \"C\" fn process(frame: &mut KernelFrame) -> bool {
+                let bins = unsafe { std::slice::from_raw_parts_mut(frame.bins, frame.bins_len) };
+                let params = unsafe { std::slice::from_raw_parts(frame.params, frame.params_len) };
+                for param in params {
+                    let name = unsafe { std::slice::from_raw_parts(param.name, param.name_len) };
+                    if name == b\"gain\" {
+                        bins.iter_mut().for_each(|bin| bin[0] *= param.value);
+                    }
+                }
+                true
+            }
+            ",
+        );
+        let mut kernel = DylibKernel::load(lib).unwrap();
+        let mut bins = vec![Complex32::new(1.0, 0.0)];
+        let params = vec![
+            KernelParam::new("other", 5.0),
+            KernelParam::new("gain", 3.0),
+        ];
+        kernel
+            .apply(
+                &mut bins,
+                FrameInfo {
+                    params: &params,
+                    ..info()
+                },
+            )
+            .unwrap();
+        assert_almost_eq(bins[0].re, 3.0);
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn kernel_state_persists_between_frames() {
+        let lib = compile_test_kernel(
+            "
+            #[no_mangle]
+            pub extern \"C\" fn init(_: u32, window_len: u32, _: u32) -> *mut u8 {
+                Box::into_raw(Box::new(window_len as f32)) as *mut u8
+            }
+
+            #[no_mangle]
+            pub extern \"C\" fn process(frame: &mut KernelFrame) -> bool {
+                let calls = unsafe { &mut *(frame.state as *mut f32) };
+                *calls += 1.0;
+                unsafe { (*frame.bins)[0] = *calls };
+                true
+            }
+
+            #[no_mangle]
+            pub extern \"C\" fn teardown(state: *mut u8) {
+                drop(unsafe { Box::from_raw(state as *mut f32) });
+            }
+            ",
+        );
+        let mut kernel = DylibKernel::load(lib).unwrap();
+        let mut bins = vec![Complex32::new(0.0, 0.0)];
+        kernel.apply(&mut bins, info()).unwrap();
+        assert_almost_eq(bins[0].re, 3.0);
+        kernel.apply(&mut bins, info()).unwrap();
+        assert_almost_eq(bins[0].re, 4.0);
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn kernel_failures_are_reported() {
+        let lib = compile_test_kernel(
+            "
+            #[no_mangle]
+            pub extern \"C\" fn process(_frame: &mut KernelFrame) -> bool {
+                std::panic::catch_unwind(|| panic!(\"oops\")).is_ok()
+            }
+            ",
+        );
+        let mut kernel = DylibKernel::load(lib).unwrap();
+        assert!(kernel
+            .apply(&mut [Complex32::new(0.0, 0.0)], info())
+            .is_err());
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn load_rejects_mismatched_abi_version() {
+        let lib = compile_raw_test_kernel(
+            "
+            #[no_mangle]
+            pub static ROCODER_KERNEL_ABI_VERSION: u32 = 999;
+
+            #[no_mangle]
+            pub extern \"C\" fn process(_frame: *mut u8) -> bool {
+                true
+            }
+            ",
+        );
+        assert!(DylibKernel::load(lib).is_err());
+    }
+
+    #[cfg(all(feature = "native", not(target_os = "windows")))]
+    #[test]
+    fn load_rejects_legacy_kernel() {
+        let lib = compile_raw_test_kernel(
+            "
+            #[no_mangle]
+            pub fn apply(_elapsed_ms: usize, input: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+                input
+            }
+            ",
+        );
+        assert!(DylibKernel::load(lib).is_err());
+    }
+}
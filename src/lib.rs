@@ -5,21 +5,76 @@ mod test_utils;
 
 pub mod audio;
 pub mod audio_files;
+pub mod block_stretcher;
+pub mod builtin_kernels;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod clock_sync;
+#[cfg(feature = "native")]
 pub mod cpal_utils;
 pub mod crossfade;
+pub mod cue_sheet;
+pub mod denoise;
 pub mod duration_parser;
+pub mod effect_processor;
+pub mod effects;
 pub mod fft;
+pub mod file_sink_processor;
+pub mod generator_processor;
+pub mod generators;
+#[cfg(test)]
+mod golden_tests;
+pub mod granular;
+#[cfg(feature = "native")]
 pub mod hotswapper;
+pub mod hpss;
+pub mod http_api;
+pub mod journal;
+pub mod kernel;
+pub mod limiter;
+pub mod log_file;
+pub mod loudness;
+pub mod markers;
 pub mod math;
+pub mod metrics;
+pub mod midi;
 pub mod mixer;
+pub mod mixer_processor;
+pub mod osc;
+pub mod output_watchdog;
+pub mod pcm_stream;
+#[cfg(feature = "native")]
 pub mod player_processor;
 pub mod power;
+pub mod realtime_check;
+#[cfg(feature = "native")]
 pub mod recorder;
+#[cfg(feature = "native")]
 pub mod recorder_processor;
 pub mod resampler;
+pub mod reverb;
+pub mod ring_buffer;
+pub mod routing;
 pub mod runtime_setup;
+#[cfg(feature = "scripting")]
+pub mod script_kernel;
+pub mod sequence_processor;
 pub mod signal_flow;
+pub mod simd;
 pub mod slices;
+pub mod smoothing;
+pub mod spectral_eq;
+pub mod stretch;
+pub mod stretch_engine;
 pub mod stretcher;
 pub mod stretcher_processor;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "native")]
+pub mod virtual_audio;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod windows;
+pub mod wsola;
+
+pub use stretch::{stretch, StretchOptions};
@@ -0,0 +1,87 @@
+use crate::power;
+use std::time::Duration;
+
+pub const DEFAULT_CEILING_DB: f32 = -0.3;
+pub const DEFAULT_RELEASE: Duration = Duration::from_millis(200);
+
+/// Brickwall peak limiter for interleaved output
+///
+/// Gain drops instantly to keep every sample under the ceiling, then recovers
+/// exponentially over the release time. All channels share one gain so the
+/// stereo image doesn't shift while limiting.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    ceiling: f32,
+    release_coefficient: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(ceiling_db: f32, release: Duration, sample_rate: u32) -> Limiter {
+        let release_samples = release.as_secs_f32() * sample_rate as f32;
+        Limiter {
+            ceiling: power::decibels_to_amplitude(ceiling_db),
+            release_coefficient: if release_samples > 0.0 {
+                (-1.0 / release_samples).exp()
+            } else {
+                0.0
+            },
+            gain: 1.0,
+        }
+    }
+
+    /// Limit one frame holding a sample for each channel
+    #[inline]
+    pub fn process_frame(&mut self, frame: &mut [f32]) {
+        let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let target = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain = target + (self.gain - target) * self.release_coefficient;
+        }
+        for sample in frame.iter_mut() {
+            *sample *= self.gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn quiet_frames_pass_through() {
+        let mut limiter = Limiter::new(0.0, DEFAULT_RELEASE, 44100);
+        let mut frame = vec![0.5, -0.9];
+        limiter.process_frame(&mut frame);
+        assert_almost_eq_by_element(frame, vec![0.5, -0.9]);
+    }
+
+    #[test]
+    fn loud_frames_are_held_at_ceiling() {
+        let mut limiter = Limiter::new(0.0, DEFAULT_RELEASE, 44100);
+        let mut frame = vec![2.0, -0.5];
+        limiter.process_frame(&mut frame);
+        assert_almost_eq_by_element(frame, vec![1.0, -0.25]);
+    }
+
+    #[test]
+    fn gain_recovers_over_release() {
+        let mut limiter = Limiter::new(0.0, Duration::from_millis(10), 1000);
+        limiter.process_frame(&mut [2.0]);
+        let mut frame = vec![0.5];
+        limiter.process_frame(&mut frame);
+        assert!(frame[0] > 0.25 && frame[0] < 0.5);
+        for _ in 0..200 {
+            frame = vec![0.5];
+            limiter.process_frame(&mut frame);
+        }
+        assert_almost_eq(frame[0], 0.5);
+    }
+}
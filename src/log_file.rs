@@ -0,0 +1,139 @@
+//! A log file that rotates itself once it grows too large, for long running
+//! installations where nothing else is rotating logs
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MAX_LEN: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_BACKUPS: usize = 5;
+
+/// Appends to a file, moving it to `<path>.1` when it passes its size limit,
+/// `<path>.1` to `<path>.2` and so on, dropping the oldest
+///
+/// Clones write to the same file, so one can be handed to the logger and
+/// another kept to reopen the file.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_len: u64,
+    backups: usize,
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    Ok((file, len))
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<LogFile> {
+        LogFile::with_limits(path, DEFAULT_MAX_LEN, DEFAULT_BACKUPS)
+    }
+
+    pub fn with_limits(path: &Path, max_len: u64, backups: usize) -> io::Result<LogFile> {
+        let (file, len) = open_append(path)?;
+        Ok(LogFile(Arc::new(Mutex::new(Inner {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_len,
+            backups,
+        }))))
+    }
+
+    /// Open the file at the path again, e.g. after logrotate has moved it away
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        let (file, len) = open_append(&inner.path)?;
+        inner.file = file;
+        inner.len = len;
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn backup_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.backups > 0 {
+            for n in (1..self.backups).rev() {
+                let from = self.backup_path(n);
+                if from.exists() {
+                    fs::rename(from, self.backup_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.backup_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        let (file, len) = open_append(&self.path)?;
+        self.file = file;
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        inner.file.write_all(buf)?;
+        inner.len += buf.len() as u64;
+        // Only rotate between lines, so no line is split across files
+        if inner.len >= inner.max_len && buf.ends_with(b"\n") {
+            inner.rotate()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rotates_whole_lines_and_drops_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rocoder.log");
+        let mut log = LogFile::with_limits(&path, 10, 2).unwrap();
+        for line in [
+            "first ",
+            "line\n",
+            "second line\n",
+            "third line\n",
+            "fourth\n",
+        ] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("rocoder.log"), "fourth\n");
+        assert_eq!(read("rocoder.log.1"), "third line\n");
+        assert_eq!(read("rocoder.log.2"), "second line\n");
+        assert!(!dir.path().join("rocoder.log.3").exists());
+    }
+
+    #[test]
+    fn reopens_a_moved_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rocoder.log");
+        let mut log = LogFile::open(&path).unwrap();
+        log.write_all(b"before\n").unwrap();
+        fs::rename(&path, dir.path().join("moved.log")).unwrap();
+        log.reopen().unwrap();
+        log.write_all(b"after\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::audio::{Audio, AudioSpec};
+use crate::audio_files::{AudioReader, AudioWriter, WavReader, WavWriter};
+use crate::power::{self, Biquad};
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Gating blocks are 400 ms long and start every 100 ms
+const STEPS_PER_BLOCK: usize = 4;
+const STEP_SECS: f64 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+/// Blocks this far below the absolute-gated loudness are left out too
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// The K-weighting of BS.1770: a high shelf for the head's effect on sound,
+/// then a high pass. The coefficients are derived for any sample rate the
+/// way libebur128 does, which reproduces the 48 kHz ones in the standard.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+/// Measures integrated loudness as in EBU R128 and ITU-R BS.1770
+///
+/// Every channel is weighted equally, which is what the standard asks for
+/// mono and stereo. Surround layouts would need their rear channels
+/// weighted up and the LFE channel left out.
+pub struct LoudnessMeter {
+    filters: Vec<[Biquad; 2]>,
+    step_len: usize,
+    /// Sum of the weighted squares of every channel in the current step
+    step_energy: f64,
+    step_frames: usize,
+    /// Mean square of the most recent steps, enough to make a block
+    recent_steps: VecDeque<f64>,
+    /// Mean square of every gating block so far
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(spec: AudioSpec) -> LoudnessMeter {
+        LoudnessMeter {
+            filters: (0..spec.channels)
+                .map(|_| k_weighting(spec.sample_rate))
+                .collect(),
+            step_len: (spec.sample_rate as f64 * STEP_SECS).round() as usize,
+            step_energy: 0.0,
+            step_frames: 0,
+            recent_steps: VecDeque::with_capacity(STEPS_PER_BLOCK),
+            blocks: vec![],
+            peak: 0.0,
+        }
+    }
+
+    /// Measure one frame, with a sample for each channel
+    pub fn add_frame(&mut self, frame: &[f32]) {
+        for (sample, filters) in frame.iter().zip(self.filters.iter_mut()) {
+            self.peak = self.peak.max(sample.abs());
+            let weighted = filters
+                .iter_mut()
+                .fold(*sample as f64, |x, filter| filter.process(x));
+            self.step_energy += weighted * weighted;
+        }
+        self.step_frames += 1;
+        if self.step_frames == self.step_len {
+            if self.recent_steps.len() == STEPS_PER_BLOCK {
+                self.recent_steps.pop_front();
+            }
+            self.recent_steps
+                .push_back(self.step_energy / self.step_len as f64);
+            if self.recent_steps.len() == STEPS_PER_BLOCK {
+                self.blocks
+                    .push(self.recent_steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64);
+            }
+            self.step_energy = 0.0;
+            self.step_frames = 0;
+        }
+    }
+
+    /// The gated loudness of everything measured so far, in LUFS
+    ///
+    /// `None` until there is at least one block above the absolute gate,
+    /// e.g. for anything shorter than 400 ms or entirely silent.
+    pub fn integrated(&self) -> Option<f64> {
+        let mean_above = |gate: f64| {
+            let gated: Vec<f64> = self
+                .blocks
+                .iter()
+                .copied()
+                .filter(|energy| energy_to_lufs(*energy) > gate)
+                .collect();
+            if gated.is_empty() {
+                None
+            } else {
+                Some(gated.iter().sum::<f64>() / gated.len() as f64)
+            }
+        };
+        let relative_gate = energy_to_lufs(mean_above(ABSOLUTE_GATE_LUFS)?) + RELATIVE_GATE_LU;
+        mean_above(relative_gate.max(ABSOLUTE_GATE_LUFS)).map(energy_to_lufs)
+    }
+
+    /// The highest absolute sample level measured
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+}
+
+/// The integrated loudness of `audio` in LUFS
+pub fn measure(audio: &Audio) -> Option<f64> {
+    let mut meter = LoudnessMeter::new(audio.spec);
+    let mut frame = vec![0.0; audio.data.len()];
+    for i in 0..audio.data.first().map_or(0, |channel| channel.len()) {
+        for (sample, channel) in frame.iter_mut().zip(&audio.data) {
+            *sample = channel[i];
+        }
+        meter.add_frame(&frame);
+    }
+    meter.integrated()
+}
+
+/// Scale a .wav file so its integrated loudness is `target_lufs`, returning
+/// the loudness it had
+///
+/// The file is read twice, once to measure and once to apply the gain to a
+/// copy that then replaces it, so it never has to fit in memory.
+pub fn normalize_wav(path: &Path, target_lufs: f64) -> Result<f64> {
+    let path_str = path
+        .to_str()
+        .with_context(|| format!("{:?} isn't a valid UTF-8 path", path))?;
+    let mut reader = WavReader::open(path_str)?;
+    let spec = reader.spec();
+    let mut meter = LoudnessMeter::new(spec);
+    let mut frame = Vec::with_capacity(spec.channels as usize);
+    for sample in &mut reader {
+        frame.push(sample);
+        if frame.len() == spec.channels as usize {
+            meter.add_frame(&frame);
+            frame.clear();
+        }
+    }
+    let measured = match meter.integrated() {
+        Some(measured) => measured,
+        None => bail!("{:?} is too short or quiet to measure its loudness", path),
+    };
+    let gain_db = (target_lufs - measured) as f32;
+    let gain = power::decibels_to_amplitude(gain_db);
+    info!(
+        "Measured {:.1} LUFS, applying {:+.1} dB to reach {:.1} LUFS",
+        measured, gain_db, target_lufs
+    );
+    let peak_db = power::amplitude_to_decibels(meter.peak() * gain);
+    if peak_db > 0.0 {
+        warn!(
+            "The normalized output peaks at {:+.1} dBFS, so it will clip if converted to integer samples",
+            peak_db
+        );
+    }
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let normalized = tempfile::Builder::new()
+        .prefix(".rocoder-normalize")
+        .suffix(".wav")
+        .tempfile_in(dir.unwrap_or(Path::new(".")))?;
+    let mut writer = WavWriter::new(io::BufWriter::new(normalized.as_file().try_clone()?), spec)?;
+    for sample in WavReader::open(path_str)? {
+        writer.write(sample * gain)?;
+    }
+    writer.finalize()?;
+    // The copy is created private, so give it the render's permissions back
+    fs::set_permissions(normalized.path(), fs::metadata(path)?.permissions())?;
+    normalized
+        .persist(path)
+        .with_context(|| format!("failed to replace {:?}", path))?;
+    Ok(measured)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn sine(frequency: f32, amplitude: f32, secs: f32, channels: u16, sample_rate: u32) -> Audio {
+        let len = (secs * sample_rate as f32) as usize;
+        let channel: Vec<f32> = (0..len)
+            .map(|i| {
+                amplitude
+                    * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin()
+            })
+            .collect();
+        Audio {
+            spec: AudioSpec {
+                channels,
+                sample_rate,
+            },
+            data: vec![channel; channels as usize],
+            markers: vec![],
+        }
+    }
+
+    #[test]
+    fn stereo_sine_at_minus_23_dbfs_reads_minus_23_lufs() {
+        // The first case of EBU Tech 3341
+        for sample_rate in [44100, 48000] {
+            let audio = sine(
+                1000.0,
+                power::decibels_to_amplitude(-23.0),
+                20.0,
+                2,
+                sample_rate,
+            );
+            let lufs = measure(&audio).unwrap();
+            assert!((lufs - -23.0).abs() < 0.1, "{} at {} Hz", lufs, sample_rate);
+        }
+    }
+
+    #[test]
+    fn silence_is_gated_out() {
+        let mut audio = sine(1000.0, power::decibels_to_amplitude(-23.0), 10.0, 2, 48000);
+        for channel in audio.data.iter_mut() {
+            channel.extend(vec![0.0; 48000 * 10]);
+        }
+        let lufs = measure(&audio).unwrap();
+        assert!((lufs - -23.0).abs() < 0.1, "{}", lufs);
+        assert_eq!(measure(&sine(1000.0, 0.0, 10.0, 2, 48000)), None);
+        assert_eq!(measure(&sine(1000.0, 0.5, 0.3, 2, 48000)), None);
+    }
+
+    #[test]
+    fn wav_files_are_normalized_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quiet.wav");
+        let audio = sine(440.0, 0.01, 2.0, 2, 44100);
+        let mut writer = WavWriter::open(path.to_str().unwrap(), audio.spec).unwrap();
+        writer.write_slice(audio.as_slice()).unwrap();
+        writer.finalize().unwrap();
+
+        let before = normalize_wav(&path, -16.0).unwrap();
+        assert_eq!(Some(before), measure(&audio));
+        let normalized = WavReader::open(path.to_str().unwrap()).unwrap().read_all();
+        assert_eq!(normalized.data[0].len(), audio.data[0].len());
+        assert!((measure(&normalized).unwrap() - -16.0).abs() < 0.01);
+        let gain = normalized.data[0][100] / audio.data[0][100];
+        assert_almost_eq_by_element(
+            normalized.data[1].clone(),
+            audio.data[1].iter().map(|s| s * gain).collect(),
+        );
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn normalizing_keeps_the_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.wav");
+        let audio = sine(440.0, 0.1, 1.0, 1, 44100);
+        let mut writer = WavWriter::open(path.to_str().unwrap(), audio.spec).unwrap();
+        writer.write_slice(audio.as_slice()).unwrap();
+        writer.finalize().unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        normalize_wav(&path, -16.0).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+}
@@ -1,24 +1,68 @@
-use rocoder::audio::{Audio, AudioBus, AudioSpec};
-use rocoder::audio_files::{AudioReader, AudioWriter, WavReader, WavWriter};
+use rocoder::audio::{Audio, AudioBus, AudioSpec, BusPolicy, Overflow};
+use rocoder::audio_files::{AudioReader, AudioWriter, RawFormat, RawReader, WavReader, WavWriter};
+use rocoder::builtin_kernels::BuiltinKernel;
+use rocoder::clock_sync::{self, Follower, Leader, Trigger};
+use rocoder::cpal_utils::{self, Backend, BufferRequest};
+use rocoder::cue_sheet::CueSheet;
+use rocoder::denoise::{self, NoiseProfile, Subtraction};
 use rocoder::duration_parser;
-use rocoder::player_processor::{AudioOutputProcessor, AudioOutputProcessorControlMessage};
+use rocoder::effect_processor::EffectProcessor;
+use rocoder::effects::{self, Delay, Lowpass, TimeDomainEffect, Tremolo};
+use rocoder::fft::SpectrumTap;
+use rocoder::file_sink_processor::{FileSinkProcessor, FileSinkTarget};
+use rocoder::generators::{Generator, Waveform};
+use rocoder::granular::{GrainParams, GranularStretcher};
+use rocoder::hotswapper;
+use rocoder::hpss;
+use rocoder::http_api::{self, Request, Response};
+use rocoder::journal::{Journal, Value};
+use rocoder::kernel::{self, KernelSource};
+use rocoder::log_file::LogFile;
+use rocoder::loudness;
+use rocoder::markers::{self, Marker};
+use rocoder::metrics::{self, Counter};
+use rocoder::midi::{self, MidiAction, MidiMapping};
+use rocoder::mixer::PeakMeter;
+use rocoder::mixer_processor::MixerProcessor;
+use rocoder::osc::{self, OscArg, OscMessage};
+use rocoder::output_watchdog::{AlertHook, OperatingHours, Watchdog};
+use rocoder::pcm_stream::{StreamAddr, StreamListener, StreamSourceProcessor};
+use rocoder::player_processor::{
+    self, AudioOutputProcessor, AudioOutputProcessorControlMessage, BusId,
+};
+use rocoder::power;
 use rocoder::recorder;
-use rocoder::runtime_setup;
+use rocoder::reverb::ConvolutionReverb;
+use rocoder::routing::Routing;
+use rocoder::runtime_setup::{self, ServiceSignal};
+use rocoder::sequence_processor::SequenceProcessor;
+use rocoder::signal_flow::graph::{Graph, Pipeline};
 use rocoder::signal_flow::node::Node;
+use rocoder::signal_flow::supervisor::RestartPolicy;
+use rocoder::spectral_eq::{EqBand, SpectralEq};
+use rocoder::stretch_engine::{EngineKind, StretchEngine};
 use rocoder::stretcher::Stretcher;
-use rocoder::stretcher_processor::{StretcherProcessor, StretcherProcessorControlMessage};
+use rocoder::stretcher_processor::{
+    StretchProgress, StretcherProcessor, StretcherProcessorControlMessage,
+};
+#[cfg(feature = "tui")]
+use rocoder::tui::{self, Dashboard, KeyAction, Spectrogram, Tui};
 use rocoder::windows;
+use rocoder::wsola::WsolaStretcher;
 
-use anyhow::Result;
-use crossbeam_channel::unbounded;
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::{unbounded, RecvTimeoutError, Sender};
 use ctrlc;
 
+use log::LevelFilter;
+use std::fs;
 use std::io;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU16, Ordering};
-use std::sync::Arc;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use structopt::{clap::AppSettings, StructOpt};
 
 #[macro_use]
@@ -43,10 +87,39 @@ struct Opt {
         help = "The maximum amount of audio to process ahead of time. this controls the response time to changes like kernel modifications.")]
     buffer_dur: Duration,
 
+    #[structopt(
+        long = "engine",
+        default_value = "vocoder",
+        help = "The stretching algorithm: vocoder; granular for overlapping grains of the input; wsola, which suits factors from 0.5 to 2; or auto to pick wsola or the vocoder by factor"
+    )]
+    engine: EngineKind,
+
+    #[structopt(
+        long = "grain",
+        default_value = "0.1",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "With --engine granular, how long each grain is (hh:mm:ss.ss)")]
+    grain: Duration,
+
+    #[structopt(
+        long = "grain-jitter",
+        default_value = "0.5",
+        help = "With --engine granular, how far ahead each grain may start at random, as a fraction of its length"
+    )]
+    grain_jitter: f32,
+
+    #[structopt(
+        long = "grain-density",
+        default_value = "4",
+        help = "With --engine granular, how many grains overlap at once"
+    )]
+    grain_density: f32,
+
     #[structopt(
         short = "f",
         long = "factor",
         default_value = "1",
+        global = true,
         help = "Stretch factor; e.g. 5 to slow 5x and 0.2 to speed up 5x"
     )]
     factor: f32,
@@ -71,31 +144,221 @@ struct Opt {
         short = "i",
         long = "input",
         parse(from_os_str),
-        help = "An audio file; currently supports .wav and .mp3. Use '-' for stdin. Omit this option to record audio from your default sound input device."
+        help = "An audio file; currently supports .wav and .mp3. Use '-' for a .wav on stdin, or raw samples with --spec. Omit this option to record audio from your default sound input device."
     )]
     input: Option<PathBuf>,
 
+    #[structopt(
+        long = "spec",
+        help = "Read stdin as raw interleaved samples with this sample rate and channel count, e.g. 44100:2, rather than as a .wav. Needs -i -."
+    )]
+    spec: Option<AudioSpec>,
+
+    #[structopt(
+        long = "raw-format",
+        default_value = "f32le",
+        help = "How raw samples on stdin and stdout are encoded: f32le or s16le"
+    )]
+    raw_format: RawFormat,
+
+    #[structopt(
+        long = "generate",
+        conflicts_with = "input",
+        help = "Stretch a test signal instead of input audio: sine:<hz>, noise, pink or click:<bpm>. Lasts --duration, or 10 seconds."
+    )]
+    generate: Option<Waveform>,
+
+    #[structopt(
+        long = "trim-to-onset",
+        help = "When recording, start the recording at its first onset rather than where it rose above the noise"
+    )]
+    trim_to_onset: bool,
+
+    #[structopt(
+        long = "pre-roll",
+        default_value = "0.1",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "When recording, keep this much before where the sound was found to start, so its attack isn't lost (hh:mm:ss.ss)"
+    )]
+    pre_roll: Duration,
+
     #[structopt(
         long = "rotate-channels",
         help = "Rotate the input audio channels. With stereo audio this means swapping the left and right channels"
     )]
     rotate_channels: bool,
 
+    #[structopt(
+        long = "invert-polarity",
+        number_of_values = 1,
+        help = "Invert the polarity of the given input channel (0-indexed). May be given more than once."
+    )]
+    invert_polarity: Vec<usize>,
+
     #[structopt(
         long = "freq-kernel",
-        help = "Path to a rust frequency kernel file",
+        number_of_values = 1,
+        help = "Path to a rust (.rs) or Rhai script (.rhai) frequency kernel file. May be given more than once to chain kernels in order.",
         parse(from_os_str)
     )]
-    freq_kernel: Option<PathBuf>,
+    freq_kernel: Vec<PathBuf>,
+
+    #[structopt(
+        long = "kernel",
+        number_of_values = 1,
+        help = "A built-in frequency kernel: thin, blur, harmonic, whisper, shift or smear. May be given more than once. Built-in kernels run before any --freq-kernel files."
+    )]
+    kernel: Vec<BuiltinKernel>,
+
+    #[structopt(
+        long = "denoise",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Take the first part of the input, this long, as a sample of background noise, cut it off, and subtract that noise from the rest before stretching (hh:mm:ss.ss)")]
+    denoise: Option<Duration>,
+
+    #[structopt(
+        long = "denoise-over-subtraction",
+        default_value = "2",
+        help = "With --denoise, how many times the noise profile is subtracted; higher removes more noise and more of the sound"
+    )]
+    denoise_over_subtraction: f32,
+
+    #[structopt(
+        long = "denoise-floor",
+        default_value = "0.05",
+        help = "With --denoise, the least of each frequency that's kept, as a fraction from 0 to 1"
+    )]
+    denoise_floor: f32,
+
+    #[structopt(
+        long = "hpss",
+        help = "Separate the input's harmonic and percussive parts, stretch only the harmonic part, and mix the percussive part in unstretched"
+    )]
+    hpss: bool,
+
+    #[structopt(
+        long = "percussive-mix",
+        default_value = "1",
+        help = "With --hpss, the level of the unstretched percussive part, from 0 (dropped) to 1"
+    )]
+    percussive_mix: f32,
+
+    #[structopt(
+        long = "smear",
+        help = "Smear the stretched sound over time by averaging each frequency over recent windows, from 0 (off) to 1 (fully averaged)"
+    )]
+    smear: Option<f32>,
+
+    #[structopt(
+        long = "smear-frames",
+        default_value = "8",
+        help = "How many windows --smear averages over"
+    )]
+    smear_frames: usize,
+
+    #[structopt(
+        long = "kernel-param",
+        number_of_values = 1,
+        help = "A named value passed to frequency kernels, as name=value. May be given more than once.",
+        parse(try_from_str = kernel::parse_param)
+    )]
+    kernel_param: Vec<(String, f32)>,
+
+    #[structopt(
+        long = "kernel-crossfade",
+        default_value = "0.1",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Crossfade duration when a changed frequency kernel is swapped in (hh:mm:ss.ss)")]
+    kernel_crossfade: Duration,
+
+    #[structopt(
+        long = "eq",
+        number_of_values = 1,
+        help = "An EQ band applied to the stretched spectrum, as <lowshelf|highshelf|peak>:<Hz>:<dB>[:<Q>], e.g. peak:1000:-4:1.4. May be given more than once."
+    )]
+    eq: Vec<EqBand>,
+
+    #[structopt(
+        long = "decorrelate",
+        help = "How far apart the channels' random phases are, from 0 (the same, a narrow image) to 1 (unrelated, the widest)"
+    )]
+    decorrelate: Option<f32>,
+
+    #[structopt(
+        long = "cue",
+        parse(from_os_str),
+        help = "When rendering to a file, stretch each segment listed in this cue sheet with its own factor and pitch, and join them"
+    )]
+    cue: Option<PathBuf>,
+
+    #[structopt(
+        long = "cue-crossfade",
+        default_value = "1",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Crossfade duration between the stretched segments of a cue sheet (hh:mm:ss.ss)")]
+    cue_crossfade: Duration,
+
+    #[structopt(
+        long = "labels",
+        parse(from_os_str),
+        help = "Find onsets in the input, and write them and the input's other markers as an Audacity label track, placed where they fall in the stretched output"
+    )]
+    labels: Option<PathBuf>,
+
+    #[structopt(
+        long = "lowpass",
+        help = "Filter out frequencies above this many Hz after processing"
+    )]
+    lowpass: Option<f32>,
+
+    #[structopt(
+        long = "tremolo",
+        help = "Modulate the processed output's volume at this many Hz"
+    )]
+    tremolo: Option<f32>,
+
+    #[structopt(
+        long = "delay",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Add echoes at this interval after processing (hh:mm:ss.ss)")]
+    delay: Option<Duration>,
+
+    #[structopt(
+        long = "reverb",
+        parse(from_os_str),
+        help = "Convolve the processed output with this impulse response .wav file"
+    )]
+    reverb: Option<PathBuf>,
+
+    #[structopt(
+        long = "reverb-mix",
+        default_value = "0.3",
+        help = "Reverb level from 0 (dry only) to 1 (reverb only)"
+    )]
+    reverb_mix: f32,
+
+    #[structopt(
+        long = "target-lufs",
+        allow_hyphen_values = true,
+        help = "When rendering to a .wav file, normalize it to this integrated loudness in LUFS, e.g. -16"
+    )]
+    target_lufs: Option<f64>,
 
     #[structopt(
         short = "x",
         long = "fade",
         default_value = "1",
         parse(try_from_str = duration_parser::parse_duration),
-        help = "Fade generated audio in and out for the given duration (hh:mm:ss.ss)")]
+        help = "Fade generated audio in and out for the given duration, and fade out a stretch cut short by --max-output (hh:mm:ss.ss)")]
     fade: Duration,
 
+    #[structopt(
+        long = "max-output",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Stop the stretch after this much output, fading it out over --fade, however long the input and factor would make it (hh:mm:ss.ss)"
+    )]
+    max_output: Option<Duration>,
+
     #[structopt(
         short = "s",
         long = "start",
@@ -116,146 +379,2207 @@ struct Opt {
         short = "o",
         long = "output",
         parse(from_os_str),
-        help = "Output .wav file path. Uses 32-bit float."
+        help = "Output .wav file path. Uses 32-bit float. Use '-' to write raw interleaved samples to stdout, encoded as --raw-format."
     )]
     output: Option<PathBuf>,
-}
 
-fn main() -> Result<()> {
-    runtime_setup::setup_logging();
-    let opt = Opt::from_args();
+    #[structopt(
+        long = "ab",
+        help = "During playback, press ENTER to toggle between the processed output and the unprocessed input"
+    )]
+    ab: bool,
 
-    let audio = load_audio(&opt);
-    let total_samples_len = audio.data[0].len();
-    let spec = audio.spec;
-    let window = windows::hanning(opt.window_len);
+    #[structopt(
+        long = "output-channels",
+        help = "Number of output device channels. Defaults to the input channel count."
+    )]
+    output_channels: Option<u16>,
 
-    let stretchers = audio
-        .data
-        .into_iter()
-        .map(|channel| {
-            let (stretcher_in_tx, stretcher_in_rx) = unbounded();
-            let stretcher = Stretcher::new(
-                spec,
-                stretcher_in_rx,
-                opt.factor,
-                opt.amplitude,
-                opt.pitch_multiple,
-                window.clone(),
-                opt.buffer_dur,
-                opt.freq_kernel.clone(),
-            );
-            if stretcher_in_tx.send(channel).is_err() {
-                warn!("failed to send channel data");
-            }
-            stretcher
-        })
-        .collect();
-    let expected_total_samples = Some((total_samples_len as f32 * opt.factor) as usize);
-    let (stretcher_processor, bus) = StretcherProcessor::new(stretchers, expected_total_samples);
-    let stretcher_node = Node::new(stretcher_processor);
+    #[structopt(
+        long = "pan",
+        help = "Speaker position for the first channel of output; fractional positions pan between adjacent speakers"
+    )]
+    pan: Option<f32>,
 
-    handle_result(&opt, bus, stretcher_node)?;
-    Ok(())
+    #[structopt(
+        long = "no-limiter",
+        help = "Disable the limiter that keeps played output from clipping"
+    )]
+    no_limiter: bool,
+
+    #[structopt(
+        long = "osc-listen",
+        help = "During playback, listen for OSC control messages on this address, e.g. 0.0.0.0:9000"
+    )]
+    osc_listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "midi-device",
+        parse(from_os_str),
+        requires = "midi-map",
+        help = "During playback, read MIDI from this raw MIDI device, e.g. /dev/snd/midiC1D0"
+    )]
+    midi_device: Option<PathBuf>,
+
+    #[structopt(
+        long = "midi-map",
+        parse(from_os_str),
+        requires = "midi-device",
+        help = "File mapping MIDI controllers and notes to controls; see the README"
+    )]
+    midi_map: Option<PathBuf>,
+
+    #[structopt(
+        long = "http-listen",
+        help = "During playback, serve a status and control HTTP API on this address, e.g. 0.0.0.0:8080"
+    )]
+    http_listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "sync-lead",
+        conflicts_with = "sync-follow",
+        help = "Lead other rocoders started with --sync-follow, listening for them on this UDP address, e.g. 0.0.0.0:9100. Playback starts once --sync-followers have joined, and quitting stops them all."
+    )]
+    sync_lead: Option<SocketAddr>,
+
+    #[structopt(
+        long = "sync-followers",
+        default_value = "1",
+        help = "How many followers to wait for before starting, with --sync-lead"
+    )]
+    sync_followers: usize,
+
+    #[structopt(
+        long = "sync-follow",
+        help = "Start and stop playing in sync with the rocoder leading at this UDP address, e.g. 10.0.0.1:9100"
+    )]
+    sync_follow: Option<SocketAddr>,
+
+    #[structopt(
+        long = "metrics-listen",
+        help = "Serve Prometheus metrics at /metrics on this address, e.g. 0.0.0.0:9100"
+    )]
+    metrics_listen: Option<SocketAddr>,
+
+    #[structopt(
+        long = "metrics-log",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "Log every metric at this interval, e.g. 1:00 for every minute"
+    )]
+    metrics_log: Option<Duration>,
+
+    #[structopt(
+        long = "journal",
+        parse(from_os_str),
+        help = "Append a JSON line to this file for each run and each live control change"
+    )]
+    journal: Option<PathBuf>,
+
+    #[structopt(
+        long = "backend",
+        default_value = "default",
+        possible_values = &["default", "jack", "asio"],
+        help = "Audio system to play and record through. JACK needs a build with --features jack, and ASIO Windows and --features asio"
+    )]
+    backend: String,
+
+    #[structopt(
+        long = "jack-client-name",
+        default_value = "rocoder",
+        help = "Client name to register with JACK; the output and input clients get _out and _in appended"
+    )]
+    jack_client_name: String,
+
+    #[structopt(
+        long = "device",
+        help = "Play and record through the audio device with this name rather than the default one; with --backend asio, the ASIO driver's name"
+    )]
+    device: Option<String>,
+
+    #[structopt(
+        long = "buffer-size",
+        help = "Output buffer size in frames. Smaller buffers lower latency, larger ones avoid dropouts"
+    )]
+    buffer_size: Option<u32>,
+
+    #[structopt(
+        long = "low-latency",
+        conflicts_with = "buffer-size",
+        help = "Use the smallest output buffer the device supports, pin processing threads to CPUs, and report the latency"
+    )]
+    low_latency: bool,
+
+    #[structopt(
+        long = "threads",
+        default_value = "1",
+        help = "Stretch this many channels at once on separate threads, to render faster on multicore machines"
+    )]
+    threads: usize,
+
+    #[structopt(
+        long = "bus-capacity",
+        help = "Hold at most this many chunks per channel between processors, rather than letting a slow one queue audio without limit"
+    )]
+    bus_capacity: Option<usize>,
+
+    #[structopt(
+        long = "bus-overflow",
+        default_value = "block",
+        help = "What a full bus does: block, drop-oldest or drop-newest"
+    )]
+    bus_overflow: Overflow,
+
+    #[structopt(
+        long = "seed",
+        help = "Seed for the random phases and noise, to reproduce a run. A random seed is logged if not given"
+    )]
+    seed: Option<u64>,
+
+    #[cfg(feature = "tui")]
+    #[structopt(
+        long = "tui",
+        help = "While playing, show a full-screen dashboard with level meters and keys to change the stretch"
+    )]
+    tui: bool,
+
+    #[structopt(short = "v", long = "verbose", help = "Log debugging detail too")]
+    verbose: bool,
+
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        conflicts_with = "verbose",
+        help = "Only log warnings and errors"
+    )]
+    quiet: bool,
+
+    #[structopt(
+        long = "daemon",
+        help = "Run unattended, e.g. under systemd: never read the terminal, fade out on SIGTERM, and reload the MIDI map and reopen the log file on SIGHUP"
+    )]
+    daemon: bool,
+
+    #[structopt(
+        long = "check",
+        help = "Check everything the other options need without processing any audio: probe the input, open the audio devices, compile kernels, load the reverb and MIDI map and bind the listen addresses, reporting every check that fails"
+    )]
+    check: bool,
+
+    #[structopt(
+        long = "log-file",
+        parse(from_os_str),
+        help = "Log to this file instead of the terminal, rotating it when it reaches 10 MB and keeping 5 old ones"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[structopt(
+        long = "watchdog",
+        parse(try_from_str = duration_parser::parse_duration),
+        help = "While playing, alert once the output has been silent or underrunning for this long (hh:mm:ss.ss)"
+    )]
+    watchdog: Option<Duration>,
+
+    #[structopt(
+        long = "watchdog-hook",
+        help = "Run this shell command, or POST to this http:// URL, for each watchdog alert"
+    )]
+    watchdog_hook: Option<AlertHook>,
+
+    #[structopt(
+        long = "watchdog-hours",
+        help = "Only alert during these local hours, e.g. 10:00-18:00"
+    )]
+    watchdog_hours: Option<OperatingHours>,
+
+    #[structopt(
+        long = "silence-threshold",
+        default_value = "-60",
+        allow_hyphen_values = true,
+        help = "Peak level in dBFS below which the watchdog counts the output as silent"
+    )]
+    silence_threshold: f32,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn load_audio(opt: &Opt) -> Audio {
-    let mut audio = match &opt.input {
-        Some(path) => {
-            if path.to_str() == Some("-") {
-                let mut reader = WavReader::new(io::stdin()).unwrap();
-                reader.read_all()
-            } else {
-                let mut reader = WavReader::open(path.to_str().unwrap()).unwrap();
-                reader.read_all()
-            }
-        }
-        None => recorder::record_audio(&AudioSpec {
-            channels: 2,
-            sample_rate: 44100,
-        }),
-    };
+// What to do with the audio. The options above are shared by every subcommand.
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Stretch the audio and play it through the output device, with live controls. This is the default.
+    Play,
+    /// Stretch to a file as fast as the CPU allows, logging progress as it goes
+    #[structopt(alias = "stretch")]
+    Render {
+        #[structopt(
+            parse(from_os_str),
+            help = "Output .wav file path. Use '-' to write raw samples to stdout, or tcp://<host>:<port> or unix://<path> to stream to a rocoder running receive."
+        )]
+        output: PathBuf,
+    },
+    /// Wait for another rocoder to stream to this address, and play what it sends
+    Receive {
+        #[structopt(help = "Address to listen on: tcp://<host>:<port> or unix://<path>")]
+        address: StreamAddr,
+    },
+    /// Stretch every .wav file in a directory into another directory, several files at once
+    Batch {
+        #[structopt(
+            long = "in",
+            parse(from_os_str),
+            help = "Directory of .wav files to stretch"
+        )]
+        input_dir: PathBuf,
+        #[structopt(
+            long = "out",
+            parse(from_os_str),
+            help = "Directory to write the stretched files to under the same names. Created if missing"
+        )]
+        output_dir: PathBuf,
+        #[structopt(
+            long = "jobs",
+            help = "Files to stretch at once. Defaults to the number of CPUs"
+        )]
+        jobs: Option<usize>,
+    },
+    /// Record from the input device until Enter is pressed, and save the recording unstretched
+    Record {
+        #[structopt(parse(from_os_str), help = "Output .wav file path")]
+        output: PathBuf,
+    },
+}
 
-    if opt.start.is_some() || opt.duration.is_some() {
-        audio.clip_in_place(opt.start, opt.duration);
+impl Opt {
+    #[cfg(feature = "tui")]
+    fn tui_enabled(&self) -> bool {
+        self.tui
+            && !self.check
+            && !self.daemon
+            && self.output.is_none()
+            && !matches!(self.command, Some(Command::Render { .. }))
     }
 
-    if opt.rotate_channels {
-        audio.rotate_channels();
+    #[cfg(not(feature = "tui"))]
+    fn tui_enabled(&self) -> bool {
+        false
     }
 
-    audio
-}
+    /// Whether any option works on the vocoder's spectrum
+    fn uses_spectrum(&self) -> bool {
+        !self.kernel.is_empty()
+            || !self.freq_kernel.is_empty()
+            || self.smear.is_some()
+            || !self.eq.is_empty()
+            || self.decorrelate.is_some()
+    }
 
-fn handle_result(
-    opt: &Opt,
-    audio_bus: AudioBus,
-    stretcher_node: Node<StretcherProcessor, StretcherProcessorControlMessage>,
-) -> Result<()> {
-    match &opt.output {
-        Some(path) => {
-            // This approach requires the entire audio output to fit
-            // in memory before we save it. Changes would be needed to
-            // stream output directly to disk.
-            let output_audio = audio_bus.into_audio();
-            let mut writer = WavWriter::open(path.to_str().unwrap(), output_audio.spec).unwrap();
-            writer.write_into_channels(output_audio.data)?;
-            writer.finalize().unwrap();
-        }
-        None => {
-            play(audio_bus, Some(opt.fade));
+    /// The engine to stretch by `factor` with, leaving `auto` on the vocoder
+    /// if the spectrum is needed
+    fn engine_for(&self, factor: f32) -> EngineKind {
+        match self.engine {
+            EngineKind::Auto if self.uses_spectrum() => EngineKind::Vocoder,
+            engine => engine.for_factor(factor),
         }
     }
-    stretcher_node.join();
-    Ok(())
-}
-
-const PLAY_POLL: Duration = Duration::from_millis(500);
 
-fn play(bus: AudioBus, fade: Option<Duration>) {
-    let player_node = Arc::new(Node::new(AudioOutputProcessor::new(bus.spec)));
-    player_node
-        .send_control_message(AudioOutputProcessorControlMessage::ConnectBus {
-            fade,
-            bus,
-            id: 0,
-            shutdown_when_finished: true,
-        })
-        .unwrap();
-    let quit_counter = Arc::new(AtomicU16::new(0));
-    let player_node_clone = Arc::clone(&player_node);
-    let quit_counter_clone = Arc::clone(&quit_counter);
-    ctrlc::set_handler(move || {
-        control_c_handler(&quit_counter_clone, Arc::clone(&player_node_clone));
-    })
-    .unwrap();
-    loop {
-        thread::sleep(PLAY_POLL);
-        if player_node.is_finished() {
-            // need to explicitly exit with a non-zero exit code so the control-c quit
-            // makes it to the shell so, for instance, bash loops can be broken.
-            std::process::exit(1);
+    fn watchdog(&self) -> Option<Watchdog> {
+        let mut watchdog = Watchdog::new(self.watchdog?, self.silence_threshold);
+        if let Some(hours) = self.watchdog_hours {
+            watchdog = watchdog.with_operating_hours(hours);
+        }
+        if let Some(hook) = &self.watchdog_hook {
+            watchdog = watchdog.with_hook(hook.clone());
+        }
+        Some(watchdog)
+    }
+
+    fn bus_policy(&self) -> BusPolicy {
+        BusPolicy {
+            capacity: self.bus_capacity,
+            overflow: self.bus_overflow,
+        }
+    }
+
+    fn backend(&self) -> Backend {
+        match self.backend.as_str() {
+            "jack" => Backend::Jack {
+                client_name: self.jack_client_name.clone(),
+            },
+            "asio" => Backend::Asio {
+                driver: self.device.clone(),
+            },
+            _ => match &self.device {
+                Some(name) => Backend::Device { name: name.clone() },
+                None => Backend::Default,
+            },
         }
     }
 }
 
-const QUIT_FADE: Option<Duration> = Some(Duration::from_secs(3));
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let output_path = match (&opt.command, &opt.output) {
+        (Some(Command::Render { .. }) | Some(Command::Record { .. }), Some(_)) => {
+            bail!("render and record take the output path themselves, so don't pass -o as well")
+        }
+        (Some(Command::Play), Some(_)) => {
+            bail!("play plays through the output device, so it can't take -o; use render")
+        }
+        (Some(Command::Render { output }), None) | (None, Some(output)) => Some(output.clone()),
+        _ => None,
+    };
+    let output_target = output_path
+        .clone()
+        .map(|path| FileSinkTarget::from_path(path, opt.raw_format));
+    let log_file = opt
+        .log_file
+        .as_deref()
+        .map(LogFile::open)
+        .transpose()
+        .context("failed to open the log file")?;
+    runtime_setup::setup_logging(
+        matches!(output_target, Some(FileSinkTarget::Stdout(_))),
+        if opt.tui_enabled() {
+            // Logging would scribble over the dashboard
+            LevelFilter::Off
+        } else if opt.verbose {
+            LevelFilter::Debug
+        } else if opt.quiet {
+            LevelFilter::Warn
+        } else {
+            LevelFilter::Info
+        },
+        log_file,
+    );
+    if opt.check {
+        return check(&opt, output_target.as_ref(), output_path.as_deref());
+    }
+    validate(&opt, output_target.as_ref())?;
+    if let Some(Command::Record { output }) = &opt.command {
+        return record_to_file(&opt, output);
+    }
+    if let Some(Command::Receive { address }) = &opt.command {
+        return receive(&opt, address);
+    }
+    if let Some(Command::Batch {
+        input_dir,
+        output_dir,
+        jobs,
+    }) = &opt.command
+    {
+        return batch(&opt, input_dir, output_dir, *jobs);
+    }
+    let cue_sheet = opt.cue.as_deref().map(CueSheet::from_file).transpose()?;
+    export_metrics(&opt)?;
+    let mut journal = opt.journal.as_deref().map(Journal::open).transpose()?;
+
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    info!("Using random seed {}", seed);
+    let mut audio = load_audio(&opt, seed)?;
+    if let Some(cue_sheet) = &cue_sheet {
+        cue_sheet.check_fits(audio.duration())?;
+    }
+    if let Some(path) = &opt.labels {
+        markers::mark_onsets(&mut audio);
+        let stretched: Vec<Marker> = audio
+            .markers
+            .iter()
+            .map(|marker| marker.scaled(opt.factor))
+            .collect();
+        markers::write_audacity_labels(path, &stretched, audio.spec.sample_rate)?;
+    }
+    let started = Instant::now();
+    record(
+        &mut journal,
+        "start",
+        &[
+            ("source", source_description(&opt).into()),
+            ("source_secs", audio.duration().as_secs_f64().into()),
+            ("factor", opt.factor.into()),
+            ("window_len", opt.window_len.into()),
+            ("amplitude", opt.amplitude.into()),
+            ("pitch_multiple", (opt.pitch_multiple as f64).into()),
+            // As a string, since f64 can't hold every u64
+            ("seed", seed.to_string().into()),
+            (
+                "output",
+                match &output_path {
+                    Some(path) => path.display().to_string().into(),
+                    None => "speakers".into(),
+                },
+            ),
+        ],
+    );
+    let bypass_bus = if opt.ab {
+        Some(AudioBus::from_audio(audio.clone()))
+    } else {
+        None
+    };
+    let percussive = separate_percussive(&opt, &mut audio);
+    let total_samples_len = audio.data[0].len();
+    let spec = audio.spec;
+    if opt.low_latency {
+        runtime_setup::enable_thread_pinning();
+        info!(
+            "Stretching adds {:.1} ms of latency, a window's length; use a smaller --window to lower it",
+            opt.window_len as f32 / spec.sample_rate as f32 * 1000.0
+        );
+    }
 
-fn control_c_handler(
-    quit_counter: &Arc<AtomicU16>,
-    node: Arc<Node<AudioOutputProcessor, AudioOutputProcessorControlMessage>>,
-) {
-    if quit_counter.fetch_add(1, Ordering::Relaxed) > 0 {
-        // If ctrl-c was received more than once, quit without fading out
-        println!("\nExiting immediately");
-        node.send_control_message(AudioOutputProcessorControlMessage::Shutdown { fade: None })
-            .unwrap();
-        return;
+    let stretch_progress = StretchProgress::new();
+    let spectrum = SpectrumTap::new();
+    let mut graph = Graph::new().with_bus_policy(opt.bus_policy());
+    let stretched_id = match &cue_sheet {
+        Some(cue_sheet) => {
+            add_cue_stretchers(&opt, &mut graph, &audio, cue_sheet, seed, &stretch_progress)?
+        }
+        None => {
+            let stretchers = build_stretchers(&opt, audio, opt.factor, opt.pitch_multiple, seed);
+            let expected_total_samples = Some(expected_stretch_len(
+                &opt,
+                total_samples_len,
+                spec.sample_rate,
+            ));
+            let progress = stretch_progress.clone();
+            // Only the dashboard draws the spectrum, so don't copy frames out for nothing
+            let tap = opt.tui_enabled().then(|| spectrum.clone());
+            let threads = opt.threads;
+            let (max_output, fade) = (opt.max_output, opt.fade);
+            graph.add_node("stretcher", move |_| {
+                let (processor, bus) = StretcherProcessor::new(stretchers, expected_total_samples);
+                let processor = limit_output(processor, max_output, fade)
+                    .with_workers(threads)
+                    .with_progress(progress);
+                Ok((
+                    match tap {
+                        Some(tap) => processor.with_spectrum_tap(tap),
+                        None => processor,
+                    },
+                    bus,
+                ))
+            })?;
+            "stretcher"
+        }
+    };
+    let stretched_id = add_percussive(&mut graph, stretched_id, percussive)?;
+    let output_id = add_effects(&opt, &mut graph, spec, stretched_id)?;
+
+    let result = match output_target {
+        Some(target) => {
+            let normalize = match (&target, opt.target_lufs) {
+                (FileSinkTarget::Wav(path), Some(target_lufs)) => Some((path.clone(), target_lufs)),
+                _ => None,
+            };
+            let frames_written = Arc::new(AtomicUsize::new(0));
+            let progress = Arc::clone(&frames_written);
+            graph.add_sink("file", move |mut inputs| {
+                Ok(Node::new(
+                    FileSinkProcessor::new(inputs.remove(0), target)?.with_progress(progress),
+                ))
+            })?;
+            graph.connect(output_id, "file")?;
+            render(
+                graph.start()?,
+                &frames_written,
+                &stretch_progress,
+                spec.sample_rate,
+            )
+            .and_then(|_| match normalize {
+                Some((path, target_lufs)) => loudness::normalize_wav(&path, target_lufs)
+                    .map(|_| ())
+                    .with_context(|| format!("failed to normalize {:?}", path)),
+                None => Ok(()),
+            })
+        }
+        None => {
+            // Load the mapping first so a bad one fails before any audio starts
+            let midi_mapping = opt
+                .midi_map
+                .as_deref()
+                .map(MidiMapping::from_file)
+                .transpose()?;
+            let peak_meter = PeakMeter::new();
+            let true_peak_meter = PeakMeter::new();
+            let output_bus = add_player(
+                &opt,
+                &mut graph,
+                output_id,
+                bypass_bus,
+                peak_meter.clone(),
+                true_peak_meter.clone(),
+            )?;
+            let sync = wait_for_sync_start(&opt)?;
+            play(
+                graph.start()?,
+                &opt,
+                output_bus,
+                midi_mapping,
+                sync,
+                Monitors {
+                    peak_meter,
+                    true_peak_meter,
+                    stretch_progress,
+                    spectrum,
+                    channels: spec.channels,
+                },
+                &mut journal,
+            )
+        }
+    };
+    let mut fields = vec![("elapsed_secs", started.elapsed().as_secs_f64().into())];
+    if let Err(e) = &result {
+        fields.push(("error", e.to_string().into()));
+    }
+    record(&mut journal, "finish", &fields);
+    result
+}
+
+/// Reject combinations of options that can't work together
+fn validate(opt: &Opt, output_target: Option<&FileSinkTarget>) -> Result<()> {
+    // Recording runs until Enter is pressed, which nobody can do to a daemon
+    let records = matches!(opt.command, Some(Command::Record { .. }))
+        || (opt.input.is_none()
+            && opt.generate.is_none()
+            && !matches!(
+                opt.command,
+                Some(Command::Receive { .. }) | Some(Command::Batch { .. })
+            ));
+    if opt.daemon && records {
+        bail!("--daemon can't record from the input device; give it -i or --generate");
+    }
+    if (opt.sync_lead.is_some() || opt.sync_follow.is_some()) && output_target.is_some() {
+        bail!("--sync-lead and --sync-follow only apply to playback");
+    }
+    if opt.watchdog.is_none() && (opt.watchdog_hook.is_some() || opt.watchdog_hours.is_some()) {
+        bail!("--watchdog-hook and --watchdog-hours need --watchdog");
+    }
+    if opt.target_lufs.is_some() && !matches!(output_target, Some(FileSinkTarget::Wav(_))) {
+        bail!("--target-lufs needs a .wav output, since the whole render is measured before it's normalized");
+    }
+    if opt.cue.is_some() && (output_target.is_none() || opt.command.is_some()) {
+        bail!("--cue only applies to rendering a single input to a file");
+    }
+    if !(opt.factor > 0.0 && opt.factor.is_finite()) {
+        bail!("--factor must be more than 0, where below 1 compresses the input in time");
+    }
+    if opt
+        .smear
+        .is_some_and(|amount| !(0.0..=1.0).contains(&amount))
+    {
+        bail!("--smear must be from 0 to 1");
+    }
+    if opt
+        .decorrelate
+        .is_some_and(|intensity| !(0.0..=1.0).contains(&intensity))
+    {
+        bail!("--decorrelate must be from 0 to 1");
+    }
+    if opt.bus_capacity == Some(0) {
+        bail!("--bus-capacity must be at least 1");
+    }
+    if opt.bus_capacity.is_none() && opt.bus_overflow != Overflow::Block {
+        bail!("--bus-overflow needs --bus-capacity, since buses are unbounded without it");
+    }
+    if matches!(opt.engine, EngineKind::Granular | EngineKind::Wsola) && opt.uses_spectrum() {
+        bail!("--kernel, --freq-kernel, --smear, --eq and --decorrelate work on the vocoder's spectrum, so they can't be used with --engine granular or wsola");
+    }
+    if opt.engine == EngineKind::Granular
+        && (opt.grain.is_zero()
+            || !(0.0..=1.0).contains(&opt.grain_jitter)
+            || !(1.0..).contains(&opt.grain_density))
+    {
+        bail!("--grain must be more than 0, --grain-jitter from 0 to 1 and --grain-density at least 1");
+    }
+    if opt.smear_frames == 0 {
+        bail!("--smear-frames must be at least 1");
+    }
+    if opt.denoise_over_subtraction < 0.0 || !(0.0..=1.0).contains(&opt.denoise_floor) {
+        bail!(
+            "--denoise-over-subtraction can't be negative, and --denoise-floor must be from 0 to 1"
+        );
+    }
+    if !(0.0..=1.0).contains(&opt.percussive_mix) {
+        bail!("--percussive-mix must be from 0 to 1");
+    }
+    if opt.labels.is_some() && opt.cue.is_some() {
+        bail!("--labels can't place markers in the segments of a cue sheet");
+    }
+    if opt.max_output.is_some() && opt.cue.is_some() {
+        bail!("--max-output can't be combined with --cue, whose segments set the length");
+    }
+    if opt.watchdog.is_some() && output_target.is_some() {
+        bail!("--watchdog only applies to playback");
+    }
+    if opt.spec.is_some() && opt.input.as_deref() != Some(Path::new("-")) {
+        bail!("--spec describes raw samples on stdin, so it needs -i -");
+    }
+    Ok(())
+}
+
+/// With --check, try everything the options need short of processing audio,
+/// reporting each check and failing if any of them did
+fn check(
+    opt: &Opt,
+    output_target: Option<&FileSinkTarget>,
+    output_path: Option<&Path>,
+) -> Result<()> {
+    let mut checks = Checks::default();
+    checks.run("options", || {
+        validate(opt, output_target).map(|_| "valid".to_string())
+    });
+
+    // What the rest is checked against when the input can't be probed
+    let mut spec = AudioSpec {
+        channels: 2,
+        sample_rate: 44100,
+    };
+    let mut input_duration = None;
+    match (&opt.command, &opt.input) {
+        (Some(Command::Receive { address }), _) => {
+            checks.run("receive address", || {
+                StreamListener::bind(address)?;
+                Ok(format!("{} is free", address))
+            });
+        }
+        (Some(Command::Batch { input_dir, .. }), _) => {
+            checks.run("batch input", || {
+                let inputs = batch_inputs(input_dir)?;
+                Ok(format!(
+                    "{} .wav files in {}",
+                    inputs.len(),
+                    input_dir.display()
+                ))
+            });
+        }
+        _ if opt.generate.is_some() => {}
+        (_, Some(path)) if path.to_str() == Some("-") => {}
+        (_, Some(path)) => {
+            checks.run("input", || {
+                let reader = WavReader::open(path.to_str().unwrap())
+                    .with_context(|| format!("failed to open {:?}", path))?;
+                spec = reader.spec();
+                let duration = Duration::from_secs_f64(
+                    reader.duration().unwrap_or(0) as f64 / spec.sample_rate as f64,
+                );
+                input_duration = Some(duration);
+                Ok(format!(
+                    "{}, {} channels at {} Hz, {:.1} s",
+                    path.display(),
+                    spec.channels,
+                    spec.sample_rate,
+                    duration.as_secs_f32()
+                ))
+            });
+        }
+        (_, None) => {
+            checks.run("input device", || {
+                cpal_utils::probe_input(&opt.backend(), spec.channels, spec.sample_rate)
+            });
+        }
+    }
+    if let Some(path) = &opt.cue {
+        checks.run("cue sheet", || {
+            let cue_sheet = CueSheet::from_file(path)?;
+            if let Some(duration) = input_duration {
+                cue_sheet.check_fits(duration)?;
+            }
+            Ok(path.display().to_string())
+        });
+    }
+    for path in &opt.freq_kernel {
+        checks.run("kernel", || {
+            hotswapper::load_kernel(path)?;
+            Ok(path.display().to_string())
+        });
+    }
+    if let Some(path) = &opt.reverb {
+        checks.run("reverb", || {
+            ConvolutionReverb::from_file(path.to_str().unwrap(), &spec, opt.reverb_mix)?;
+            Ok(path.display().to_string())
+        });
+    }
+    if let Some(path) = &opt.midi_map {
+        checks.run("MIDI map", || {
+            MidiMapping::from_file(path)?;
+            Ok(path.display().to_string())
+        });
+    }
+
+    match (&opt.command, output_target) {
+        (Some(Command::Receive { .. }), _) | (None | Some(Command::Play), None) => {
+            checks.run("output device", || {
+                let buffer = match (opt.low_latency, opt.buffer_size) {
+                    (true, _) => BufferRequest::Smallest,
+                    (false, Some(frames)) => BufferRequest::Frames(frames),
+                    (false, None) => BufferRequest::Default,
+                };
+                cpal_utils::probe_output(
+                    &opt.backend(),
+                    opt.output_channels.unwrap_or(spec.channels),
+                    spec.sample_rate,
+                    buffer,
+                )
+            });
+        }
+        (Some(Command::Record { output }), _) => {
+            checks.run("output", || check_parent_dir(output));
+        }
+        (Some(Command::Batch { output_dir, .. }), _) => {
+            checks.run("batch output", || match output_dir.is_dir() {
+                true => Ok(output_dir.display().to_string()),
+                false => check_parent_dir(output_dir),
+            });
+        }
+        // Connecting to a receiver would start it playing, and stdout can't fail up front
+        (_, Some(FileSinkTarget::Wav(_))) => {
+            checks.run("output", || check_parent_dir(output_path.unwrap()));
+        }
+        _ => {}
+    }
+    for (name, path) in [("journal", &opt.journal), ("labels", &opt.labels)] {
+        if let Some(path) = path {
+            checks.run(name, || check_parent_dir(path));
+        }
+    }
+    for (name, addr) in [
+        ("HTTP listen", opt.http_listen),
+        ("metrics listen", opt.metrics_listen),
+    ] {
+        if let Some(addr) = addr {
+            checks.run(name, || {
+                TcpListener::bind(addr)?;
+                Ok(format!("{} is free", addr))
+            });
+        }
+    }
+    for (name, addr) in [("OSC listen", opt.osc_listen), ("sync lead", opt.sync_lead)] {
+        if let Some(addr) = addr {
+            checks.run(name, || {
+                UdpSocket::bind(addr)?;
+                Ok(format!("{} is free", addr))
+            });
+        }
+    }
+    checks.finish()
+}
+
+/// Tallies the checks run by `check`, printing each as it finishes
+#[derive(Default)]
+struct Checks {
+    run: usize,
+    failed: usize,
+}
+
+impl Checks {
+    fn run(&mut self, name: &str, check: impl FnOnce() -> Result<String>) {
+        self.run += 1;
+        match check() {
+            Ok(detail) => println!("ok      {}: {}", name, detail),
+            Err(e) => {
+                self.failed += 1;
+                println!("FAILED  {}: {:#}", name, e);
+            }
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.failed > 0 {
+            bail!("{} of {} checks failed", self.failed, self.run);
+        }
+        println!("All {} checks passed", self.run);
+        Ok(())
+    }
+}
+
+/// Whether a file could be created at `path`, without creating it
+fn check_parent_dir(path: &Path) -> Result<String> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        bail!("{:?} isn't a directory", parent);
+    }
+    if fs::metadata(parent)?.permissions().readonly() {
+        bail!("{:?} is read-only", parent);
+    }
+    Ok(path.display().to_string())
+}
+
+/// A stretcher for each channel of `audio`, set up from the options but for
+/// the factor and pitch
+fn build_stretchers(
+    opt: &Opt,
+    audio: Audio,
+    factor: f32,
+    pitch_multiple: i8,
+    seed: u64,
+) -> Vec<Box<dyn StretchEngine>> {
+    match opt.engine_for(factor) {
+        EngineKind::Granular => {
+            return build_granular_stretchers(opt, audio, factor, pitch_multiple, seed)
+        }
+        EngineKind::Wsola => {
+            info!("Stretching {}x with WSOLA", factor);
+            return build_wsola_stretchers(opt, audio, factor, pitch_multiple);
+        }
+        _ => {}
     }
-    println!(
-        "\nGot quit signal, fading out audio for {:#?}",
-        QUIT_FADE.unwrap()
+    let spec = audio.spec;
+    let window = windows::hanning(opt.window_len);
+    let kernel_sources: Vec<KernelSource> = opt
+        .kernel
+        .iter()
+        .copied()
+        .chain(opt.smear.map(|_| BuiltinKernel::Smear))
+        .map(KernelSource::Builtin)
+        .chain(opt.freq_kernel.iter().cloned().map(KernelSource::File))
+        .collect();
+    let eq = SpectralEq::new(opt.eq.clone());
+    audio
+        .data
+        .into_iter()
+        .enumerate()
+        .map(|(i, channel)| {
+            let (stretcher_in_tx, stretcher_in_rx) = unbounded();
+            let mut stretcher = Stretcher::new(
+                spec,
+                stretcher_in_rx,
+                factor,
+                opt.amplitude,
+                pitch_multiple,
+                window.clone(),
+                opt.buffer_dur,
+                kernel_sources.clone(),
+            );
+            match opt.decorrelate {
+                // The channels share phases, each offset by its own amount
+                Some(intensity) => {
+                    stretcher.set_seed(seed);
+                    stretcher.set_decorrelation(intensity, seed.wrapping_add(i as u64 + 1));
+                }
+                // Each channel gets its own phases, as it would unseeded
+                None => stretcher.set_seed(seed.wrapping_add(i as u64)),
+            }
+            stretcher.set_kernel_crossfade(opt.kernel_crossfade);
+            for (name, value) in opt.kernel_param.iter() {
+                stretcher.set_kernel_param(name, *value);
+            }
+            if let Some(amount) = opt.smear {
+                stretcher.set_kernel_param("smear_amount", amount);
+                stretcher.set_kernel_param("smear_frames", opt.smear_frames as f32);
+            }
+            if !eq.is_empty() {
+                stretcher.set_spectral_eq(&eq);
+            }
+            if stretcher_in_tx.send(channel).is_err() {
+                warn!("failed to send channel data");
+            }
+            Box::new(stretcher) as Box<dyn StretchEngine>
+        })
+        .collect()
+}
+
+fn build_wsola_stretchers(
+    opt: &Opt,
+    audio: Audio,
+    factor: f32,
+    pitch_multiple: i8,
+) -> Vec<Box<dyn StretchEngine>> {
+    let spec = audio.spec;
+    audio
+        .data
+        .into_iter()
+        .map(|channel| {
+            let (stretcher_in_tx, stretcher_in_rx) = unbounded();
+            let stretcher = WsolaStretcher::new(
+                spec,
+                stretcher_in_rx,
+                factor,
+                opt.amplitude,
+                pitch_multiple,
+                opt.buffer_dur,
+            );
+            if stretcher_in_tx.send(channel).is_err() {
+                warn!("failed to send channel data");
+            }
+            Box::new(stretcher) as Box<dyn StretchEngine>
+        })
+        .collect()
+}
+
+fn build_granular_stretchers(
+    opt: &Opt,
+    audio: Audio,
+    factor: f32,
+    pitch_multiple: i8,
+    seed: u64,
+) -> Vec<Box<dyn StretchEngine>> {
+    let spec = audio.spec;
+    let grain = GrainParams {
+        len: audio.duration_to_sample(opt.grain).max(2),
+        jitter: opt.grain_jitter,
+        density: opt.grain_density,
+    };
+    audio
+        .data
+        .into_iter()
+        .enumerate()
+        .map(|(i, channel)| {
+            let (stretcher_in_tx, stretcher_in_rx) = unbounded();
+            let mut stretcher = GranularStretcher::new(
+                spec,
+                stretcher_in_rx,
+                factor,
+                opt.amplitude,
+                pitch_multiple,
+                grain,
+                opt.buffer_dur,
+            );
+            stretcher.set_seed(seed.wrapping_add(i as u64));
+            if stretcher_in_tx.send(channel).is_err() {
+                warn!("failed to send channel data");
+            }
+            Box::new(stretcher) as Box<dyn StretchEngine>
+        })
+        .collect()
+}
+
+/// How many samples per channel stretching `input_len` will produce, with
+/// any --max-output
+fn expected_stretch_len(opt: &Opt, input_len: usize, sample_rate: u32) -> usize {
+    let stretched = (input_len as f32 * opt.factor) as usize;
+    match opt.max_output {
+        Some(max) => stretched.min((max.as_secs_f32() * sample_rate as f32) as usize),
+        None => stretched,
+    }
+}
+
+/// Apply --max-output to `processor`, fading out over `fade`
+fn limit_output(
+    processor: StretcherProcessor,
+    max_output: Option<Duration>,
+    fade: Duration,
+) -> StretcherProcessor {
+    match max_output {
+        Some(max) => processor.with_output_limit(max, fade),
+        None => processor,
+    }
+}
+
+/// Stretch each segment of `cue_sheet` on its own node, with its own factor
+/// and pitch, and join them in order on a sequence node, returning its ID
+fn add_cue_stretchers(
+    opt: &Opt,
+    graph: &mut Graph,
+    audio: &Audio,
+    cue_sheet: &CueSheet,
+    seed: u64,
+    progress: &StretchProgress,
+) -> Result<&'static str> {
+    let mut ids = vec![];
+    for (i, cue) in cue_sheet.cues.iter().enumerate() {
+        // The stretchers outlive `audio`, so each cue takes its own copy, but
+        // only of its range
+        let segment = audio
+            .slice(Some(cue.start), Some(cue.duration()))
+            .with_context(|| format!("cue {} doesn't fit the source", i + 1))?
+            .to_audio();
+        let expected_total_samples = Some((segment.data[0].len() as f32 * cue.factor) as usize);
+        // Give every channel of every segment its own phases
+        let segment_seed = seed.wrapping_add((i * audio.data.len()) as u64);
+        let stretchers =
+            build_stretchers(opt, segment, cue.factor, cue.pitch_multiple, segment_seed);
+        let threads = opt.threads;
+        let progress = progress.clone();
+        let id = format!("cue {}", i + 1);
+        graph.add_node(&id, move |_| {
+            let (processor, bus) = StretcherProcessor::new(stretchers, expected_total_samples);
+            Ok((processor.with_workers(threads).with_progress(progress), bus))
+        })?;
+        ids.push(id);
+    }
+    let crossfade_len = audio.duration_to_sample(opt.cue_crossfade);
+    let policy = graph.bus_policy();
+    graph.add_node("sequence", move |inputs| {
+        SequenceProcessor::new(inputs, crossfade_len, policy)
+    })?;
+    for id in &ids {
+        graph.connect(id, "sequence")?;
+    }
+    Ok("sequence")
+}
+
+/// The .wav files directly in `input_dir`, in order, which must be at least one
+fn batch_inputs(input_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut inputs = vec![];
+    for entry in fs::read_dir(input_dir)
+        .with_context(|| format!("failed to read the directory {:?}", input_dir))?
+    {
+        let path = entry?.path();
+        let is_wav = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+        if is_wav && path.is_file() {
+            inputs.push(path);
+        } else {
+            debug!("Skipping {:?}, which isn't a .wav file", path);
+        }
+    }
+    inputs.sort();
+    if inputs.is_empty() {
+        bail!("there are no .wav files in {:?}", input_dir);
+    }
+    Ok(inputs)
+}
+
+/// Stretch every .wav file in `input_dir` into `output_dir`, `jobs` at a
+/// time, then report how each one went
+///
+/// A file that fails doesn't stop the others, but makes the whole batch fail.
+fn batch(opt: &Opt, input_dir: &Path, output_dir: &Path, jobs: Option<usize>) -> Result<()> {
+    if opt.input.is_some() || opt.generate.is_some() || opt.output.is_some() {
+        bail!("batch reads and writes the directories it's given, so it can't be combined with -i, --generate or -o");
+    }
+    let inputs = batch_inputs(input_dir)?;
+    fs::create_dir_all(output_dir).with_context(|| format!("failed to create {:?}", output_dir))?;
+    if fs::canonicalize(input_dir)? == fs::canonicalize(output_dir)? {
+        bail!("batch would overwrite its inputs; give it a different --out directory");
+    }
+    let jobs = jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+        .clamp(1, inputs.len());
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    info!(
+        "Stretching {} files with {} at a time, using random seed {}",
+        inputs.len(),
+        jobs,
+        seed
     );
-    node.send_control_message(AudioOutputProcessorControlMessage::Shutdown { fade: QUIT_FADE })
+
+    let started = Instant::now();
+    let (queue_tx, queue) = unbounded();
+    for (i, input) in inputs.iter().enumerate() {
+        queue_tx.send((i, input)).unwrap();
+    }
+    drop(queue_tx);
+    let mut results: Vec<Option<(Result<()>, Duration)>> = inputs.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                let queue = queue.clone();
+                scope.spawn(move || {
+                    let mut done = vec![];
+                    for (i, input) in queue {
+                        let file_started = Instant::now();
+                        let result = render_batch_file(opt, input, output_dir, seed);
+                        done.push((i, result, file_started.elapsed()));
+                    }
+                    done
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, result, elapsed) in worker.join().unwrap() {
+                results[i] = Some((result, elapsed));
+            }
+        }
+    });
+
+    let mut failures = 0;
+    info!(
+        "Batch finished in {:.1} s:",
+        started.elapsed().as_secs_f32()
+    );
+    for (input, result) in inputs.iter().zip(results) {
+        let name = input
+            .file_name()
+            .unwrap_or(input.as_os_str())
+            .to_string_lossy();
+        match result.unwrap() {
+            (Ok(()), elapsed) => info!("  {}: {:.1} s", name, elapsed.as_secs_f32()),
+            (Err(e), elapsed) => {
+                failures += 1;
+                error!(
+                    "  {}: failed after {:.1} s: {:#}",
+                    name,
+                    elapsed.as_secs_f32(),
+                    e
+                );
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{} of {} files failed", failures, inputs.len());
+    }
+    Ok(())
+}
+
+/// Stretch one file of a batch into the file of the same name in
+/// `output_dir`, with the effects and loudness target from the options
+fn render_batch_file(opt: &Opt, input: &Path, output_dir: &Path, seed: u64) -> Result<()> {
+    let name = input
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} has no file name", input))?;
+    let output = output_dir.join(name);
+    let input_str = input
+        .to_str()
+        .ok_or_else(|| anyhow!("{:?} isn't valid UTF-8", input))?;
+    let mut audio = WavReader::open(input_str)
+        .with_context(|| format!("failed to open {:?}", input))?
+        .read_all();
+    prepare_audio(opt, &mut audio)?;
+    let percussive = separate_percussive(opt, &mut audio);
+    let spec = audio.spec;
+    let expected_total_samples = Some(expected_stretch_len(
+        opt,
+        audio.data[0].len(),
+        spec.sample_rate,
+    ));
+    let stretchers = build_stretchers(opt, audio, opt.factor, opt.pitch_multiple, seed);
+    let threads = opt.threads;
+    let (max_output, fade) = (opt.max_output, opt.fade);
+    let mut graph = Graph::new().with_bus_policy(opt.bus_policy());
+    graph.add_node("stretcher", move |_| {
+        let (processor, bus) = StretcherProcessor::new(stretchers, expected_total_samples);
+        Ok((
+            limit_output(processor, max_output, fade).with_workers(threads),
+            bus,
+        ))
+    })?;
+    let stretched_id = add_percussive(&mut graph, "stretcher", percussive)?;
+    let output_id = add_effects(opt, &mut graph, spec, stretched_id)?;
+    let target = FileSinkTarget::Wav(output.clone());
+    graph.add_sink("file", move |mut inputs| {
+        Ok(Node::new(FileSinkProcessor::new(inputs.remove(0), target)?))
+    })?;
+    graph.connect(output_id, "file")?;
+    graph.start()?.join()?;
+    if let Some(target_lufs) = opt.target_lufs {
+        loudness::normalize_wav(&output, target_lufs)?;
+    }
+    Ok(())
+}
+
+/// Wait for a render to a file to finish, logging how far the stretch has
+/// got and roughly how long is left every `PROGRESS_INTERVAL`
+fn render(
+    pipeline: Pipeline,
+    frames_written: &AtomicUsize,
+    stretch_progress: &StretchProgress,
+    sample_rate: u32,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut last_report = started;
+    while !pipeline.is_finished() {
+        thread::sleep(RENDER_POLL);
+        if last_report.elapsed() < PROGRESS_INTERVAL {
+            continue;
+        }
+        last_report = Instant::now();
+        let rendered_secs = frames_written.load(Ordering::Relaxed) as f32 / sample_rate as f32;
+        match stretch_progress.fraction() {
+            Some(fraction) if fraction > 0.0 && fraction < 1.0 => {
+                let left = started.elapsed().as_secs_f32() * (1.0 - fraction) / fraction;
+                info!(
+                    "Rendered {:.0}% ({:.1} s of audio), about {:.0} s left",
+                    fraction * 100.0,
+                    rendered_secs,
+                    left.ceil()
+                );
+            }
+            _ => info!("Rendered {:.1} s of audio", rendered_secs),
+        }
+    }
+    pipeline.join()?;
+    let elapsed = started.elapsed().as_secs_f32();
+    let rendered_secs = frames_written.load(Ordering::Relaxed) as f32 / sample_rate as f32;
+    info!(
+        "Rendered {:.1} s of audio in {:.1} s, {:.0}x realtime",
+        rendered_secs,
+        elapsed,
+        rendered_secs / elapsed.max(f32::EPSILON)
+    );
+    Ok(())
+}
+
+/// Add an event to the journal, if there is one, without letting a failure
+/// to write it stop the audio
+fn record(journal: &mut Option<Journal>, event: &str, fields: &[(&str, Value)]) {
+    if let Some(journal) = journal {
+        if let Err(e) = journal.record(event, fields) {
+            warn!("Failed to write {} to the journal: {}", event, e);
+        }
+    }
+}
+
+fn source_description(opt: &Opt) -> String {
+    match (&opt.generate, &opt.input) {
+        (Some(waveform), _) => waveform.to_string(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => "recording".to_string(),
+    }
+}
+
+/// Chain the time-domain effects requested in `opt` after node `input_id`,
+/// returning the ID of the last node in the chain
+type EffectBuilder = Box<dyn FnMut() -> Result<Box<dyn TimeDomainEffect>> + Send>;
+
+fn add_effects<'a>(
+    opt: &Opt,
+    graph: &mut Graph,
+    spec: AudioSpec,
+    input_id: &'a str,
+) -> Result<&'a str> {
+    // Effects are built afresh each time a failed effect node is restarted
+    let mut effect_chain: Vec<(&'static str, EffectBuilder)> = vec![];
+    if let Some(cutoff) = opt.lowpass {
+        effect_chain.push((
+            "lowpass",
+            Box::new(move || Ok(Box::new(Lowpass::new(&spec, cutoff)))),
+        ));
+    }
+    if let Some(rate) = opt.tremolo {
+        effect_chain.push((
+            "tremolo",
+            Box::new(move || {
+                Ok(Box::new(Tremolo::new(
+                    &spec,
+                    rate,
+                    effects::DEFAULT_TREMOLO_DEPTH,
+                )))
+            }),
+        ));
+    }
+    if let Some(time) = opt.delay {
+        effect_chain.push((
+            "delay",
+            Box::new(move || {
+                Ok(Box::new(Delay::new(
+                    &spec,
+                    time,
+                    effects::DEFAULT_DELAY_FEEDBACK,
+                    effects::DEFAULT_DELAY_MIX,
+                )))
+            }),
+        ));
+    }
+    if let Some(path) = &opt.reverb {
+        let path = path.to_str().unwrap().to_string();
+        let mix = opt.reverb_mix;
+        // Load the impulse response up front so a bad file is reported before anything starts
+        let mut loaded = Some(ConvolutionReverb::from_file(&path, &spec, mix)?);
+        effect_chain.push((
+            "reverb",
+            Box::new(move || match loaded.take() {
+                Some(reverb) => Ok(Box::new(reverb)),
+                None => Ok(Box::new(ConvolutionReverb::from_file(&path, &spec, mix)?)),
+            }),
+        ));
+    }
+    let mut previous_id = input_id;
+    let policy = graph.bus_policy();
+    for (id, mut build_effect) in effect_chain {
+        graph.add_supervised_node(id, RestartPolicy::default(), move |mut inputs| {
+            Ok(EffectProcessor::new(
+                inputs.remove(0),
+                build_effect()?,
+                policy,
+            ))
+        })?;
+        graph.connect(previous_id, id)?;
+        previous_id = id;
+    }
+    Ok(previous_id)
+}
+
+const GENERATE_AMPLITUDE: f32 = 0.5;
+const GENERATE_DURATION: Duration = Duration::from_secs(10);
+
+fn load_audio(opt: &Opt, seed: u64) -> Result<Audio> {
+    let mut audio = match &opt.input {
+        _ if opt.generate.is_some() => {
+            let spec = AudioSpec {
+                channels: 2,
+                sample_rate: 44100,
+            };
+            let mut generator =
+                Generator::new(opt.generate.unwrap(), GENERATE_AMPLITUDE, spec.sample_rate);
+            generator.set_seed(seed);
+            generator.render(&spec, opt.duration.unwrap_or(GENERATE_DURATION))
+        }
+        Some(path) => match (path.to_str(), opt.spec) {
+            (Some("-"), Some(spec)) => {
+                RawReader::new(io::stdin().lock(), spec, opt.raw_format).read_all()?
+            }
+            (Some("-"), None) => WavReader::new(io::stdin())
+                .context("failed to read a .wav from stdin; pass --spec for raw samples")?
+                .read_all(),
+            _ => WavReader::open(path.to_str().unwrap())
+                .with_context(|| format!("failed to open {:?}", path))?
+                .read_all(),
+        },
+        None => recorder::record_audio(
+            &AudioSpec {
+                channels: 2,
+                sample_rate: 44100,
+            },
+            &opt.backend(),
+            &recorder::Conditioning {
+                pre_roll: opt.pre_roll,
+                trim_to_onset: opt.trim_to_onset,
+                ..Default::default()
+            },
+        ),
+    };
+    prepare_audio(opt, &mut audio)?;
+    Ok(audio)
+}
+
+/// With --hpss, leave only the harmonic part of `audio` to be stretched,
+/// returning the percussive part at its mix level unless it's dropped
+fn separate_percussive(opt: &Opt, audio: &mut Audio) -> Option<Audio> {
+    if !opt.hpss {
+        return None;
+    }
+    let started = Instant::now();
+    let (harmonic, mut percussive) = hpss::separate(audio, hpss::DEFAULT_WINDOW_LEN);
+    info!(
+        "Separated the harmonic and percussive parts in {:.1} s",
+        started.elapsed().as_secs_f32()
+    );
+    *audio = harmonic;
+    if opt.percussive_mix == 0.0 {
+        return None;
+    }
+    percussive.amplify_in_place(opt.percussive_mix);
+    Some(percussive)
+}
+
+/// Mix `percussive`, unstretched, into the output of node `input_id`,
+/// returning the ID of the node to take the mix from
+fn add_percussive<'a>(
+    graph: &mut Graph,
+    input_id: &'a str,
+    percussive: Option<Audio>,
+) -> Result<&'a str> {
+    let percussive = match percussive {
+        Some(percussive) => percussive,
+        None => return Ok(input_id),
+    };
+    let policy = graph.bus_policy();
+    graph.add_node("percussive mix", move |mut inputs| {
+        inputs.push(AudioBus::from_audio(percussive));
+        MixerProcessor::new(inputs, policy)
+    })?;
+    graph.connect(input_id, "percussive mix")?;
+    Ok("percussive mix")
+}
+
+/// Clip, rearrange and denoise loaded audio as the options ask
+fn prepare_audio(opt: &Opt, audio: &mut Audio) -> Result<()> {
+    if opt.start.is_some() || opt.duration.is_some() {
+        audio
+            .clip_in_place(opt.start, opt.duration)
+            .context("--start and --duration must fall within the input")?;
+    }
+
+    if opt.rotate_channels {
+        audio.rotate_channels();
+    }
+
+    for channel in &opt.invert_polarity {
+        audio.invert_polarity(*channel);
+    }
+
+    if let Some(calibration) = opt.denoise {
+        if calibration >= audio.duration() {
+            bail!(
+                "--denoise {:?} would leave nothing of the {:?} input to stretch",
+                calibration,
+                audio.duration()
+            );
+        }
+        let noise = audio.slice(None, Some(calibration))?;
+        let profile = NoiseProfile::capture(noise, denoise::DEFAULT_WINDOW_LEN)
+            .context("failed to profile the noise for --denoise")?;
+        audio.clip_in_place(Some(calibration), None)?;
+        denoise::subtract(
+            audio,
+            &profile,
+            Subtraction {
+                over_subtraction: opt.denoise_over_subtraction,
+                floor: opt.denoise_floor,
+            },
+        )?;
+        info!(
+            "Subtracted the noise profiled from the first {:?}",
+            calibration
+        );
+    }
+    Ok(())
+}
+
+/// Play audio streamed from another rocoder, which has already stretched it,
+/// until the sender closes the stream or the user quits
+fn receive(opt: &Opt, address: &StreamAddr) -> Result<()> {
+    if opt.input.is_some() || opt.generate.is_some() || opt.output.is_some() {
+        bail!("receive plays what it's sent, so it can't be combined with -i, --generate or -o");
+    }
+    let listener = StreamListener::bind(address)
+        .with_context(|| format!("failed to listen on {}", address))?;
+    info!("Waiting for a sender on {}", address);
+    let reader = listener.accept()?;
+    let spec = reader.spec;
+    info!(
+        "Receiving {} channels at {} Hz",
+        spec.channels, spec.sample_rate
+    );
+    let mut graph = Graph::new().with_bus_policy(opt.bus_policy());
+    graph.add_node("stream", move |_| Ok(StreamSourceProcessor::new(reader)))?;
+    let output_id = add_effects(opt, &mut graph, spec, "stream")?;
+    let peak_meter = PeakMeter::new();
+    let true_peak_meter = PeakMeter::new();
+    add_player(
+        opt,
+        &mut graph,
+        output_id,
+        None,
+        peak_meter.clone(),
+        true_peak_meter.clone(),
+    )?;
+    let pipeline = graph.start()?;
+    let mut watchdog = opt.watchdog();
+    let underruns = player_processor::underrun_counter();
+    let mut clip_warnings = ClipWarnings::new();
+
+    let (quit_tx, quit_rx) = unbounded();
+    ctrlc::set_handler(move || {
+        let _ = quit_tx.send(());
+    })?;
+    loop {
+        if let Some((id, e)) = pipeline.try_recv_error() {
+            error!("{} stopped: {}", id, e);
+            pipeline.shutdown(Duration::ZERO)?;
+            return Err(anyhow!("playback failed"));
+        }
+        let output = pipeline
+            .node::<AudioOutputProcessor, AudioOutputProcessorControlMessage>("output")
+            .unwrap();
+        if output.is_finished() {
+            return pipeline.shutdown(Duration::ZERO);
+        }
+        if let Some(watchdog) = &mut watchdog {
+            watchdog.poll(peak_meter.take(), underruns.get());
+        }
+        clip_warnings.poll(true_peak_meter.take());
+        if quit_rx.recv_timeout(PLAY_POLL).is_ok() {
+            info!("Got quit signal, fading out audio for {:#?}", QUIT_FADE);
+            pipeline.shutdown(QUIT_FADE)?;
+            return Err(anyhow!("interrupted"));
+        }
+    }
+}
+
+/// Record from the input device and save the recording without stretching it
+fn record_to_file(opt: &Opt, output: &Path) -> Result<()> {
+    if opt.input.is_some() || opt.generate.is_some() {
+        bail!("record takes audio from the input device, so it can't be combined with -i or --generate");
+    }
+    // No noise is generated, so the seed doesn't matter
+    let mut audio = load_audio(opt, 0)?;
+    if let Some(path) = &opt.labels {
+        markers::mark_onsets(&mut audio);
+        markers::write_audacity_labels(path, &audio.markers, audio.spec.sample_rate)?;
+    }
+    let mut writer = WavWriter::open(output.to_str().unwrap(), audio.spec)
+        .with_context(|| format!("failed to create {:?}", output))?;
+    writer.write_slice(audio.as_slice())?;
+    writer.finalize()?;
+    info!("Saved the recording to {}", output.display());
+    Ok(())
+}
+
+fn export_metrics(opt: &Opt) -> Result<()> {
+    if let Some(addr) = opt.metrics_listen {
+        http_api::serve(addr, |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response {
+                    status: 200,
+                    content_type: "text/plain; version=0.0.4",
+                    body: metrics::registry().render_prometheus(),
+                },
+                _ => Response::text(404, "not found"),
+            }
+        })?;
+    }
+    if let Some(interval) = opt.metrics_log {
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            info!("metrics:\n{}", metrics::registry().render_prometheus());
+        });
+    }
+    Ok(())
+}
+
+/// Add the audio output device to the graph as node "output", fed by
+/// `input_id`, returning the ID of the bus that carries it
+fn add_player(
+    opt: &Opt,
+    graph: &mut Graph,
+    input_id: &str,
+    bypass_bus: Option<AudioBus>,
+    peak_meter: PeakMeter,
+    true_peak_meter: PeakMeter,
+) -> Result<BusId> {
+    let input_bus = BusId::allocate();
+    let output_channels = opt.output_channels;
+    let fade = opt.fade;
+    let no_limiter = opt.no_limiter;
+    let pan = opt.pan;
+    let backend = opt.backend();
+    let buffer = match (opt.low_latency, opt.buffer_size) {
+        (true, _) => BufferRequest::Smallest,
+        (false, Some(frames)) => BufferRequest::Frames(frames),
+        (false, None) => BufferRequest::Default,
+    };
+    graph.add_sink("output", move |mut inputs| {
+        let bus = inputs.remove(0);
+        let input_channels = bus.spec.channels;
+        let output_spec = AudioSpec {
+            channels: output_channels.unwrap_or(input_channels),
+            sample_rate: bus.spec.sample_rate,
+        };
+        let player_node = Node::new(
+            AudioOutputProcessor::new(output_spec)
+                .with_peak_meter(peak_meter)
+                .with_true_peak_meter(true_peak_meter)
+                .with_backend(backend)
+                .with_buffer(buffer),
+        );
+        player_node.send_control_message(AudioOutputProcessorControlMessage::ConnectBus {
+            fade: Some(fade),
+            bus,
+            id: input_bus,
+            shutdown_when_finished: true,
+        })?;
+        if no_limiter {
+            player_node.send_control_message(AudioOutputProcessorControlMessage::DisableLimiter)?;
+        }
+        if let Some(position) = pan {
+            player_node.send_control_message(
+                AudioOutputProcessorControlMessage::SetBusRouting {
+                    id: input_bus,
+                    routing: Routing::panned(input_channels, output_spec.channels, position),
+                },
+            )?;
+        }
+        if let Some(bypass_bus) = bypass_bus {
+            player_node.send_control_message(
+                AudioOutputProcessorControlMessage::ConnectBypassBus {
+                    id: BusId::allocate(),
+                    bus: bypass_bus,
+                },
+            )?;
+        }
+        Ok(player_node)
+    })?;
+    graph.connect(input_id, "output")?;
+    Ok(input_bus)
+}
+
+const PLAY_POLL: Duration = Duration::from_millis(500);
+/// Short enough for the dashboard's meter to move smoothly
+const TUI_POLL: Duration = Duration::from_millis(50);
+/// Each up or down key multiplies or divides the stretch factor by this
+#[cfg(feature = "tui")]
+const FACTOR_KEY_STEP: f32 = 1.25;
+const RENDER_POLL: Duration = Duration::from_millis(50);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const QUIT_FADE: Duration = Duration::from_secs(3);
+/// Clipping is summed up at most this often, so a loud passage isn't a flood of warnings
+const CLIP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+/// Time for the start trigger to reach every follower before anyone plays
+const SYNC_START_DELAY: Duration = Duration::from_millis(500);
+
+/// Ramp for gain changes from OSC or MIDI, so stepped controllers don't click
+const REMOTE_GAIN_RAMP: Duration = Duration::from_millis(50);
+
+enum PlayEvent {
+    Quit,
+    /// Reload the MIDI map and reopen the log file
+    Reload,
+    ToggleBypass,
+    #[cfg(feature = "tui")]
+    Key(KeyAction),
+    /// An OSC message, or an HTTP API request with the same address
+    Osc(OscMessage),
+    Midi(Vec<MidiAction>),
+}
+
+/// A change to a playing pipeline, from whichever remote control asked for it
+#[derive(Debug)]
+enum PlayControl {
+    SetFactor(f32),
+    SetFrozen(bool),
+    SetKernelParam { name: String, value: f32 },
+    SetOutputGain { db: f32 },
+    SetBypass(bool),
+    Pause,
+    Resume,
+}
+
+impl From<MidiAction> for PlayControl {
+    fn from(action: MidiAction) -> Self {
+        match action {
+            MidiAction::SetFactor(factor) => PlayControl::SetFactor(factor),
+            MidiAction::SetKernelParam { name, value } => {
+                PlayControl::SetKernelParam { name, value }
+            }
+            MidiAction::SetOutputGain { db } => PlayControl::SetOutputGain { db },
+            MidiAction::SetFrozen(frozen) => PlayControl::SetFrozen(frozen),
+            MidiAction::SetBypass(enabled) => PlayControl::SetBypass(enabled),
+        }
+    }
+}
+
+impl PlayControl {
+    /// The control's name and value, for the journal
+    fn describe(&self) -> (&'static str, Value) {
+        match self {
+            PlayControl::SetFactor(factor) => ("factor", (*factor).into()),
+            PlayControl::SetFrozen(frozen) => ("frozen", (*frozen).into()),
+            PlayControl::SetKernelParam { name, value } => {
+                ("kernel_param", format!("{}={}", name, value).into())
+            }
+            PlayControl::SetOutputGain { db } => ("output_gain_db", (*db).into()),
+            PlayControl::SetBypass(enabled) => ("bypass", (*enabled).into()),
+            PlayControl::Pause => ("paused", true.into()),
+            PlayControl::Resume => ("paused", false.into()),
+        }
+    }
+}
+
+/// What has been set on a playing pipeline, for the HTTP status report
+struct PlayState {
+    started: Instant,
+    /// The output's bus carrying the stretched audio
+    output_bus: BusId,
+    factor: f32,
+    frozen: bool,
+    paused: bool,
+    bypass: bool,
+    output_gain_db: f32,
+}
+
+/// This instance's part in starting and stopping with others
+enum SyncRole {
+    Leader(Leader),
+    Follower(Follower),
+}
+
+/// With --sync-lead or --sync-follow, wait until it's time for every
+/// instance to start playing
+fn wait_for_sync_start(opt: &Opt) -> Result<Option<SyncRole>> {
+    if let Some(addr) = opt.sync_lead {
+        let mut leader = Leader::bind(addr)?;
+        info!(
+            "Waiting for {} followers to join on {}",
+            opt.sync_followers, addr
+        );
+        leader.wait_for_followers(opt.sync_followers);
+        let start_at = SystemTime::now() + SYNC_START_DELAY;
+        leader.send(Trigger::Start, start_at)?;
+        clock_sync::sleep_until(start_at);
+        return Ok(Some(SyncRole::Leader(leader)));
+    }
+    if let Some(addr) = opt.sync_follow {
+        let mut follower = Follower::join(addr)?;
+        info!("Waiting for the leader to start");
+        return match follower.next_trigger()? {
+            (Trigger::Start, at) => {
+                clock_sync::sleep_until(at);
+                Ok(Some(SyncRole::Follower(follower)))
+            }
+            (Trigger::Stop, _) => Err(anyhow!("the leader stopped before starting")),
+        };
+    }
+    Ok(None)
+}
+
+/// What a playing pipeline reports back, for the status API and dashboard
+struct Monitors {
+    peak_meter: PeakMeter,
+    true_peak_meter: PeakMeter,
+    stretch_progress: StretchProgress,
+    /// Only the dashboard shows the spectrum and stretcher count
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    spectrum: SpectrumTap,
+    #[cfg_attr(not(feature = "tui"), allow(dead_code))]
+    channels: u16,
+}
+
+/// Play until the output finishes or the user quits
+///
+/// The first ctrl-c fades the pipeline out over `QUIT_FADE`, a second one
+/// stops it immediately. Quitting returns an error so the exit code tells
+/// the shell, e.g. so bash loops can be broken.
+fn play(
+    pipeline: Pipeline,
+    opt: &Opt,
+    output_bus: BusId,
+    midi_mapping: Option<MidiMapping>,
+    mut sync: Option<SyncRole>,
+    monitors: Monitors,
+    journal: &mut Option<Journal>,
+) -> Result<()> {
+    let (events_tx, events_rx) = unbounded();
+    let ctrlc_tx = events_tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = ctrlc_tx.send(PlayEvent::Quit);
+    })?;
+    // Shared with the MIDI thread so a reload can swap it
+    let midi_mapping = midi_mapping.map(|mapping| Arc::new(Mutex::new(mapping)));
+    if let (Some(device), Some(mapping)) = (&opt.midi_device, &midi_mapping) {
+        let mapping = Arc::clone(mapping);
+        midi::listen_device(device, events_tx.clone(), move |message| {
+            PlayEvent::Midi(mapping.lock().unwrap().actions(&message))
+        })?;
+    }
+    if opt.daemon {
+        runtime_setup::listen_service_signals(events_tx.clone(), |signal| match signal {
+            ServiceSignal::Terminate => PlayEvent::Quit,
+            ServiceSignal::Reload => PlayEvent::Reload,
+        })?;
+    }
+    if let Some(addr) = opt.osc_listen {
+        osc::listen(addr, events_tx.clone(), |message| {
+            match message.address.as_str() {
+                "/quit" => PlayEvent::Quit,
+                _ => PlayEvent::Osc(message),
+            }
+        })?;
+    }
+    if let Some(SyncRole::Follower(mut follower)) = sync.take() {
+        let events = events_tx.clone();
+        thread::spawn(move || loop {
+            match follower.next_trigger() {
+                Ok((Trigger::Stop, at)) => {
+                    clock_sync::sleep_until(at);
+                    info!("The sync leader stopped");
+                    let _ = events.send(PlayEvent::Quit);
+                    return;
+                }
+                Ok((Trigger::Start, _)) => {}
+                Err(e) => {
+                    warn!("Stopped listening to the sync leader: {}", e);
+                    return;
+                }
+            }
+        });
+    }
+    let status = Arc::new(Mutex::new(String::new()));
+    if let Some(addr) = opt.http_listen {
+        let events = events_tx.clone();
+        let status = Arc::clone(&status);
+        http_api::serve(addr, move |request| handle_http(request, &events, &status))?;
+    }
+    #[cfg(feature = "tui")]
+    let mut tui = if opt.tui_enabled() {
+        tui::listen_keys(events_tx.clone(), |action| match action {
+            KeyAction::FadeOut => PlayEvent::Quit,
+            KeyAction::ToggleBypass => PlayEvent::ToggleBypass,
+            action => PlayEvent::Key(action),
+        });
+        Some(Tui::start()?)
+    } else {
+        None
+    };
+    #[cfg(feature = "tui")]
+    let mut spectrogram = Spectrogram::new();
+    // The dashboard has its own bypass key, and reading lines would fight it
+    // for stdin. A daemon has no one at the terminal to press Enter.
+    if opt.ab && !opt.tui_enabled() && !opt.daemon {
+        thread::spawn(move || toggle_bypass_on_enter(events_tx));
+    }
+    let poll = if opt.tui_enabled() {
+        TUI_POLL
+    } else {
+        PLAY_POLL
+    };
+    let mut state = PlayState {
+        started: Instant::now(),
+        output_bus,
+        factor: opt.factor,
+        frozen: false,
+        paused: false,
+        bypass: false,
+        output_gain_db: 0.0,
+    };
+    let mut quit_deadline = None;
+    let mut watchdog = opt.watchdog();
+    let underruns = player_processor::underrun_counter();
+    let mut clip_warnings = ClipWarnings::new();
+    loop {
+        if let Some((id, e)) = pipeline.try_recv_error() {
+            error!("{} stopped: {}", id, e);
+            pipeline.shutdown(Duration::ZERO)?;
+            return Err(anyhow!("playback failed"));
+        }
+        let output = pipeline
+            .node::<AudioOutputProcessor, AudioOutputProcessorControlMessage>("output")
+            .unwrap();
+        if output.is_finished() {
+            return pipeline.shutdown(Duration::ZERO);
+        }
+        let peak = monitors.peak_meter.take();
+        if let Some(watchdog) = &mut watchdog {
+            // Pausing is silent on purpose
+            if state.paused {
+                watchdog.reset();
+            } else {
+                watchdog.poll(peak, underruns.get());
+            }
+        }
+        let true_peak = monitors.true_peak_meter.take();
+        clip_warnings.poll(true_peak);
+        let stretch_progress = monitors.stretch_progress.fraction();
+        *status.lock().unwrap() = status_json(
+            &pipeline,
+            &state,
+            peak,
+            true_peak,
+            clip_warnings.clips.get(),
+            stretch_progress,
+        );
+        #[cfg(feature = "tui")]
+        if let Some(tui) = &mut tui {
+            if let Some(magnitudes) = monitors.spectrum.take() {
+                spectrogram.push(&magnitudes);
+            }
+            let stretching = pipeline
+                .node_states()
+                .any(|(id, running)| id == "stretcher" && running);
+            tui.draw(
+                &Dashboard {
+                    elapsed: state.started.elapsed(),
+                    factor: state.factor,
+                    active_stretchers: if stretching {
+                        monitors.channels as usize
+                    } else {
+                        0
+                    },
+                    progress: stretch_progress,
+                    output_peak: peak,
+                    frozen: state.frozen,
+                    paused: state.paused,
+                    bypass: state.bypass,
+                    output_gain_db: state.output_gain_db,
+                },
+                &spectrogram,
+            )?;
+        }
+        let event = match events_rx.recv_timeout(poll) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            // The ctrl-c handler keeps a sender for the life of the process
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        };
+        let controls = match event {
+            Some(PlayEvent::Quit) if quit_deadline.is_none() => {
+                info!("Got quit signal, fading out audio for {:#?}", QUIT_FADE);
+                if let Some(SyncRole::Leader(leader)) = &mut sync {
+                    if let Err(e) = leader.send(Trigger::Stop, SystemTime::now()) {
+                        warn!("Failed to stop the followers: {}", e);
+                    }
+                }
+                pipeline.fade_out(QUIT_FADE)?;
+                quit_deadline = Some(Instant::now() + QUIT_FADE);
+                vec![]
+            }
+            Some(PlayEvent::Quit) => {
+                // If ctrl-c was received more than once, quit without fading out
+                info!("Exiting immediately");
+                pipeline.shutdown(Duration::ZERO)?;
+                return Err(anyhow!("interrupted"));
+            }
+            Some(PlayEvent::Reload) => {
+                info!("Reloading");
+                runtime_setup::reopen_log_file();
+                if let (Some(path), Some(mapping)) = (&opt.midi_map, &midi_mapping) {
+                    match MidiMapping::from_file(path) {
+                        Ok(reloaded) => *mapping.lock().unwrap() = reloaded,
+                        Err(e) => warn!("Keeping the old MIDI map: {}", e),
+                    }
+                }
+                vec![]
+            }
+            Some(PlayEvent::ToggleBypass) => {
+                info!("Bypass {}", if state.bypass { "off" } else { "on" });
+                vec![PlayControl::SetBypass(!state.bypass)]
+            }
+            Some(PlayEvent::Osc(message)) => match osc_control(&message) {
+                Ok(control) => vec![control],
+                Err(e) => {
+                    warn!("Ignoring OSC message {}: {}", message.address, e);
+                    vec![]
+                }
+            },
+            Some(PlayEvent::Midi(actions)) => actions.into_iter().map(PlayControl::from).collect(),
+            #[cfg(feature = "tui")]
+            Some(PlayEvent::Key(action)) => key_controls(action, &state),
+            None => vec![],
+        };
+        for control in controls {
+            match apply_control(&pipeline, &control, &mut state) {
+                Ok(()) => {
+                    let (name, value) = control.describe();
+                    record(
+                        journal,
+                        "control",
+                        &[("control", name.into()), ("value", value)],
+                    );
+                }
+                Err(e) => warn!("Failed to apply {:?}: {}", control, e),
+            }
+        }
+        if matches!(quit_deadline, Some(deadline) if Instant::now() > deadline) {
+            pipeline.shutdown(Duration::ZERO)?;
+            return Err(anyhow!("interrupted"));
+        }
+    }
+}
+
+/// The controls a dashboard key press asks for, given what is set now
+#[cfg(feature = "tui")]
+fn key_controls(action: KeyAction, state: &PlayState) -> Vec<PlayControl> {
+    match action {
+        KeyAction::StretchMore => vec![PlayControl::SetFactor(state.factor * FACTOR_KEY_STEP)],
+        KeyAction::StretchLess => vec![PlayControl::SetFactor(state.factor / FACTOR_KEY_STEP)],
+        KeyAction::ToggleFreeze => vec![PlayControl::SetFrozen(!state.frozen)],
+        KeyAction::TogglePause if state.paused => vec![PlayControl::Resume],
+        KeyAction::TogglePause => vec![PlayControl::Pause],
+        // Sent as their own events instead
+        KeyAction::ToggleBypass | KeyAction::FadeOut => vec![],
+    }
+}
+
+/// Read a control from an OSC address and its first argument
+fn osc_control(message: &OscMessage) -> Result<PlayControl> {
+    let number = || {
+        message
+            .number()
+            .ok_or_else(|| anyhow!("expected a number argument"))
+    };
+    Ok(match message.address.as_str() {
+        "/stretch/factor" => PlayControl::SetFactor(number()?),
+        "/stretch/freeze" => PlayControl::SetFrozen(number()? != 0.0),
+        "/output/gain" => PlayControl::SetOutputGain { db: number()? },
+        "/output/bypass" => PlayControl::SetBypass(number()? != 0.0),
+        "/pause" => PlayControl::Pause,
+        "/resume" => PlayControl::Resume,
+        address => match address.strip_prefix("/kernel/param/") {
+            Some(name) => PlayControl::SetKernelParam {
+                name: name.to_string(),
+                value: number()?,
+            },
+            None => bail!("unknown address"),
+        },
+    })
+}
+
+/// Send a control to the playback pipeline's nodes, recording it in `state`
+fn apply_control(pipeline: &Pipeline, control: &PlayControl, state: &mut PlayState) -> Result<()> {
+    let stretcher = pipeline
+        .node::<StretcherProcessor, StretcherProcessorControlMessage>("stretcher")
+        .unwrap();
+    let output = pipeline
+        .node::<AudioOutputProcessor, AudioOutputProcessorControlMessage>("output")
         .unwrap();
+    match control {
+        PlayControl::SetFactor(factor) => {
+            stretcher.send_control_message(StretcherProcessorControlMessage::SetFactor {
+                factor: *factor,
+            })?;
+            state.factor = *factor;
+        }
+        PlayControl::SetFrozen(frozen) => {
+            stretcher.send_control_message(StretcherProcessorControlMessage::SetFrozen {
+                frozen: *frozen,
+            })?;
+            state.frozen = *frozen;
+        }
+        PlayControl::SetKernelParam { name, value } => {
+            stretcher.send_control_message(StretcherProcessorControlMessage::SetKernelParam {
+                name: name.clone(),
+                value: *value,
+            })?;
+        }
+        PlayControl::SetOutputGain { db } => {
+            output.send_control_message(AudioOutputProcessorControlMessage::SetBusGain {
+                id: state.output_bus,
+                db: *db,
+                ramp: REMOTE_GAIN_RAMP,
+            })?;
+            state.output_gain_db = *db;
+        }
+        PlayControl::SetBypass(enabled) => {
+            output.send_control_message(AudioOutputProcessorControlMessage::SetBypass {
+                enabled: *enabled,
+            })?;
+            state.bypass = *enabled;
+        }
+        PlayControl::Pause | PlayControl::Resume => {
+            let paused = matches!(control, PlayControl::Pause);
+            let (stretcher_msg, output_msg) = if paused {
+                (
+                    StretcherProcessorControlMessage::Pause,
+                    AudioOutputProcessorControlMessage::Pause,
+                )
+            } else {
+                (
+                    StretcherProcessorControlMessage::Resume,
+                    AudioOutputProcessorControlMessage::Resume,
+                )
+            };
+            stretcher.send_control_message(stretcher_msg)?;
+            output.send_control_message(output_msg)?;
+            state.paused = paused;
+        }
+    }
+    Ok(())
+}
+
+fn status_json(
+    pipeline: &Pipeline,
+    state: &PlayState,
+    peak: f32,
+    true_peak: f32,
+    clips: f64,
+    stretch_progress: Option<f32>,
+) -> String {
+    let nodes: Vec<String> = pipeline
+        .node_states()
+        .map(|(id, running)| {
+            format!(
+                "{{\"id\":{},\"running\":{}}}",
+                http_api::json_string(id),
+                running
+            )
+        })
+        .collect();
+    let level_db = |level: f32| {
+        if level > 0.0 {
+            format!("{:.1}", power::amplitude_to_decibels(level))
+        } else {
+            "null".to_string()
+        }
+    };
+    let percent_complete = match stretch_progress {
+        Some(fraction) => format!("{:.1}", fraction * 100.0),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"uptime_secs\":{:.1},\"percent_complete\":{},\"stretch_factor\":{},\"frozen\":{},\"paused\":{},\"bypass\":{},\"output_gain_db\":{},\"output_peak_dbfs\":{},\"output_true_peak_dbtp\":{},\"output_clips\":{},\"nodes\":[{}]}}",
+        state.started.elapsed().as_secs_f32(),
+        percent_complete,
+        state.factor,
+        state.frozen,
+        state.paused,
+        state.bypass,
+        state.output_gain_db,
+        level_db(peak),
+        level_db(true_peak),
+        clips,
+        nodes.join(",")
+    )
+}
+
+/// Warns when the mix clips, summing up each `CLIP_WARNING_INTERVAL`
+struct ClipWarnings {
+    clips: Counter,
+    /// The clip count as of the last warning
+    warned_clips: f64,
+    last_warning: Option<Instant>,
+    /// The highest true peak while there have been clips to warn about
+    true_peak: f32,
+}
+
+impl ClipWarnings {
+    fn new() -> ClipWarnings {
+        let clips = player_processor::clip_counter();
+        ClipWarnings {
+            warned_clips: clips.get(),
+            clips,
+            last_warning: None,
+            true_peak: 0.0,
+        }
+    }
+
+    /// Fold in the true peak since the last poll, and warn if the mix has
+    /// clipped and the last warning was long enough ago
+    fn poll(&mut self, true_peak: f32) {
+        let clips = self.clips.get();
+        if clips == self.warned_clips {
+            self.true_peak = 0.0;
+            return;
+        }
+        self.true_peak = self.true_peak.max(true_peak);
+        if let Some(last_warning) = self.last_warning {
+            if last_warning.elapsed() < CLIP_WARNING_INTERVAL {
+                return;
+            }
+        }
+        warn!(
+            "The mix went over full scale in {} frames, and the limited output peaked at {:+.1} dBTP",
+            clips - self.warned_clips,
+            power::amplitude_to_decibels(self.true_peak)
+        );
+        self.warned_clips = clips;
+        self.last_warning = Some(Instant::now());
+        self.true_peak = 0.0;
+    }
+}
+
+/// Report status on `GET /status`, and treat a `POST` as an OSC message to
+/// the same address, with the body as its number argument if there is one
+fn handle_http(request: &Request, events: &Sender<PlayEvent>, status: &Mutex<String>) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => Response::json(status.lock().unwrap().clone()),
+        ("POST", "/quit") => {
+            let _ = events.send(PlayEvent::Quit);
+            Response::text(202, "quitting")
+        }
+        ("POST", path) => {
+            let body = request.body.trim();
+            let args = match body {
+                "" => vec![],
+                _ => match body.parse() {
+                    Ok(value) => vec![OscArg::Float(value)],
+                    Err(_) => return Response::text(400, "expected a number"),
+                },
+            };
+            let message = OscMessage {
+                address: path.to_string(),
+                args,
+            };
+            if let Err(e) = osc_control(&message) {
+                return Response::text(400, &e.to_string());
+            }
+            let _ = events.send(PlayEvent::Osc(message));
+            Response::text(202, "accepted")
+        }
+        (_, "/status") => Response::text(405, "use GET"),
+        _ => Response::text(404, "not found"),
+    }
+}
+
+fn toggle_bypass_on_enter(events: Sender<PlayEvent>) {
+    println!("Press ENTER to toggle bypass");
+    let mut throwaway_input = String::new();
+    while io::stdin().read_line(&mut throwaway_input).unwrap_or(0) > 0 {
+        if events.send(PlayEvent::ToggleBypass).is_err() {
+            return;
+        }
+    }
 }
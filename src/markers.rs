@@ -0,0 +1,194 @@
+//! Labelled positions and ranges in a piece of audio
+//!
+//! Markers are kept in frames on `Audio`, and follow the audio through
+//! clipping. They can be written out as an Audacity label track, which
+//! Audacity and most other editors can import.
+
+use crate::audio::Audio;
+use crate::power;
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// How far apart onset detection measures the level
+const ONSET_HOP: Duration = Duration::from_millis(10);
+/// How much louder than the window before a window must be to count as an onset
+const ONSET_RISE_DB: f32 = 9.0;
+/// Rises that stay below this level are left out as noise
+const ONSET_FLOOR_DB: f32 = -50.0;
+/// Onsets closer together than this are counted once
+const ONSET_MIN_GAP: Duration = Duration::from_millis(100);
+
+/// A point, or a region if it has an end, in frames from the start of the audio
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub label: String,
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+impl Marker {
+    pub fn point(label: &str, position: usize) -> Marker {
+        Marker {
+            label: label.to_string(),
+            start: position,
+            end: None,
+        }
+    }
+
+    pub fn region(label: &str, start: usize, end: usize) -> Marker {
+        Marker {
+            label: label.to_string(),
+            start,
+            end: Some(end.max(start)),
+        }
+    }
+
+    /// Where this marker falls once everything before `start` and from `end`
+    /// on has been cut away, if it's still there at all
+    ///
+    /// Regions are trimmed to what's left of them.
+    pub fn clipped(&self, start: usize, end: usize) -> Option<Marker> {
+        let marker_end = self.end.unwrap_or(self.start);
+        let inside = match self.end {
+            Some(_) => self.start < end && marker_end > start,
+            None => (start..=end).contains(&self.start),
+        };
+        inside.then(|| Marker {
+            label: self.label.clone(),
+            start: self.start.max(start) - start,
+            end: self.end.map(|_| marker_end.min(end) - start),
+        })
+    }
+
+    /// This marker with its positions multiplied by `factor`, e.g. to place
+    /// it in audio stretched by that factor
+    pub fn scaled(&self, factor: f32) -> Marker {
+        let scale = |position: usize| (position as f64 * factor as f64).round() as usize;
+        Marker {
+            label: self.label.clone(),
+            start: scale(self.start),
+            end: self.end.map(scale),
+        }
+    }
+}
+
+/// Mark where notes and other sounds start in `audio`, as points labelled
+/// "onset"
+///
+/// This looks for sudden jumps in level rather than analysing the
+/// spectrum, which is enough for percussive sounds and speech but misses
+/// notes that slide or swell in.
+pub fn mark_onsets(audio: &mut Audio) {
+    let onsets: Vec<Marker> = find_onsets(audio)
+        .into_iter()
+        .map(|onset| Marker::point("onset", onset))
+        .collect();
+    info!("Found {} onsets", onsets.len());
+    audio.markers.extend(onsets);
+}
+
+/// The frames where sounds start in `audio`, found as for `mark_onsets`
+pub fn find_onsets(audio: &Audio) -> Vec<usize> {
+    let hop = audio.duration_to_sample(ONSET_HOP).max(1);
+    let min_gap = audio.duration_to_sample(ONSET_MIN_GAP);
+    let len = audio.data.first().map_or(0, |channel| channel.len());
+    let mut previous_db = f32::NEG_INFINITY;
+    let mut last_onset: Option<usize> = None;
+    let mut onsets = vec![];
+    for start in (0..len).step_by(hop) {
+        let db = audio
+            .data
+            .iter()
+            .map(|channel| power::audio_power(&channel[start..(start + hop).min(len)]))
+            .fold(f32::NEG_INFINITY, f32::max);
+        let rose = db > ONSET_FLOOR_DB && db - previous_db.max(ONSET_FLOOR_DB) >= ONSET_RISE_DB;
+        if rose && last_onset.is_none_or(|last| start - last >= min_gap) {
+            onsets.push(start);
+            last_onset = Some(start);
+        }
+        previous_db = db;
+    }
+    onsets
+}
+
+/// Format `markers` as an Audacity label track: a line per marker with its
+/// start and end in seconds and its label, separated by tabs
+pub fn audacity_labels(markers: &[Marker], sample_rate: u32) -> String {
+    let secs = |position: usize| position as f64 / sample_rate as f64;
+    let mut labels = String::new();
+    for marker in markers {
+        let start = secs(marker.start);
+        let end = marker.end.map_or(start, secs);
+        // Tabs and newlines would break the format
+        let label = marker.label.replace(['\t', '\n', '\r'], " ");
+        writeln!(labels, "{:.6}\t{:.6}\t{}", start, end, label).unwrap();
+    }
+    labels
+}
+
+/// Write `markers` to `path` as an Audacity label track
+pub fn write_audacity_labels(path: &Path, markers: &[Marker], sample_rate: u32) -> Result<()> {
+    fs::write(path, audacity_labels(markers, sample_rate))
+        .with_context(|| format!("failed to write labels to {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn markers_follow_clipping() {
+        let mut audio = generate_audio(0.0, 100, 1, 10);
+        audio.markers = vec![
+            Marker::point("before", 5),
+            Marker::point("inside", 30),
+            Marker::region("overlapping", 10, 40),
+            Marker::region("after", 90, 95),
+        ];
+        audio
+            .clip_in_place(Some(Duration::from_secs(2)), Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(
+            audio.markers,
+            vec![
+                Marker::point("inside", 10),
+                Marker::region("overlapping", 0, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn onsets_are_found_where_the_level_jumps() {
+        let mut audio = generate_audio(0.0, 44100, 2, 44100);
+        for start in [4410, 22050] {
+            for sample in audio.data[1][start..start + 2205].iter_mut() {
+                *sample = 0.5;
+            }
+        }
+        mark_onsets(&mut audio);
+        assert_eq!(
+            audio.markers,
+            vec![Marker::point("onset", 4410), Marker::point("onset", 22050)]
+        );
+    }
+
+    #[test]
+    fn labels_are_written_in_audacity_format() {
+        let markers = vec![
+            Marker::point("onset", 22050),
+            Marker::region("take\t2", 44100, 66150),
+        ];
+        assert_eq!(
+            audacity_labels(&markers, 44100),
+            "0.500000\t0.500000\tonset\n1.000000\t1.500000\ttake 2\n"
+        );
+        assert_eq!(
+            Marker::region("take", 10, 20).scaled(2.5),
+            Marker::region("take", 25, 50)
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A value that only goes up, such as a count of dropped chunks
+#[derive(Clone, Debug)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    pub fn add(&self, amount: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + amount).to_bits())
+            });
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// A value that can go up and down, such as a queue length
+#[derive(Clone)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    /// Values by their rendered labels, e.g. `{processor="stretcher"}`
+    series: BTreeMap<String, Arc<AtomicU64>>,
+}
+
+/// Named metrics that processors update as they run, for exporting
+///
+/// Registering the same name and labels twice returns the same value, so
+/// every instance of a processor adds to one series.
+#[derive(Default)]
+pub struct Registry {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(
+        &self,
+        name: &'static str,
+        labels: &[(&str, &str)],
+        help: &'static str,
+    ) -> Counter {
+        Counter(self.register(name, labels, help, Kind::Counter))
+    }
+
+    pub fn gauge(&self, name: &'static str, labels: &[(&str, &str)], help: &'static str) -> Gauge {
+        Gauge(self.register(name, labels, help, Kind::Gauge))
+    }
+
+    fn register(
+        &self,
+        name: &'static str,
+        labels: &[(&str, &str)],
+        help: &'static str,
+        kind: Kind,
+    ) -> Arc<AtomicU64> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family {
+            help,
+            kind,
+            series: BTreeMap::new(),
+        });
+        assert!(
+            family.kind == kind,
+            "metric {} registered as both a counter and a gauge",
+            name
+        );
+        Arc::clone(
+            family
+                .series
+                .entry(render_labels(labels))
+                .or_insert_with(|| Arc::new(AtomicU64::new(0f64.to_bits()))),
+        )
+    }
+
+    /// Every metric in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in self.families.lock().unwrap().iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            out.push_str(&format!("# HELP {} {}\n", name, family.help));
+            out.push_str(&format!("# TYPE {} {}\n", name, kind));
+            for (labels, value) in family.series.iter() {
+                let value = f64::from_bits(value.load(Ordering::Relaxed));
+                out.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        }
+        out
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// The process-wide registry the built-in processors report to
+pub fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(Registry::new)
+}
+
+/// Time a kind of processor spends working, summed over its instances and
+/// threads, for seeing where the CPU goes
+pub fn busy_counter(processor: &str) -> Counter {
+    registry().counter(
+        "rocoder_processor_busy_seconds_total",
+        &[("processor", processor)],
+        "Time spent processing audio",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_registered_metrics() {
+        let registry = Registry::new();
+        let stretcher = registry.counter(
+            "busy_seconds_total",
+            &[("processor", "stretcher")],
+            "Time spent processing",
+        );
+        let effect = registry.counter(
+            "busy_seconds_total",
+            &[("processor", "effect")],
+            "Time spent processing",
+        );
+        registry
+            .counter(
+                "busy_seconds_total",
+                &[("processor", "stretcher")],
+                "Time spent processing",
+            )
+            .add(1.5);
+        stretcher.inc();
+        effect.inc();
+        registry.gauge("queue_len", &[], "Queued chunks").set(3.0);
+        assert_eq!(stretcher.get(), 2.5);
+        assert_eq!(
+            registry.render_prometheus(),
+            "# HELP busy_seconds_total Time spent processing\n\
+             # TYPE busy_seconds_total counter\n\
+             busy_seconds_total{processor=\"effect\"} 1\n\
+             busy_seconds_total{processor=\"stretcher\"} 2.5\n\
+             # HELP queue_len Queued chunks\n\
+             # TYPE queue_len gauge\n\
+             queue_len 3\n"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::Sender;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8 },
+    ControlChange { controller: u8, value: u8 },
+}
+
+/// Turns a raw MIDI byte stream into messages, on any channel
+///
+/// Handles running status, and skips system exclusive, real-time and
+/// message types that can't be mapped.
+#[derive(Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: Vec<u8>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            // Real-time messages can appear anywhere, even between data bytes
+            0xf8..=0xff => None,
+            0x80..=0xef => {
+                self.status = Some(byte);
+                self.data.clear();
+                None
+            }
+            // System common and exclusive messages cancel running status
+            0xf0..=0xf7 => {
+                self.status = None;
+                None
+            }
+            _ => {
+                let status = self.status?;
+                self.data.push(byte);
+                let len = match status & 0xf0 {
+                    0xc0 | 0xd0 => 1,
+                    _ => 2,
+                };
+                if self.data.len() < len {
+                    return None;
+                }
+                let data = std::mem::take(&mut self.data);
+                match status & 0xf0 {
+                    0x90 if data[1] > 0 => Some(MidiMessage::NoteOn {
+                        note: data[0],
+                        velocity: data[1],
+                    }),
+                    0x80 | 0x90 => Some(MidiMessage::NoteOff { note: data[0] }),
+                    0xb0 => Some(MidiMessage::ControlChange {
+                        controller: data[0],
+                        value: data[1],
+                    }),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// Something a mapped MIDI message does to the running processors
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiAction {
+    SetFactor(f32),
+    SetKernelParam { name: String, value: f32 },
+    SetOutputGain { db: f32 },
+    SetFrozen(bool),
+    SetBypass(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CcTarget {
+    Factor,
+    KernelParam(String),
+    OutputGain,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NoteTarget {
+    Freeze,
+    Bypass,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum MidiRule {
+    /// Scale a controller's 0-127 range linearly onto `min..=max`
+    Cc {
+        controller: u8,
+        target: CcTarget,
+        min: f32,
+        max: f32,
+    },
+    /// Switch something on while a note is held
+    Note { note: u8, target: NoteTarget },
+}
+
+/// How MIDI messages map onto control messages, read from a config file
+///
+/// Each line is one mapping, and `#` starts a comment:
+///
+/// ```text
+/// cc 1 factor 0.5 16          # controller 1 sweeps the stretch factor
+/// cc 2 param cutoff 0 1       # a kernel parameter
+/// cc 7 gain -60 0             # output gain in dB
+/// note 36 freeze              # hold the current sound while the note is down
+/// note 37 bypass              # hear the unprocessed input while held
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiMapping {
+    rules: Vec<MidiRule>,
+}
+
+impl MidiMapping {
+    pub fn from_file(path: &Path) -> Result<MidiMapping> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("failed to read MIDI mapping {:?}", path))?;
+        MidiMapping::parse(&config).with_context(|| format!("invalid MIDI mapping {:?}", path))
+    }
+
+    pub fn parse(config: &str) -> Result<MidiMapping> {
+        let mut rules = vec![];
+        for (i, line) in config.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(line).with_context(|| format!("line {}: {:?}", i + 1, line))?);
+        }
+        Ok(MidiMapping { rules })
+    }
+
+    /// The actions a message triggers, in the order they were mapped
+    pub fn actions(&self, message: &MidiMessage) -> Vec<MidiAction> {
+        self.rules
+            .iter()
+            .filter_map(|rule| match (rule, message) {
+                (
+                    MidiRule::Cc {
+                        controller,
+                        target,
+                        min,
+                        max,
+                    },
+                    MidiMessage::ControlChange {
+                        controller: received,
+                        value,
+                    },
+                ) if controller == received => {
+                    let value = min + (max - min) * *value as f32 / 127.0;
+                    Some(match target {
+                        CcTarget::Factor => MidiAction::SetFactor(value),
+                        CcTarget::KernelParam(name) => MidiAction::SetKernelParam {
+                            name: name.clone(),
+                            value,
+                        },
+                        CcTarget::OutputGain => MidiAction::SetOutputGain { db: value },
+                    })
+                }
+                (MidiRule::Note { note, target }, MidiMessage::NoteOn { note: received, .. })
+                | (MidiRule::Note { note, target }, MidiMessage::NoteOff { note: received })
+                    if note == received =>
+                {
+                    let held = matches!(message, MidiMessage::NoteOn { .. });
+                    Some(match target {
+                        NoteTarget::Freeze => MidiAction::SetFrozen(held),
+                        NoteTarget::Bypass => MidiAction::SetBypass(held),
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn parse_rule(line: &str) -> Result<MidiRule> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let number = |i: usize| -> Result<u8> {
+        let word = words.get(i).ok_or_else(|| anyhow!("missing MIDI number"))?;
+        match word.parse::<u8>() {
+            Ok(n) if n < 128 => Ok(n),
+            _ => bail!("invalid MIDI number {:?}", word),
+        }
+    };
+    let float = |i: usize| -> Result<f32> {
+        let word = words.get(i).ok_or_else(|| anyhow!("missing range"))?;
+        word.parse::<f32>()
+            .map_err(|_| anyhow!("invalid number {:?}", word))
+    };
+    match words.as_slice() {
+        ["cc", _, "factor", _, _] => {
+            let (min, max) = (float(3)?, float(4)?);
+            // Below 1 compresses, but the factor can't reach 0
+            if !(min > 0.0 && max > 0.0) {
+                bail!("the factor range must be above 0, got {} to {}", min, max);
+            }
+            Ok(MidiRule::Cc {
+                controller: number(1)?,
+                target: CcTarget::Factor,
+                min,
+                max,
+            })
+        }
+        ["cc", _, "param", name, _, _] => Ok(MidiRule::Cc {
+            controller: number(1)?,
+            target: CcTarget::KernelParam(name.to_string()),
+            min: float(4)?,
+            max: float(5)?,
+        }),
+        ["cc", _, "gain", _, _] => Ok(MidiRule::Cc {
+            controller: number(1)?,
+            target: CcTarget::OutputGain,
+            min: float(3)?,
+            max: float(4)?,
+        }),
+        ["note", _, "freeze"] => Ok(MidiRule::Note {
+            note: number(1)?,
+            target: NoteTarget::Freeze,
+        }),
+        ["note", _, "bypass"] => Ok(MidiRule::Note {
+            note: number(1)?,
+            target: NoteTarget::Bypass,
+        }),
+        _ => bail!(
+            "expected one of: cc <n> factor <min> <max>, cc <n> param <name> <min> <max>, \
+             cc <n> gain <min> <max>, note <n> freeze, note <n> bypass"
+        ),
+    }
+}
+
+/// Read raw MIDI from a device file, such as an ALSA `/dev/snd/midiC1D0`,
+/// sending each message to `messages`
+pub fn listen_device<T, F>(path: &Path, messages: Sender<T>, wrap: F) -> Result<()>
+where
+    T: Send + 'static,
+    F: Fn(MidiMessage) -> T + Send + 'static,
+{
+    let mut device =
+        fs::File::open(path).with_context(|| format!("failed to open MIDI device {:?}", path))?;
+    info!("Reading MIDI from {:?}", path);
+    thread::spawn(move || {
+        let mut parser = MidiParser::new();
+        let mut buf = [0; 256];
+        loop {
+            let len = match device.read(&mut buf) {
+                Ok(0) => {
+                    warn!("MIDI device closed");
+                    return;
+                }
+                Ok(len) => len,
+                Err(e) => {
+                    error!("MIDI input stopped: {}", e);
+                    return;
+                }
+            };
+            for byte in &buf[..len] {
+                if let Some(message) = parser.push(*byte) {
+                    if messages.send(wrap(message)).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_all(bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut parser = MidiParser::new();
+        bytes.iter().filter_map(|byte| parser.push(*byte)).collect()
+    }
+
+    #[test]
+    fn parses_running_status_and_skips_other_messages() {
+        assert_eq!(
+            parse_all(&[0xb3, 1, 64, 2, 127, 0xf8, 0x90, 36, 100, 36, 0, 0xc0, 5, 0x80, 37, 0]),
+            vec![
+                MidiMessage::ControlChange {
+                    controller: 1,
+                    value: 64
+                },
+                MidiMessage::ControlChange {
+                    controller: 2,
+                    value: 127
+                },
+                MidiMessage::NoteOn {
+                    note: 36,
+                    velocity: 100
+                },
+                MidiMessage::NoteOff { note: 36 },
+                MidiMessage::NoteOff { note: 37 },
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_messages_to_actions() {
+        let mapping = MidiMapping::parse(
+            "# performance setup\n\
+             cc 1 factor 1 9\n\
+             cc 2 param cutoff 0 1   # filter\n\
+             note 36 freeze\n",
+        )
+        .unwrap();
+        assert_eq!(
+            mapping.actions(&MidiMessage::ControlChange {
+                controller: 1,
+                value: 127
+            }),
+            vec![MidiAction::SetFactor(9.0)]
+        );
+        assert_eq!(
+            mapping.actions(&MidiMessage::ControlChange {
+                controller: 2,
+                value: 0
+            }),
+            vec![MidiAction::SetKernelParam {
+                name: "cutoff".to_string(),
+                value: 0.0
+            }]
+        );
+        assert_eq!(
+            mapping.actions(&MidiMessage::NoteOn {
+                note: 36,
+                velocity: 1
+            }),
+            vec![MidiAction::SetFrozen(true)]
+        );
+        assert_eq!(
+            mapping.actions(&MidiMessage::NoteOff { note: 36 }),
+            vec![MidiAction::SetFrozen(false)]
+        );
+        assert!(mapping
+            .actions(&MidiMessage::NoteOff { note: 37 })
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        assert!(MidiMapping::parse("cc 128 factor 1 2").is_err());
+        assert!(MidiMapping::parse("cc 1 factor 1").is_err());
+        assert!(MidiMapping::parse("cc 1 factor 0 2").is_err());
+        assert!(MidiMapping::parse("cc 1 factor 0.25 -1").is_err());
+        assert!(MidiMapping::parse("cc 1 factor 0.25 1").is_ok());
+        assert!(MidiMapping::parse("note 1 capture").is_err());
+    }
+}
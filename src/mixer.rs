@@ -1,14 +1,21 @@
-use crate::audio::{Audio, AudioBus, AudioSpec};
+use crate::audio::{Audio, AudioBus, AudioSpec, Timestamp};
+use crate::limiter::Limiter;
 use crate::math;
+use crate::metrics::{self, Counter, Gauge};
+use crate::power::{self, TruePeakDetector};
+use crate::routing::Routing;
 use crate::slices;
+use crate::smoothing::SmoothedParam;
 use anyhow::{bail, Result};
 use std::cmp::{Ord, Ordering};
-use std::collections::HashMap;
-use std::sync::atomic::{self, AtomicBool};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{self, AtomicBool, AtomicU32};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 const STATUS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Short enough to feel instant, long enough not to click
+const MUTE_RAMP: Duration = Duration::from_millis(10);
 
 #[derive(Debug, Copy, Clone)]
 pub struct Keyframe {
@@ -43,19 +50,47 @@ struct Layer {
     buffer: Audio,
     buffer_pos: usize,
     shutdown_when_finished: bool,
+    /// Only audible while the mixer is in bypass mode, when it replaces all other layers
+    bypass_source: bool,
+    routing: Routing,
+    /// Order in which the layer was connected, used to duck older layers
+    seq: u64,
+    duck: SmoothedParam,
+    gain: SmoothedParam,
+    /// Samples left before the layer is dropped, once a disconnect is requested
+    disconnect_countdown: Option<usize>,
     last_status_report_instant: Instant,
+    /// Timestamps for frames that haven't been mixed yet
+    timestamps: VecDeque<Timestamp>,
+    /// How long the last timestamped frame took to get from its source to
+    /// the mix
+    latency: Option<Duration>,
+    latency_gauge: Gauge,
 }
 
 impl Layer {
     fn new(bus: AudioBus, shutdown_when_finished: bool) -> Self {
         Layer {
             buffer: Audio::from_spec(&bus.spec),
+            routing: Routing::direct(bus.spec.channels, bus.spec.channels),
             bus,
             shutdown_when_finished,
+            bypass_source: false,
+            seq: 0,
+            duck: SmoothedParam::new(1.0),
+            gain: SmoothedParam::new(1.0),
+            disconnect_countdown: None,
             amp_keyframes: vec![],
             total_samples_played: 0,
             buffer_pos: 0,
             last_status_report_instant: Instant::now(),
+            timestamps: VecDeque::new(),
+            latency: None,
+            latency_gauge: metrics::registry().gauge(
+                "rocoder_mix_latency_seconds",
+                &[],
+                "How long the latest timestamped frame took from its source to the output mix",
+            ),
         }
     }
 
@@ -63,6 +98,11 @@ impl Layer {
         self.prune_keyframes();
         self.log_status();
         let mut chunk = self.bus.collect_chunk()?;
+        // An empty chunk has nothing to mix, and would leave no sample to read
+        while chunk.is_empty() {
+            chunk = self.bus.collect_chunk()?;
+        }
+        self.measure_latency(chunk.data[0].len());
         for index in 0..chunk.data[0].len() {
             let amp = self.current_amp();
             for channel in chunk.data.iter_mut() {
@@ -75,6 +115,34 @@ impl Layer {
         Ok(())
     }
 
+    /// Note the latency of any timestamped frame in the `len` frames about
+    /// to be mixed
+    fn measure_latency(&mut self, len: usize) {
+        self.timestamps.extend(self.bus.take_timestamps());
+        let start = self.total_samples_played as u64;
+        while let Some(timestamp) = self.timestamps.front() {
+            if timestamp.sample >= start + len as u64 {
+                break;
+            }
+            if timestamp.sample >= start {
+                let latency = timestamp.at.elapsed();
+                self.latency_gauge.set(latency.as_secs_f64());
+                self.latency = Some(latency);
+            }
+            self.timestamps.pop_front();
+        }
+    }
+
+    fn latency_status(&self) -> String {
+        match self.latency {
+            Some(latency) => format!(
+                ", {:.1} ms behind its source",
+                latency.as_secs_f32() * 1000.0
+            ),
+            None => String::new(),
+        }
+    }
+
     fn log_status(&mut self) {
         if self.last_status_report_instant.elapsed() < STATUS_REPORT_INTERVAL {
             return;
@@ -91,14 +159,15 @@ impl Layer {
                     ((played_dur.as_secs_f32() / total_dur.as_secs_f32()) * 100.0) as u16;
                 // TODO make this include how long left in minutes & seconds
                 info!(
-                    "Played {}s of {}s ~ {}%",
+                    "Played {}s of {}s ~ {}%{}",
                     played_dur.as_secs(),
                     total_dur.as_secs(),
-                    percent_played
+                    percent_played,
+                    self.latency_status()
                 );
             }
             None => {
-                info!("Played {}s", played_dur.as_secs());
+                info!("Played {}s{}", played_dur.as_secs(), self.latency_status());
             }
         }
         self.last_status_report_instant = Instant::now();
@@ -190,10 +259,47 @@ impl Layer {
     }
 }
 
+/// The highest absolute sample level in the mix since it was last read,
+/// shared between the audio callback and whoever is monitoring it
+#[derive(Debug, Clone, Default)]
+pub struct PeakMeter(Arc<AtomicU32>);
+
+impl PeakMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record(&self, level: f32) {
+        // Non-negative floats order the same as their bits
+        self.0
+            .fetch_max(level.abs().to_bits(), atomic::Ordering::Relaxed);
+    }
+
+    /// Read the peak level and start measuring again
+    pub fn take(&self) -> f32 {
+        f32::from_bits(self.0.swap(0, atomic::Ordering::Relaxed))
+    }
+}
+
 pub struct Mixer {
     pub spec: AudioSpec,
     pub finished_flag: Arc<AtomicBool>,
     layers: HashMap<u32, Layer>,
+    /// Per-output-channel multiplier; -1.0 for inverted polarity
+    channel_polarities: Vec<f32>,
+    bypass: bool,
+    /// Amplitude applied to a layer for each layer connected after it
+    duck_amp: f32,
+    duck_ramp: Duration,
+    next_layer_seq: u64,
+    limiter: Option<Limiter>,
+    /// While paused the mixer outputs silence and holds every layer where it is
+    paused: bool,
+    peak_meter: Option<PeakMeter>,
+    /// Measures the final mix between samples, after the limiter
+    true_peak: Option<(PeakMeter, TruePeakDetector)>,
+    /// Counts frames where the mix went over full scale before the limiter
+    clip_counter: Option<Counter>,
 }
 
 impl Mixer {
@@ -202,18 +308,39 @@ impl Mixer {
             finished_flag: Arc::new(AtomicBool::from(false)),
             spec: *spec,
             layers: HashMap::new(),
+            channel_polarities: vec![1.0; spec.channels as usize],
+            bypass: false,
+            duck_amp: 1.0,
+            duck_ramp: Duration::from_secs(0),
+            next_layer_seq: 0,
+            limiter: None,
+            paused: false,
+            peak_meter: None,
+            true_peak: None,
+            clip_counter: None,
         }
     }
 
     pub fn fill_buffer(&mut self, out_buf: &mut [f32]) {
         slices::zero_slice(out_buf);
+        if self.paused {
+            return;
+        }
         for buffer_interleaved_samples in out_buf.chunks_mut(self.spec.channels as usize) {
             // loop body covers 1 sample across all layers & channels
             let mut closed_layer_ids: Vec<u32> = Vec::with_capacity(0);
             for (layer_id, layer) in self.layers.iter_mut() {
-                if layer.buffer_pos >= layer.buffer.data[0].len() {
+                let disconnected = match layer.disconnect_countdown.as_mut() {
+                    Some(0) => true,
+                    Some(remaining) => {
+                        *remaining -= 1;
+                        false
+                    }
+                    None => false,
+                };
+                if disconnected || layer.buffer_pos >= layer.buffer.data[0].len() {
                     // sets layer.buffer_pos = 0
-                    if layer.load_next_chunk().is_err() {
+                    if disconnected || layer.load_next_chunk().is_err() {
                         if layer.shutdown_when_finished {
                             info!("Layer finished and requested mixer shutdown; setting flag.");
                             self.finished_flag.store(true, atomic::Ordering::Relaxed);
@@ -222,32 +349,210 @@ impl Mixer {
                         continue;
                     };
                 }
-                for (channel_idx, out_sample_channel) in
-                    buffer_interleaved_samples.iter_mut().enumerate()
-                {
-                    *out_sample_channel += layer.buffer.data[channel_idx][layer.buffer_pos];
+                // Layers on the inactive side of the bypass keep being consumed so
+                // they stay in sync, they just aren't heard.
+                let amp = layer.duck.next_value() * layer.gain.next_value();
+                if layer.bypass_source == self.bypass {
+                    layer.routing.mix_frame(
+                        |channel_idx| layer.buffer.data[channel_idx][layer.buffer_pos] * amp,
+                        buffer_interleaved_samples,
+                    );
                 }
                 layer.buffer_pos += 1;
             }
+            for (out_sample_channel, polarity) in buffer_interleaved_samples
+                .iter_mut()
+                .zip(&self.channel_polarities)
+            {
+                *out_sample_channel *= polarity;
+            }
+            if let Some(counter) = &self.clip_counter {
+                if buffer_interleaved_samples.iter().any(|s| s.abs() > 1.0) {
+                    counter.inc();
+                }
+            }
+            if let Some(limiter) = self.limiter.as_mut() {
+                limiter.process_frame(buffer_interleaved_samples);
+            }
+            if let Some(meter) = &self.peak_meter {
+                for sample in buffer_interleaved_samples.iter() {
+                    meter.record(*sample);
+                }
+            }
+            if let Some((meter, detector)) = self.true_peak.as_mut() {
+                meter.record(detector.process_frame(buffer_interleaved_samples));
+            }
             if !closed_layer_ids.is_empty() {
                 for layer_id in closed_layer_ids.into_iter() {
                     self.layers.remove(&layer_id);
                 }
+                self.update_duck_targets();
             }
         }
     }
 
+    /// Whether any bus is connected, including bypass buses
+    pub fn has_layers(&self) -> bool {
+        !self.layers.is_empty()
+    }
+
     pub fn insert_layer(
         &mut self,
         id: u32,
         bus: AudioBus,
         shutdown_when_finished: bool,
     ) -> Result<()> {
-        let layer = Layer::new(bus, shutdown_when_finished);
+        let mut layer = Layer::new(bus, shutdown_when_finished);
+        layer.routing = Routing::direct(layer.bus.spec.channels, self.spec.channels);
+        layer.seq = self.next_layer_seq;
+        self.next_layer_seq += 1;
         self.layers.insert(id, layer);
+        self.update_duck_targets();
         Ok(())
     }
 
+    /// Attenuate every layer by `db` for each layer connected after it, ramping over `ramp`
+    ///
+    /// A `db` of 0.0 disables ducking.
+    pub fn set_ducking(&mut self, db: f32, ramp: Duration) {
+        self.duck_amp = power::decibels_to_amplitude(db);
+        self.duck_ramp = ramp;
+        self.update_duck_targets();
+    }
+
+    /// Ramp a layer's gain to `db` over `ramp`, on top of any fades and ducking
+    pub fn set_gain(&mut self, id: u32, db: f32, ramp: Duration) -> Result<()> {
+        let ramp_samples = self.dur_to_sample(ramp);
+        match self.layers.get_mut(&id) {
+            Some(layer) => {
+                layer
+                    .gain
+                    .ramp_to(power::decibels_to_amplitude(db), ramp_samples);
+                Ok(())
+            }
+            None => bail!("Layer not found"),
+        }
+    }
+
+    /// Quickly ramp a layer's gain to silence; restore it with `set_gain`
+    pub fn mute(&mut self, id: u32) -> Result<()> {
+        let ramp_samples = self.dur_to_sample(MUTE_RAMP);
+        match self.layers.get_mut(&id) {
+            Some(layer) => {
+                layer.gain.ramp_to(0.0, ramp_samples);
+                Ok(())
+            }
+            None => bail!("Layer not found"),
+        }
+    }
+
+    /// Fade a layer out over `fade` (immediately if `None`), then drop it
+    pub fn disconnect(&mut self, id: u32, fade: Option<Duration>) -> Result<()> {
+        let fade_samples = self.dur_to_sample(fade.unwrap_or_default());
+        match self.layers.get_mut(&id) {
+            Some(layer) => {
+                layer.gain.ramp_to(0.0, fade_samples);
+                layer.disconnect_countdown = Some(fade_samples);
+                Ok(())
+            }
+            None => bail!("Layer not found"),
+        }
+    }
+
+    fn dur_to_sample(&self, dur: Duration) -> usize {
+        (dur.as_secs_f32() * self.spec.sample_rate as f32) as usize
+    }
+
+    fn update_duck_targets(&mut self) {
+        let ramp_samples = self.dur_to_sample(self.duck_ramp);
+        let seqs: Vec<u64> = self
+            .layers
+            .values()
+            .filter(|layer| !layer.bypass_source)
+            .map(|layer| layer.seq)
+            .collect();
+        for layer in self.layers.values_mut() {
+            if layer.bypass_source {
+                continue;
+            }
+            let newer_layers = seqs.iter().filter(|seq| **seq > layer.seq).count();
+            layer
+                .duck
+                .ramp_to(self.duck_amp.powi(newer_layers as i32), ramp_samples);
+        }
+    }
+
+    /// Insert a layer carrying unprocessed source audio, heard only while bypass is enabled
+    pub fn insert_bypass_layer(&mut self, id: u32, bus: AudioBus) -> Result<()> {
+        let mut layer = Layer::new(bus, false);
+        layer.routing = Routing::direct(layer.bus.spec.channels, self.spec.channels);
+        layer.bypass_source = true;
+        self.layers.insert(id, layer);
+        Ok(())
+    }
+
+    pub fn set_routing(&mut self, id: u32, routing: Routing) -> Result<()> {
+        if routing.output_channels != self.spec.channels {
+            bail!(
+                "Routing has {} output channels but mixer has {}",
+                routing.output_channels,
+                self.spec.channels
+            );
+        }
+        match self.layers.get_mut(&id) {
+            Some(layer) if layer.bus.spec.channels != routing.input_channels => bail!(
+                "Routing has {} input channels but layer has {}",
+                routing.input_channels,
+                layer.bus.spec.channels
+            ),
+            Some(layer) => {
+                layer.routing = routing;
+                Ok(())
+            }
+            None => bail!("Layer not found"),
+        }
+    }
+
+    /// Set or remove the limiter applied to the final mix
+    pub fn set_limiter(&mut self, limiter: Option<Limiter>) {
+        self.limiter = limiter;
+    }
+
+    /// Switch between playing bypass layers only and playing all other layers
+    pub fn set_bypass(&mut self, enabled: bool) {
+        self.bypass = enabled;
+    }
+
+    /// Measure the peak level of the final mix with `meter`
+    pub fn set_peak_meter(&mut self, meter: Option<PeakMeter>) {
+        self.peak_meter = meter;
+    }
+
+    /// Measure the true peak of the final mix with `meter`
+    pub fn set_true_peak_meter(&mut self, meter: Option<PeakMeter>) {
+        self.true_peak = meter.map(|meter| (meter, TruePeakDetector::new(self.spec.channels)));
+    }
+
+    /// Count the frames where the mix goes over full scale before the
+    /// limiter, which would clip if it weren't for the limiter
+    pub fn set_clip_counter(&mut self, counter: Option<Counter>) {
+        self.clip_counter = counter;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn set_channel_polarity(&mut self, channel: usize, inverted: bool) -> Result<()> {
+        match self.channel_polarities.get_mut(channel) {
+            Some(polarity) => {
+                *polarity = if inverted { -1.0 } else { 1.0 };
+                Ok(())
+            }
+            None => bail!("Channel {} not found", channel),
+        }
+    }
+
     pub fn fade_out_all_layers(&mut self, dur: Duration) {
         for layer in self.layers.values_mut() {
             layer.fade_from_now(0.0, dur);
@@ -293,8 +598,29 @@ impl Mixer {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::audio::BusPolicy;
     use crate::test_utils::*;
     use crossbeam_channel::unbounded;
+    use proptest::prelude::*;
+
+    #[test]
+    fn layers_measure_latency_from_timestamps() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 44100,
+        };
+        let (bus, mut sender) = AudioBus::bounded(spec, None, BusPolicy::UNBOUNDED);
+        let at = Instant::now() - Duration::from_millis(50);
+        sender.send_timestamp(Timestamp { sample: 6, at });
+        sender.send_frame(vec![vec![1.0; 4]]);
+        sender.send_frame(vec![vec![1.0; 4]]);
+        let mut layer = Layer::new(bus, false);
+        layer.load_next_chunk().unwrap();
+        assert!(layer.latency.is_none());
+        layer.load_next_chunk().unwrap();
+        assert!(layer.latency.unwrap() >= Duration::from_millis(50));
+        assert!(layer.timestamps.is_empty());
+    }
 
     #[test]
     fn prune_keyframes() {
@@ -351,6 +677,216 @@ mod test {
         assert_almost_eq(layer.amp_keyframes[1].val, 0.5);
     }
 
+    #[test]
+    fn fill_buffer_with_inverted_channel_polarity() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let mut mixer = Mixer::new(&spec);
+        let audio = generate_audio(0.5, 2, 2, 44100);
+        mixer
+            .insert_layer(0, AudioBus::from_audio(audio), false)
+            .unwrap();
+        mixer.set_channel_polarity(1, true).unwrap();
+        let mut out = vec![0.0; 4];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.5, -0.5, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn fill_buffer_with_bypass() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 44100,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(0.5, 4, 1, 44100)),
+                false,
+            )
+            .unwrap();
+        mixer
+            .insert_bypass_layer(1, AudioBus::from_audio(generate_audio(0.25, 4, 1, 44100)))
+            .unwrap();
+        let mut out = vec![0.0; 2];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out.clone(), vec![0.5, 0.5]);
+        mixer.set_bypass(true);
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.25, 0.25]);
+        // the silent layer keeps pace with the audible one
+        assert_eq!(mixer.layers[&0].buffer_pos, 4);
+        assert_eq!(mixer.layers[&1].buffer_pos, 4);
+    }
+
+    #[test]
+    fn fill_buffer_routes_layer_onto_more_channels() {
+        let spec = AudioSpec {
+            channels: 4,
+            sample_rate: 44100,
+        };
+        let mut mixer = Mixer::new(&spec);
+        let mut audio = generate_audio(0.5, 1, 2, 44100);
+        audio.data[1][0] = 0.25;
+        mixer
+            .insert_layer(0, AudioBus::from_audio(audio), false)
+            .unwrap();
+        mixer.set_routing(0, Routing::panned(2, 4, 2.0)).unwrap();
+        let mut out = vec![0.0; 4];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.0, 0.0, 0.5, 0.25]);
+    }
+
+    #[test]
+    fn set_routing_with_mismatched_channels_fails() {
+        let mut mixer = Mixer::new(&AudioSpec {
+            channels: 4,
+            sample_rate: 44100,
+        });
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(0.5, 1, 2, 44100)),
+                false,
+            )
+            .unwrap();
+        assert!(mixer.set_routing(0, Routing::direct(2, 2)).is_err());
+        assert!(mixer.set_routing(0, Routing::direct(1, 4)).is_err());
+    }
+
+    #[test]
+    fn fill_buffer_ducks_older_layers_until_newer_ones_finish() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 44100,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer.set_ducking(power::amplitude_to_decibels(0.5), Duration::from_secs(0));
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(0.5, 4, 1, 44100)),
+                false,
+            )
+            .unwrap();
+        mixer
+            .insert_layer(
+                1,
+                AudioBus::from_audio(generate_audio(0.25, 1, 1, 44100)),
+                false,
+            )
+            .unwrap();
+        let mut out = vec![0.0; 4];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.5, 0.25, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn fill_buffer_with_limiter() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 44100,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer.set_limiter(Some(Limiter::new(0.0, Duration::from_secs(1), 44100)));
+        for id in 0..3 {
+            mixer
+                .insert_layer(
+                    id,
+                    AudioBus::from_audio(generate_audio(0.5, 1, 1, 44100)),
+                    false,
+                )
+                .unwrap();
+        }
+        let mut out = vec![0.0; 1];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![1.0]);
+    }
+
+    #[test]
+    fn fill_buffer_with_layer_gain_and_mute() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(0.5, 20, 1, 1000)),
+                false,
+            )
+            .unwrap();
+        mixer
+            .set_gain(0, power::amplitude_to_decibels(0.5), Duration::from_secs(0))
+            .unwrap();
+        let mut out = vec![0.0; 1];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out.clone(), vec![0.25]);
+        mixer.mute(0).unwrap();
+        let mut out = vec![0.0; 19];
+        mixer.fill_buffer(&mut out);
+        assert!(out[0] > 0.0);
+        assert_almost_eq(out[18], 0.0);
+        assert!(mixer.set_gain(1, 0.0, Duration::from_secs(0)).is_err());
+    }
+
+    #[test]
+    fn disconnect_fades_then_drops_layer() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(1.0, 10, 1, 1000)),
+                true,
+            )
+            .unwrap();
+        mixer.disconnect(0, Some(Duration::from_millis(4))).unwrap();
+        let mut out = vec![0.0; 6];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+        assert!(mixer.layers.is_empty());
+        assert!(mixer.finished_flag.load(atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn disconnect_without_fade_drops_layer_immediately() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer
+            .insert_layer(
+                0,
+                AudioBus::from_audio(generate_audio(1.0, 10, 1, 1000)),
+                false,
+            )
+            .unwrap();
+        mixer.disconnect(0, None).unwrap();
+        let mut out = vec![0.0; 2];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.0, 0.0]);
+        assert!(mixer.layers.is_empty());
+        assert!(mixer.disconnect(0, None).is_err());
+    }
+
+    #[test]
+    fn set_channel_polarity_on_missing_channel_fails() {
+        let mut mixer = Mixer::new(&AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        });
+        assert!(mixer.set_channel_polarity(2, true).is_err());
+    }
+
     fn basic_layer() -> Layer {
         let (_, rx) = unbounded();
         let spec = AudioSpec {
@@ -361,6 +897,7 @@ mod test {
             spec,
             channels: vec![rx],
             expected_total_samples: None,
+            timestamps: None,
         };
         Layer::new(bus, false)
     }
@@ -371,4 +908,149 @@ mod test {
             val: 1.0,
         }
     }
+
+    #[test]
+    fn fill_buffer_holds_layers_while_paused() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let mut audio = Audio::from_spec(&spec);
+        audio.data[0] = vec![1.0, 2.0, 3.0, 4.0];
+        let mut mixer = Mixer::new(&spec);
+        mixer
+            .insert_layer(0, AudioBus::from_audio(audio), true)
+            .unwrap();
+        let mut out = vec![0.0; 2];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![1.0, 2.0]);
+        mixer.set_paused(true);
+        let mut out = vec![0.0; 3];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![0.0; 3]);
+        mixer.set_paused(false);
+        let mut out = vec![0.0; 2];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq_by_element(out, vec![3.0, 4.0]);
+    }
+
+    #[test]
+    fn peak_meter_reads_the_final_mix() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 1000,
+        };
+        let mut mixer = Mixer::new(&spec);
+        let meter = PeakMeter::new();
+        mixer.set_peak_meter(Some(meter.clone()));
+        let mut audio = Audio::from_spec(&spec);
+        audio.data[0] = vec![0.25, -0.75, 0.5];
+        mixer
+            .insert_layer(0, AudioBus::from_audio(audio), false)
+            .unwrap();
+        let mut out = vec![0.0; 3];
+        mixer.fill_buffer(&mut out);
+        assert_almost_eq(meter.take(), 0.75);
+        assert_almost_eq(meter.take(), 0.0);
+    }
+
+    #[test]
+    fn clips_are_counted_before_the_limiter() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 1000,
+        };
+        let mut mixer = Mixer::new(&spec);
+        mixer.set_limiter(Some(Limiter::new(0.0, Duration::from_millis(10), 1000)));
+        let counter = metrics::Registry::new().counter("clips", &[], "Clips");
+        mixer.set_clip_counter(Some(counter.clone()));
+        let true_peak = PeakMeter::new();
+        mixer.set_true_peak_meter(Some(true_peak.clone()));
+        let mut audio = Audio::from_spec(&spec);
+        audio.data[0] = vec![0.5, 1.5, 0.5, -2.0];
+        audio.data[1] = vec![0.5, 0.5, 1.0, 0.5];
+        // Silence after, for the true peak estimate to catch up
+        for channel in audio.data.iter_mut() {
+            channel.resize(12, 0.0);
+        }
+        mixer
+            .insert_layer(0, AudioBus::from_audio(audio), false)
+            .unwrap();
+        let mut out = vec![0.0; 24];
+        mixer.fill_buffer(&mut out);
+        assert_eq!(counter.get(), 2.0);
+        assert!(out.iter().all(|sample| sample.abs() <= 1.0));
+        assert!(true_peak.take() > 0.5);
+    }
+
+    /// Up to four layers of finite audio, each with `channels` channels
+    fn arbitrary_layers(channels: u16) -> impl Strategy<Value = Vec<Audio>> {
+        let layer = (0usize..64).prop_flat_map(move |len| {
+            prop::collection::vec(prop::collection::vec(-1.0f32..1.0, len), channels as usize)
+                .prop_map(move |data| Audio {
+                    data,
+                    spec: AudioSpec {
+                        channels,
+                        sample_rate: 8000,
+                    },
+                    markers: vec![],
+                })
+        });
+        prop::collection::vec(layer, 1..5)
+    }
+
+    fn mix(spec: AudioSpec, layers: &[Audio], frames: usize) -> Vec<f32> {
+        let mut mixer = Mixer::new(&spec);
+        for (id, layer) in layers.iter().enumerate() {
+            mixer
+                .insert_layer(id as u32, AudioBus::from_audio(layer.clone()), false)
+                .unwrap();
+        }
+        let mut out = vec![f32::NAN; frames * spec.channels as usize];
+        mixer.fill_buffer(&mut out);
+        out
+    }
+
+    proptest! {
+        #[test]
+        fn fill_buffer_sums_layers_frame_by_frame(
+            (channels, layers) in (1u16..5).prop_flat_map(|channels| {
+                (Just(channels), arbitrary_layers(channels))
+            }),
+            frames in 0usize..96,
+        ) {
+            let spec = AudioSpec {
+                channels,
+                sample_rate: 8000,
+            };
+            let out = mix(spec, &layers, frames);
+            prop_assert_eq!(out.len(), frames * channels as usize);
+            prop_assert!(out.iter().all(|sample| sample.is_finite()));
+            for (frame, samples) in out.chunks(channels as usize).enumerate() {
+                for (channel, sample) in samples.iter().enumerate() {
+                    // Layers that have run out add nothing
+                    let expected: f32 = layers
+                        .iter()
+                        .filter_map(|layer| layer.data[channel].get(frame))
+                        .sum();
+                    prop_assert!((sample - expected).abs() < 1e-5);
+                }
+            }
+        }
+
+        #[test]
+        fn a_single_layer_at_unity_gain_passes_through(
+            (channels, layers) in (1u16..5).prop_flat_map(|channels| {
+                (Just(channels), arbitrary_layers(channels))
+            }),
+        ) {
+            let spec = AudioSpec {
+                channels,
+                sample_rate: 8000,
+            };
+            let layer = &layers[0];
+            let out = mix(spec, std::slice::from_ref(layer), layer.len());
+            prop_assert_eq!(out, layer.interleaved());
+        }
+    }
 }
@@ -0,0 +1,304 @@
+use crate::audio::{AudioBus, AudioSpec, BusPolicy, BusSender};
+use crate::power;
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::smoothing::SmoothedParam;
+use anyhow::{bail, Result};
+use crossbeam_channel::{unbounded, Receiver, Select, Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum MixerProcessorControlMessage {
+    Shutdown,
+    /// Ramp one input, by its index in the inputs given to `new`, to a gain in dB
+    SetInputGain {
+        input: usize,
+        db: f32,
+        ramp: Duration,
+    },
+    /// Ramp the gain of the whole mix to `db`
+    SetMasterGain {
+        db: f32,
+        ramp: Duration,
+    },
+}
+
+impl ControlMessage for MixerProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        MixerProcessorControlMessage::Shutdown
+    }
+}
+
+struct MixerInput {
+    bus: AudioBus,
+    /// Samples received on each channel that haven't been mixed yet
+    queues: Vec<VecDeque<f32>>,
+    open: Vec<bool>,
+    gain: SmoothedParam,
+}
+
+impl MixerInput {
+    fn is_open(&self) -> bool {
+        self.open.iter().any(|open| *open)
+    }
+
+    /// Frames this input can contribute to the mix right now, or `None` if it
+    /// has ended and shouldn't hold the mix back
+    fn ready_frames(&self) -> Option<usize> {
+        if self.is_open() {
+            self.queues.iter().map(|queue| queue.len()).min()
+        } else {
+            None
+        }
+    }
+}
+
+/// Sums any number of buses with the same spec into one, with a gain for
+/// each input and one for the mix
+///
+/// Inputs stay aligned: the mix only advances as far as every input that
+/// is still open has audio for. Inputs that have ended contribute what
+/// they have left and then silence.
+pub struct MixerProcessor {
+    spec: AudioSpec,
+    inputs: Vec<MixerInput>,
+    master_gain: SmoothedParam,
+    output: BusSender,
+}
+
+impl MixerProcessor {
+    pub fn new(inputs: Vec<AudioBus>, policy: BusPolicy) -> Result<(MixerProcessor, AudioBus)> {
+        let spec = match inputs.first() {
+            Some(bus) => bus.spec,
+            None => bail!("a mixer needs at least one input"),
+        };
+        if let Some(bus) = inputs.iter().find(|bus| bus.spec != spec) {
+            bail!(
+                "mixer inputs must share a spec, got {:?} and {:?}",
+                spec,
+                bus.spec
+            );
+        }
+        let expected_total_samples = inputs
+            .iter()
+            .filter_map(|bus| bus.expected_total_samples)
+            .max();
+        let (output, sender) = AudioBus::bounded(spec, expected_total_samples, policy);
+        let inputs = inputs
+            .into_iter()
+            .map(|bus| MixerInput {
+                queues: vec![VecDeque::new(); bus.channels.len()],
+                open: vec![true; bus.channels.len()],
+                gain: SmoothedParam::new(1.0),
+                bus,
+            })
+            .collect();
+        Ok((
+            MixerProcessor {
+                spec,
+                inputs,
+                master_gain: SmoothedParam::new(1.0),
+                output: sender,
+            },
+            output,
+        ))
+    }
+
+    /// Block until any input channel has audio or closes, or `INPUT_POLL` passes
+    fn wait_for_input(&self) {
+        let mut select = Select::new();
+        for input in &self.inputs {
+            for (channel, open) in input.bus.channels.iter().zip(&input.open) {
+                if *open {
+                    select.recv(channel);
+                }
+            }
+        }
+        let _ = select.ready_timeout(INPUT_POLL);
+    }
+
+    fn receive(&mut self) {
+        for input in self.inputs.iter_mut() {
+            for (i, channel) in input.bus.channels.iter().enumerate() {
+                while input.open[i] {
+                    match channel.try_recv() {
+                        Ok(chunk) => input.queues[i].extend(chunk),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => input.open[i] = false,
+                    }
+                }
+            }
+            // Every input starts with the mix, so their frames line up with it
+            self.output
+                .forward_timestamps(&input.bus.take_timestamps(), 0);
+        }
+    }
+
+    /// Mix and send as many frames as every open input allows
+    fn mix(&mut self) -> bool {
+        let frames = match self
+            .inputs
+            .iter()
+            .filter_map(MixerInput::ready_frames)
+            .min()
+        {
+            Some(frames) => frames,
+            // Every input has ended, so flush whatever is left
+            None => self
+                .inputs
+                .iter()
+                .flat_map(|input| input.queues.iter().map(|queue| queue.len()))
+                .max()
+                .unwrap_or(0),
+        };
+        if frames == 0 {
+            return true;
+        }
+        let mut mixed = vec![vec![0.0; frames]; self.spec.channels as usize];
+        for input in self.inputs.iter_mut() {
+            for frame in 0..frames {
+                let amp = input.gain.next_value();
+                for (queue, out) in input.queues.iter_mut().zip(mixed.iter_mut()) {
+                    if let Some(sample) = queue.pop_front() {
+                        out[frame] += sample * amp;
+                    }
+                }
+            }
+        }
+        for frame in 0..frames {
+            let amp = self.master_gain.next_value();
+            for out in mixed.iter_mut() {
+                out[frame] *= amp;
+            }
+        }
+        self.output.send_frame(mixed)
+    }
+
+    fn is_done(&self) -> bool {
+        self.inputs
+            .iter()
+            .all(|input| !input.is_open() && input.queues.iter().all(VecDeque::is_empty))
+    }
+
+    fn ramp_samples(&self, ramp: Duration) -> usize {
+        (ramp.as_secs_f32() * self.spec.sample_rate as f32) as usize
+    }
+}
+
+impl Processor<MixerProcessorControlMessage> for MixerProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<MixerProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            while !self.is_done() {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                self.wait_for_input();
+                self.receive();
+                if !self.mix() {
+                    info!("mixer output disconnected, stopping");
+                    break;
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<MixerProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                MixerProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                MixerProcessorControlMessage::SetInputGain { input, db, ramp } => {
+                    let ramp_samples = self.ramp_samples(ramp);
+                    match self.inputs.get_mut(input) {
+                        Some(input) => input
+                            .gain
+                            .ramp_to(power::decibels_to_amplitude(db), ramp_samples),
+                        None => warn!("Mixer has no input {}", input),
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                MixerProcessorControlMessage::SetMasterGain { db, ramp } => {
+                    let ramp_samples = self.ramp_samples(ramp);
+                    self.master_gain
+                        .ramp_to(power::decibels_to_amplitude(db), ramp_samples);
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    #[test]
+    fn mixes_inputs_with_gain() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 10,
+        };
+        let (a, a_senders) = AudioBus::from_spec(spec, Some(4));
+        let (b, b_senders) = AudioBus::from_spec(spec, Some(2));
+        let (mixer, output) = MixerProcessor::new(vec![a, b], BusPolicy::UNBOUNDED).unwrap();
+        assert_eq!(output.expected_total_samples, Some(4));
+        let node = Node::new(mixer);
+        node.send_control_message(MixerProcessorControlMessage::SetInputGain {
+            input: 1,
+            db: power::amplitude_to_decibels(2.0),
+            ramp: Duration::ZERO,
+        })
+        .unwrap();
+        node.send_control_message(MixerProcessorControlMessage::SetMasterGain {
+            db: power::amplitude_to_decibels(0.5),
+            ramp: Duration::ZERO,
+        })
+        .unwrap();
+        // give the mixer time to apply both gains
+        thread::sleep(Duration::from_millis(200));
+        for sender in &a_senders {
+            sender.send(vec![1.0; 4]).unwrap();
+        }
+        for sender in &b_senders {
+            sender.send(vec![0.5; 2]).unwrap();
+        }
+        drop(a_senders);
+        drop(b_senders);
+        let mixed = output.into_audio();
+        node.join().unwrap();
+        for channel in mixed.data {
+            assert_almost_eq_by_element(channel, vec![1.0, 1.0, 0.5, 0.5]);
+        }
+    }
+
+    #[test]
+    fn inputs_must_share_a_spec() {
+        let a = AudioBus::from_audio(generate_audio(1.0, 4, 2, 10));
+        let b = AudioBus::from_audio(generate_audio(1.0, 4, 1, 10));
+        assert!(MixerProcessor::new(vec![a, b], BusPolicy::UNBOUNDED).is_err());
+        assert!(MixerProcessor::new(vec![], BusPolicy::UNBOUNDED).is_err());
+    }
+}
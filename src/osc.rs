@@ -0,0 +1,230 @@
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::Sender;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+
+/// Large enough for any packet sent over UDP
+const MAX_PACKET_LEN: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    /// The argument as a number, since controllers differ in whether they send ints or floats
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(value) => Some(*value as f32),
+            OscArg::Float(value) => Some(*value),
+            OscArg::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            OscArg::String(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    /// The first argument as a number, if there is one
+    pub fn number(&self) -> Option<f32> {
+        self.args.first().and_then(OscArg::as_f32)
+    }
+}
+
+/// Decode an OSC packet, flattening any bundles into their messages
+///
+/// Bundle time tags are ignored, so everything takes effect on arrival.
+pub fn decode(packet: &[u8]) -> Result<Vec<OscMessage>> {
+    let mut reader = Reader {
+        buf: packet,
+        pos: 0,
+    };
+    let mut messages = vec![];
+    if packet.starts_with(b"#bundle\0") {
+        reader.pos = 16; // "#bundle\0" and the time tag
+        while reader.pos < packet.len() {
+            let len = reader.i32()? as usize;
+            let element = reader.take(len)?;
+            messages.extend(decode(element)?);
+        }
+    } else {
+        messages.push(reader.message()?);
+    }
+    Ok(messages)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.saturating_add(len);
+        match self.buf.get(self.pos..end) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => bail!("OSC packet ended early"),
+        }
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A null-terminated string padded to a multiple of four bytes
+    fn string(&mut self) -> Result<String> {
+        let rest = &self.buf[self.pos.min(self.buf.len())..];
+        let len = match rest.iter().position(|byte| *byte == 0) {
+            Some(len) => len,
+            None => bail!("OSC string isn't terminated"),
+        };
+        let string = String::from_utf8(rest[..len].to_vec()).context("OSC string isn't UTF-8")?;
+        self.take((len + 4) & !3)?;
+        Ok(string)
+    }
+
+    fn message(&mut self) -> Result<OscMessage> {
+        let address = self.string()?;
+        if !address.starts_with('/') {
+            bail!("invalid OSC address {:?}", address);
+        }
+        // Very old senders may leave out the type tags when there are no arguments
+        if self.pos == self.buf.len() {
+            return Ok(OscMessage {
+                address,
+                args: vec![],
+            });
+        }
+        let type_tags = self.string()?;
+        let mut args = vec![];
+        for tag in type_tags.chars().skip(1) {
+            args.push(match tag {
+                'i' => OscArg::Int(self.i32()?),
+                'f' => OscArg::Float(f32::from_bits(self.i32()? as u32)),
+                's' => OscArg::String(self.string()?),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                _ => bail!("unsupported OSC argument type {:?}", tag),
+            });
+        }
+        Ok(OscMessage { address, args })
+    }
+}
+
+/// Listen for OSC packets on `addr`, sending each message they contain to `messages`
+///
+/// Malformed packets are logged and skipped. The listener stops once
+/// `messages` is disconnected and another packet arrives.
+pub fn listen<T, F>(addr: SocketAddr, messages: Sender<T>, wrap: F) -> Result<()>
+where
+    T: Send + 'static,
+    F: Fn(OscMessage) -> T + Send + 'static,
+{
+    let socket = UdpSocket::bind(addr).with_context(|| format!("failed to listen on {}", addr))?;
+    info!("Listening for OSC on {}", addr);
+    thread::spawn(move || {
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("OSC listener stopped: {}", e);
+                    return;
+                }
+            };
+            match decode(&buf[..len]) {
+                Ok(decoded) => {
+                    for message in decoded {
+                        if messages.send(wrap(message)).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => warn!("Ignoring OSC packet: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn padded(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn float_message(address: &str, value: f32) -> Vec<u8> {
+        let mut packet = padded(address);
+        packet.extend(padded(",f"));
+        packet.extend(value.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn decodes_messages() {
+        let messages = decode(&float_message("/stretch/factor", 2.5)).unwrap();
+        assert_eq!(
+            messages,
+            vec![OscMessage {
+                address: "/stretch/factor".to_string(),
+                args: vec![OscArg::Float(2.5)],
+            }]
+        );
+
+        let mut packet = padded("/kernel/param");
+        packet.extend(padded(",siT"));
+        packet.extend(padded("cutoff"));
+        packet.extend(3i32.to_be_bytes());
+        let message = decode(&packet).unwrap().remove(0);
+        assert_eq!(
+            message.args,
+            vec![
+                OscArg::String("cutoff".to_string()),
+                OscArg::Int(3),
+                OscArg::Bool(true)
+            ]
+        );
+        assert_eq!(message.number(), None);
+    }
+
+    #[test]
+    fn decodes_bundles() {
+        let mut packet = padded("#bundle");
+        packet.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for (address, value) in [("/a", 1.0), ("/b", 2.0)] {
+            let message = float_message(address, value);
+            packet.extend((message.len() as i32).to_be_bytes());
+            packet.extend(message);
+        }
+        let messages = decode(&packet).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].address, "/b");
+        assert_eq!(messages[1].number(), Some(2.0));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        assert!(decode(b"nope").is_err());
+        let mut truncated = float_message("/a", 1.0);
+        truncated.truncate(truncated.len() - 2);
+        assert!(decode(&truncated).is_err());
+    }
+}
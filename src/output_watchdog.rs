@@ -0,0 +1,413 @@
+//! Noticing when an installation has gone quiet, and telling someone
+//!
+//! The watchdog is polled with the output's peak level and underrun count.
+//! Once the output has been silent or underrunning for long enough, during
+//! operating hours if those are set, it logs an alert and runs a hook: a
+//! shell command, or an HTTP webhook. It alerts again when the sound comes back.
+
+use crate::http_api;
+use crate::power;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily time range, such as a gallery's opening hours, which may run past midnight
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OperatingHours {
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+fn parse_time_of_day(s: &str) -> Result<u32> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected HH:MM, got {:?}", s))?;
+    let hours: u32 = hours.parse().context("invalid hours")?;
+    let minutes: u32 = minutes.parse().context("invalid minutes")?;
+    if hours > 24 || minutes > 59 || hours * 60 + minutes > MINUTES_PER_DAY {
+        bail!("{:?} isn't a time of day", s);
+    }
+    Ok(hours * 60 + minutes)
+}
+
+impl FromStr for OperatingHours {
+    type Err = anyhow::Error;
+
+    /// Parse `HH:MM-HH:MM`, e.g. `10:00-18:00`
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected HH:MM-HH:MM, got {:?}", s))?;
+        Ok(OperatingHours {
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+        })
+    }
+}
+
+impl OperatingHours {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+/// Minutes since midnight in the local time zone
+#[cfg(unix)]
+pub fn local_minute_of_day() -> u32 {
+    // Safe because localtime_r only writes to the tm it's given
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    tm.tm_hour as u32 * 60 + tm.tm_min as u32
+}
+
+/// Minutes since midnight UTC, since the local time zone isn't looked up here
+#[cfg(not(unix))]
+pub fn local_minute_of_day() -> u32 {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_secs() / 60 % MINUTES_PER_DAY as u64) as u32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// The output has been silent for this long
+    Silent(Duration),
+    /// The output has been running out of audio for this long
+    Underrunning(Duration),
+    /// The output is playing again after an alert
+    Recovered,
+}
+
+impl Alert {
+    /// A short name for the alert, passed to hooks
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Alert::Silent(_) => "silence",
+            Alert::Underrunning(_) => "underrun",
+            Alert::Recovered => "recovered",
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::Silent(duration) => write!(f, "output has been silent for {:?}", duration),
+            Alert::Underrunning(duration) => {
+                write!(f, "output has been underrunning for {:?}", duration)
+            }
+            Alert::Recovered => write!(f, "output is playing again"),
+        }
+    }
+}
+
+/// What to run when an alert is raised
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlertHook {
+    /// Run with `sh -c`, with the alert in `ROCODER_ALERT` and `ROCODER_ALERT_MESSAGE`
+    Command(String),
+    /// POST the alert as JSON to a plain HTTP URL
+    Webhook { host: String, path: String },
+}
+
+impl FromStr for AlertHook {
+    type Err = anyhow::Error;
+
+    /// Parse an `http://` URL as a webhook, and anything else as a shell command
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("https://") {
+            bail!("webhooks must be plain http://; use a command such as curl for https");
+        }
+        match s.strip_prefix("http://") {
+            Some(rest) => {
+                let (host, path) = match rest.find('/') {
+                    Some(slash) => (&rest[..slash], &rest[slash..]),
+                    None => (rest, "/"),
+                };
+                if host.is_empty() {
+                    bail!("webhook URL {:?} has no host", s);
+                }
+                let host = if host.contains(':') {
+                    host.to_string()
+                } else {
+                    format!("{}:80", host)
+                };
+                Ok(AlertHook::Webhook {
+                    host,
+                    path: path.to_string(),
+                })
+            }
+            None if s.trim().is_empty() => bail!("the alert hook is empty"),
+            None => Ok(AlertHook::Command(s.to_string())),
+        }
+    }
+}
+
+impl AlertHook {
+    /// Run the hook, waiting for the command to exit or the webhook to answer
+    pub fn fire(&self, alert: &Alert) -> Result<()> {
+        match self {
+            AlertHook::Command(command) => {
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .env("ROCODER_ALERT", alert.kind())
+                    .env("ROCODER_ALERT_MESSAGE", alert.to_string())
+                    .status()
+                    .with_context(|| format!("failed to run {:?}", command))?;
+                if !status.success() {
+                    bail!("{:?} exited with {}", command, status);
+                }
+            }
+            AlertHook::Webhook { host, path } => {
+                let addr = host
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("{} didn't resolve to an address", host))?;
+                let mut stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+                stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+                stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+                let body = format!(
+                    "{{\"alert\":{},\"message\":{}}}",
+                    http_api::json_string(alert.kind()),
+                    http_api::json_string(&alert.to_string())
+                );
+                write!(
+                    stream,
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    path,
+                    host,
+                    body.len(),
+                    body
+                )?;
+                let mut status_line = String::new();
+                BufReader::new(&stream).read_line(&mut status_line)?;
+                match status_line.split_whitespace().nth(1) {
+                    Some(status) if status.starts_with('2') => {}
+                    _ => bail!("webhook answered {:?}", status_line.trim_end()),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tracks how long the output has been in trouble, and raises alerts
+pub struct Watchdog {
+    after: Duration,
+    threshold_db: f32,
+    hours: Option<OperatingHours>,
+    hook: Option<AlertHook>,
+    /// When the current run of silence or underruns started
+    trouble_since: Option<Instant>,
+    underran: bool,
+    alerted: bool,
+    last_underruns: f64,
+}
+
+impl Watchdog {
+    /// Alert once the output has been below `threshold_db` or underrunning for `after`
+    pub fn new(after: Duration, threshold_db: f32) -> Watchdog {
+        Watchdog {
+            after,
+            threshold_db,
+            hours: None,
+            hook: None,
+            trouble_since: None,
+            underran: false,
+            alerted: false,
+            last_underruns: 0.0,
+        }
+    }
+
+    /// Only watch the output during these hours
+    pub fn with_operating_hours(mut self, hours: OperatingHours) -> Self {
+        self.hours = Some(hours);
+        self
+    }
+
+    /// Run `hook` for each alert, as well as logging it
+    pub fn with_hook(mut self, hook: AlertHook) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    /// Forget any silence so far, e.g. while the output is paused on purpose
+    pub fn reset(&mut self) {
+        self.trouble_since = None;
+        self.underran = false;
+        self.alerted = false;
+    }
+
+    /// Check the output's peak level and total underruns since the last poll
+    ///
+    /// Alerts are logged, and the hook is run on its own thread so a slow
+    /// one doesn't hold up the caller.
+    pub fn poll(&mut self, peak: f32, underruns: f64) {
+        let alert = match self.update(Instant::now(), local_minute_of_day(), peak, underruns) {
+            Some(alert) => alert,
+            None => return,
+        };
+        match alert {
+            Alert::Recovered => info!("Watchdog: {}", alert),
+            _ => error!("Watchdog: {}", alert),
+        }
+        if let Some(hook) = self.hook.clone() {
+            thread::spawn(move || {
+                if let Err(e) = hook.fire(&alert) {
+                    warn!("The watchdog's alert hook failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn update(
+        &mut self,
+        now: Instant,
+        minute_of_day: u32,
+        peak: f32,
+        underruns: f64,
+    ) -> Option<Alert> {
+        let underrunning = underruns > self.last_underruns;
+        self.last_underruns = underruns;
+        if self
+            .hours
+            .is_some_and(|hours| !hours.contains(minute_of_day))
+        {
+            self.reset();
+            return None;
+        }
+        if power::amplitude_to_decibels(peak) >= self.threshold_db && !underrunning {
+            let recovered = self.alerted;
+            self.reset();
+            return if recovered {
+                Some(Alert::Recovered)
+            } else {
+                None
+            };
+        }
+        let since = *self.trouble_since.get_or_insert(now);
+        self.underran |= underrunning;
+        let duration = now - since;
+        if self.alerted || duration < self.after {
+            return None;
+        }
+        self.alerted = true;
+        Some(if self.underran {
+            Alert::Underrunning(duration)
+        } else {
+            Alert::Silent(duration)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::net::TcpListener;
+
+    #[test]
+    fn operating_hours_can_run_past_midnight() {
+        let day: OperatingHours = "10:00-18:30".parse().unwrap();
+        assert!(!day.contains(9 * 60 + 59));
+        assert!(day.contains(10 * 60));
+        assert!(!day.contains(18 * 60 + 30));
+        let night: OperatingHours = "22:00-02:00".parse().unwrap();
+        assert!(night.contains(23 * 60));
+        assert!(night.contains(60));
+        assert!(!night.contains(12 * 60));
+        assert!("10:00".parse::<OperatingHours>().is_err());
+        assert!("10:00-25:00".parse::<OperatingHours>().is_err());
+        assert!("10:60-12:00".parse::<OperatingHours>().is_err());
+    }
+
+    #[test]
+    fn alerts_once_per_silence_and_on_recovery() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let noon = 12 * 60;
+        let mut watchdog = Watchdog::new(Duration::from_secs(10), -60.0)
+            .with_operating_hours("10:00-18:00".parse().unwrap());
+        assert_eq!(watchdog.update(at(0), noon, 0.5, 0.0), None);
+        assert_eq!(watchdog.update(at(1), noon, 0.0, 0.0), None);
+        assert_eq!(
+            watchdog.update(at(11), noon, 0.0, 0.0),
+            Some(Alert::Silent(Duration::from_secs(10)))
+        );
+        assert_eq!(watchdog.update(at(20), noon, 0.0, 0.0), None);
+        assert_eq!(
+            watchdog.update(at(21), noon, 0.5, 0.0),
+            Some(Alert::Recovered)
+        );
+
+        // Underruns count as trouble even while there is some sound
+        assert_eq!(watchdog.update(at(30), noon, 0.5, 1.0), None);
+        assert_eq!(
+            watchdog.update(at(40), noon, 0.5, 2.0),
+            Some(Alert::Underrunning(Duration::from_secs(10)))
+        );
+        assert_eq!(
+            watchdog.update(at(41), noon, 0.5, 2.0),
+            Some(Alert::Recovered)
+        );
+
+        // Silence after hours is expected
+        let evening = 19 * 60;
+        assert_eq!(watchdog.update(at(50), evening, 0.0, 2.0), None);
+        assert_eq!(watchdog.update(at(70), evening, 0.0, 2.0), None);
+    }
+
+    #[test]
+    fn hooks_parse_and_webhooks_post_json() {
+        assert_eq!(
+            "notify-send quiet".parse::<AlertHook>().unwrap(),
+            AlertHook::Command("notify-send quiet".to_string())
+        );
+        assert_eq!(
+            "http://example.com".parse::<AlertHook>().unwrap(),
+            AlertHook::Webhook {
+                host: "example.com:80".to_string(),
+                path: "/".to_string()
+            }
+        );
+        assert!("https://example.com/hook".parse::<AlertHook>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let (requests_tx, requests) = unbounded();
+        http_api::serve(addr, move |request| {
+            requests_tx.send(request.clone()).unwrap();
+            http_api::Response::text(200, "ok")
+        })
+        .unwrap();
+        let hook: AlertHook = format!("http://{}/alerts", addr).parse().unwrap();
+        hook.fire(&Alert::Silent(Duration::from_secs(600))).unwrap();
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/alerts");
+        assert_eq!(
+            request.body,
+            "{\"alert\":\"silence\",\"message\":\"output has been silent for 600s\"}"
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Streaming audio between rocoder instances over TCP or a Unix socket
+//!
+//! A stream starts with a header: the bytes `ROCO`, the sample rate as a
+//! little-endian u32 and the channel count as a little-endian u16. Blocks of
+//! interleaved little-endian f32 samples follow, each prefixed with its
+//! sample count as a little-endian u32. Closing the connection ends the stream.
+
+use crate::audio::{AudioBus, AudioSpec};
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+const MAGIC: &[u8; 4] = b"ROCO";
+/// Blocks longer than this are taken to be a corrupt stream rather than audio
+const MAX_BLOCK_SAMPLES: usize = 1 << 22;
+/// Blocks queued per channel before reading more, so a slow player pushes
+/// back on the sender through the socket
+const CHUNK_QUEUE: usize = 8;
+
+/// Where a stream is sent to or received from
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamAddr {
+    /// `host:port`, resolved when connecting or binding
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl StreamAddr {
+    /// Parse `tcp://<host>:<port>` or `unix://<path>`, or `None` if `s` is neither
+    pub fn parse(s: &str) -> Option<StreamAddr> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return Some(StreamAddr::Tcp(addr.to_string()));
+        }
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Some(StreamAddr::Unix(PathBuf::from(path)));
+        }
+        None
+    }
+}
+
+impl FromStr for StreamAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<StreamAddr> {
+        StreamAddr::parse(s).ok_or_else(|| {
+            anyhow!(
+                "invalid address {:?}, expected tcp://<host>:<port> or unix://<path>",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for StreamAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamAddr::Tcp(addr) => write!(f, "tcp://{}", addr),
+            #[cfg(unix)]
+            StreamAddr::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The sending end of a stream
+///
+/// Samples are written one at a time, interleaved, and go out a block at a time.
+pub struct StreamWriter {
+    connection: BufWriter<Connection>,
+    block: Vec<f32>,
+}
+
+impl StreamWriter {
+    /// Connect to a listening receiver and send it the stream header
+    pub fn connect(addr: &StreamAddr, spec: AudioSpec) -> Result<StreamWriter> {
+        let connection = match addr {
+            StreamAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                // Blocks are already batched, so don't hold them back any further
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+            #[cfg(unix)]
+            StreamAddr::Unix(path) => Connection::Unix(UnixStream::connect(path)?),
+        };
+        let mut connection = BufWriter::new(connection);
+        connection.write_all(MAGIC)?;
+        connection.write_all(&spec.sample_rate.to_le_bytes())?;
+        connection.write_all(&spec.channels.to_le_bytes())?;
+        Ok(StreamWriter {
+            connection,
+            block: vec![],
+        })
+    }
+
+    pub fn write(&mut self, sample: f32) {
+        self.block.push(sample);
+    }
+
+    /// Send the samples written since the last block as one block
+    pub fn end_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.connection
+            .write_all(&(self.block.len() as u32).to_le_bytes())?;
+        for sample in self.block.drain(..) {
+            self.connection.write_all(&sample.to_le_bytes())?;
+        }
+        self.connection.flush()?;
+        Ok(())
+    }
+
+    /// Send any last samples, ending the stream once this is dropped
+    pub fn finish(mut self) -> Result<()> {
+        self.end_block()
+    }
+}
+
+/// Waits for a sender to connect
+pub enum StreamListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl StreamListener {
+    pub fn bind(addr: &StreamAddr) -> Result<StreamListener> {
+        Ok(match addr {
+            StreamAddr::Tcp(addr) => StreamListener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            StreamAddr::Unix(path) => {
+                // A socket left behind by an earlier receiver would make binding fail
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                StreamListener::Unix(UnixListener::bind(path)?)
+            }
+        })
+    }
+
+    /// Wait for the next sender and read its stream header
+    pub fn accept(&self) -> Result<StreamReader> {
+        let connection = match self {
+            StreamListener::Tcp(listener) => Connection::Tcp(listener.accept()?.0),
+            #[cfg(unix)]
+            StreamListener::Unix(listener) => Connection::Unix(listener.accept()?.0),
+        };
+        StreamReader::new(connection)
+    }
+}
+
+/// The receiving end of a stream
+pub struct StreamReader {
+    pub spec: AudioSpec,
+    connection: BufReader<Connection>,
+}
+
+impl StreamReader {
+    fn new(connection: Connection) -> Result<StreamReader> {
+        let mut connection = BufReader::new(connection);
+        let mut header = [0; 10];
+        connection
+            .read_exact(&mut header)
+            .context("failed to read the stream header")?;
+        if &header[..4] != MAGIC {
+            bail!("the sender isn't a rocoder stream");
+        }
+        let spec = AudioSpec {
+            sample_rate: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+            channels: u16::from_le_bytes(header[8..10].try_into().unwrap()),
+        };
+        if spec.channels == 0 || spec.sample_rate == 0 {
+            bail!("invalid stream spec {:?}", spec);
+        }
+        Ok(StreamReader { spec, connection })
+    }
+
+    /// The next block of interleaved samples, or `None` once the sender has
+    /// closed the stream
+    pub fn read_block(&mut self) -> Result<Option<Vec<f32>>> {
+        let mut len = [0; 4];
+        match self.connection.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_BLOCK_SAMPLES || !len.is_multiple_of(self.spec.channels as usize) {
+            bail!("invalid block of {} samples", len);
+        }
+        let mut bytes = vec![0; len * 4];
+        self.connection
+            .read_exact(&mut bytes)
+            .context("the stream ended partway through a block")?;
+        Ok(Some(
+            bytes
+                .chunks_exact(4)
+                .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()))
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamSourceProcessorControlMessage {
+    Shutdown,
+}
+
+impl ControlMessage for StreamSourceProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        StreamSourceProcessorControlMessage::Shutdown
+    }
+}
+
+/// A source node emitting the audio arriving on a stream
+///
+/// Reading blocks while waiting on the sender, so a shutdown takes effect
+/// once the next block arrives or the sender goes away.
+pub struct StreamSourceProcessor {
+    reader: StreamReader,
+    outputs: Vec<Sender<Vec<f32>>>,
+}
+
+impl StreamSourceProcessor {
+    pub fn new(reader: StreamReader) -> (StreamSourceProcessor, AudioBus) {
+        let spec = reader.spec;
+        let (outputs, receivers) = (0..spec.channels).map(|_| bounded(CHUNK_QUEUE)).unzip();
+        (
+            StreamSourceProcessor { reader, outputs },
+            AudioBus {
+                spec,
+                channels: receivers,
+                expected_total_samples: None,
+                timestamps: None,
+            },
+        )
+    }
+
+    fn run(&mut self, ctrl_rx: &Receiver<StreamSourceProcessorControlMessage>) -> Result<()> {
+        let num_channels = self.outputs.len();
+        while let ProcessorState::Running = self.handle_control_messages(ctrl_rx)? {
+            let block = match self.reader.read_block()? {
+                Some(block) => block,
+                None => {
+                    info!("sender closed the stream");
+                    break;
+                }
+            };
+            for (channel, output) in self.outputs.iter().enumerate() {
+                let chunk = block
+                    .iter()
+                    .skip(channel)
+                    .step_by(num_channels)
+                    .copied()
+                    .collect();
+                if output.send(chunk).is_err() {
+                    info!("stream output disconnected, stopping");
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Processor<StreamSourceProcessorControlMessage> for StreamSourceProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<StreamSourceProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            if let Err(e) = self.run(&ctrl_rx) {
+                let _ = errors.send(NodeError::Failed(e));
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<StreamSourceProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(StreamSourceProcessorControlMessage::Shutdown) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::file_sink_processor::{FileSinkProcessor, FileSinkTarget};
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            StreamAddr::parse("tcp://10.0.0.5:9000"),
+            Some(StreamAddr::Tcp("10.0.0.5:9000".to_string()))
+        );
+        assert_eq!(StreamAddr::parse("out.wav"), None);
+        assert!("10.0.0.5:9000".parse::<StreamAddr>().is_err());
+    }
+
+    #[test]
+    fn streams_between_a_file_sink_and_a_stream_source() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let listener = StreamListener::Tcp(TcpListener::bind("127.0.0.1:0").unwrap());
+        let addr = match &listener {
+            StreamListener::Tcp(listener) => {
+                StreamAddr::Tcp(listener.local_addr().unwrap().to_string())
+            }
+            #[cfg(unix)]
+            StreamListener::Unix(_) => unreachable!(),
+        };
+        let receiver = thread::spawn(move || {
+            let (processor, bus) = StreamSourceProcessor::new(listener.accept().unwrap());
+            let node = Node::new(processor);
+            let audio = bus.into_audio();
+            node.join().unwrap();
+            audio
+        });
+
+        let (bus, senders) = AudioBus::from_spec(spec, None);
+        let sink = Node::new(FileSinkProcessor::new(bus, FileSinkTarget::Stream(addr)).unwrap());
+        senders[0].send(vec![0.1, 0.2, 0.3]).unwrap();
+        senders[1].send(vec![-0.1, -0.2]).unwrap();
+        senders[1].send(vec![-0.3]).unwrap();
+        drop(senders);
+        sink.join().unwrap();
+
+        let audio = receiver.join().unwrap();
+        assert_eq!(audio.spec, spec);
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![0.1, 0.2, 0.3]);
+        assert_almost_eq_by_element(audio.data[1].clone(), vec![-0.1, -0.2, -0.3]);
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        });
+        let connection = Connection::Tcp(listener.accept().unwrap().0);
+        assert!(StreamReader::new(connection).is_err());
+        sender.join().unwrap();
+    }
+}
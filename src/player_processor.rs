@@ -1,19 +1,55 @@
 use crate::audio::{AudioBus, AudioSpec};
-use crate::cpal_utils;
-use crate::mixer::Mixer;
-use crate::signal_flow::node::{ControlMessage, Processor, ProcessorState};
-use anyhow::Result;
+use crate::cpal_utils::{self, Backend, BufferRequest};
+use crate::limiter::{self, Limiter};
+use crate::metrics::{self, Counter};
+use crate::mixer::{Mixer, PeakMeter};
+use crate::realtime_check;
+use crate::resampler::StreamingResampler;
+use crate::ring_buffer::{ring_buffer, RingProducer};
+use crate::routing::Routing;
+use crate::runtime_setup;
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::slices;
+use crate::virtual_audio::VirtualAudio;
+use anyhow::{anyhow, Context, Result};
 use cpal::{
     self,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
+    traits::{DeviceTrait, StreamTrait},
+    BufferSize,
 };
 use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
 const PLAYBACK_SLEEP: Duration = Duration::from_millis(250);
+const LATENCY_UNKNOWN: u64 = u64::MAX;
+/// Mixed audio queued ahead of the output callback; the minimum added latency
+const OUTPUT_QUEUE: Duration = Duration::from_millis(20);
+const FEED_BLOCK_FRAMES: usize = 64;
+const FEED_POLL: Duration = Duration::from_millis(1);
+
+/// Identifies a bus connected to an audio output, for messages targeting it
+///
+/// IDs are handed out in sequence, so no two buses in a process share one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BusId(u32);
+
+impl BusId {
+    /// A new ID, for connecting a bus and targeting it in later messages
+    pub fn allocate() -> BusId {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        BusId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for BusId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug)]
 pub enum AudioOutputProcessorControlMessage {
@@ -21,11 +57,60 @@ pub enum AudioOutputProcessorControlMessage {
         fade: Option<Duration>,
     },
     ConnectBus {
-        id: u32,
+        id: BusId,
         bus: AudioBus,
         fade: Option<Duration>,
         shutdown_when_finished: bool,
     },
+    /// Fade a connected bus out over `fade` (immediately if `None`), then drop it
+    DisconnectBus {
+        id: BusId,
+        fade: Option<Duration>,
+    },
+    /// Connect a bus of unprocessed source audio for A/B comparison; see `SetBypass`
+    ConnectBypassBus {
+        id: BusId,
+        bus: AudioBus,
+    },
+    /// While enabled, only bypass buses are heard. Other buses keep playing silently.
+    SetBypass {
+        enabled: bool,
+    },
+    /// Attenuate each bus by `db` for every bus connected after it, e.g. `-6.0`.
+    /// Gain ramps over `ramp` and is restored as newer buses finish. `0.0` disables.
+    SetDucking {
+        db: f32,
+        ramp: Duration,
+    },
+    /// Ramp a connected bus to a gain in dB, relative to its faded level
+    SetBusGain {
+        id: BusId,
+        db: f32,
+        ramp: Duration,
+    },
+    /// Silence a connected bus without disconnecting it. Undo with `SetBusGain`.
+    MuteBus {
+        id: BusId,
+    },
+    /// Limit the final mix to `ceiling_db` dBFS. A limiter is on by default.
+    SetLimiter {
+        ceiling_db: f32,
+        release: Duration,
+    },
+    DisableLimiter,
+    /// Change how a connected bus is spread across the output channels
+    SetBusRouting {
+        id: BusId,
+        routing: Routing,
+    },
+    SetChannelPolarity {
+        channel: usize,
+        inverted: bool,
+    },
+    /// Output silence without consuming any bus, keeping the device open
+    Pause,
+    /// Continue playing every bus from where `Pause` left it
+    Resume,
 }
 
 impl ControlMessage for AudioOutputProcessorControlMessage {
@@ -34,77 +119,212 @@ impl ControlMessage for AudioOutputProcessorControlMessage {
             fade: Some(Duration::from_secs(1)),
         }
     }
+
+    fn immediate_shutdown_msg() -> Self {
+        AudioOutputProcessorControlMessage::Shutdown { fade: None }
+    }
 }
 
 pub struct AudioOutputProcessor {
     spec: AudioSpec,
     mixer: Arc<Mutex<Mixer>>,
     shutdown_after: Option<Instant>,
+    backend: Backend,
+    buffer: BufferRequest,
 }
 
 impl AudioOutputProcessor {
     pub fn new(spec: AudioSpec) -> Self {
+        let mut mixer = Mixer::new(&spec);
+        mixer.set_limiter(Some(Limiter::new(
+            limiter::DEFAULT_CEILING_DB,
+            limiter::DEFAULT_RELEASE,
+            spec.sample_rate,
+        )));
+        mixer.set_clip_counter(Some(clip_counter()));
         AudioOutputProcessor {
-            mixer: Arc::new(Mutex::new(Mixer::new(&spec))),
+            mixer: Arc::new(Mutex::new(mixer)),
             shutdown_after: None,
+            backend: Backend::Default,
+            buffer: BufferRequest::Default,
             spec,
         }
     }
 
-    fn run(mut self, ctrl_rx: Receiver<AudioOutputProcessorControlMessage>) -> Result<()> {
-        let mixer_arc = Arc::clone(&self.mixer);
-        let host = cpal::default_host();
-        let output_device = host.default_output_device().unwrap();
-        info!("Using default output device: \"{}\"", output_device.name()?);
+    /// Measure the peak level of everything played with `meter`
+    pub fn with_peak_meter(self, meter: PeakMeter) -> Self {
+        self.mixer.lock().unwrap().set_peak_meter(Some(meter));
+        self
+    }
+
+    /// Measure the true peak of everything played with `meter`
+    pub fn with_true_peak_meter(self, meter: PeakMeter) -> Self {
+        self.mixer.lock().unwrap().set_true_peak_meter(Some(meter));
+        self
+    }
+
+    /// Play through `backend` rather than the default output device
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Ask the device for a buffer size; smaller buffers lower latency but
+    /// risk dropouts
+    pub fn with_buffer(mut self, buffer: BufferRequest) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    fn run(
+        mut self,
+        ctrl_rx: Receiver<AudioOutputProcessorControlMessage>,
+        errors: Sender<NodeError>,
+    ) -> Result<()> {
+        if let Backend::Virtual(virtual_audio) = &self.backend {
+            let virtual_audio = virtual_audio.clone();
+            return self.run_virtual(virtual_audio, ctrl_rx);
+        }
+        // Microseconds from a callback to its audio being played, once known
+        let latency_us = Arc::new(AtomicU64::new(LATENCY_UNKNOWN));
+        let callback_latency_us = Arc::clone(&latency_us);
+        let output_device = self.backend.output_device()?;
+        info!("Using output device: \"{}\"", output_device.name()?);
         let supported_configs = output_device
             .supported_output_configs()
-            .expect("failed to query output device configs");
-        let stream_config = cpal_utils::find_output_stream_config(
+            .context("failed to query output device configs")?;
+        let stream_config = cpal_utils::negotiate_output_stream_config(
             supported_configs,
             self.spec.channels,
             self.spec.sample_rate,
+            self.buffer,
         )?;
+        let resampler = if stream_config.sample_rate.0 == self.spec.sample_rate {
+            None
+        } else {
+            info!(
+                "The output device doesn't support {} Hz, resampling to {} Hz",
+                self.spec.sample_rate, stream_config.sample_rate.0
+            );
+            Some(StreamingResampler::new(
+                self.spec.channels,
+                self.spec.sample_rate,
+                stream_config.sample_rate.0,
+            ))
+        };
+
+        // The mixer runs on its own thread, and the callback only copies its
+        // output out of a ring buffer, so the callback never locks or allocates.
+        let mut queue_frames =
+            (stream_config.sample_rate.0 as f32 * OUTPUT_QUEUE.as_secs_f32()) as u32;
+        if let BufferSize::Fixed(frames) = stream_config.buffer_size {
+            queue_frames = queue_frames.max(frames * 2);
+        }
+        let (producer, mut consumer) =
+            ring_buffer(queue_frames as usize * self.spec.channels as usize);
+        let stop_feeding = Arc::new(AtomicBool::new(false));
+        let feeder = {
+            let mixer = Arc::clone(&self.mixer);
+            let stop_feeding = Arc::clone(&stop_feeding);
+            let channels = self.spec.channels;
+            thread::spawn(move || feed_output(mixer, resampler, producer, channels, stop_feeding))
+        };
+        let underruns = underrun_counter();
+        let mut started = false;
         let output_stream = output_device
             .build_output_stream(
                 &stream_config,
-                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                    // react to stream events and read or write stream data here.
-                    let mut mixer = mixer_arc.lock().unwrap();
-                    mixer.fill_buffer(data);
+                move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                    let _guard = realtime_check::enter_callback();
+                    let timestamp = info.timestamp();
+                    if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                        callback_latency_us.store(latency.as_micros() as u64, Ordering::Relaxed);
+                    }
+                    let read = consumer.pop(data);
+                    slices::zero_slice(&mut data[read..]);
+                    // Running short while the queue first fills isn't an underrun
+                    if read == data.len() {
+                        started = true;
+                    } else if started {
+                        underruns.inc();
+                    }
                 },
                 move |err| {
-                    panic!("audio output stream failed: {:?}", err);
+                    let _ = errors.send(NodeError::Failed(anyhow!(
+                        "audio output stream failed: {:?}",
+                        err
+                    )));
                 },
             )
-            .expect("failed to build output stream");
-        output_stream.play().expect("failed to start output stream");
+            .context("failed to build output stream")?;
+        output_stream
+            .play()
+            .context("failed to start output stream")?;
 
+        let mut latency_reported = false;
         loop {
-            match self.handle_control_messages(&ctrl_rx)? {
-                ProcessorState::Finished => {
-                    break;
-                }
-                _ => {}
+            let latency = latency_us.load(Ordering::Relaxed);
+            if !latency_reported && latency != LATENCY_UNKNOWN {
+                info!("Output latency: {:.1} ms", latency as f32 / 1000.0);
+                latency_reported = true;
             }
-            if self
-                .mixer
-                .lock()
-                .unwrap()
-                .finished_flag
-                .load(Ordering::Relaxed)
-            {
+            if self.poll_finished(&ctrl_rx)? {
                 break;
             }
-            if let Some(shutdown_after) = self.shutdown_after {
-                if Instant::now() > shutdown_after {
-                    break;
-                }
-            }
             thread::sleep(PLAYBACK_SLEEP);
         }
+        drop(output_stream);
+        stop_feeding.store(true, Ordering::Relaxed);
+        let _ = feeder.join();
         Ok(())
     }
 
+    /// Mix into the virtual output's capture as fast as the buses deliver,
+    /// without a device or a clock
+    fn run_virtual(
+        mut self,
+        virtual_audio: VirtualAudio,
+        ctrl_rx: Receiver<AudioOutputProcessorControlMessage>,
+    ) -> Result<()> {
+        let mut block = vec![0.0; FEED_BLOCK_FRAMES * self.spec.channels as usize];
+        while !self.poll_finished(&ctrl_rx)? {
+            let mut mixer = self.mixer.lock().unwrap();
+            // A device would play silence until a bus is connected, but the
+            // capture starts with the first one so it doesn't depend on timing
+            if !mixer.has_layers() && virtual_audio.captured().is_none() {
+                drop(mixer);
+                thread::sleep(FEED_POLL);
+                continue;
+            }
+            mixer.fill_buffer(&mut block);
+            drop(mixer);
+            virtual_audio.capture(self.spec, &block)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the next control message, returning whether playback should end
+    fn poll_finished(
+        &mut self,
+        ctrl_rx: &Receiver<AudioOutputProcessorControlMessage>,
+    ) -> Result<bool> {
+        if let ProcessorState::Finished = self.handle_control_messages(ctrl_rx)? {
+            return Ok(true);
+        }
+        if self
+            .mixer
+            .lock()
+            .unwrap()
+            .finished_flag
+            .load(Ordering::Relaxed)
+        {
+            return Ok(true);
+        }
+        Ok(self
+            .shutdown_after
+            .is_some_and(|shutdown_after| Instant::now() > shutdown_after))
+    }
+
     const FADE_SHUTDOWN_PADDING: Duration = Duration::from_secs(1);
 
     fn fade_shutdown(&mut self, fade_dur: Duration) {
@@ -113,14 +333,61 @@ impl AudioOutputProcessor {
     }
 }
 
+/// Times audio reached any output too late to be played
+pub fn underrun_counter() -> Counter {
+    metrics::registry().counter(
+        "rocoder_output_underruns_total",
+        &[],
+        "Times audio reached the output too late to be played",
+    )
+}
+
+/// Frames where the mix went over full scale before the limiter, so would
+/// have clipped without it
+pub fn clip_counter() -> Counter {
+    metrics::registry().counter(
+        "rocoder_output_clips_total",
+        &[],
+        "Frames where the mix went over full scale before the limiter",
+    )
+}
+
+/// Keep the output queue topped up with mixed audio until told to stop
+fn feed_output(
+    mixer: Arc<Mutex<Mixer>>,
+    mut resampler: Option<StreamingResampler>,
+    mut queue: RingProducer,
+    channels: u16,
+    stop: Arc<AtomicBool>,
+) {
+    runtime_setup::pin_processing_thread();
+    let mut block = vec![0.0; FEED_BLOCK_FRAMES * channels as usize];
+    while !stop.load(Ordering::Relaxed) && !queue.is_abandoned() {
+        if queue.free_len() < block.len() {
+            thread::sleep(FEED_POLL);
+            continue;
+        }
+        let mut mixer = mixer.lock().unwrap();
+        match resampler.as_mut() {
+            Some(resampler) => resampler.fill(&mut block, |frame| mixer.fill_buffer(frame)),
+            None => mixer.fill_buffer(&mut block),
+        }
+        drop(mixer);
+        queue.push(&block);
+    }
+}
+
 impl Processor<AudioOutputProcessorControlMessage> for AudioOutputProcessor {
     fn start(
         self,
         finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
     ) -> (Sender<AudioOutputProcessorControlMessage>, JoinHandle<()>) {
         let (ctrl_tx, ctrl_rx) = unbounded();
         let handle = thread::spawn(move || {
-            self.run(ctrl_rx).unwrap();
+            if let Err(e) = self.run(ctrl_rx, errors.clone()) {
+                let _ = errors.send(NodeError::Failed(e));
+            }
             finished.store(true, Ordering::Relaxed);
         });
         (ctrl_tx, handle)
@@ -146,8 +413,71 @@ impl Processor<AudioOutputProcessorControlMessage> for AudioOutputProcessor {
                     shutdown_when_finished,
                 } => {
                     let mut mixer = self.mixer.lock().unwrap();
-                    mixer.insert_layer(id, bus, shutdown_when_finished)?;
-                    mixer.fade_in_out(id, fade.clone(), fade)?;
+                    mixer.insert_layer(id.0, bus, shutdown_when_finished)?;
+                    mixer.fade_in_out(id.0, fade.clone(), fade)?;
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::DisconnectBus { id, fade } => {
+                    if let Err(e) = self.mixer.lock().unwrap().disconnect(id.0, fade) {
+                        warn!("Failed to disconnect bus {}: {}", id, e);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::ConnectBypassBus { id, bus } => {
+                    self.mixer.lock().unwrap().insert_bypass_layer(id.0, bus)?;
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetBypass { enabled } => {
+                    self.mixer.lock().unwrap().set_bypass(enabled);
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetDucking { db, ramp } => {
+                    self.mixer.lock().unwrap().set_ducking(db, ramp);
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetBusGain { id, db, ramp } => {
+                    if let Err(e) = self.mixer.lock().unwrap().set_gain(id.0, db, ramp) {
+                        warn!("Failed to set gain of bus {}: {}", id, e);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::MuteBus { id } => {
+                    if let Err(e) = self.mixer.lock().unwrap().mute(id.0) {
+                        warn!("Failed to mute bus {}: {}", id, e);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetLimiter {
+                    ceiling_db,
+                    release,
+                } => {
+                    let limiter = Limiter::new(ceiling_db, release, self.spec.sample_rate);
+                    self.mixer.lock().unwrap().set_limiter(Some(limiter));
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::DisableLimiter => {
+                    self.mixer.lock().unwrap().set_limiter(None);
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetBusRouting { id, routing } => {
+                    if let Err(e) = self.mixer.lock().unwrap().set_routing(id.0, routing) {
+                        warn!("Failed to set routing of bus {}: {}", id, e);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::SetChannelPolarity { channel, inverted } => {
+                    let mut mixer = self.mixer.lock().unwrap();
+                    if let Err(e) = mixer.set_channel_polarity(channel, inverted) {
+                        warn!("Failed to set channel polarity: {}", e);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::Pause => {
+                    self.mixer.lock().unwrap().set_paused(true);
+                    Ok(ProcessorState::Running)
+                }
+                AudioOutputProcessorControlMessage::Resume => {
+                    self.mixer.lock().unwrap().set_paused(false);
                     Ok(ProcessorState::Running)
                 }
             },
@@ -1,3 +1,8 @@
+use anyhow::{bail, Result};
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::time::Duration;
+
 const MIN_DECIBELS: f32 = -99999999.0;
 
 /// Convert a linear amplitude (0-1) to a decibel measurement relative to max amplitude
@@ -7,6 +12,310 @@ fn relative_decibels(raw_amp: f32) -> f32 {
     (raw_amp.abs().log10() * 20.0).max(MIN_DECIBELS)
 }
 
+/// Convert a decibel gain to a linear amplitude multiplier
+pub fn decibels_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Convert a linear amplitude to decibels, which for a sample level is dBFS
+///
+/// Silence is `-inf`.
+pub fn amplitude_to_decibels(amplitude: f32) -> f32 {
+    20.0 * amplitude.abs().log10()
+}
+
+/// Root mean square of `samples`, or 0.0 if there are none
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Per-sample decay of a level that should fall by a factor of e over `integration`
+fn decay_coefficient(integration: Duration, sample_rate: u32) -> f32 {
+    let samples = integration.as_secs_f32() * sample_rate as f32;
+    if samples > 0.0 {
+        (-1.0 / samples).exp()
+    } else {
+        0.0
+    }
+}
+
+/// A second order IIR section in transposed direct form II
+#[derive(Debug, Clone)]
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// A section with numerator `b` and denominator `1, a[0], a[1]`
+    pub(crate) fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    /// The bilinear transform of the analog section
+    /// `(b[0]s² + b[1]s + b[2]) / (a[0]s² + a[1]s + a[2])`
+    fn from_analog(b: [f64; 3], a: [f64; 3], sample_rate: u32) -> Biquad {
+        let k = 2.0 * sample_rate as f64;
+        let digital = |c: [f64; 3]| {
+            [
+                c[0] * k * k + c[1] * k + c[2],
+                2.0 * (c[2] - c[0] * k * k),
+                c[0] * k * k - c[1] * k + c[2],
+            ]
+        };
+        let b = digital(b);
+        let a = digital(a);
+        Biquad::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+
+    pub(crate) fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /// How much the section scales a sine at `frequency`
+    fn gain_at(&self, frequency: f64, sample_rate: u32) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        let magnitude = |c: [f64; 3]| {
+            let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+            let im = c[1] * w.sin() + c[2] * (2.0 * w).sin();
+            (re * re + im * im).sqrt()
+        };
+        magnitude(self.b) / magnitude([1.0, self.a[0], self.a[1]])
+    }
+}
+
+/// Frequency weighting applied to a signal before its level is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weighting {
+    /// Flat, so the level is the plain signal level
+    Z,
+    /// IEC 61672 A-weighting, which follows the ear at quiet levels and
+    /// leaves out most rumble and handling noise
+    A,
+    /// IEC 61672 C-weighting, nearly flat but for the extremes
+    C,
+}
+
+impl FromStr for Weighting {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Weighting> {
+        match name.to_ascii_lowercase().as_str() {
+            "z" => Ok(Weighting::Z),
+            "a" => Ok(Weighting::A),
+            "c" => Ok(Weighting::C),
+            _ => bail!("unknown weighting {:?}, expected a, c or z", name),
+        }
+    }
+}
+
+/// Filters a signal with one of the standard weighting curves
+///
+/// The analog poles of IEC 61672 are carried over with the bilinear
+/// transform, and the result is scaled to unity gain at 1 kHz. That is
+/// accurate through the midrange and bass, and falls away early near the
+/// Nyquist frequency, which only matters for the top octave.
+#[derive(Debug, Clone)]
+pub struct WeightingFilter {
+    sections: Vec<Biquad>,
+    gain: f64,
+}
+
+impl WeightingFilter {
+    pub fn new(weighting: Weighting, sample_rate: u32) -> WeightingFilter {
+        let pole = |frequency: f64| 2.0 * PI * frequency;
+        let (w1, w2, w3, w4) = (
+            pole(20.598997),
+            pole(107.65265),
+            pole(737.86223),
+            pole(12194.217),
+        );
+        let low_cut = Biquad::from_analog([1.0, 0.0, 0.0], [1.0, 2.0 * w1, w1 * w1], sample_rate);
+        let high_cut =
+            Biquad::from_analog([0.0, 0.0, w4 * w4], [1.0, 2.0 * w4, w4 * w4], sample_rate);
+        let sections = match weighting {
+            Weighting::Z => vec![],
+            Weighting::A => vec![
+                low_cut,
+                Biquad::from_analog([1.0, 0.0, 0.0], [1.0, w2 + w3, w2 * w3], sample_rate),
+                high_cut,
+            ],
+            Weighting::C => vec![low_cut, high_cut],
+        };
+        let gain = 1.0
+            / sections
+                .iter()
+                .map(|section| section.gain_at(1000.0, sample_rate))
+                .product::<f64>();
+        WeightingFilter { sections, gain }
+    }
+
+    pub fn filter(&mut self, sample: f32) -> f32 {
+        let weighted = self
+            .sections
+            .iter_mut()
+            .fold(sample as f64, |x, section| section.process(x));
+        (weighted * self.gain) as f32
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample = self.filter(*sample);
+        }
+    }
+}
+
+/// RMS level averaged over an integration time, like a VU meter
+///
+/// Squares are folded into an exponential moving average with the
+/// integration time as its time constant.
+#[derive(Debug, Clone)]
+pub struct RmsMeter {
+    coefficient: f32,
+    mean_square: f32,
+    weighting: WeightingFilter,
+}
+
+impl RmsMeter {
+    pub fn new(integration: Duration, sample_rate: u32) -> RmsMeter {
+        RmsMeter::weighted(integration, sample_rate, Weighting::Z)
+    }
+
+    /// A meter that measures the signal through a weighting filter
+    pub fn weighted(integration: Duration, sample_rate: u32, weighting: Weighting) -> RmsMeter {
+        RmsMeter {
+            coefficient: decay_coefficient(integration, sample_rate),
+            mean_square: 0.0,
+            weighting: WeightingFilter::new(weighting, sample_rate),
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for sample in samples {
+            let sample = self.weighting.filter(*sample);
+            self.mean_square =
+                sample * sample + self.coefficient * (self.mean_square - sample * sample);
+        }
+    }
+
+    /// The level as a linear amplitude
+    pub fn level(&self) -> f32 {
+        self.mean_square.sqrt()
+    }
+
+    pub fn level_db(&self) -> f32 {
+        amplitude_to_decibels(self.level())
+    }
+}
+
+/// Peak level that jumps up to each new peak, then falls away over an
+/// integration time, like a PPM
+#[derive(Debug, Clone)]
+pub struct PeakLevelMeter {
+    coefficient: f32,
+    peak: f32,
+}
+
+impl PeakLevelMeter {
+    pub fn new(integration: Duration, sample_rate: u32) -> PeakLevelMeter {
+        PeakLevelMeter {
+            coefficient: decay_coefficient(integration, sample_rate),
+            peak: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for sample in samples {
+            self.peak = sample.abs().max(self.peak * self.coefficient);
+        }
+    }
+
+    /// The level as a linear amplitude
+    pub fn level(&self) -> f32 {
+        self.peak
+    }
+
+    pub fn level_db(&self) -> f32 {
+        amplitude_to_decibels(self.peak)
+    }
+}
+
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+/// Samples either side of the point being interpolated
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Estimates the true peak of a signal, the highest level it reaches
+/// between samples once converted to analog, as in ITU-R BS.1770
+///
+/// Each channel is oversampled by four with a windowed sinc interpolator.
+/// A mix that never has a sample above full scale can still have a true
+/// peak above it, and clip in a DAC or lossy encoder.
+#[derive(Debug, Clone)]
+pub struct TruePeakDetector {
+    /// Interpolation taps for each position between samples
+    phases: Vec<[f32; TRUE_PEAK_TAPS]>,
+    /// The latest samples of each channel, oldest first
+    history: Vec<[f32; TRUE_PEAK_TAPS]>,
+}
+
+impl TruePeakDetector {
+    pub fn new(channels: u16) -> TruePeakDetector {
+        let half = TRUE_PEAK_TAPS as f32 / 2.0;
+        let phases = (0..TRUE_PEAK_OVERSAMPLING)
+            .map(|phase| {
+                let mut taps = [0.0; TRUE_PEAK_TAPS];
+                for (k, tap) in taps.iter_mut().enumerate() {
+                    // Interpolate between the two middle samples of the history
+                    let t = (half - 1.0) + phase as f32 / TRUE_PEAK_OVERSAMPLING as f32 - k as f32;
+                    let sinc = if t == 0.0 {
+                        1.0
+                    } else {
+                        (std::f32::consts::PI * t).sin() / (std::f32::consts::PI * t)
+                    };
+                    let window = 0.5 * (1.0 + (std::f32::consts::PI * t / half).cos());
+                    *tap = sinc * window;
+                }
+                taps
+            })
+            .collect();
+        TruePeakDetector {
+            phases,
+            history: vec![[0.0; TRUE_PEAK_TAPS]; channels as usize],
+        }
+    }
+
+    /// Measure one frame holding a sample for each channel, returning the
+    /// true peak as a linear amplitude
+    ///
+    /// The estimate lags the input by half the interpolator's length.
+    pub fn process_frame(&mut self, frame: &[f32]) -> f32 {
+        let mut peak = 0.0f32;
+        for (sample, history) in frame.iter().zip(self.history.iter_mut()) {
+            history.copy_within(1.., 0);
+            history[TRUE_PEAK_TAPS - 1] = *sample;
+            for taps in &self.phases {
+                let value: f32 = taps.iter().zip(history.iter()).map(|(t, s)| t * s).sum();
+                peak = peak.max(value.abs());
+            }
+        }
+        peak
+    }
+}
+
 pub fn audio_power(audio: &[f32]) -> f32 {
     let raw_amp = audio
         .iter()
@@ -16,6 +325,27 @@ pub fn audio_power(audio: &[f32]) -> f32 {
     return relative_decibels(raw_amp);
 }
 
+/// Correlation between two channels, from -1 (identical but inverted) to 1 (identical)
+///
+/// Strongly negative values usually mean one channel is wired with reversed polarity.
+/// Returns 0.0 if either channel is silent.
+pub fn correlation(left: &[f32], right: &[f32]) -> f32 {
+    let mut product_sum = 0.0;
+    let mut left_square_sum = 0.0;
+    let mut right_square_sum = 0.0;
+    for (l, r) in left.iter().zip(right) {
+        product_sum += l * r;
+        left_square_sum += l * l;
+        right_square_sum += r * r;
+    }
+    let denominator = (left_square_sum * right_square_sum).sqrt();
+    if denominator == 0.0 {
+        0.0
+    } else {
+        product_sum / denominator
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -27,4 +357,112 @@ mod test {
         assert_almost_eq(relative_decibels(0.1), -19.999999999);
         assert_almost_eq(relative_decibels(1.0), 0.0);
     }
+
+    #[test]
+    fn test_decibels_to_amplitude() {
+        assert_almost_eq(decibels_to_amplitude(0.0), 1.0);
+        assert_almost_eq(decibels_to_amplitude(-20.0), 0.1);
+        assert_almost_eq(decibels_to_amplitude(relative_decibels(0.3)), 0.3);
+        assert_almost_eq(amplitude_to_decibels(-0.1), -20.0);
+        assert_eq!(amplitude_to_decibels(0.0), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_meters() {
+        let sine: Vec<f32> = (0..4400)
+            .map(|i| (i as f32 * 2.0 * std::f32::consts::PI / 100.0).sin() * 0.5)
+            .collect();
+        assert_almost_eq(rms(&sine), 0.5 / 2f32.sqrt());
+        assert_eq!(rms(&[]), 0.0);
+
+        let mut rms_meter = RmsMeter::new(Duration::from_millis(10), 44100);
+        let mut peak_meter = PeakLevelMeter::new(Duration::from_millis(10), 44100);
+        rms_meter.process(&sine);
+        peak_meter.process(&sine);
+        // A tenth of a second is ten time constants, so the average has settled
+        assert!((rms_meter.level_db() - amplitude_to_decibels(0.5 / 2f32.sqrt())).abs() < 0.5);
+        assert!(peak_meter.level() <= 0.5 && peak_meter.level() > 0.45);
+
+        // Silence for one time constant leaves the peak at 1/e
+        peak_meter.process(&[1.0]);
+        peak_meter.process(&[0.0; 441]);
+        assert!((peak_meter.level() - (-1.0f32).exp()).abs() < 0.01);
+    }
+
+    fn weighted_gain_db(weighting: Weighting, frequency: f32) -> f32 {
+        let sine: Vec<f32> = (0..44100)
+            .map(|i| (i as f32 * 2.0 * std::f32::consts::PI * frequency / 44100.0).sin())
+            .collect();
+        let mut filter = WeightingFilter::new(weighting, 44100);
+        let mut weighted = sine.clone();
+        filter.process(&mut weighted);
+        // Skip the first half second while the filter settles
+        amplitude_to_decibels(rms(&weighted[22050..]) / rms(&sine[22050..]))
+    }
+
+    #[test]
+    fn test_weighting() {
+        // Reference values from the IEC 61672 tables
+        for (weighting, frequency, expected_db) in [
+            (Weighting::Z, 50.0, 0.0),
+            (Weighting::A, 50.0, -30.2),
+            (Weighting::A, 100.0, -19.1),
+            (Weighting::A, 1000.0, 0.0),
+            (Weighting::A, 2000.0, 1.2),
+            (Weighting::C, 50.0, -1.3),
+            (Weighting::C, 1000.0, 0.0),
+        ] {
+            let gain_db = weighted_gain_db(weighting, frequency);
+            assert!(
+                (gain_db - expected_db).abs() < 0.2,
+                "{:?} at {} Hz: {:.2} dB",
+                weighting,
+                frequency,
+                gain_db
+            );
+        }
+        assert_eq!("A".parse::<Weighting>().unwrap(), Weighting::A);
+        assert!("b".parse::<Weighting>().is_err());
+    }
+
+    #[test]
+    fn test_weighted_meter_ignores_rumble() {
+        let rumble: Vec<f32> = (0..44100)
+            .map(|i| (i as f32 * 2.0 * std::f32::consts::PI * 30.0 / 44100.0).sin())
+            .collect();
+        let mut flat = RmsMeter::new(Duration::from_millis(100), 44100);
+        let mut a_weighted = RmsMeter::weighted(Duration::from_millis(100), 44100, Weighting::A);
+        flat.process(&rumble);
+        a_weighted.process(&rumble);
+        assert!(a_weighted.level_db() < flat.level_db() - 30.0);
+    }
+
+    #[test]
+    fn test_true_peak() {
+        // A quarter of the sample rate, sampled either side of each peak
+        let sine: Vec<f32> = (0..64)
+            .map(|i| (i as f32 * std::f32::consts::FRAC_PI_2 + std::f32::consts::FRAC_PI_4).sin())
+            .collect();
+        let mut detector = TruePeakDetector::new(1);
+        let true_peak = sine
+            .iter()
+            .map(|sample| detector.process_frame(&[*sample]))
+            .fold(0.0f32, f32::max);
+        assert!(sine.iter().all(|sample| sample.abs() < 0.71));
+        assert!((true_peak - 1.0).abs() < 0.05, "{}", true_peak);
+    }
+
+    #[test]
+    fn test_correlation() {
+        let signal = vec![0.1, -0.5, 0.9, 0.0, -0.3];
+        let inverted: Vec<f32> = signal.iter().map(|s| -s).collect();
+        assert_almost_eq(correlation(&signal, &signal), 1.0);
+        assert_almost_eq(correlation(&signal, &inverted), -1.0);
+        assert_almost_eq(correlation(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn test_correlation_with_silence() {
+        assert_almost_eq(correlation(&[0.5, 0.5], &[0.0, 0.0]), 0.0);
+    }
 }
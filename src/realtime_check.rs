@@ -0,0 +1,105 @@
+//! Checks that audio callbacks don't allocate
+//!
+//! Allocating can take a lock inside the allocator, so a callback that
+//! allocates can miss its deadline and glitch. Build with the
+//! `realtime-check` feature to panic whenever a callback allocates;
+//! otherwise the checks compile to nothing.
+
+#[cfg(feature = "realtime-check")]
+pub use checked::*;
+
+#[cfg(not(feature = "realtime-check"))]
+pub struct CallbackGuard;
+
+/// Mark the current thread as running an audio callback until the guard drops
+#[cfg(not(feature = "realtime-check"))]
+#[inline(always)]
+pub fn enter_callback() -> CallbackGuard {
+    CallbackGuard
+}
+
+#[cfg(feature = "realtime-check")]
+mod checked {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Allocations so far in this thread's current callback, if it is in one
+        static CALLBACK_ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Counts allocations made while a thread is in an audio callback
+    pub struct CheckingAllocator;
+
+    impl CheckingAllocator {
+        fn count(&self) {
+            let _ = CALLBACK_ALLOCATIONS.try_with(|allocations| {
+                if let Some(count) = allocations.get() {
+                    allocations.set(Some(count + 1));
+                }
+            });
+        }
+    }
+
+    unsafe impl GlobalAlloc for CheckingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.count();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.count();
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.count();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CheckingAllocator = CheckingAllocator;
+
+    pub struct CallbackGuard;
+
+    /// Mark the current thread as running an audio callback until the guard drops
+    pub fn enter_callback() -> CallbackGuard {
+        CALLBACK_ALLOCATIONS.with(|allocations| allocations.set(Some(0)));
+        CallbackGuard
+    }
+
+    impl Drop for CallbackGuard {
+        fn drop(&mut self) {
+            let allocations = CALLBACK_ALLOCATIONS.with(|allocations| allocations.take());
+            if let Some(count @ 1..) = allocations {
+                if !std::thread::panicking() {
+                    panic!(
+                        "an audio callback allocated or freed memory {} times",
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn allows_callbacks_that_do_not_allocate() {
+            let mut buf = [0.0f32; 4];
+            let _guard = enter_callback();
+            buf[0] = 1.0;
+            std::hint::black_box(&buf);
+        }
+
+        #[test]
+        #[should_panic(expected = "an audio callback allocated")]
+        fn panics_when_a_callback_allocates() {
+            let _guard = enter_callback();
+            let _ = std::hint::black_box(vec![0.0f32; 4]);
+        }
+    }
+}
@@ -1,32 +1,80 @@
 use cpal::{
     self,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
+    traits::{DeviceTrait, StreamTrait},
 };
 use std::io;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
-use crate::audio::{Audio, AudioSpec};
-use crate::cpal_utils;
+use crate::audio::{Audio, AudioError, AudioSpec};
+use crate::cpal_utils::{self, Backend};
+use crate::markers::{self, Marker};
 use crate::power;
+use crate::realtime_check;
+use crate::ring_buffer::{ring_buffer, RingConsumer};
 
 /// Simple audio recording
 
 const NOISE_ANALYSIS_WINDOW_SIZE: Duration = Duration::from_millis(100);
 const NOISE_THRESHOLD_PERCENTILE: usize = 30;
+const INVERTED_POLARITY_CORRELATION: f32 = -0.5;
+/// Input queued between collector polls before samples are lost
+const INPUT_QUEUE: Duration = Duration::from_secs(1);
+const COLLECT_POLL: Duration = Duration::from_millis(50);
+const COLLECT_LEN: usize = 8192;
+pub const DEFAULT_EDGE_FADE: Duration = Duration::from_millis(5);
+/// One analysis window, since that's how late autocrop can find the start
+pub const DEFAULT_PRE_ROLL: Duration = NOISE_ANALYSIS_WINDOW_SIZE;
 
-pub fn record_audio(audio_spec: &AudioSpec) -> Audio {
-    // wait_for_enter_keypress("Press ENTER to start recording");
-    let host = cpal::default_host();
-    let (raw_samples_sender, raw_samples_receiver) = mpsc::channel::<f32>();
+/// How a recording is cropped and cleaned up before it's stretched
+///
+/// Cropping cuts the waveform wherever an analysis window falls, and a
+/// stretch turns the step at each end into a loud click.
+#[derive(Debug, Clone, Copy)]
+pub struct Conditioning {
+    /// Keep this much before where the sound was found to start, since
+    /// finding it lags the attack
+    pub pre_roll: Duration,
+    /// Fade each end in or out over this long
+    pub edge_fade: Duration,
+    /// Subtract each channel's mean, so the fades don't leave a thump
+    pub remove_dc: bool,
+    /// Drop everything before the first onset, so the stretch starts on
+    /// the attack rather than the breath or rustle before it
+    pub trim_to_onset: bool,
+}
 
-    let input_device = host
-        .default_input_device()
-        .expect("failed to get default input device");
-    info!(
-        "Using default input device: \"{}\"",
-        input_device.name().unwrap()
+impl Default for Conditioning {
+    fn default() -> Conditioning {
+        Conditioning {
+            pre_roll: DEFAULT_PRE_ROLL,
+            edge_fade: DEFAULT_EDGE_FADE,
+            remove_dc: true,
+            trim_to_onset: false,
+        }
+    }
+}
+
+pub fn record_audio(
+    audio_spec: &AudioSpec,
+    backend: &Backend,
+    conditioning: &Conditioning,
+) -> Audio {
+    // wait_for_enter_keypress("Press ENTER to start recording");
+    let (mut producer, consumer) = ring_buffer(
+        (audio_spec.sample_rate as f32 * INPUT_QUEUE.as_secs_f32()) as usize
+            * audio_spec.channels as usize,
     );
+    let stop_collecting = Arc::new(AtomicBool::new(false));
+    let collector = {
+        let stop = Arc::clone(&stop_collecting);
+        thread::spawn(move || collect_raw_samples(consumer, stop))
+    };
+
+    let input_device = backend.input_device().expect("failed to get input device");
+    info!("Using input device: \"{}\"", input_device.name().unwrap());
 
     let supported_configs = input_device
         .supported_input_configs()
@@ -42,15 +90,8 @@ pub fn record_audio(audio_spec: &AudioSpec) -> Audio {
         .build_input_stream(
             &stream_config,
             move |data: &[f32], &_: &cpal::InputCallbackInfo| {
-                // react to stream events and read or write stream data here.
-                for sample in data.iter() {
-                    match raw_samples_sender.send(*sample) {
-                        Err(e) => {
-                            error!("failed to send recorded sample: {}", e);
-                        }
-                        _ => (),
-                    }
-                }
+                let _guard = realtime_check::enter_callback();
+                producer.push(data);
             },
             move |err| {
                 panic!("audio input stream failed: {:?}", err);
@@ -60,20 +101,82 @@ pub fn record_audio(audio_spec: &AudioSpec) -> Audio {
     input_stream.play().expect("failed to start input stream");
 
     wait_for_enter_keypress("Press ENTER to finish recording");
-    let mut audio = collect_samples(audio_spec, raw_samples_receiver);
+    drop(input_stream);
+    stop_collecting.store(true, Ordering::Relaxed);
+    let raw_samples = collector.join().expect("sample collector panicked");
+    let mut audio = collect_samples(audio_spec, &raw_samples);
     auto_split_mono(&mut audio);
+    log_channel_correlation(&audio);
     autocrop_audio(
         &mut audio,
         NOISE_ANALYSIS_WINDOW_SIZE,
         NOISE_THRESHOLD_PERCENTILE,
-    );
-    drop(input_stream);
+        conditioning.pre_roll,
+    )
+    .expect("autocrop points fall within the recording");
+    condition(&mut audio, conditioning).expect("conditioning stays within the recording");
     audio
 }
 
-fn collect_samples(spec: &AudioSpec, raw_samples_receiver: mpsc::Receiver<f32>) -> Audio {
+/// Clean up the ends of a captured recording as `conditioning` says
+pub fn condition(audio: &mut Audio, conditioning: &Conditioning) -> Result<(), AudioError> {
+    if conditioning.remove_dc {
+        remove_dc(audio);
+    }
+    if conditioning.trim_to_onset {
+        if let Some(&onset) = markers::find_onsets(audio).first() {
+            let start = onset.saturating_sub(audio.duration_to_sample(conditioning.pre_roll));
+            info!(
+                "Trimming {:?} before the first onset",
+                audio.sample_to_duration(start)
+            );
+            audio.clip_to_samples(start, audio.len())?;
+        }
+    }
+    let len = audio.len();
+    let fade = audio
+        .duration_to_sample(conditioning.edge_fade)
+        .min(len / 2);
+    audio.fade_in_at_sample(0, fade)?;
+    audio.fade_out_at_sample(len - fade, fade)
+}
+
+fn remove_dc(audio: &mut Audio) {
+    for channel in audio.data.iter_mut() {
+        if channel.is_empty() {
+            continue;
+        }
+        let mean = channel.iter().sum::<f32>() / channel.len() as f32;
+        for sample in channel.iter_mut() {
+            *sample -= mean;
+        }
+    }
+}
+
+/// Drain interleaved samples from the input callback until told to stop
+fn collect_raw_samples(mut queue: RingConsumer, stop: Arc<AtomicBool>) -> Vec<f32> {
+    let mut samples = vec![];
+    let mut buf = vec![0.0; COLLECT_LEN];
+    loop {
+        // Check before draining so nothing queued before the stop is missed
+        let stopping = stop.load(Ordering::Relaxed);
+        loop {
+            let count = queue.pop(&mut buf);
+            if count == 0 {
+                break;
+            }
+            samples.extend_from_slice(&buf[..count]);
+        }
+        if stopping {
+            return samples;
+        }
+        thread::sleep(COLLECT_POLL);
+    }
+}
+
+fn collect_samples(spec: &AudioSpec, raw_samples: &[f32]) -> Audio {
     let mut audio = Audio::from_spec(&spec);
-    for (i, sample) in raw_samples_receiver.try_iter().enumerate() {
+    for (i, sample) in raw_samples.iter().copied().enumerate() {
         audio.data[i % spec.channels as usize].push(sample);
     }
     audio
@@ -142,23 +245,45 @@ fn auto_split_mono(audio: &mut Audio) {
     }
 }
 
+/// Report how closely the first two channels match, warning if they look polarity-inverted
+fn log_channel_correlation(audio: &Audio) {
+    if audio.data.len() < 2 {
+        return;
+    }
+    let correlation = power::correlation(&audio.data[0], &audio.data[1]);
+    info!("Input channel correlation: {:.2}", correlation);
+    if correlation < INVERTED_POLARITY_CORRELATION {
+        warn!("Input channels look polarity-inverted; check wiring or use --invert-polarity");
+    }
+}
+
 /// Analyze audio to determine when the recording subject begins and ends,
-/// and crop to fit it
-fn autocrop_audio(audio: &mut Audio, analysis_window: Duration, threshold_percentile: usize) {
+/// and crop to fit it, marking the subject as a "subject" region
+///
+/// `pre_roll` more is kept before the subject, which is left out of the
+/// region.
+fn autocrop_audio(
+    audio: &mut Audio,
+    analysis_window: Duration,
+    threshold_percentile: usize,
+    pre_roll: Duration,
+) -> Result<(), AudioError> {
     let amplitudes = chunked_audio_power(&audio, analysis_window);
     let autocrop_points = determine_autocrop_points(&amplitudes, threshold_percentile);
     if autocrop_points.is_none() {
-        return;
+        return Ok(());
     }
-    let (start, end) = autocrop_points.unwrap();
-    let start_time = audio.sample_to_duration(start);
-    let clip_dur = audio.sample_to_duration(end - start);
+    let (subject_start, end) = autocrop_points.unwrap();
+    audio
+        .markers
+        .push(Marker::region("subject", subject_start, end));
+    let start = subject_start.saturating_sub(audio.duration_to_sample(pre_roll));
     info!(
         "autocropping audio to start {:?} later and end {:?} earlier",
-        start_time,
-        audio.sample_to_duration(audio.data[0].len() - end)
+        audio.sample_to_duration(start),
+        audio.sample_to_duration(audio.len() - end)
     );
-    audio.clip_in_place(Some(start_time), Some(clip_dur));
+    audio.clip_to_samples(start, end)
 }
 
 fn determine_noise_threshold(amplitudes: &Vec<(usize, f32)>, threshold_percentile: usize) -> f32 {
@@ -257,15 +382,53 @@ mod test {
         assert_eq!(determine_autocrop_points(&amplitudes, 10), None);
     }
 
+    #[test]
+    fn test_condition() {
+        let mut audio = generate_audio(0.0, 4000, 1, 1000);
+        // A DC offset, then a jump in level half way through
+        for (i, sample) in audio.data[0].iter_mut().enumerate() {
+            *sample =
+                0.1 + if i >= 2000 { 0.5 } else { 0.001 } * if i % 2 == 0 { 1.0 } else { -1.0 };
+        }
+        condition(
+            &mut audio,
+            &Conditioning {
+                pre_roll: Duration::ZERO,
+                trim_to_onset: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(audio.data[0].len(), 2000);
+        assert_eq!(audio.data[0][0], 0.0);
+        assert!(audio.data[0][5..100].iter().all(|s| s.abs() > 0.4));
+        assert!(audio.data[0][1999].abs() < 0.25);
+    }
+
     #[test]
     fn test_autocrop_audio() {
         let mut audio = generate_audio(0.0, 5, 2, 1);
         audio.data[0] = vec![0.0, 1.0, 0.1, -1.0, 0.0];
         audio.data[1] = vec![0.0, -1.0, -0.1, 0.7, 0.0];
-        autocrop_audio(&mut audio, Duration::from_secs(1), 20);
+        autocrop_audio(&mut audio, Duration::from_secs(1), 20, Duration::ZERO).unwrap();
         assert_eq!(audio.data[0].len(), 3);
         assert_eq!(audio.data[1].len(), 3);
         assert_almost_eq_by_element(audio.data[0].clone(), vec![1.0, 0.1, -1.0]);
         assert_almost_eq_by_element(audio.data[1].clone(), vec![-1.0, -0.1, 0.7]);
     }
+
+    #[test]
+    fn test_autocrop_audio_keeps_pre_roll() {
+        let mut audio = generate_audio(0.0, 6, 1, 1);
+        audio.data[0] = vec![0.0, 0.01, 0.0, 1.0, 0.5, 0.0];
+        autocrop_audio(
+            &mut audio,
+            Duration::from_secs(1),
+            60,
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert_almost_eq_by_element(audio.data[0].clone(), vec![0.01, 0.0, 1.0, 0.5]);
+        assert_eq!(audio.markers, vec![Marker::region("subject", 2, 4)]);
+    }
 }
@@ -1,24 +1,33 @@
-use crate::audio::{AudioBus, AudioSpec};
-use crate::cpal_utils;
-use crate::signal_flow::node::{ControlMessage, Processor, ProcessorState};
+use crate::audio::{AudioBus, AudioSpec, BusPolicy, BusSender, SharedAudio, Timestamp};
+use crate::cpal_utils::{self, Backend};
+use crate::metrics;
+use crate::realtime_check;
+use crate::ring_buffer::{ring_buffer, RingConsumer};
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use cpal::{
     self,
-    traits::{DeviceTrait, HostTrait, StreamTrait},
+    traits::{DeviceTrait, StreamTrait},
 };
 use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const RECORDER_POLL: Duration = Duration::from_millis(100);
+/// Input queued for the recorder thread between polls before samples are lost
+const INPUT_QUEUE: Duration = Duration::from_secs(1);
+const DRAIN_FRAMES: usize = 4096;
 
 #[derive(Debug)]
 pub enum RecorderProcessorControlMessage {
     Shutdown,
+    /// Discard input until `Resume`, keeping the input device open
+    Pause,
+    Resume,
 }
 
 impl ControlMessage for RecorderProcessorControlMessage {
@@ -30,56 +39,116 @@ impl ControlMessage for RecorderProcessorControlMessage {
 pub struct RecorderProcessor {
     spec: AudioSpec,
     finished: Arc<AtomicBool>,
-    channel_senders: Vec<Sender<Vec<f32>>>,
+    paused: Arc<AtomicBool>,
+    output: BusSender,
+    backend: Backend,
+    replay: Option<Replay>,
+}
+
+/// Audio played into the recorder's bus in place of the input device
+struct Replay {
+    audio: SharedAudio,
+    speed: f32,
 }
 
 impl RecorderProcessor {
-    pub fn new(spec: AudioSpec) -> (RecorderProcessor, AudioBus) {
-        let (bus, channel_senders) = AudioBus::from_spec(spec, None);
+    pub fn new(spec: AudioSpec, policy: BusPolicy) -> (RecorderProcessor, AudioBus) {
+        let (bus, output) = AudioBus::bounded(spec, None, policy);
         (
             RecorderProcessor {
                 spec,
-                channel_senders,
+                output,
                 finished: Arc::new(AtomicBool::new(false)),
+                paused: Arc::new(AtomicBool::new(false)),
+                backend: Backend::Default,
+                replay: None,
             },
             bus,
         )
     }
 
-    fn run(mut self, ctrl_rx: Receiver<RecorderProcessorControlMessage>) -> Result<()> {
-        let host = cpal::default_host();
-        let input_device = host
-            .default_input_device()
-            .expect("failed to get default input device");
-        info!(
-            "Using default input device: \"{}\"",
-            input_device.name().unwrap()
-        );
+    /// Record through `backend` rather than the default input device
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Instead of recording, send `audio` as though it were arriving from
+    /// the input device, `speed` times faster than realtime, finishing at its
+    /// end. For tuning and testing whatever listens to the recorder without a
+    /// microphone. Shared audio is read in place rather than copied.
+    pub fn with_replay(mut self, audio: impl Into<SharedAudio>, speed: f32) -> Self {
+        self.replay = Some(Replay {
+            audio: audio.into(),
+            speed,
+        });
+        self
+    }
+
+    fn run(
+        mut self,
+        ctrl_rx: Receiver<RecorderProcessorControlMessage>,
+        errors: Sender<NodeError>,
+    ) -> Result<()> {
+        if let Some(replay) = self.replay.take() {
+            return self.run_replay(replay, ctrl_rx);
+        }
+        if let Backend::Virtual(virtual_audio) = &self.backend {
+            let replay = Replay {
+                audio: virtual_audio.input()?,
+                speed: virtual_audio.speed(),
+            };
+            return self.run_replay(replay, ctrl_rx);
+        }
+        let input_device = self.backend.input_device()?;
+        info!("Using input device: \"{}\"", input_device.name()?);
 
         let supported_configs = input_device
             .supported_input_configs()
-            .expect("failed to query input device configs");
+            .context("failed to query input device configs")?;
         let stream_config = cpal_utils::find_input_stream_config(
             supported_configs,
             self.spec.channels,
             self.spec.sample_rate,
         )?;
 
-        let channel_senders = self.channel_senders.clone();
+        // The callback only copies into a ring buffer, so it never locks or
+        // allocates; this thread splits the queued samples into chunks.
+        let channels = self.spec.channels as usize;
+        let (mut producer, mut consumer) = ring_buffer(
+            (self.spec.sample_rate as f32 * INPUT_QUEUE.as_secs_f32()) as usize * channels,
+        );
+        let mut drain_buf = vec![0.0; DRAIN_FRAMES * channels];
+        let overruns = metrics::registry().counter(
+            "rocoder_input_overruns_total",
+            &[],
+            "Times recorded audio was lost because the recorder fell behind",
+        );
+        let paused = Arc::clone(&self.paused);
 
         let input_stream = input_device
             .build_input_stream(
                 &stream_config,
                 move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                    // react to stream events and read or write stream data here.
-                    send_samples_from_raw_input(data, self.spec.channels, &channel_senders)
+                    let _guard = realtime_check::enter_callback();
+                    if paused.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    if producer.push(data) < data.len() {
+                        overruns.inc();
+                    }
                 },
                 move |err| {
-                    panic!("audio input stream failed: {:?}", err);
+                    let _ = errors.send(NodeError::Failed(anyhow!(
+                        "audio input stream failed: {:?}",
+                        err
+                    )));
                 },
             )
-            .expect("failed to build input stream");
-        input_stream.play().expect("failed to start input stream");
+            .context("failed to build input stream")?;
+        input_stream
+            .play()
+            .context("failed to start input stream")?;
         loop {
             if self.finished.load(Ordering::Relaxed) {
                 break;
@@ -91,16 +160,111 @@ impl RecorderProcessor {
                 _ => {}
             }
             thread::sleep(RECORDER_POLL);
+            if !send_queued_samples(&mut consumer, &mut drain_buf, self.spec, &mut self.output) {
+                info!("recorder output disconnected, stopping");
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_replay(
+        mut self,
+        replay: Replay,
+        ctrl_rx: Receiver<RecorderProcessorControlMessage>,
+    ) -> Result<()> {
+        let Replay { audio, speed } = replay;
+        if audio.spec != self.spec {
+            bail!(
+                "can't replay {} channels at {} Hz into a recorder of {} channels at {} Hz",
+                audio.spec.channels,
+                audio.spec.sample_rate,
+                self.spec.channels,
+                self.spec.sample_rate
+            );
+        }
+        if !(speed > 0.0 && speed.is_finite()) {
+            bail!("replay speed must be more than 0");
+        }
+        info!("Replaying {:?} of audio at {}x", audio.duration(), speed);
+        let frames_per_sec = self.spec.sample_rate as f64 * speed as f64;
+        let total_frames = audio.data[0].len();
+        let mut position = 0;
+        // Fractions of a frame due but not yet sent, so no time is lost between polls
+        let mut owed = 0.0;
+        let mut last_poll = Instant::now();
+        let mut buf = Vec::with_capacity(DRAIN_FRAMES * self.spec.channels as usize);
+        while position < total_frames && !self.finished.load(Ordering::Relaxed) {
+            if let ProcessorState::Finished = self.handle_control_messages(&ctrl_rx)? {
+                break;
+            }
+            thread::sleep(RECORDER_POLL);
+            let now = Instant::now();
+            let elapsed = now - last_poll;
+            last_poll = now;
+            // Paused time is skipped over like a paused device's input
+            if self.paused.load(Ordering::Relaxed) {
+                continue;
+            }
+            owed += elapsed.as_secs_f64() * frames_per_sec;
+            let end = (position + owed as usize).min(total_frames);
+            owed = owed.fract();
+            for start in (position..end).step_by(DRAIN_FRAMES) {
+                let chunk_end = (start + DRAIN_FRAMES).min(end);
+                buf.clear();
+                for i in start..chunk_end {
+                    buf.extend(audio.data.iter().map(|channel| channel[i]));
+                }
+                // As if the chunk had been waiting since it would have been captured
+                let timestamp = Timestamp {
+                    sample: self.output.frames_sent(),
+                    at: now - Duration::from_secs_f64((end - start) as f64 / frames_per_sec),
+                };
+                self.output.send_timestamp(timestamp);
+                if !send_samples_from_raw_input(&buf, self.spec.channels, &mut self.output) {
+                    info!("recorder output disconnected, stopping");
+                    return Ok(());
+                }
+            }
+            position = end;
         }
         Ok(())
     }
 }
 
-fn send_samples_from_raw_input(
-    buf: &[f32],
-    n_channels: u16,
-    channel_senders: &Vec<Sender<Vec<f32>>>,
-) {
+fn send_queued_samples(
+    queue: &mut RingConsumer,
+    buf: &mut [f32],
+    spec: AudioSpec,
+    output: &mut BusSender,
+) -> bool {
+    let n_channels = spec.channels;
+    loop {
+        let queued = queue.len();
+        let len = queued.min(buf.len());
+        // Whole frames only, so the channels stay aligned
+        let len = len - len % n_channels as usize;
+        if len == 0 {
+            return true;
+        }
+        // The oldest frame queued was captured about as long ago as the
+        // queue lasts
+        let queued_dur = Duration::from_secs_f32(
+            (queued / n_channels as usize) as f32 / spec.sample_rate as f32,
+        );
+        let timestamp = Timestamp {
+            sample: output.frames_sent(),
+            at: Instant::now() - queued_dur,
+        };
+        output.send_timestamp(timestamp);
+        queue.pop(&mut buf[..len]);
+        if !send_samples_from_raw_input(&buf[..len], n_channels, output) {
+            return false;
+        }
+    }
+}
+
+fn send_samples_from_raw_input(buf: &[f32], n_channels: u16, output: &mut BusSender) -> bool {
     // optimisation opportunity here by creating inner vecs with capacities
     let mut channels: Vec<Vec<f32>> = (0..n_channels).map(|_| vec![]).collect();
     for buffer_interleaved_samples in buf.chunks(n_channels as usize) {
@@ -112,11 +276,7 @@ fn send_samples_from_raw_input(
             }
         }
     }
-    for (i, channel) in channels.into_iter().enumerate() {
-        unsafe {
-            channel_senders.get_unchecked(i).send(channel).unwrap();
-        }
-    }
+    output.send_frame(channels)
 }
 
 impl Processor<RecorderProcessorControlMessage> for RecorderProcessor {
@@ -130,6 +290,14 @@ impl Processor<RecorderProcessorControlMessage> for RecorderProcessor {
                     self.finished.store(true, Ordering::Relaxed);
                     Ok(ProcessorState::Finished)
                 }
+                RecorderProcessorControlMessage::Pause => {
+                    self.paused.store(true, Ordering::Relaxed);
+                    Ok(ProcessorState::Running)
+                }
+                RecorderProcessorControlMessage::Resume => {
+                    self.paused.store(false, Ordering::Relaxed);
+                    Ok(ProcessorState::Running)
+                }
             },
             Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
             Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
@@ -139,12 +307,64 @@ impl Processor<RecorderProcessorControlMessage> for RecorderProcessor {
     fn start(
         self,
         finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
     ) -> (Sender<RecorderProcessorControlMessage>, JoinHandle<()>) {
         let (ctrl_tx, ctrl_rx) = unbounded();
         let handle = thread::spawn(move || {
-            self.run(ctrl_rx).unwrap();
+            if let Err(e) = self.run(ctrl_rx, errors.clone()) {
+                let _ = errors.send(NodeError::Failed(e));
+            }
             finished.store(true, Ordering::Relaxed);
         });
         (ctrl_tx, handle)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::Audio;
+    use crate::signal_flow::node::Node;
+
+    #[test]
+    fn replay_sends_the_audio_through_the_bus() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 44100,
+        };
+        let audio = Audio {
+            data: vec![
+                (0..10000).map(|i| i as f32 / 10000.0).collect(),
+                (0..10000).map(|i| -(i as f32) / 10000.0).collect(),
+            ],
+            spec,
+            markers: vec![],
+        };
+        let (recorder, bus) = RecorderProcessor::new(spec, BusPolicy::UNBOUNDED);
+        let node = Node::new(recorder.with_replay(audio.clone(), 100.0));
+        let replayed = bus.into_audio();
+        node.join().unwrap();
+        assert_eq!(replayed.data, audio.data);
+    }
+
+    #[test]
+    fn replay_rejects_a_mismatched_spec() {
+        let (recorder, _bus) = RecorderProcessor::new(
+            AudioSpec {
+                channels: 1,
+                sample_rate: 44100,
+            },
+            BusPolicy::UNBOUNDED,
+        );
+        let audio = Audio {
+            data: vec![vec![0.0; 10]; 2],
+            spec: AudioSpec {
+                channels: 2,
+                sample_rate: 44100,
+            },
+            markers: vec![],
+        };
+        let node = Node::new(recorder.with_replay(audio, 1.0));
+        assert!(node.join().is_err());
+    }
+}
@@ -20,7 +20,7 @@ fn resample_faster(samples: &[f32], factor: usize) -> Vec<f32> {
 fn resample_slower(samples: &[f32], factor: usize) -> Vec<f32> {
     debug_assert!(factor > 1);
     let mut result = Vec::with_capacity(samples.len() * factor);
-    for i in 0..samples.len() - 1 {
+    for i in 0..samples.len().saturating_sub(1) {
         let current_src_sample = samples[i];
         let next_src_sample = samples[i + 1];
         for j in 0..factor {
@@ -34,10 +34,69 @@ fn resample_slower(samples: &[f32], factor: usize) -> Vec<f32> {
     result
 }
 
+/// Resample between arbitrary sample rates by linear interpolation
+pub fn resample_to_rate(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = from_rate as f64 / to_rate as f64;
+    let len = ((samples.len() - 1) as f64 / step) as usize + 1;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let index = pos as usize;
+            let next = samples[(index + 1).min(samples.len() - 1)];
+            lerp(samples[index], next, pos.fract() as f32)
+        })
+        .collect()
+}
+
+/// Converts interleaved audio pulled from a source at one sample rate to
+/// another as it is needed, by linear interpolation
+pub struct StreamingResampler {
+    channels: usize,
+    /// Source frames per output frame
+    step: f64,
+    /// Position between `current` and `next`, in source frames
+    pos: f64,
+    current: Vec<f32>,
+    next: Vec<f32>,
+}
+
+impl StreamingResampler {
+    pub fn new(channels: u16, from_rate: u32, to_rate: u32) -> Self {
+        StreamingResampler {
+            channels: channels as usize,
+            step: from_rate as f64 / to_rate as f64,
+            // Pull two frames before the first output frame
+            pos: 2.0,
+            current: vec![0.0; channels as usize],
+            next: vec![0.0; channels as usize],
+        }
+    }
+
+    /// Fill `out` with interleaved frames, calling `pull` to fill one source
+    /// frame at a time whenever more are needed
+    pub fn fill(&mut self, out: &mut [f32], mut pull: impl FnMut(&mut [f32])) {
+        for frame in out.chunks_mut(self.channels) {
+            while self.pos >= 1.0 {
+                std::mem::swap(&mut self.current, &mut self.next);
+                pull(&mut self.next);
+                self.pos -= 1.0;
+            }
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = lerp(self.current[channel], self.next[channel], self.pos as f32);
+            }
+            self.pos += self.step;
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_utils::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_resample_noop() {
@@ -52,4 +111,66 @@ mod test {
             vec![1.0, 3.0],
         );
     }
+
+    #[test]
+    fn test_resample_to_rate() {
+        let v = vec![0.0, 1.0, 2.0];
+        assert_almost_eq_by_element(resample_to_rate(&v, 10, 10), v.clone());
+        assert_almost_eq_by_element(resample_to_rate(&v, 10, 20), vec![0.0, 0.5, 1.0, 1.5, 2.0]);
+        assert_almost_eq_by_element(resample_to_rate(&v, 20, 10), vec![0.0, 2.0]);
+    }
+
+    #[test]
+    fn streaming_resampler_matches_resample_to_rate() {
+        // Stereo, with the right channel negated
+        let source: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        let mut frames = source.iter();
+        let mut resampler = StreamingResampler::new(2, 10, 20);
+        let mut out = [0.0; 10];
+        let mut pull = |frame: &mut [f32]| {
+            let sample = *frames.next().unwrap();
+            frame.copy_from_slice(&[sample, -sample]);
+        };
+        // Fill across two calls to check the position carries over
+        resampler.fill(&mut out[..4], &mut pull);
+        resampler.fill(&mut out[4..], &mut pull);
+        let left: Vec<f32> = out.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = out.iter().skip(1).step_by(2).copied().collect();
+        let expected = resample_to_rate(&source[..3], 10, 20);
+        assert_almost_eq_by_element(left, expected.clone());
+        assert_almost_eq_by_element(right, expected.iter().map(|s| -s).collect());
+    }
+
+    proptest! {
+        #[test]
+        fn resample_stays_within_the_input(
+            samples in prop::collection::vec(-1.0f32..1.0, 0..500),
+            factor in prop::sample::select(vec![-4i8, -3, -2, 1, 2, 3, 4]),
+        ) {
+            let resampled = resample(&samples, factor);
+            let expected_len = match factor {
+                1 => samples.len(),
+                f if f > 1 => samples.len().div_ceil(f as usize),
+                f => samples.len().saturating_sub(1) * f.unsigned_abs() as usize,
+            };
+            prop_assert_eq!(resampled.len(), expected_len);
+            for sample in resampled {
+                prop_assert!(sample.is_finite() && sample.abs() <= 1.0);
+            }
+        }
+
+        #[test]
+        fn resample_to_rate_spans_the_same_time(
+            samples in prop::collection::vec(-1.0f32..1.0, 1..500),
+            from_rate in 1000u32..96000,
+            to_rate in 1000u32..96000,
+        ) {
+            let resampled = resample_to_rate(&samples, from_rate, to_rate);
+            let expected_len = (samples.len() - 1) as f64 * to_rate as f64 / from_rate as f64;
+            prop_assert!((resampled.len() as f64 - 1.0 - expected_len).abs() <= 1.0);
+            for sample in resampled {
+                prop_assert!(sample.is_finite() && sample.abs() <= 1.0);
+            }
+        }
+    }
 }
@@ -0,0 +1,230 @@
+use crate::audio::{Audio, AudioSpec};
+use crate::audio_files::{AudioReader, WavReader};
+use crate::effects::TimeDomainEffect;
+use crate::resampler;
+use anyhow::{bail, Result};
+use rustfft::num_complex::Complex32;
+use rustfft::{Fft, FftPlanner};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+pub const DEFAULT_BLOCK_LEN: usize = 1024;
+
+/// Convolution reverb using uniformly partitioned overlap-save convolution
+///
+/// The impulse response is split into blocks, so the cost per sample stays
+/// low even for long responses. Output, both wet and dry, is delayed by
+/// one block.
+pub struct ConvolutionReverb {
+    block_len: usize,
+    forward_fft: Arc<dyn Fft<f32>>,
+    inverse_fft: Arc<dyn Fft<f32>>,
+    mix: f32,
+    channels: Vec<ReverbChannel>,
+}
+
+struct ReverbChannel {
+    /// Spectrum of each block of the impulse response, zero padded to two blocks
+    partitions: Vec<Vec<Complex32>>,
+    /// Spectra of the most recent input blocks, newest first
+    history: VecDeque<Vec<Complex32>>,
+    /// The previous input block followed by the block being filled
+    input: Vec<f32>,
+    filled: usize,
+    output: VecDeque<f32>,
+}
+
+impl ConvolutionReverb {
+    /// `mix` is the wet level from 0 (dry only) to 1 (reverb only)
+    ///
+    /// Channels of the output use the impulse response channel with the same
+    /// index, wrapping around, so a mono response is used for every channel.
+    pub fn new(
+        spec: &AudioSpec,
+        impulse_response: &Audio,
+        mix: f32,
+        block_len: usize,
+    ) -> Result<ConvolutionReverb> {
+        if impulse_response.data.is_empty() || impulse_response.data[0].is_empty() {
+            bail!("impulse response is empty");
+        }
+        let mut planner = FftPlanner::new();
+        let forward_fft = planner.plan_fft_forward(block_len * 2);
+        let inverse_fft = planner.plan_fft_inverse(block_len * 2);
+        let responses: Vec<Vec<f32>> = impulse_response
+            .data
+            .iter()
+            .map(|channel| {
+                resampler::resample_to_rate(
+                    channel,
+                    impulse_response.spec.sample_rate,
+                    spec.sample_rate,
+                )
+            })
+            .collect();
+        // normalize to unit energy so that responses recorded at any level sound similar
+        let energy = responses
+            .iter()
+            .map(|channel| channel.iter().map(|s| s * s).sum::<f32>())
+            .fold(0.0, f32::max);
+        if energy == 0.0 {
+            bail!("impulse response is silent");
+        }
+        let scale = 1.0 / energy.sqrt();
+        let channels = (0..spec.channels as usize)
+            .map(|i| {
+                let response = &responses[i % responses.len()];
+                let partitions: Vec<Vec<Complex32>> = response
+                    .chunks(block_len)
+                    .map(|chunk| {
+                        let mut buf: Vec<Complex32> = chunk
+                            .iter()
+                            .map(|s| Complex32::new(s * scale, 0.0))
+                            .collect();
+                        buf.resize(block_len * 2, Complex32::new(0.0, 0.0));
+                        forward_fft.process(&mut buf);
+                        buf
+                    })
+                    .collect();
+                ReverbChannel {
+                    history: VecDeque::from(vec![
+                        vec![Complex32::new(0.0, 0.0); block_len * 2];
+                        partitions.len()
+                    ]),
+                    partitions,
+                    input: vec![0.0; block_len * 2],
+                    filled: 0,
+                    output: VecDeque::from(vec![0.0; block_len]),
+                }
+            })
+            .collect();
+        Ok(ConvolutionReverb {
+            block_len,
+            forward_fft,
+            inverse_fft,
+            mix,
+            channels,
+        })
+    }
+
+    pub fn from_file(path: &str, spec: &AudioSpec, mix: f32) -> Result<ConvolutionReverb> {
+        let impulse_response = WavReader::open(path)?.read_all();
+        ConvolutionReverb::new(spec, &impulse_response, mix, DEFAULT_BLOCK_LEN)
+    }
+}
+
+impl ReverbChannel {
+    fn process_block(&mut self, forward_fft: &dyn Fft<f32>, inverse_fft: &dyn Fft<f32>, mix: f32) {
+        let block_len = self.input.len() / 2;
+        let mut spectrum: Vec<Complex32> =
+            self.input.iter().map(|s| Complex32::new(*s, 0.0)).collect();
+        forward_fft.process(&mut spectrum);
+        self.history.pop_back();
+        self.history.push_front(spectrum);
+
+        let mut sum = vec![Complex32::new(0.0, 0.0); block_len * 2];
+        for (input, partition) in self.history.iter().zip(&self.partitions) {
+            for ((sum, x), h) in sum.iter_mut().zip(input).zip(partition) {
+                *sum += x * h;
+            }
+        }
+        inverse_fft.process(&mut sum);
+
+        // the first half wraps around circularly, so only the second half is valid
+        let norm = 1.0 / (block_len * 2) as f32;
+        for (dry, wet) in self.input[block_len..].iter().zip(&sum[block_len..]) {
+            self.output
+                .push_back(dry * (1.0 - mix) + wet.re * norm * mix);
+        }
+        self.input.copy_within(block_len.., 0);
+        self.filled = 0;
+    }
+}
+
+impl TimeDomainEffect for ConvolutionReverb {
+    fn process(&mut self, channel: usize, samples: &mut [f32]) {
+        let channel = &mut self.channels[channel];
+        for sample in samples.iter_mut() {
+            channel.input[self.block_len + channel.filled] = *sample;
+            channel.filled += 1;
+            if channel.filled == self.block_len {
+                channel.process_block(&*self.forward_fft, &*self.inverse_fft, self.mix);
+            }
+            *sample = channel.output.pop_front().unwrap_or(0.0);
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.block_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn spec(channels: u16) -> AudioSpec {
+        AudioSpec {
+            channels,
+            sample_rate: 44100,
+        }
+    }
+
+    fn direct_convolution(input: &[f32], response: &[f32]) -> Vec<f32> {
+        (0..input.len())
+            .map(|n| {
+                (0..response.len())
+                    .filter(|k| *k <= n)
+                    .map(|k| input[n - k] * response[k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        // unit energy, so normalization doesn't change it
+        let response = vec![0.6, 0.0, 0.0, 0.0, 0.0, 0.48, 0.0, 0.0, 0.0, 0.0, 0.64];
+        let impulse_response = Audio {
+            data: vec![response.clone()],
+            spec: spec(1),
+            markers: vec![],
+        };
+        let mut reverb = ConvolutionReverb::new(&spec(1), &impulse_response, 1.0, 4).unwrap();
+        let input: Vec<f32> = (0..40).map(|i| ((i * 7) % 5) as f32 - 2.0).collect();
+        let mut output = vec![];
+        // uneven chunks to exercise partially filled blocks
+        for chunk in input.chunks(3) {
+            let mut chunk = chunk.to_vec();
+            reverb.process(0, &mut chunk);
+            output.extend(chunk);
+        }
+        let expected = direct_convolution(&input, &response);
+        assert_almost_eq_by_element(output[4..].to_vec(), expected[..36].to_vec());
+    }
+
+    #[test]
+    fn dry_signal_is_delayed_by_one_block() {
+        let impulse_response = Audio {
+            data: vec![vec![1.0]],
+            spec: spec(1),
+            markers: vec![],
+        };
+        let mut reverb = ConvolutionReverb::new(&spec(2), &impulse_response, 0.0, 2).unwrap();
+        let mut samples = vec![1.0, 2.0, 3.0, 4.0];
+        reverb.process(1, &mut samples);
+        assert_almost_eq_by_element(samples, vec![0.0, 0.0, 1.0, 2.0]);
+        assert_eq!(reverb.latency(), 2);
+    }
+
+    #[test]
+    fn silent_response_is_rejected() {
+        let impulse_response = Audio {
+            data: vec![vec![0.0; 8]],
+            spec: spec(1),
+            markers: vec![],
+        };
+        assert!(ConvolutionReverb::new(&spec(1), &impulse_response, 0.5, 4).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct Shared {
+    /// Samples stored as their bits, so both ends can touch them without locks
+    samples: Box<[AtomicU32]>,
+    /// Total samples ever written; only the producer changes it
+    written: AtomicUsize,
+    /// Total samples ever read; only the consumer changes it
+    read: AtomicUsize,
+}
+
+impl Shared {
+    fn len(&self) -> usize {
+        self.written
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+/// A fixed-size queue of samples between one producer and one consumer,
+/// which never locks or allocates once created, for handing audio to and
+/// from device callbacks
+pub fn ring_buffer(capacity: usize) -> (RingProducer, RingConsumer) {
+    let shared = Arc::new(Shared {
+        samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+    (
+        RingProducer {
+            shared: Arc::clone(&shared),
+        },
+        RingConsumer { shared },
+    )
+}
+
+pub struct RingProducer {
+    shared: Arc<Shared>,
+}
+
+impl RingProducer {
+    /// Room for this many more samples
+    pub fn free_len(&self) -> usize {
+        self.shared.samples.len() - self.shared.len()
+    }
+
+    /// Write as many of `samples` as fit, returning how many were written
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let capacity = self.shared.samples.len();
+        let written = self.shared.written.load(Ordering::Relaxed);
+        let count = samples.len().min(self.free_len());
+        for (i, sample) in samples[..count].iter().enumerate() {
+            self.shared.samples[written.wrapping_add(i) % capacity]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.shared
+            .written
+            .store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Whether the consumer has been dropped
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+pub struct RingConsumer {
+    shared: Arc<Shared>,
+}
+
+impl RingConsumer {
+    /// Samples waiting to be read
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fill `out` with as many waiting samples as there are room for,
+    /// returning how many were read
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let capacity = self.shared.samples.len();
+        let read = self.shared.read.load(Ordering::Relaxed);
+        let count = out.len().min(self.len());
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                self.shared.samples[read.wrapping_add(i) % capacity].load(Ordering::Relaxed),
+            );
+        }
+        self.shared
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Whether the producer has been dropped
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn wraps_around_and_stops_when_full_or_empty() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 3);
+        let mut out = [0.0; 2];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [1.0, 2.0]);
+        assert_eq!(producer.push(&[4.0, 5.0, 6.0, 7.0]), 3);
+        assert_eq!(producer.free_len(), 0);
+        let mut out = [0.0; 5];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out[..4], [3.0, 4.0, 5.0, 6.0]);
+        assert!(consumer.is_empty());
+        assert!(!consumer.is_abandoned());
+        drop(producer);
+        assert!(consumer.is_abandoned());
+    }
+
+    #[test]
+    fn passes_samples_between_threads_in_order() {
+        let (mut producer, mut consumer) = ring_buffer(16);
+        let writer = thread::spawn(move || {
+            let samples: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+            let mut sent = 0;
+            while sent < samples.len() {
+                sent += producer.push(&samples[sent..(sent + 7).min(samples.len())]);
+            }
+        });
+        let mut received = vec![];
+        let mut out = [0.0; 5];
+        while received.len() < 1000 {
+            let count = consumer.pop(&mut out);
+            received.extend_from_slice(&out[..count]);
+        }
+        writer.join().unwrap();
+        assert!(received.iter().enumerate().all(|(i, s)| *s == i as f32));
+    }
+}
@@ -0,0 +1,131 @@
+use std::f32;
+
+/// Maps the channels of a layer onto the mixer's output channels
+///
+/// Output channels are treated as a ring of speakers, so panning past the
+/// last speaker wraps around to the first.
+#[derive(Debug, Clone)]
+pub struct Routing {
+    pub input_channels: u16,
+    pub output_channels: u16,
+    /// (input channel, output channel, gain)
+    connections: Vec<(usize, usize, f32)>,
+}
+
+impl Routing {
+    /// Send input channel `i` to output channel `i`, wrapping if there are more inputs than outputs
+    pub fn direct(input_channels: u16, output_channels: u16) -> Routing {
+        Routing::panned(input_channels, output_channels, 0.0)
+    }
+
+    /// Place input channel `i` at speaker position `position + i`
+    ///
+    /// Whole positions land on a single speaker, while fractional positions are
+    /// spread across the two adjacent speakers with constant-power panning.
+    pub fn panned(input_channels: u16, output_channels: u16, position: f32) -> Routing {
+        assert!(output_channels > 0);
+        let n_outputs = output_channels as usize;
+        let mut connections = vec![];
+        for input in 0..input_channels as usize {
+            let channel_position = (position + input as f32).rem_euclid(output_channels as f32);
+            let left = channel_position.floor() as usize % n_outputs;
+            let right = (left + 1) % n_outputs;
+            let angle = channel_position.fract() * f32::consts::FRAC_PI_2;
+            if right == left {
+                // A single speaker gets the whole channel wherever it's panned
+                connections.push((input, left, 1.0));
+                continue;
+            }
+            connections.push((input, left, angle.cos()));
+            if angle > 0.0 {
+                connections.push((input, right, angle.sin()));
+            }
+        }
+        Routing {
+            input_channels,
+            output_channels,
+            connections,
+        }
+    }
+
+    /// Mix one frame of input samples (one per input channel) into an output frame
+    #[inline]
+    pub fn mix_frame(&self, input: impl Fn(usize) -> f32, output: &mut [f32]) {
+        for (input_channel, output_channel, gain) in self.connections.iter() {
+            output[*output_channel] += input(*input_channel) * gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use proptest::prelude::*;
+
+    fn route(routing: &Routing, frame: &[f32]) -> Vec<f32> {
+        let mut out = vec![0.0; routing.output_channels as usize];
+        routing.mix_frame(|channel| frame[channel], &mut out);
+        out
+    }
+
+    #[test]
+    fn direct_with_matching_channels() {
+        let routing = Routing::direct(2, 2);
+        assert_almost_eq_by_element(route(&routing, &[0.1, 0.2]), vec![0.1, 0.2]);
+    }
+
+    #[test]
+    fn direct_onto_more_speakers() {
+        let routing = Routing::direct(2, 4);
+        assert_almost_eq_by_element(route(&routing, &[0.1, 0.2]), vec![0.1, 0.2, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn direct_onto_fewer_speakers_wraps() {
+        let routing = Routing::direct(2, 1);
+        assert_almost_eq_by_element(route(&routing, &[0.1, 0.2]), vec![0.3]);
+    }
+
+    #[test]
+    fn panned_to_speaker_pair() {
+        let routing = Routing::panned(2, 4, 2.0);
+        assert_almost_eq_by_element(route(&routing, &[0.1, 0.2]), vec![0.0, 0.0, 0.1, 0.2]);
+    }
+
+    #[test]
+    fn panned_wraps_around_ring() {
+        let routing = Routing::panned(2, 4, 3.0);
+        assert_almost_eq_by_element(route(&routing, &[0.1, 0.2]), vec![0.2, 0.0, 0.0, 0.1]);
+    }
+
+    #[test]
+    fn panned_between_speakers_is_constant_power() {
+        let routing = Routing::panned(1, 4, 1.5);
+        let out = route(&routing, &[1.0]);
+        assert_almost_eq_by_element(out.clone(), vec![0.0, 0.70710677, 0.70710677, 0.0]);
+        assert_almost_eq(out.iter().map(|s| s * s).sum(), 1.0);
+    }
+
+    proptest! {
+        #[test]
+        fn panning_keeps_the_power_of_each_channel(
+            input_channels in 1u16..8,
+            output_channels in 1u16..8,
+            position in -16.0f32..16.0,
+            frame in prop::collection::vec(-1.0f32..1.0, 8),
+        ) {
+            let routing = Routing::panned(input_channels, output_channels, position);
+            for channel in 0..input_channels as usize {
+                // One channel at a time, since channels sharing a speaker add up
+                let mut solo = vec![0.0; input_channels as usize];
+                solo[channel] = frame[channel];
+                let out = route(&routing, &solo);
+                prop_assert_eq!(out.len(), output_channels as usize);
+                prop_assert!(out.iter().all(|sample| sample.is_finite()));
+                let power: f32 = out.iter().map(|s| s * s).sum();
+                prop_assert!((power - frame[channel] * frame[channel]).abs() < 1e-4);
+            }
+        }
+    }
+}
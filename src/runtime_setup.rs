@@ -1,17 +1,127 @@
+use crate::log_file::LogFile;
+use anyhow::Result;
+use crossbeam_channel::Sender;
 use simplelog::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
-pub fn setup_logging() {
+static PIN_THREADS: AtomicBool = AtomicBool::new(false);
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+static LOG_FILE: OnceLock<LogFile> = OnceLock::new();
+
+/// Log at `level` and above to `log_file` if given, otherwise to stdout, or
+/// to stderr when stdout carries audio
+pub fn setup_logging(stderr: bool, level: LevelFilter, log_file: Option<LogFile>) {
     let config = ConfigBuilder::new()
         .set_time_level(LevelFilter::Error)
         .set_location_level(LevelFilter::Error)
         .set_target_level(LevelFilter::Error)
         .set_location_level(LevelFilter::Error)
         .build();
+    if let Some(log_file) = log_file {
+        let _ = LOG_FILE.set(log_file.clone());
+        WriteLogger::init(level, config, log_file).unwrap();
+        return;
+    }
     CombinedLogger::init(vec![TermLogger::new(
-        LevelFilter::Debug,
+        level,
         config,
-        TerminalMode::Stdout,
+        if stderr {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Stdout
+        },
         ColorChoice::Auto,
     )])
     .unwrap();
 }
+
+/// Reopen the log file, if logging to one, e.g. after logrotate has moved it away
+pub fn reopen_log_file() {
+    if let Some(log_file) = LOG_FILE.get() {
+        if let Err(e) = log_file.reopen() {
+            error!("Failed to reopen the log file: {}", e);
+        }
+    }
+}
+
+/// What a service manager is asking for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ServiceSignal {
+    /// SIGTERM: stop, gracefully
+    Terminate,
+    /// SIGHUP: reload configuration and reopen the log file
+    Reload,
+}
+
+/// Turn SIGTERM and SIGHUP into events with `wrap`, sending them on `events`
+#[cfg(unix)]
+pub fn listen_service_signals<T, F>(events: Sender<T>, wrap: F) -> Result<()>
+where
+    T: Send + 'static,
+    F: Fn(ServiceSignal) -> T + Send + 'static,
+{
+    use signal_hook::consts::{SIGHUP, SIGTERM};
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGHUP])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let signal = match signal {
+                SIGTERM => ServiceSignal::Terminate,
+                _ => ServiceSignal::Reload,
+            };
+            if events.send(wrap(signal)).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen_service_signals<T, F>(_events: Sender<T>, _wrap: F) -> Result<()>
+where
+    T: Send + 'static,
+    F: Fn(ServiceSignal) -> T + Send + 'static,
+{
+    anyhow::bail!("signals are only supported on Unix")
+}
+
+/// Pin each processing thread started from now on to a CPU of its own, in turn
+pub fn enable_thread_pinning() {
+    PIN_THREADS.store(true, Ordering::Relaxed);
+}
+
+/// Pin the calling thread to the next CPU if pinning is enabled and the OS
+/// allows it, so the scheduler doesn't move it around mid-stream
+pub fn pin_processing_thread() {
+    if !PIN_THREADS.load(Ordering::Relaxed) {
+        return;
+    }
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let cpu = NEXT_CPU.fetch_add(1, Ordering::Relaxed) % cpus;
+    if let Err(e) = pin_to_cpu(cpu) {
+        warn!("Failed to pin a processing thread to CPU {}: {}", cpu, e);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> std::io::Result<()> {
+    // Safe because the set is initialized before use and only read by the call
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "thread pinning is only supported on Linux",
+    ))
+}
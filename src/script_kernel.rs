@@ -0,0 +1,187 @@
+use crate::kernel::{FrameInfo, Kernel};
+use anyhow::{bail, Result};
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Position, Scope, AST, FLOAT, INT};
+use rustfft::num_complex::Complex32;
+
+const PROCESS_FN: &str = "process";
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// A frequency kernel interpreted from a Rhai script
+///
+/// Scripts define a `process()` function which edits the frame bound to
+/// `this`, e.g. `this.scale(i, 2.0)`. They are much slower than compiled
+/// kernels but reload almost instantly.
+pub struct ScriptKernel {
+    engine: Engine,
+    ast: AST,
+}
+
+/// The frame exposed to scripts as `this`
+#[derive(Clone)]
+struct ScriptFrame {
+    bins: Vec<Complex32>,
+    sample_rate: u32,
+    window_len: u32,
+    channel: u32,
+    elapsed_ms: u64,
+    params: Vec<(String, f32)>,
+}
+
+impl ScriptFrame {
+    fn bin(&mut self, i: INT) -> ScriptResult<&mut Complex32> {
+        let len = self.bins.len();
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| self.bins.get_mut(i))
+            .ok_or_else(|| EvalAltResult::ErrorArrayBounds(len, i, Position::NONE).into())
+    }
+}
+
+impl ScriptKernel {
+    pub fn new(source: &str) -> Result<ScriptKernel> {
+        let mut engine = Engine::new();
+        engine
+            .register_type_with_name::<ScriptFrame>("Frame")
+            .register_get("len", |f: &mut ScriptFrame| f.bins.len() as INT)
+            .register_get("sample_rate", |f: &mut ScriptFrame| f.sample_rate as INT)
+            .register_get("window_len", |f: &mut ScriptFrame| f.window_len as INT)
+            .register_get("channel", |f: &mut ScriptFrame| f.channel as INT)
+            .register_get("elapsed_ms", |f: &mut ScriptFrame| f.elapsed_ms as INT)
+            .register_fn(
+                "param",
+                |f: &mut ScriptFrame, name: &str, default: FLOAT| {
+                    f.params
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map_or(default, |(_, v)| *v as FLOAT)
+                },
+            )
+            .register_fn("re", |f: &mut ScriptFrame, i: INT| {
+                f.bin(i).map(|c| c.re as FLOAT)
+            })
+            .register_fn("im", |f: &mut ScriptFrame, i: INT| {
+                f.bin(i).map(|c| c.im as FLOAT)
+            })
+            .register_fn("mag", |f: &mut ScriptFrame, i: INT| {
+                f.bin(i).map(|c| c.norm() as FLOAT)
+            })
+            .register_fn(
+                "set",
+                |f: &mut ScriptFrame, i: INT, re: FLOAT, im: FLOAT| -> ScriptResult<()> {
+                    *f.bin(i)? = Complex32::new(re as f32, im as f32);
+                    Ok(())
+                },
+            )
+            .register_fn(
+                "scale",
+                |f: &mut ScriptFrame, i: INT, factor: FLOAT| -> ScriptResult<()> {
+                    *f.bin(i)? *= factor as f32;
+                    Ok(())
+                },
+            );
+        let ast = engine.compile(source)?;
+        if !ast.iter_functions().any(|f| f.name == PROCESS_FN) {
+            bail!("kernel script does not define a `process` function");
+        }
+        Ok(ScriptKernel { engine, ast })
+    }
+}
+
+impl Kernel for ScriptKernel {
+    fn apply(&mut self, bins: &mut [Complex32], info: FrameInfo<'_>) -> Result<()> {
+        let mut this = Dynamic::from(ScriptFrame {
+            bins: bins.to_vec(),
+            sample_rate: info.sample_rate,
+            window_len: info.window_len,
+            channel: info.channel,
+            elapsed_ms: info.elapsed_ms,
+            params: info
+                .params()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        });
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this);
+        // whatever `process` returns is ignored
+        let _ = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            PROCESS_FN,
+            (),
+        )?;
+        match this.try_cast::<ScriptFrame>() {
+            Some(frame) if frame.bins.len() == bins.len() => {
+                bins.copy_from_slice(&frame.bins);
+                Ok(())
+            }
+            _ => bail!("kernel script replaced `this`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kernel::KernelParam;
+    use crate::test_utils::*;
+
+    fn info() -> FrameInfo<'static> {
+        FrameInfo {
+            sample_rate: 44100,
+            window_len: 2,
+            channel: 1,
+            elapsed_ms: 0,
+            params: &[],
+        }
+    }
+
+    fn run(source: &str, info: FrameInfo) -> Result<Vec<f32>> {
+        let mut kernel = ScriptKernel::new(source)?;
+        let mut bins = vec![Complex32::new(1.0, 2.0), Complex32::new(-3.0, 0.5)];
+        kernel.apply(&mut bins, info)?;
+        Ok(bins.iter().flat_map(|c| vec![c.re, c.im]).collect())
+    }
+
+    #[test]
+    fn script_edits_bins() {
+        let source = "
+            fn process() {
+                for i in 0..this.len {
+                    this.set(i, this.re(i) * 2.0, -this.im(i));
+                }
+                this.scale(1, this.channel.to_float());
+            }
+        ";
+        assert_almost_eq_by_element(run(source, info()).unwrap(), vec![2.0, -2.0, -6.0, -0.5]);
+    }
+
+    #[test]
+    fn script_reads_params() {
+        let source = "
+            fn process() {
+                this.scale(0, this.param(\"gain\", 1.0));
+                this.scale(1, this.param(\"missing\", 0.0));
+            }
+        ";
+        let params = vec![KernelParam::new("gain", 3.0)];
+        let info = FrameInfo {
+            params: &params,
+            ..info()
+        };
+        assert_almost_eq_by_element(run(source, info).unwrap(), vec![3.0, 6.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn script_without_process_is_rejected() {
+        assert!(ScriptKernel::new("fn other() {}").is_err());
+        assert!(ScriptKernel::new("fn process() {").is_err());
+    }
+
+    #[test]
+    fn script_errors_are_reported() {
+        assert!(run("fn process() { this.scale(5, 1.0); }", info()).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use crate::audio::{AudioBus, AudioSpec, BusPolicy, BusSender};
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use anyhow::{bail, Result};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum SequenceProcessorControlMessage {
+    Shutdown,
+}
+
+impl ControlMessage for SequenceProcessorControlMessage {
+    fn shutdown_msg() -> Self {
+        SequenceProcessorControlMessage::Shutdown
+    }
+}
+
+/// Plays buses with the same spec one after another, crossfading the end
+/// of each into the start of the next
+///
+/// Only the input being played and the tail of the one before it are read,
+/// so inputs further along wait on their own bounded output channels. The
+/// crossfade is equal power, which suits stretched audio since neighbouring
+/// segments aren't correlated. A segment shorter than the crossfade only
+/// fades in for as long as it lasts, under the rest of the one before it,
+/// and the segment after it starts without a crossfade.
+pub struct SequenceProcessor {
+    inputs: VecDeque<AudioBus>,
+    crossfade_len: usize,
+    /// Samples of the current input on each channel that haven't been sent
+    pending: Vec<VecDeque<f32>>,
+    /// The tail of the previous input, still to be faded out under the current one
+    fading_out: Vec<VecDeque<f32>>,
+    fade_len: usize,
+    output: BusSender,
+}
+
+impl SequenceProcessor {
+    pub fn new(
+        inputs: Vec<AudioBus>,
+        crossfade_len: usize,
+        policy: BusPolicy,
+    ) -> Result<(SequenceProcessor, AudioBus)> {
+        let spec = match inputs.first() {
+            Some(bus) => bus.spec,
+            None => bail!("a sequence needs at least one input"),
+        };
+        if let Some(bus) = inputs.iter().find(|bus| bus.spec != spec) {
+            bail!(
+                "sequence inputs must share a spec, got {:?} and {:?}",
+                spec,
+                bus.spec
+            );
+        }
+        let expected_total_samples = inputs
+            .iter()
+            .map(|bus| bus.expected_total_samples)
+            .sum::<Option<usize>>()
+            .map(|total| total.saturating_sub(crossfade_len * (inputs.len() - 1)));
+        let (output, sender) = AudioBus::bounded(spec, expected_total_samples, policy);
+        let channels = spec.channels as usize;
+        Ok((
+            SequenceProcessor {
+                inputs: inputs.into(),
+                crossfade_len,
+                pending: vec![VecDeque::new(); channels],
+                fading_out: vec![VecDeque::new(); channels],
+                fade_len: 0,
+                output: sender,
+            },
+            output,
+        ))
+    }
+
+    fn spec(&self) -> AudioSpec {
+        self.inputs[0].spec
+    }
+
+    /// Wait up to `INPUT_POLL` for audio from the current input, returning
+    /// false once every channel of it has closed
+    fn receive(&mut self, open: &mut [bool]) -> bool {
+        for (i, channel) in self.inputs[0].channels.iter().enumerate() {
+            if !open[i] {
+                continue;
+            }
+            match channel.recv_timeout(INPUT_POLL) {
+                Ok(chunk) => self.pending[i].extend(chunk),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => open[i] = false,
+            }
+        }
+        open.iter().any(|open| *open)
+    }
+
+    /// Send the oldest `frames` pending frames, mixed with what's left of the
+    /// previous input's tail
+    fn send(&mut self, frames: usize) -> bool {
+        if frames == 0 {
+            return true;
+        }
+        let mut out = vec![Vec::with_capacity(frames); self.pending.len()];
+        for (i, (pending, fading_out)) in self
+            .pending
+            .iter_mut()
+            .zip(self.fading_out.iter_mut())
+            .enumerate()
+        {
+            for sample in pending.drain(..frames) {
+                out[i].push(match fading_out.pop_front() {
+                    Some(old) => {
+                        let position = self.fade_len - fading_out.len();
+                        let t = (position as f32 - 0.5) / self.fade_len as f32 * FRAC_PI_2;
+                        sample * t.sin() + old * t.cos()
+                    }
+                    None => sample,
+                });
+            }
+        }
+        self.send_channels(out)
+    }
+
+    /// Send whatever is left of the previous input's tail on its own, faded out
+    fn flush_fading_out(&mut self) -> bool {
+        let fade_len = self.fade_len;
+        let out: Vec<Vec<f32>> = self
+            .fading_out
+            .iter_mut()
+            .map(|fading_out| {
+                let remaining = fading_out.len();
+                fading_out
+                    .drain(..)
+                    .enumerate()
+                    .map(|(j, old)| {
+                        let position = fade_len - remaining + j + 1;
+                        old * ((position as f32 - 0.5) / fade_len as f32 * FRAC_PI_2).cos()
+                    })
+                    .collect()
+            })
+            .collect();
+        out[0].is_empty() || self.send_channels(out)
+    }
+
+    fn send_channels(&mut self, out: Vec<Vec<f32>>) -> bool {
+        self.output.send_frame(out)
+    }
+
+    fn pending_frames(&self) -> usize {
+        self.pending.iter().map(VecDeque::len).min().unwrap_or(0)
+    }
+
+    /// Once the current input has ended, send all of it but the tail that
+    /// will fade under the next one
+    fn finish_input(&mut self) -> bool {
+        let is_last = self.inputs.len() == 1;
+        let frames = self.pending_frames();
+        let mut keep = if is_last {
+            0
+        } else {
+            self.crossfade_len.min(frames)
+        };
+        if !self.send(frames - keep) {
+            return false;
+        }
+        if !self.fading_out[0].is_empty() {
+            // This input was too short to cover the whole fade
+            if !self.send(keep) || !self.flush_fading_out() {
+                return false;
+            }
+            keep = 0;
+        }
+        for (pending, fading_out) in self.pending.iter_mut().zip(self.fading_out.iter_mut()) {
+            fading_out.extend(pending.drain(..keep));
+            pending.clear();
+        }
+        self.fade_len = keep;
+        self.inputs.pop_front();
+        true
+    }
+}
+
+impl Processor<SequenceProcessorControlMessage> for SequenceProcessor {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<SequenceProcessorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let mut open = vec![true; self.spec().channels as usize];
+            while !self.inputs.is_empty() {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                let sent = if self.receive(&mut open) {
+                    let hold = self.crossfade_len;
+                    self.send(self.pending_frames().saturating_sub(hold))
+                } else {
+                    open.iter_mut().for_each(|open| *open = true);
+                    self.finish_input()
+                };
+                if !sent {
+                    info!("sequence output disconnected, stopping");
+                    break;
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<SequenceProcessorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(SequenceProcessorControlMessage::Shutdown) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    fn bus(samples: Vec<f32>) -> AudioBus {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 10,
+        };
+        let (bus, senders) = AudioBus::from_spec(spec, Some(samples.len()));
+        for sender in senders {
+            // Split across chunks, as a stretcher would send it
+            for chunk in samples.chunks(3) {
+                sender.send(chunk.to_vec()).unwrap();
+            }
+        }
+        bus
+    }
+
+    #[test]
+    fn crossfades_inputs_in_order() {
+        let (sequence, output) = SequenceProcessor::new(
+            vec![bus(vec![1.0; 6]), bus(vec![2.0; 8])],
+            4,
+            BusPolicy::UNBOUNDED,
+        )
+        .unwrap();
+        assert_eq!(output.expected_total_samples, Some(10));
+        let node = Node::new(sequence);
+        let audio = output.into_audio();
+        node.join().unwrap();
+        for channel in audio.data {
+            assert_eq!(channel.len(), 10);
+            assert_almost_eq_by_element(channel[..2].to_vec(), vec![1.0; 2]);
+            assert_almost_eq_by_element(channel[6..].to_vec(), vec![2.0; 4]);
+            // Equal power: the first input's share falls as the second's rises
+            for (j, sample) in channel[2..6].iter().enumerate() {
+                let t = (j as f32 + 0.5) / 4.0 * FRAC_PI_2;
+                assert!((sample - (2.0 * t.sin() + t.cos())).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn short_inputs_fade_in_for_as_long_as_they_last() {
+        let (sequence, output) = SequenceProcessor::new(
+            vec![bus(vec![1.0; 4]), bus(vec![2.0; 2]), bus(vec![3.0; 3])],
+            4,
+            BusPolicy::UNBOUNDED,
+        )
+        .unwrap();
+        let node = Node::new(sequence);
+        let audio = output.into_audio();
+        node.join().unwrap();
+        // The second input overlaps the first, and the third follows them both
+        assert_eq!(audio.data[0].len(), 4 + 3);
+        assert!(audio.data[0][..4].iter().all(|sample| *sample > 0.0));
+        assert_almost_eq_by_element(audio.data[0][4..].to_vec(), vec![3.0; 3]);
+    }
+
+    #[test]
+    fn inputs_must_share_a_spec() {
+        let a = AudioBus::from_audio(generate_audio(1.0, 4, 2, 10));
+        let b = AudioBus::from_audio(generate_audio(1.0, 4, 1, 10));
+        assert!(SequenceProcessor::new(vec![a, b], 2, BusPolicy::UNBOUNDED).is_err());
+        assert!(SequenceProcessor::new(vec![], 2, BusPolicy::UNBOUNDED).is_err());
+    }
+}
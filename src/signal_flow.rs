@@ -1 +1,7 @@
+pub mod compensation;
+pub mod graph;
 pub mod node;
+mod patch;
+pub mod rechunker;
+pub mod splitter;
+pub mod supervisor;
@@ -0,0 +1,117 @@
+use super::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::audio::{AudioBus, BusPolicy, BusSender};
+use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum LatencyCompensatorControlMessage {
+    Shutdown,
+}
+
+impl ControlMessage for LatencyCompensatorControlMessage {
+    fn shutdown_msg() -> Self {
+        LatencyCompensatorControlMessage::Shutdown
+    }
+}
+
+/// Delays a bus by a fixed number of frames, so that a path with little
+/// latency, e.g. the dry signal, lines up with a slower parallel one
+///
+/// The delay is sent as silence ahead of the input, so the output is
+/// `frames` longer than the input.
+pub struct LatencyCompensator {
+    input: AudioBus,
+    frames: usize,
+    output: BusSender,
+}
+
+impl LatencyCompensator {
+    pub fn new(
+        input: AudioBus,
+        frames: usize,
+        policy: BusPolicy,
+    ) -> (LatencyCompensator, AudioBus) {
+        let (output, sender) = AudioBus::bounded(
+            input.spec,
+            input.expected_total_samples.map(|len| len + frames),
+            policy,
+        );
+        (
+            LatencyCompensator {
+                input,
+                frames,
+                output: sender,
+            },
+            output,
+        )
+    }
+}
+
+impl Processor<LatencyCompensatorControlMessage> for LatencyCompensator {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<LatencyCompensatorControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let silence = vec![vec![0.0; self.frames]; self.input.channels.len()];
+            let mut open_channels = if self.output.send_frame(silence) {
+                self.input.channels.len()
+            } else {
+                0
+            };
+            'outer: while open_channels > 0 {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                open_channels = 0;
+                for (i, channel) in self.input.channels.iter().enumerate() {
+                    match channel.recv_timeout(INPUT_POLL) {
+                        Ok(chunk) => {
+                            open_channels += 1;
+                            if !self.output.send(i, chunk) {
+                                info!("latency compensator output disconnected, stopping");
+                                break 'outer;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => open_channels += 1,
+                        Err(RecvTimeoutError::Disconnected) => {}
+                    }
+                }
+                self.output
+                    .forward_timestamps(&self.input.take_timestamps(), self.frames as i64);
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<LatencyCompensatorControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                LatencyCompensatorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.frames
+    }
+}
@@ -0,0 +1,965 @@
+use super::compensation::LatencyCompensator;
+use super::node::{ControlMessage, Node, NodeError, Processor};
+use super::patch::{FanOut, FanOutControlMessage, PatchPoint, PatchPointControlMessage};
+use super::rechunker::Rechunker;
+use super::supervisor::{RestartPolicy, Supervisor};
+use crate::audio::{AudioBus, AudioSpec, BusPolicy};
+use anyhow::{anyhow, bail, Result};
+use std::any::Any;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `Pipeline::shutdown` waits for nodes to stop once asked
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+
+/// A started node, with its processor and control message types erased
+pub trait GraphNode: Send {
+    fn request_shutdown(&self) -> Result<()>;
+    fn request_immediate_shutdown(&self) -> Result<()>;
+    fn is_finished(&self) -> bool;
+    fn has_exited(&self) -> bool;
+    fn try_recv_error(&self) -> Option<NodeError>;
+    fn join(self: Box<Self>) -> Result<(), NodeError>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<P, M> GraphNode for Node<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    fn request_shutdown(&self) -> Result<()> {
+        self.send_control_message(M::shutdown_msg())
+    }
+
+    fn request_immediate_shutdown(&self) -> Result<()> {
+        self.send_control_message(M::immediate_shutdown_msg())
+    }
+
+    fn is_finished(&self) -> bool {
+        Node::is_finished(self)
+    }
+
+    fn has_exited(&self) -> bool {
+        Node::has_exited(self)
+    }
+
+    fn try_recv_error(&self) -> Option<NodeError> {
+        Node::try_recv_error(self)
+    }
+
+    fn join(self: Box<Self>) -> Result<(), NodeError> {
+        Node::join(*self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Builds a node, returning it with its output bus and its latency in frames
+type Build =
+    Box<dyn FnOnce(Vec<AudioBus>) -> Result<(Box<dyn GraphNode>, Option<AudioBus>, usize)>>;
+
+struct PendingNode {
+    id: String,
+    has_output: bool,
+    /// Frames per chunk the node wants on each of its inputs, if it cares
+    input_chunk_len: Option<usize>,
+    /// Whether to delay inputs so they all lag the sources equally
+    align_inputs: bool,
+    build: Build,
+}
+
+/// A declarative description of a signal flow
+///
+/// Nodes are added with IDs and a function that builds their processor from
+/// their input buses, and edges connect one node's output bus to another's
+/// input. Nothing runs until `start`, which builds and starts every node
+/// after all of its inputs, copying a bus for each consumer when one output
+/// feeds several nodes.
+///
+/// Each node's latency is what its processor reports when built, and the
+/// latency of its output is that plus the most of any of its inputs.
+#[derive(Default)]
+pub struct Graph {
+    nodes: Vec<PendingNode>,
+    /// (from, to) node indices, in the order they were connected
+    edges: Vec<(usize, usize)>,
+    /// For the buses of the nodes the graph adds itself, to delay and
+    /// rechunk inputs
+    bus_policy: BusPolicy,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph::default()
+    }
+
+    /// Bound the buses of the nodes the graph adds between the ones it's
+    /// given by `policy`, rather than leaving them unbounded
+    pub fn with_bus_policy(mut self, policy: BusPolicy) -> Graph {
+        self.bus_policy = policy;
+        self
+    }
+
+    /// The policy nodes added to this graph should bound their output buses by
+    pub fn bus_policy(&self) -> BusPolicy {
+        self.bus_policy
+    }
+
+    /// Add a node whose processor produces an output bus
+    ///
+    /// `build` receives the buses of incoming edges in the order they were connected.
+    pub fn add_node<P, M, F>(&mut self, id: &str, build: F) -> Result<()>
+    where
+        P: Processor<M>,
+        M: ControlMessage,
+        F: FnOnce(Vec<AudioBus>) -> Result<(P, AudioBus)> + 'static,
+    {
+        self.add(
+            id,
+            true,
+            Box::new(move |inputs| {
+                let (processor, output) = build(inputs)?;
+                let latency = processor.latency();
+                let node: Box<dyn GraphNode> = Box::new(Node::new(processor));
+                Ok((node, Some(output), latency))
+            }),
+        )
+    }
+
+    /// Add a node that is rebuilt and restarted whenever it fails
+    ///
+    /// `build` is called once at start like `add_node`, then again with
+    /// fresh input buses for each restart. The node is looked up as a
+    /// `Supervisor<P, M>`, and takes control messages wrapped in
+    /// `SupervisorControlMessage::Forward`.
+    pub fn add_supervised_node<P, M, F>(
+        &mut self,
+        id: &str,
+        policy: RestartPolicy,
+        build: F,
+    ) -> Result<()>
+    where
+        P: Processor<M>,
+        M: ControlMessage,
+        F: FnMut(Vec<AudioBus>) -> Result<(P, AudioBus)> + Send + 'static,
+    {
+        let supervisor_id = id.to_string();
+        self.add_node(id, move |inputs| {
+            Supervisor::new(&supervisor_id, inputs, policy, build)
+        })
+    }
+
+    /// Add a node with no output bus
+    ///
+    /// `build` returns an already started node, since sinks such as the audio
+    /// output take their buses through control messages.
+    pub fn add_sink<P, M, F>(&mut self, id: &str, build: F) -> Result<()>
+    where
+        P: Processor<M>,
+        M: ControlMessage,
+        F: FnOnce(Vec<AudioBus>) -> Result<Node<P, M>> + 'static,
+    {
+        self.add(
+            id,
+            false,
+            Box::new(move |inputs| {
+                let node: Box<dyn GraphNode> = Box::new(build(inputs)?);
+                Ok((node, None, 0))
+            }),
+        )
+    }
+
+    fn add(&mut self, id: &str, has_output: bool, build: Build) -> Result<()> {
+        if self.index_of(id).is_ok() {
+            bail!("signal graph already has a node {:?}", id);
+        }
+        self.nodes.push(PendingNode {
+            id: id.to_string(),
+            has_output,
+            input_chunk_len: None,
+            align_inputs: false,
+            build,
+        });
+        Ok(())
+    }
+
+    /// Re-chunk every input of `id` to exactly `frames` frames per chunk,
+    /// but for the last, so its processor needn't cope with whatever size
+    /// arrives
+    pub fn set_input_chunk_len(&mut self, id: &str, frames: usize) -> Result<()> {
+        if frames == 0 {
+            bail!("chunks must be at least 1 frame long");
+        }
+        let index = self.index_of(id)?;
+        self.nodes[index].input_chunk_len = Some(frames);
+        Ok(())
+    }
+
+    /// Delay the inputs of `id` that lag the graph's sources by less than
+    /// the latest of them, so that parallel paths, e.g. a dry signal and a
+    /// stretched one, arrive in step
+    ///
+    /// Only edges connected before start are aligned.
+    pub fn align_inputs(&mut self, id: &str) -> Result<()> {
+        let index = self.index_of(id)?;
+        self.nodes[index].align_inputs = true;
+        Ok(())
+    }
+
+    /// Feed the output bus of `from` into `to`
+    pub fn connect(&mut self, from: &str, to: &str) -> Result<()> {
+        let from_index = self.index_of(from)?;
+        let to_index = self.index_of(to)?;
+        if !self.nodes[from_index].has_output {
+            bail!("signal graph node {:?} has no output to connect", from);
+        }
+        self.edges.push((from_index, to_index));
+        Ok(())
+    }
+
+    /// Build and start every node, upstream nodes first
+    ///
+    /// If any node fails to build, the nodes already started are shut down.
+    pub fn start(self) -> Result<Pipeline> {
+        let order = self.start_order()?;
+        let ids: Vec<String> = self.nodes.iter().map(|node| node.id.clone()).collect();
+        let mut pending: Vec<Option<PendingNode>> = self.nodes.into_iter().map(Some).collect();
+        let mut edge_buses: Vec<Option<AudioBus>> = self.edges.iter().map(|_| None).collect();
+        // How far each started node's output lags the sources
+        let mut latencies = vec![0; ids.len()];
+        let mut running = Pipeline {
+            nodes: vec![],
+            ports: HashMap::new(),
+            edges: vec![],
+            untaken_outputs: HashSet::new(),
+        };
+        for index in order {
+            let PendingNode {
+                id,
+                input_chunk_len,
+                align_inputs,
+                build,
+                ..
+            } = pending[index].take().unwrap();
+            let mut ports = Ports::default();
+            let mut inputs = vec![];
+            let input_latency = self
+                .edges
+                .iter()
+                .filter(|(_, to)| *to == index)
+                .map(|(from, _)| latencies[*from])
+                .max()
+                .unwrap_or(0);
+            for (edge, (from, to)) in self.edges.iter().enumerate() {
+                if *to != index {
+                    continue;
+                }
+                let from_id = ids[*from].clone();
+                let bus = edge_buses[edge].take().unwrap();
+                let spec = bus.spec;
+                let (patch_point, input) = PatchPoint::new(&from_id, bus);
+                running.edges.push(RunningEdge {
+                    from: from_id,
+                    to: id.clone(),
+                    slot: ports.inputs.len(),
+                });
+                ports.inputs.push((running.nodes.len(), spec));
+                running.nodes.push((
+                    format!("{} input {}", id, ports.inputs.len()),
+                    Box::new(Node::new(patch_point)),
+                ));
+                let delay = input_latency - latencies[*from];
+                let input = if align_inputs && delay > 0 {
+                    let (compensator, input) =
+                        LatencyCompensator::new(input, delay, self.bus_policy);
+                    running.nodes.push((
+                        format!("{} input {} delay", id, ports.inputs.len()),
+                        Box::new(Node::new(compensator)),
+                    ));
+                    input
+                } else {
+                    input
+                };
+                let input = match input_chunk_len {
+                    Some(frames) => {
+                        let (rechunker, input) = Rechunker::new(input, frames, self.bus_policy);
+                        running.nodes.push((
+                            format!("{} input {} rechunker", id, ports.inputs.len()),
+                            Box::new(Node::new(rechunker)),
+                        ));
+                        input
+                    }
+                    None => input,
+                };
+                inputs.push(input);
+            }
+            let (node, output, latency) = match build(inputs) {
+                Ok(built) => built,
+                Err(e) => {
+                    if let Err(shutdown_error) = running.shutdown(Duration::ZERO) {
+                        warn!("{}", shutdown_error);
+                    }
+                    bail!("failed to build signal graph node {:?}: {}", id, e);
+                }
+            };
+            running.nodes.push((id.clone(), node));
+            latencies[index] = input_latency + latency;
+            ports.latency = latencies[index];
+            if let Some(output) = output {
+                ports.output = Some(OutputPort {
+                    node: running.nodes.len(),
+                    spec: output.spec,
+                    expected_total_samples: output.expected_total_samples,
+                });
+                let mut fan_out = FanOut::new(output);
+                let mut outgoing = 0;
+                for (edge, (from, _)) in self.edges.iter().enumerate() {
+                    if *from == index {
+                        edge_buses[edge] = Some(fan_out.add_output());
+                        outgoing += 1;
+                    }
+                }
+                if outgoing == 0 {
+                    running.untaken_outputs.insert(id.clone());
+                }
+                running
+                    .nodes
+                    .push((format!("{} output", id), Box::new(Node::new(fan_out))));
+            }
+            running.ports.insert(id, ports);
+        }
+        Ok(running)
+    }
+
+    /// Topological order of the nodes, keeping the order they were added where possible
+    fn start_order(&self) -> Result<Vec<usize>> {
+        let mut in_degree = vec![0; self.nodes.len()];
+        for (_, to) in &self.edges {
+            in_degree[*to] += 1;
+        }
+        let mut ready: VecDeque<usize> = (0..self.nodes.len())
+            .filter(|i| in_degree[*i] == 0)
+            .collect();
+        let mut order = vec![];
+        while let Some(index) = ready.pop_front() {
+            order.push(index);
+            for (_, to) in self.edges.iter().filter(|(from, _)| *from == index) {
+                in_degree[*to] -= 1;
+                if in_degree[*to] == 0 {
+                    ready.push_back(*to);
+                }
+            }
+        }
+        if order.len() < self.nodes.len() {
+            let cycle: Vec<&str> = (0..self.nodes.len())
+                .filter(|i| in_degree[*i] > 0)
+                .map(|i| self.nodes[i].id.as_str())
+                .collect();
+            bail!("signal graph has a cycle through {:?}", cycle);
+        }
+        Ok(order)
+    }
+
+    fn index_of(&self, id: &str) -> Result<usize> {
+        match self.nodes.iter().position(|node| node.id == id) {
+            Some(index) => Ok(index),
+            None => bail!("signal graph has no node {:?}", id),
+        }
+    }
+}
+
+/// Changes to the edges of a running graph
+#[derive(Debug)]
+pub enum GraphControlMessage {
+    /// Mix the output of `from` into the first input of `to`, fading it in over `fade`
+    Connect {
+        from: String,
+        to: String,
+        fade: Duration,
+    },
+    /// Fade the output of `from` out of `to` over `fade`, then drop the edge
+    Disconnect {
+        from: String,
+        to: String,
+        fade: Duration,
+    },
+}
+
+#[derive(Default)]
+struct Ports {
+    output: Option<OutputPort>,
+    /// Index of each input's patch point node, and the spec it expects
+    inputs: Vec<(usize, AudioSpec)>,
+    /// Frames by which the node's output lags the sources, as of start
+    latency: usize,
+}
+
+struct OutputPort {
+    /// Index of the fan-out node copying the output
+    node: usize,
+    spec: AudioSpec,
+    expected_total_samples: Option<usize>,
+}
+
+struct RunningEdge {
+    from: String,
+    to: String,
+    slot: usize,
+}
+
+/// The running nodes of a started `Graph`
+///
+/// Every input is fed through a patch point and every output through a
+/// fan-out, so that edges can be changed while the graph runs.
+pub struct Pipeline {
+    /// In start order, so every node comes after the nodes feeding it
+    nodes: Vec<(String, Box<dyn GraphNode>)>,
+    ports: HashMap<String, Ports>,
+    edges: Vec<RunningEdge>,
+    /// Nodes whose outputs weren't connected at start and haven't been taken
+    untaken_outputs: HashSet<String>,
+}
+
+impl Pipeline {
+    /// Look up a node to send it control messages
+    ///
+    /// Returns `None` if there is no node `id` or it has different types.
+    pub fn node<P, M>(&self, id: &str) -> Option<&Node<P, M>>
+    where
+        P: Processor<M>,
+        M: ControlMessage,
+    {
+        self.nodes
+            .iter()
+            .find(|(node_id, _)| node_id == id)
+            .and_then(|(_, node)| node.as_any().downcast_ref())
+    }
+
+    /// How many frames the output of node `id` lags the graph's sources, by
+    /// the latencies its processor and those upstream reported at start
+    pub fn latency(&self, id: &str) -> Option<usize> {
+        self.ports.get(id).map(|ports| ports.latency)
+    }
+
+    /// Take the output bus of a node that had no outgoing edges at start
+    pub fn take_output(&mut self, id: &str) -> Option<AudioBus> {
+        if !self.untaken_outputs.remove(id) {
+            return None;
+        }
+        self.copy_output(id).ok()
+    }
+
+    /// Rewire the graph while it runs
+    ///
+    /// Connecting is refused if it would create a cycle, or if the output
+    /// and input have different specs.
+    pub fn send_control_message(&mut self, message: GraphControlMessage) -> Result<()> {
+        match message {
+            GraphControlMessage::Connect { from, to, fade } => {
+                if self
+                    .edges
+                    .iter()
+                    .any(|edge| edge.from == from && edge.to == to)
+                {
+                    bail!("{:?} is already connected to {:?}", from, to);
+                }
+                if from == to || self.feeds(&to, &from) {
+                    bail!("connecting {:?} to {:?} would create a cycle", from, to);
+                }
+                let (patch_point, spec) = match self.ports(&to)?.inputs.first() {
+                    Some(input) => *input,
+                    None => bail!("signal graph node {:?} has no inputs", to),
+                };
+                if self.output_port(&from)?.spec != spec {
+                    bail!("{:?} and the input of {:?} have different specs", from, to);
+                }
+                let bus = self.copy_output(&from)?;
+                self.patch_point(patch_point).send_control_message(
+                    PatchPointControlMessage::Connect {
+                        id: from.clone(),
+                        bus,
+                        fade,
+                    },
+                )?;
+                self.edges.push(RunningEdge { from, to, slot: 0 });
+            }
+            GraphControlMessage::Disconnect { from, to, fade } => {
+                let edge = match self
+                    .edges
+                    .iter()
+                    .position(|edge| edge.from == from && edge.to == to)
+                {
+                    Some(edge) => self.edges.remove(edge),
+                    None => bail!("{:?} is not connected to {:?}", from, to),
+                };
+                let (patch_point, _) = self.ports(&to)?.inputs[edge.slot];
+                self.patch_point(patch_point).send_control_message(
+                    PatchPointControlMessage::Disconnect { id: from, fade },
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.nodes.iter().all(|(_, node)| node.is_finished())
+    }
+
+    /// Every node's ID in start order, with whether it is still running
+    pub fn node_states(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.nodes
+            .iter()
+            .map(|(id, node)| (id.as_str(), !node.is_finished()))
+    }
+
+    /// Take the next error reported by any node, with the ID of the node that reported it
+    pub fn try_recv_error(&self) -> Option<(String, NodeError)> {
+        self.nodes
+            .iter()
+            .find_map(|(id, node)| node.try_recv_error().map(|error| (id.clone(), error)))
+    }
+
+    /// Start fading out the audio arriving at nodes with no outgoing edges
+    ///
+    /// Everything upstream keeps running, so this is the first half of a
+    /// graceful shutdown.
+    pub fn fade_out(&self, fade: Duration) -> Result<()> {
+        for edge in &self.edges {
+            if self.edges.iter().any(|other| other.from == edge.to) {
+                continue;
+            }
+            let (patch_point, _) = self.ports(&edge.to)?.inputs[edge.slot];
+            self.patch_point(patch_point).send_control_message(
+                PatchPointControlMessage::Disconnect {
+                    id: edge.from.clone(),
+                    fade,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Fade out over `fade`, then stop every node, sources first, and join them
+    ///
+    /// Nodes that haven't stopped within a few seconds of being asked are
+    /// left running rather than blocking forever. Returns the first error
+    /// that stopped a node, like `join`.
+    pub fn shutdown(self, fade: Duration) -> Result<()> {
+        if !fade.is_zero() {
+            self.fade_out(fade)?;
+            let faded_at = Instant::now() + fade;
+            while Instant::now() < faded_at && !self.is_finished() {
+                thread::sleep(SHUTDOWN_POLL);
+            }
+        }
+        for (id, node) in &self.nodes {
+            if node.request_immediate_shutdown().is_err() {
+                debug!("signal graph node {:?} had already stopped", id);
+            }
+        }
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while Instant::now() < deadline && !self.nodes.iter().all(|(_, node)| node.has_exited()) {
+            thread::sleep(SHUTDOWN_POLL);
+        }
+        let exited = self.nodes.into_iter().filter(|(id, node)| {
+            if !node.has_exited() {
+                warn!(
+                    "signal graph node {:?} didn't stop within {:?}, leaving it running",
+                    id, SHUTDOWN_TIMEOUT
+                );
+            }
+            node.has_exited()
+        });
+        join_all(exited)
+    }
+
+    /// Wait for every node to finish on its own
+    ///
+    /// Outputs that were never taken are discarded. Returns the first error
+    /// that stopped a node once every node has finished, and logs the rest.
+    pub fn join(self) -> Result<()> {
+        for id in &self.untaken_outputs {
+            let _ = self.copy_output(id);
+        }
+        join_all(self.nodes.into_iter())
+    }
+
+    fn ports(&self, id: &str) -> Result<&Ports> {
+        match self.ports.get(id) {
+            Some(ports) => Ok(ports),
+            None => bail!("signal graph has no node {:?}", id),
+        }
+    }
+
+    fn output_port(&self, id: &str) -> Result<&OutputPort> {
+        match &self.ports(id)?.output {
+            Some(output) => Ok(output),
+            None => bail!("signal graph node {:?} has no output", id),
+        }
+    }
+
+    /// Add a copy of a node's output bus
+    fn copy_output(&self, id: &str) -> Result<AudioBus> {
+        let output = self.output_port(id)?;
+        let (bus, sender) = AudioBus::bounded(
+            output.spec,
+            output.expected_total_samples,
+            BusPolicy::UNBOUNDED,
+        );
+        let fan_out: &Node<FanOut, FanOutControlMessage> =
+            self.nodes[output.node].1.as_any().downcast_ref().unwrap();
+        fan_out.send_control_message(FanOutControlMessage::AddOutput { output: sender })?;
+        Ok(bus)
+    }
+
+    fn patch_point(&self, index: usize) -> &Node<PatchPoint, PatchPointControlMessage> {
+        self.nodes[index].1.as_any().downcast_ref().unwrap()
+    }
+
+    /// Whether audio from `from` currently reaches `to`
+    fn feeds(&self, from: &str, to: &str) -> bool {
+        let mut stack = vec![from];
+        let mut visited = HashSet::new();
+        while let Some(id) = stack.pop() {
+            if id == to {
+                return true;
+            }
+            if visited.insert(id) {
+                stack.extend(
+                    self.edges
+                        .iter()
+                        .filter(|edge| edge.from == id)
+                        .map(|edge| edge.to.as_str()),
+                );
+            }
+        }
+        false
+    }
+}
+
+/// Join nodes in order, returning the first error and logging the rest
+fn join_all(nodes: impl Iterator<Item = (String, Box<dyn GraphNode>)>) -> Result<()> {
+    let mut first_error = None;
+    for (id, node) in nodes {
+        if let Err(e) = node.join() {
+            let e = anyhow!("signal graph node {:?} stopped: {}", id, e);
+            match first_error {
+                None => first_error = Some(e),
+                Some(_) => error!("{}", e),
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::{Audio, Timestamp};
+    use crate::effect_processor::{EffectProcessor, EffectProcessorControlMessage};
+    use crate::effects::TimeDomainEffect;
+    use crate::mixer_processor::MixerProcessor;
+    use crate::test_utils::*;
+    use std::thread;
+
+    struct Gain(f32);
+
+    impl TimeDomainEffect for Gain {
+        fn process(&mut self, _channel: usize, samples: &mut [f32]) {
+            for sample in samples.iter_mut() {
+                *sample *= self.0;
+            }
+        }
+    }
+
+    /// Reports a latency without delaying anything
+    struct Late(usize);
+
+    impl TimeDomainEffect for Late {
+        fn process(&mut self, _channel: usize, _samples: &mut [f32]) {}
+
+        fn latency(&self) -> usize {
+            self.0
+        }
+    }
+
+    fn add_source(graph: &mut Graph, id: &str, audio: Audio) {
+        graph
+            .add_node(id, move |_| {
+                Ok(EffectProcessor::new(
+                    AudioBus::from_audio(audio),
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+    }
+
+    fn add_gain(graph: &mut Graph, id: &str, gain: f32) {
+        graph
+            .add_node(id, move |mut inputs| {
+                Ok(EffectProcessor::new(
+                    inputs.remove(0),
+                    Box::new(Gain(gain)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn chain_starts_in_edge_order() {
+        let mut graph = Graph::new();
+        // added before its input to check that start order follows the edges
+        add_gain(&mut graph, "double", 2.0);
+        add_source(&mut graph, "source", generate_audio(1.0, 10, 2, 44100));
+        graph.connect("source", "double").unwrap();
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("double").unwrap().into_audio();
+        running.join().unwrap();
+        assert_almost_eq_by_element(output.data[1].clone(), vec![2.0; 10]);
+    }
+
+    #[test]
+    fn fan_out_copies_to_every_consumer() {
+        let mut graph = Graph::new();
+        add_source(&mut graph, "source", generate_audio(1.0, 10, 1, 44100));
+        add_gain(&mut graph, "double", 2.0);
+        add_gain(&mut graph, "triple", 3.0);
+        graph.connect("source", "double").unwrap();
+        graph.connect("source", "triple").unwrap();
+        let mut running = graph.start().unwrap();
+        let doubled = running.take_output("double").unwrap().into_audio();
+        let tripled = running.take_output("triple").unwrap().into_audio();
+        assert!(running.take_output("source").is_none());
+        running.join().unwrap();
+        assert_almost_eq_by_element(doubled.data[0].clone(), vec![2.0; 10]);
+        assert_almost_eq_by_element(tripled.data[0].clone(), vec![3.0; 10]);
+    }
+
+    #[test]
+    fn inputs_can_be_rechunked() {
+        let mut graph = Graph::new();
+        add_source(&mut graph, "source", generate_audio(1.0, 10, 2, 44100));
+        add_gain(&mut graph, "double", 2.0);
+        graph.connect("source", "double").unwrap();
+        graph.set_input_chunk_len("double", 4).unwrap();
+        assert!(graph.set_input_chunk_len("double", 0).is_err());
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("double").unwrap();
+        let chunks: Vec<Vec<f32>> = output.channels[0].iter().collect();
+        running.join().unwrap();
+        assert_eq!(chunks, vec![vec![2.0; 4], vec![2.0; 4], vec![2.0; 2]]);
+    }
+
+    #[test]
+    fn parallel_inputs_are_aligned() {
+        let mut graph = Graph::new();
+        add_source(&mut graph, "dry", generate_audio(1.0, 10, 1, 44100));
+        graph
+            .add_node("wet", |mut inputs| {
+                Ok(EffectProcessor::new(
+                    inputs.remove(0),
+                    Box::new(Late(3)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        graph
+            .add_node("mix", |inputs| {
+                MixerProcessor::new(inputs, BusPolicy::UNBOUNDED)
+            })
+            .unwrap();
+        graph.connect("dry", "wet").unwrap();
+        graph.connect("dry", "mix").unwrap();
+        graph.connect("wet", "mix").unwrap();
+        graph.align_inputs("mix").unwrap();
+        let mut running = graph.start().unwrap();
+        assert_eq!(running.latency("dry"), Some(0));
+        assert_eq!(running.latency("wet"), Some(3));
+        assert_eq!(running.latency("mix"), Some(3));
+        assert_eq!(running.latency("nope"), None);
+        let output = running.take_output("mix").unwrap().into_audio();
+        running.join().unwrap();
+        let mut expected = vec![1.0; 13];
+        expected[3..10].fill(2.0);
+        assert_almost_eq_by_element(output.data[0].clone(), expected);
+    }
+
+    #[test]
+    fn timestamps_pass_through_nodes() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 10,
+        };
+        let (source, mut sender) = AudioBus::bounded(spec, None, BusPolicy::UNBOUNDED);
+        let at = Instant::now();
+        sender.send_frame(vec![vec![1.0; 4]]);
+        sender.send_timestamp(Timestamp { sample: 4, at });
+        sender.send_frame(vec![vec![1.0; 4]]);
+        drop(sender);
+        let mut graph = Graph::new();
+        graph
+            .add_node("source", move |_| {
+                Ok(EffectProcessor::new(
+                    source,
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        add_gain(&mut graph, "double", 2.0);
+        graph.connect("source", "double").unwrap();
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("double").unwrap();
+        let timestamps = output.timestamps.clone().unwrap();
+        output.into_audio();
+        running.join().unwrap();
+        assert_eq!(
+            timestamps.try_iter().collect::<Vec<_>>(),
+            vec![Timestamp { sample: 4, at }]
+        );
+    }
+
+    #[test]
+    fn cycles_and_unknown_nodes_are_rejected() {
+        let mut graph = Graph::new();
+        add_gain(&mut graph, "a", 1.0);
+        add_gain(&mut graph, "b", 1.0);
+        graph.connect("a", "b").unwrap();
+        graph.connect("b", "a").unwrap();
+        assert!(graph.connect("a", "nope").is_err());
+        assert!(graph
+            .add_node("a", |_| -> Result<(EffectProcessor, AudioBus)> {
+                unreachable!()
+            })
+            .is_err());
+        assert!(graph.start().is_err());
+    }
+
+    #[test]
+    fn nodes_are_found_by_id_and_type() {
+        let mut graph = Graph::new();
+        add_source(&mut graph, "source", generate_audio(1.0, 10, 1, 44100));
+        let mut running = graph.start().unwrap();
+        let node = running
+            .node::<EffectProcessor, EffectProcessorControlMessage>("source")
+            .unwrap();
+        node.send_control_message(EffectProcessorControlMessage::SetBypass { enabled: true })
+            .unwrap();
+        assert!(running
+            .node::<FanOut, FanOutControlMessage>("source")
+            .is_none());
+        running.take_output("source").unwrap().into_audio();
+        running.shutdown(Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn edges_can_be_changed_while_running() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 10,
+        };
+        let (a, a_senders) = AudioBus::from_spec(spec, None);
+        let (b, b_senders) = AudioBus::from_spec(spec, None);
+        let mut graph = Graph::new();
+        graph
+            .add_node("a", move |_| {
+                Ok(EffectProcessor::new(
+                    a,
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        graph
+            .add_node("b", move |_| {
+                Ok(EffectProcessor::new(
+                    b,
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        add_gain(&mut graph, "out", 1.0);
+        graph.connect("a", "out").unwrap();
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("out").unwrap();
+        let send_and_receive = |a_chunk: Vec<f32>, b_chunk: Vec<f32>| {
+            // give the patch point time to apply the change
+            thread::sleep(Duration::from_millis(100));
+            a_senders[0].send(a_chunk).unwrap();
+            b_senders[0].send(b_chunk).unwrap();
+            let mut samples = vec![];
+            while samples.len() < 4 {
+                samples.extend(
+                    output.channels[0]
+                        .recv_timeout(Duration::from_secs(5))
+                        .unwrap(),
+                );
+            }
+            samples
+        };
+
+        let connect = |from: &str, to: &str| GraphControlMessage::Connect {
+            from: from.to_string(),
+            to: to.to_string(),
+            fade: Duration::ZERO,
+        };
+        running.send_control_message(connect("b", "out")).unwrap();
+        assert_almost_eq_by_element(send_and_receive(vec![1.0; 4], vec![2.0; 4]), vec![3.0; 4]);
+
+        running
+            .send_control_message(GraphControlMessage::Disconnect {
+                from: "a".to_string(),
+                to: "out".to_string(),
+                fade: Duration::ZERO,
+            })
+            .unwrap();
+        assert_almost_eq_by_element(send_and_receive(vec![1.0; 4], vec![2.0; 4]), vec![2.0; 4]);
+
+        assert!(running.send_control_message(connect("b", "out")).is_err());
+        assert!(running.send_control_message(connect("out", "out")).is_err());
+        assert!(running.send_control_message(connect("out", "a")).is_err());
+        drop(a_senders);
+        drop(b_senders);
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_sources_that_never_end() {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 10,
+        };
+        let (source, source_senders) = AudioBus::from_spec(spec, None);
+        let mut graph = Graph::new();
+        graph
+            .add_node("source", move |_| {
+                Ok(EffectProcessor::new(
+                    source,
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        add_gain(&mut graph, "out", 1.0);
+        graph.connect("source", "out").unwrap();
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("out").unwrap();
+        source_senders[0].send(vec![1.0; 4]).unwrap();
+        output.channels[0]
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        running.shutdown(Duration::from_millis(50)).unwrap();
+        drop(source_senders);
+    }
+}
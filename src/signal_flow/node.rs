@@ -1,6 +1,7 @@
 use anyhow::Result;
-use crossbeam_channel::{Receiver, Sender};
-use std::fmt::Debug;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::any::Any;
+use std::fmt::{self, Debug};
 use std::marker::PhantomData;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -8,8 +9,36 @@ use std::thread::JoinHandle;
 
 pub trait ControlMessage: Send + Sync + Debug + 'static {
     fn shutdown_msg() -> Self;
+
+    /// A shutdown message that skips any fade out the processor would otherwise do
+    fn immediate_shutdown_msg() -> Self
+    where
+        Self: Sized,
+    {
+        Self::shutdown_msg()
+    }
+}
+
+/// Why a processor thread stopped early, reported to the owner of its node
+#[derive(Debug)]
+pub enum NodeError {
+    /// The processor hit an error it couldn't recover from
+    Failed(anyhow::Error),
+    /// The processor thread panicked
+    Panicked(String),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::Failed(e) => write!(f, "processor failed: {}", e),
+            NodeError::Panicked(message) => write!(f, "processor panicked: {}", message),
+        }
+    }
 }
 
+impl std::error::Error for NodeError {}
+
 pub struct Node<P, M>
 where
     P: Processor<M>,
@@ -19,6 +48,7 @@ where
     join_handle: JoinHandle<()>,
     phantom: PhantomData<P>,
     finished: Arc<AtomicBool>,
+    errors: Receiver<NodeError>,
 }
 
 impl<P, M> Node<P, M>
@@ -28,11 +58,14 @@ where
 {
     pub fn new(processor: P) -> Node<P, M> {
         let finished = Arc::new(AtomicBool::new(false));
-        let (control_message_sender, join_handle) = processor.start(Arc::clone(&finished));
+        let (error_sender, errors) = unbounded();
+        let (control_message_sender, join_handle) =
+            processor.start(Arc::clone(&finished), error_sender);
         Node {
             control_message_sender,
             join_handle,
             finished,
+            errors,
             phantom: PhantomData,
         }
     }
@@ -47,13 +80,40 @@ where
         Ok(self.join_handle)
     }
 
-    pub fn join(self) {
-        self.join_handle.join().unwrap();
+    /// Wait for the processor thread to end, returning the error that stopped it, if any
+    pub fn join(self) -> Result<(), NodeError> {
+        if let Err(payload) = self.join_handle.join() {
+            return Err(NodeError::Panicked(panic_message(payload)));
+        }
+        match self.errors.try_recv() {
+            Ok(error) => Err(error),
+            Err(_) => Ok(()),
+        }
     }
 
     pub fn is_finished(&self) -> bool {
         self.finished.load(Ordering::Relaxed)
     }
+
+    /// Whether the processor thread has ended, including by panicking
+    pub fn has_exited(&self) -> bool {
+        self.join_handle.is_finished()
+    }
+
+    /// Take the next error reported by the processor, if any
+    pub fn try_recv_error(&self) -> Option<NodeError> {
+        self.errors.try_recv().ok()
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
 }
 
 pub enum ProcessorState {
@@ -65,7 +125,16 @@ pub trait Processor<M>: Sized + Send + 'static
 where
     M: ControlMessage,
 {
-    fn start(self, finished: Arc<AtomicBool>) -> (Sender<M>, JoinHandle<()>);
+    /// Spawn the processor thread
+    ///
+    /// Set `finished` when the thread ends. If an error stops the processor,
+    /// send it on `errors` instead of panicking, so the node's owner can
+    /// decide whether to restart it, fade out, or exit.
+    fn start(
+        self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<M>, JoinHandle<()>);
 
     /// Handle control messages, if any are ready.
     ///
@@ -76,12 +145,19 @@ where
     /// Otherwise return `Ok(ProcessorState::Running)`. If fatal unexpected errors
     /// occur, return the error.
     fn handle_control_messages(&mut self, rx: &Receiver<M>) -> Result<ProcessorState>;
+
+    /// How many frames the processor's output lags its input by, so that a
+    /// graph can delay parallel paths to match
+    fn latency(&self) -> usize {
+        0
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crossbeam_channel::{unbounded, TryRecvError};
+    use anyhow::anyhow;
+    use crossbeam_channel::TryRecvError;
     use std::thread;
     use std::time::Duration;
 
@@ -92,6 +168,24 @@ mod test {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn processor_errors_are_reported() {
+        let node = Node::new(FailingProcessor { panic: false });
+        match node.join() {
+            Err(NodeError::Failed(e)) => assert_eq!(e.to_string(), "oh no"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn processor_panics_are_reported() {
+        let node = Node::new(FailingProcessor { panic: true });
+        match node.join() {
+            Err(NodeError::Panicked(message)) => assert_eq!(message, "oh no"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
     #[derive(Debug)]
     enum TestControlMessage {
         Shutdown,
@@ -109,6 +203,7 @@ mod test {
         fn start(
             mut self,
             finished: Arc<AtomicBool>,
+            _errors: Sender<NodeError>,
         ) -> (Sender<TestControlMessage>, JoinHandle<()>) {
             let (tx, rx) = unbounded();
             let handle = thread::spawn(move || {
@@ -140,4 +235,33 @@ mod test {
             }
         }
     }
+
+    struct FailingProcessor {
+        panic: bool,
+    }
+
+    impl Processor<TestControlMessage> for FailingProcessor {
+        fn start(
+            self,
+            finished: Arc<AtomicBool>,
+            errors: Sender<NodeError>,
+        ) -> (Sender<TestControlMessage>, JoinHandle<()>) {
+            let (tx, _rx) = unbounded();
+            let handle = thread::spawn(move || {
+                if self.panic {
+                    panic!("oh no");
+                }
+                errors.send(NodeError::Failed(anyhow!("oh no"))).unwrap();
+                finished.store(true, Ordering::Relaxed);
+            });
+            (tx, handle)
+        }
+
+        fn handle_control_messages(
+            &mut self,
+            _rx: &Receiver<TestControlMessage>,
+        ) -> Result<ProcessorState> {
+            Ok(ProcessorState::Running)
+        }
+    }
 }
@@ -0,0 +1,486 @@
+use super::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::audio::{AudioBus, AudioSpec, BusPolicy, BusSender, Timestamp};
+use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Select, Sender, TryRecvError};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+/// How often a fan-out with nowhere to send audio checks for new outputs
+const IDLE_POLL: Duration = Duration::from_millis(5);
+
+#[derive(Debug)]
+pub(crate) enum FanOutControlMessage {
+    Shutdown,
+    AddOutput { output: BusSender },
+}
+
+impl ControlMessage for FanOutControlMessage {
+    fn shutdown_msg() -> Self {
+        FanOutControlMessage::Shutdown
+    }
+}
+
+/// Copies every chunk of one bus onto any number of others
+///
+/// Outputs can be added while running, and are dropped once their
+/// receivers are. Input is left unread until the first output is added,
+/// and discarded whenever there are no outputs after that.
+pub(crate) struct FanOut {
+    input: AudioBus,
+    outputs: Vec<FanOutCopy>,
+    /// Frames read from the input so far
+    input_frames: u64,
+    has_had_output: bool,
+}
+
+struct FanOutCopy {
+    output: BusSender,
+    /// How much further along the copy's frames are than the input's, for
+    /// copies added after the input started
+    offset: i64,
+}
+
+impl FanOut {
+    pub(crate) fn new(input: AudioBus) -> FanOut {
+        FanOut {
+            input,
+            outputs: vec![],
+            input_frames: 0,
+            has_had_output: false,
+        }
+    }
+
+    pub(crate) fn add_output(&mut self) -> AudioBus {
+        let (bus, output) = AudioBus::bounded(
+            self.input.spec,
+            self.input.expected_total_samples,
+            BusPolicy::UNBOUNDED,
+        );
+        self.push_output(output);
+        bus
+    }
+
+    fn push_output(&mut self, output: BusSender) {
+        self.outputs.push(FanOutCopy {
+            output,
+            offset: -(self.input_frames as i64),
+        });
+        self.has_had_output = true;
+    }
+}
+
+impl Processor<FanOutControlMessage> for FanOut {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<FanOutControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let mut open_channels = self.input.channels.len();
+            while open_channels > 0 {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                if !self.has_had_output {
+                    thread::sleep(IDLE_POLL);
+                    continue;
+                }
+                open_channels = 0;
+                for (i, channel) in self.input.channels.iter().enumerate() {
+                    let chunk = match channel.recv_timeout(INPUT_POLL) {
+                        Ok(chunk) => chunk,
+                        Err(RecvTimeoutError::Timeout) => {
+                            open_channels += 1;
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => continue,
+                    };
+                    open_channels += 1;
+                    if i == 0 {
+                        self.input_frames += chunk.len() as u64;
+                    }
+                    self.outputs
+                        .retain_mut(|copy| copy.output.send(i, chunk.clone()));
+                }
+                let timestamps = self.input.take_timestamps();
+                for copy in self.outputs.iter_mut() {
+                    copy.output.forward_timestamps(&timestamps, copy.offset);
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<FanOutControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                FanOutControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                FanOutControlMessage::AddOutput { output } => {
+                    self.push_output(output);
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PatchPointControlMessage {
+    Shutdown,
+    /// Mix in a bus, fading it in over `fade`
+    Connect {
+        id: String,
+        bus: AudioBus,
+        fade: Duration,
+    },
+    /// Fade a connected bus out over `fade`, then drop it
+    Disconnect {
+        id: String,
+        fade: Duration,
+    },
+}
+
+impl ControlMessage for PatchPointControlMessage {
+    fn shutdown_msg() -> Self {
+        PatchPointControlMessage::Shutdown
+    }
+}
+
+/// Mixes any number of buses into one, so that a node's input can be rewired while it runs
+///
+/// Finishes once a source runs out of audio and no other sources remain.
+/// Sources that are disconnected don't finish it, since another may be
+/// connected in their place.
+pub(crate) struct PatchPoint {
+    spec: AudioSpec,
+    sources: Vec<PatchSource>,
+    output: BusSender,
+    source_ended: bool,
+}
+
+struct PatchSource {
+    id: String,
+    channels: Vec<Receiver<Vec<f32>>>,
+    timestamps: Option<Receiver<Timestamp>>,
+    /// Frames the output had sent when the source was connected
+    offset: i64,
+    queues: Vec<VecDeque<f32>>,
+    /// Whether each input channel has disconnected
+    ended: Vec<bool>,
+    /// Samples taken from each channel since the source was connected
+    positions: Vec<usize>,
+    fade_in: usize,
+    /// (position, length) of a requested fade out
+    fade_out: Option<(usize, usize)>,
+}
+
+impl PatchPoint {
+    pub(crate) fn new(id: &str, input: AudioBus) -> (PatchPoint, AudioBus) {
+        let (output, sender) = AudioBus::bounded(
+            input.spec,
+            input.expected_total_samples,
+            BusPolicy::UNBOUNDED,
+        );
+        (
+            PatchPoint {
+                spec: input.spec,
+                sources: vec![PatchSource::new(id, input, 0, 0)],
+                output: sender,
+                source_ended: false,
+            },
+            output,
+        )
+    }
+
+    fn duration_to_samples(&self, duration: Duration) -> usize {
+        (duration.as_secs_f32() * self.spec.sample_rate as f32) as usize
+    }
+
+    /// Wait until a source or control message is ready, or `INPUT_POLL` passes
+    fn wait_for_input(&self, ctrl_rx: &Receiver<PatchPointControlMessage>) {
+        let mut select = Select::new();
+        select.recv(ctrl_rx);
+        for source in &self.sources {
+            for (channel, ended) in source.channels.iter().zip(&source.ended) {
+                if !ended {
+                    select.recv(channel);
+                }
+            }
+        }
+        let _ = select.ready_timeout(INPUT_POLL);
+    }
+
+    /// Mix whatever every source has ready for `channel`
+    ///
+    /// Sources that are still receiving input hold the mix back until they
+    /// catch up, so that they stay aligned with each other.
+    fn mix_channel(&mut self, channel: usize) -> Vec<f32> {
+        let available =
+            |source: &PatchSource| source.queues[channel].len().min(source.remaining(channel));
+        let waiting = self
+            .sources
+            .iter()
+            .filter(|source| !source.ended[channel] && source.remaining(channel) > 0)
+            .map(available)
+            .min();
+        let len = match waiting {
+            Some(len) => len,
+            None => self.sources.iter().map(available).max().unwrap_or(0),
+        };
+        let mut mixed = vec![0.0; len];
+        for source in self.sources.iter_mut() {
+            let take = len.min(available(source));
+            for sample in mixed[..take].iter_mut() {
+                let gain = source.gain(source.positions[channel]);
+                *sample += source.queues[channel].pop_front().unwrap() * gain;
+                source.positions[channel] += 1;
+            }
+        }
+        mixed
+    }
+
+    fn remove_finished_sources(&mut self) {
+        let n_channels = self.spec.channels as usize;
+        let mut i = 0;
+        while i < self.sources.len() {
+            let source = &self.sources[i];
+            if (0..n_channels).all(|channel| source.is_finished(channel)) {
+                if source.fade_out.is_none() {
+                    self.source_ended = true;
+                }
+                self.sources.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl PatchSource {
+    fn new(id: &str, bus: AudioBus, fade_in: usize, offset: u64) -> PatchSource {
+        let n_channels = bus.channels.len();
+        PatchSource {
+            id: id.to_string(),
+            channels: bus.channels,
+            timestamps: bus.timestamps,
+            offset: offset as i64,
+            queues: vec![VecDeque::new(); n_channels],
+            ended: vec![false; n_channels],
+            positions: vec![0; n_channels],
+            fade_in,
+            fade_out: None,
+        }
+    }
+
+    fn receive(&mut self) {
+        for (i, channel) in self.channels.iter().enumerate() {
+            loop {
+                match channel.try_recv() {
+                    Ok(chunk) => self.queues[i].extend(chunk),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        self.ended[i] = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Samples left on `channel` before a requested fade out completes
+    fn remaining(&self, channel: usize) -> usize {
+        match self.fade_out {
+            Some((start, len)) => (start + len).saturating_sub(self.positions[channel]),
+            None => usize::MAX,
+        }
+    }
+
+    fn is_finished(&self, channel: usize) -> bool {
+        self.remaining(channel) == 0 || (self.ended[channel] && self.queues[channel].is_empty())
+    }
+
+    fn gain(&self, position: usize) -> f32 {
+        let mut gain = ramp(position, 0, self.fade_in);
+        if let Some((start, len)) = self.fade_out {
+            gain *= 1.0 - ramp(position, start, len);
+        }
+        gain
+    }
+}
+
+/// Rises linearly from 0 at `start` to 1 at `start + len`
+fn ramp(position: usize, start: usize, len: usize) -> f32 {
+    if position >= start + len {
+        1.0
+    } else {
+        position.saturating_sub(start) as f32 / len as f32
+    }
+}
+
+impl Processor<PatchPointControlMessage> for PatchPoint {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<PatchPointControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            'outer: loop {
+                self.wait_for_input(&ctrl_rx);
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                for source in self.sources.iter_mut() {
+                    source.receive();
+                    let timestamps: Vec<Timestamp> = source
+                        .timestamps
+                        .iter()
+                        .flat_map(Receiver::try_iter)
+                        .collect();
+                    self.output.forward_timestamps(&timestamps, source.offset);
+                }
+                for channel in 0..self.spec.channels as usize {
+                    let mixed = self.mix_channel(channel);
+                    if !mixed.is_empty() && !self.output.send(channel, mixed) {
+                        info!("patch point output disconnected, stopping");
+                        break 'outer;
+                    }
+                }
+                self.remove_finished_sources();
+                if self.sources.is_empty() && self.source_ended {
+                    break;
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<PatchPointControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                PatchPointControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                PatchPointControlMessage::Connect { id, bus, fade } => {
+                    if bus.spec != self.spec {
+                        warn!(
+                            "can't patch {:?} with {:?} into an input of {:?}",
+                            id, bus.spec, self.spec
+                        );
+                    } else {
+                        let fade_in = self.duration_to_samples(fade);
+                        let offset = self.output.frames_sent();
+                        self.sources
+                            .push(PatchSource::new(&id, bus, fade_in, offset));
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                PatchPointControlMessage::Disconnect { id, fade } => {
+                    let fade_out = self.duration_to_samples(fade);
+                    match self.sources.iter_mut().find(|source| source.id == id) {
+                        Some(source) => {
+                            let start = source.positions.iter().copied().max().unwrap_or(0);
+                            source.fade_out = Some((start, fade_out));
+                        }
+                        None => warn!("{:?} is not patched into this input", id),
+                    }
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    const SPEC: AudioSpec = AudioSpec {
+        channels: 1,
+        sample_rate: 10,
+    };
+
+    fn receive(bus: &AudioBus, len: usize) -> Vec<f32> {
+        let mut samples = vec![];
+        while samples.len() < len {
+            samples.extend(
+                bus.channels[0]
+                    .recv_timeout(Duration::from_secs(5))
+                    .unwrap(),
+            );
+        }
+        samples
+    }
+
+    #[test]
+    fn mixes_sources_and_fades_out_on_disconnect() {
+        let (a, a_senders) = AudioBus::from_spec(SPEC, None);
+        let (b, b_senders) = AudioBus::from_spec(SPEC, None);
+        let (patch_point, output) = PatchPoint::new("a", a);
+        let node = Node::new(patch_point);
+        node.send_control_message(PatchPointControlMessage::Connect {
+            id: "b".to_string(),
+            bus: b,
+            fade: Duration::ZERO,
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        a_senders[0].send(vec![1.0; 4]).unwrap();
+        b_senders[0].send(vec![2.0; 4]).unwrap();
+        assert_almost_eq_by_element(receive(&output, 4), vec![3.0; 4]);
+
+        node.send_control_message(PatchPointControlMessage::Disconnect {
+            id: "a".to_string(),
+            fade: Duration::from_millis(400),
+        })
+        .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        a_senders[0].send(vec![1.0; 6]).unwrap();
+        b_senders[0].send(vec![2.0; 6]).unwrap();
+        assert_almost_eq_by_element(receive(&output, 6), vec![3.0, 2.75, 2.5, 2.25, 2.0, 2.0]);
+
+        // a was disconnected, so only b running out finishes the patch point
+        drop(a_senders);
+        drop(b_senders);
+        node.join().unwrap();
+    }
+
+    #[test]
+    fn fan_out_holds_input_until_it_has_an_output() {
+        let input = AudioBus::from_audio(generate_audio(1.0, 5, 1, 10));
+        let node = Node::new(FanOut::new(input));
+        thread::sleep(Duration::from_millis(20));
+        let (output, sender) = AudioBus::bounded(SPEC, None, BusPolicy::UNBOUNDED);
+        node.send_control_message(FanOutControlMessage::AddOutput { output: sender })
+            .unwrap();
+        assert_almost_eq_by_element(output.into_audio().data[0].clone(), vec![1.0; 5]);
+        node.join().unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use super::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::audio::{AudioBus, BusPolicy, BusSender};
+use anyhow::Result;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum RechunkerControlMessage {
+    Shutdown,
+}
+
+impl ControlMessage for RechunkerControlMessage {
+    fn shutdown_msg() -> Self {
+        RechunkerControlMessage::Shutdown
+    }
+}
+
+/// Buffers a bus and sends it on in chunks of a fixed number of frames, for
+/// processors that want e.g. an FFT hop at a time rather than whatever size
+/// the device callback or the previous node happened to produce
+///
+/// Every chunk but the last is exactly `chunk_len` frames. Once the input
+/// ends, whatever is left is sent as one shorter chunk.
+pub struct Rechunker {
+    input: AudioBus,
+    chunk_len: usize,
+    pending: Vec<Vec<f32>>,
+    output: BusSender,
+}
+
+impl Rechunker {
+    pub fn new(input: AudioBus, chunk_len: usize, policy: BusPolicy) -> (Rechunker, AudioBus) {
+        assert!(chunk_len > 0);
+        let (output, sender) = AudioBus::bounded(input.spec, input.expected_total_samples, policy);
+        (
+            Rechunker {
+                pending: vec![Vec::with_capacity(chunk_len * 2); input.channels.len()],
+                input,
+                chunk_len,
+                output: sender,
+            },
+            output,
+        )
+    }
+
+    /// Send every whole chunk that every channel has, returning false once
+    /// the output has gone
+    fn send_whole_chunks(&mut self) -> bool {
+        while self
+            .pending
+            .iter()
+            .all(|pending| pending.len() >= self.chunk_len)
+        {
+            let chunks = self
+                .pending
+                .iter_mut()
+                .map(|pending| {
+                    let rest = pending.split_off(self.chunk_len);
+                    mem::replace(pending, rest)
+                })
+                .collect();
+            if !self.output.send_frame(chunks) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Processor<RechunkerControlMessage> for Rechunker {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<RechunkerControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let mut closed = vec![false; self.input.channels.len()];
+            loop {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                for (i, channel) in self.input.channels.iter().enumerate() {
+                    if closed[i] || self.pending[i].len() >= self.chunk_len {
+                        continue;
+                    }
+                    match channel.recv_timeout(INPUT_POLL) {
+                        Ok(chunk) => self.pending[i].extend(chunk),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => closed[i] = true,
+                    }
+                }
+                self.output
+                    .forward_timestamps(&self.input.take_timestamps(), 0);
+                if !self.send_whole_chunks() {
+                    info!("rechunker output disconnected, stopping");
+                    break;
+                }
+                if closed.iter().all(|closed| *closed) {
+                    let rest = mem::take(&mut self.pending);
+                    if rest.iter().any(|pending| !pending.is_empty()) {
+                        self.output.send_frame(rest);
+                    }
+                    break;
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<RechunkerControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                RechunkerControlMessage::Shutdown => Ok(ProcessorState::Finished),
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use crate::signal_flow::node::Node;
+
+    #[test]
+    fn sends_fixed_size_chunks() {
+        let spec = AudioSpec {
+            channels: 2,
+            sample_rate: 10,
+        };
+        let (input, senders) = AudioBus::from_spec(spec, None);
+        let (rechunker, output) = Rechunker::new(input, 4, BusPolicy::UNBOUNDED);
+        let node = Node::new(rechunker);
+        let samples: Vec<f32> = (0..11).map(|i| i as f32).collect();
+        for chunk in [&samples[..3], &samples[3..8], &samples[8..]] {
+            senders[0].send(chunk.to_vec()).unwrap();
+            senders[1]
+                .send(chunk.iter().map(|sample| -sample).collect())
+                .unwrap();
+        }
+        drop(senders);
+        let chunks: Vec<Vec<Vec<f32>>> = output
+            .channels
+            .iter()
+            .map(|rx| rx.iter().collect())
+            .collect();
+        node.join().unwrap();
+        let lens: Vec<usize> = chunks[0].iter().map(Vec::len).collect();
+        assert_eq!(lens, vec![4, 4, 3]);
+        assert_eq!(chunks[0].concat(), samples);
+        assert_eq!(chunks[1][1], vec![-4.0, -5.0, -6.0, -7.0]);
+    }
+}
@@ -0,0 +1,244 @@
+use super::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::audio::AudioBus;
+use crate::metrics::{self, Counter};
+use anyhow::Result;
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const INPUT_POLL: Duration = Duration::from_millis(50);
+
+/// What a splitter does when one of its outputs is full
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BackPressure {
+    /// Wait for the slow consumer, holding every other output back with it
+    Block,
+    /// Skip chunks for the slow consumer so the others keep flowing, e.g.
+    /// so a file sink falling behind never stalls the speakers
+    Drop,
+}
+
+#[derive(Debug)]
+pub enum SplitterControlMessage {
+    Shutdown,
+}
+
+impl ControlMessage for SplitterControlMessage {
+    fn shutdown_msg() -> Self {
+        SplitterControlMessage::Shutdown
+    }
+}
+
+struct SplitterOutput {
+    senders: Vec<Sender<Vec<f32>>>,
+    dropped_chunks: usize,
+}
+
+/// Copies every chunk of one bus onto a fixed number of bounded buses
+///
+/// Chunks are sent one from each channel at a time, so with
+/// `BackPressure::Drop` an output always loses whole frames and its
+/// channels stay aligned. Outputs are dropped once their receivers are.
+pub struct Splitter {
+    input: AudioBus,
+    outputs: Vec<SplitterOutput>,
+    back_pressure: BackPressure,
+    dropped: Counter,
+}
+
+impl Splitter {
+    /// Split `input` into `count` buses, each holding at most `queue_len`
+    /// chunks per channel that haven't been received yet
+    pub fn new(
+        input: AudioBus,
+        count: usize,
+        queue_len: usize,
+        back_pressure: BackPressure,
+    ) -> (Splitter, Vec<AudioBus>) {
+        let mut outputs = vec![];
+        let mut buses = vec![];
+        for _ in 0..count {
+            let (senders, receivers) = (0..input.spec.channels).map(|_| bounded(queue_len)).unzip();
+            outputs.push(SplitterOutput {
+                senders,
+                dropped_chunks: 0,
+            });
+            buses.push(AudioBus {
+                spec: input.spec,
+                channels: receivers,
+                expected_total_samples: input.expected_total_samples,
+                timestamps: None,
+            });
+        }
+        (
+            Splitter {
+                input,
+                outputs,
+                back_pressure,
+                dropped: metrics::registry().counter(
+                    "rocoder_splitter_dropped_chunks_total",
+                    &[],
+                    "Chunks skipped for splitter outputs that fell behind",
+                ),
+            },
+            buses,
+        )
+    }
+
+    /// Send one chunk per channel to every output, dropping outputs that have gone
+    fn send(&mut self, chunks: &[Option<Vec<f32>>]) {
+        let back_pressure = self.back_pressure;
+        let dropped = &self.dropped;
+        self.outputs.retain_mut(|output| {
+            if back_pressure == BackPressure::Drop
+                && output.senders.iter().any(|sender| sender.is_full())
+            {
+                if output.dropped_chunks == 0 {
+                    warn!("splitter output is falling behind, dropping audio");
+                }
+                output.dropped_chunks += 1;
+                dropped.inc();
+                return true;
+            }
+            chunks
+                .iter()
+                .zip(&output.senders)
+                .all(|(chunk, sender)| match chunk {
+                    Some(chunk) => sender.send(chunk.clone()).is_ok(),
+                    None => true,
+                })
+        });
+    }
+}
+
+impl Processor<SplitterControlMessage> for Splitter {
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<SplitterControlMessage>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            let channel_count = self.input.channels.len();
+            let mut chunks: Vec<Option<Vec<f32>>> = vec![None; channel_count];
+            let mut closed = vec![false; channel_count];
+            while closed.iter().any(|closed| !closed) {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                for (i, channel) in self.input.channels.iter().enumerate() {
+                    if chunks[i].is_some() || closed[i] {
+                        continue;
+                    }
+                    match channel.recv_timeout(INPUT_POLL) {
+                        Ok(chunk) => chunks[i] = Some(chunk),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => closed[i] = true,
+                    }
+                }
+                let complete = (0..channel_count).all(|i| chunks[i].is_some() || closed[i]);
+                if complete && chunks.iter().any(Option::is_some) {
+                    self.send(&chunks);
+                    chunks.iter_mut().for_each(|chunk| *chunk = None);
+                }
+                if self.outputs.is_empty() {
+                    info!("all splitter outputs disconnected, stopping");
+                    break;
+                }
+            }
+            for output in &self.outputs {
+                if output.dropped_chunks > 0 {
+                    warn!(
+                        "splitter dropped {} chunks for a slow output",
+                        output.dropped_chunks
+                    );
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<SplitterControlMessage>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                SplitterControlMessage::Shutdown => Ok(ProcessorState::Finished),
+            },
+            Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use crate::signal_flow::node::Node;
+    use crate::test_utils::*;
+
+    const SPEC: AudioSpec = AudioSpec {
+        channels: 2,
+        sample_rate: 10,
+    };
+
+    #[test]
+    fn copies_to_every_output() {
+        let (input, senders) = AudioBus::from_spec(SPEC, None);
+        let (splitter, mut outputs) = Splitter::new(input, 2, 1, BackPressure::Block);
+        let node = Node::new(splitter);
+        for i in 0..3 {
+            senders[0].send(vec![i as f32; 2]).unwrap();
+            senders[1].send(vec![-i as f32; 2]).unwrap();
+        }
+        drop(senders);
+        let second = outputs.pop().unwrap();
+        let reader = thread::spawn(move || second.into_audio());
+        let first = outputs.pop().unwrap().into_audio();
+        let second = reader.join().unwrap();
+        node.join().unwrap();
+        for audio in [first, second] {
+            assert_almost_eq_by_element(audio.data[0].clone(), vec![0.0, 0.0, 1.0, 1.0, 2.0, 2.0]);
+            assert_almost_eq_by_element(
+                audio.data[1].clone(),
+                vec![0.0, 0.0, -1.0, -1.0, -2.0, -2.0],
+            );
+        }
+    }
+
+    #[test]
+    fn drop_skips_whole_frames_for_a_full_output() {
+        let (input, senders) = AudioBus::from_spec(SPEC, None);
+        let (splitter, mut outputs) = Splitter::new(input, 2, 1, BackPressure::Drop);
+        let node = Node::new(splitter);
+        // the second output is never read until the end, so it only gets the first chunk
+        let stalled = outputs.pop().unwrap();
+        let live = outputs.pop().unwrap();
+        for i in 0..3 {
+            senders[0].send(vec![i as f32]).unwrap();
+            senders[1].send(vec![i as f32]).unwrap();
+            for channel in &live.channels {
+                assert_almost_eq_by_element(
+                    channel.recv_timeout(Duration::from_secs(5)).unwrap(),
+                    vec![i as f32],
+                );
+            }
+        }
+        drop(senders);
+        drop(live);
+        let stalled = stalled.into_audio();
+        node.join().unwrap();
+        assert_almost_eq_by_element(stalled.data[0].clone(), vec![0.0]);
+        assert_almost_eq_by_element(stalled.data[1].clone(), vec![0.0]);
+    }
+}
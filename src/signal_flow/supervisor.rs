@@ -0,0 +1,627 @@
+//! Restarting nodes that fail, so one bad processor doesn't take a long
+//! running graph down with it
+
+use super::node::{ControlMessage, Node, NodeError, Processor, ProcessorState};
+use crate::audio::{AudioBus, AudioSpec};
+use anyhow::{anyhow, bail, Result};
+use crossbeam_channel::{unbounded, Receiver, Select, Sender, TryRecvError};
+use std::fmt::Display;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLL: Duration = Duration::from_millis(50);
+
+/// How a supervised node is restarted after it fails
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// Wait before the first restart, doubled after each failure in a row
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// A node that runs this long before failing goes back to the initial backoff
+    pub stable_after: Duration,
+    /// Give up after this many failures in a row, or never if `None`
+    pub max_restarts: Option<usize>,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            stable_after: Duration::from_secs(60),
+            max_restarts: None,
+        }
+    }
+}
+
+/// Exponential backoff between restarts
+struct Backoff {
+    policy: RestartPolicy,
+    next: Duration,
+    failures: usize,
+}
+
+impl Backoff {
+    fn new(policy: RestartPolicy) -> Backoff {
+        Backoff {
+            next: policy.initial_backoff,
+            policy,
+            failures: 0,
+        }
+    }
+
+    /// How long to wait before restarting a node that failed after running
+    /// for `ran_for`, or `None` to give up
+    fn failed(&mut self, ran_for: Duration) -> Option<Duration> {
+        if ran_for >= self.policy.stable_after {
+            self.next = self.policy.initial_backoff;
+            self.failures = 0;
+        }
+        self.failures += 1;
+        if self
+            .policy
+            .max_restarts
+            .is_some_and(|max| self.failures > max)
+        {
+            return None;
+        }
+        let wait = self.next;
+        self.next = (self.next * 2).min(self.policy.max_backoff);
+        Some(wait)
+    }
+}
+
+#[derive(Debug)]
+pub enum SupervisorControlMessage<M> {
+    Shutdown,
+    ImmediateShutdown,
+    /// Pass a message on to the supervised processor
+    ///
+    /// Dropped with a warning while the processor is down, and not replayed
+    /// to the processor that replaces it.
+    Forward(M),
+}
+
+impl<M: ControlMessage> ControlMessage for SupervisorControlMessage<M> {
+    fn shutdown_msg() -> Self {
+        SupervisorControlMessage::Shutdown
+    }
+
+    fn immediate_shutdown_msg() -> Self {
+        SupervisorControlMessage::ImmediateShutdown
+    }
+}
+
+type Build<P> = Box<dyn FnMut(Vec<AudioBus>) -> Result<(P, AudioBus)> + Send>;
+
+/// A processor built but not yet started, with the senders feeding its inputs
+struct Built<P> {
+    processor: P,
+    feeds: Vec<Vec<Option<Sender<Vec<f32>>>>>,
+    output: AudioBus,
+}
+
+struct Child<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    node: Node<P, M>,
+    feeds: Vec<Vec<Option<Sender<Vec<f32>>>>>,
+    output: AudioBus,
+    output_open: Vec<bool>,
+    started: Instant,
+}
+
+impl<P, M> Child<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    fn start(built: Built<P>) -> Child<P, M> {
+        Child {
+            node: Node::new(built.processor),
+            feeds: built.feeds,
+            output_open: vec![true; built.output.channels.len()],
+            output: built.output,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Runs a processor, building and starting a new one whenever it fails
+///
+/// The supervisor owns the node's input and output buses and relays audio
+/// to and from whichever processor is running, so the nodes around it
+/// never see a restart. Audio arriving while the processor is down is
+/// dropped rather than queued, and anything the failed processor held,
+/// such as effect tails, is lost. A processor that stops without an error
+/// is taken to have finished, and the supervisor finishes with it.
+pub struct Supervisor<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    id: String,
+    build: Build<P>,
+    backoff: Backoff,
+    inputs: Vec<AudioBus>,
+    /// Which channels of each input are still connected
+    input_open: Vec<Vec<bool>>,
+    outputs: Vec<Sender<Vec<f32>>>,
+    /// The spec of the first processor's output, which every restart must match
+    output_spec: Option<AudioSpec>,
+    first: Option<Built<P>>,
+    child: Option<Child<P, M>>,
+    restart_at: Option<Instant>,
+    phantom: PhantomData<M>,
+}
+
+impl<P, M> Supervisor<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    /// Build the first processor from `inputs`
+    ///
+    /// `build` is called again with fresh copies of the input buses for each
+    /// restart, and must return an output bus with the same spec each time.
+    pub fn new<F>(
+        id: &str,
+        inputs: Vec<AudioBus>,
+        policy: RestartPolicy,
+        build: F,
+    ) -> Result<(Supervisor<P, M>, AudioBus)>
+    where
+        F: FnMut(Vec<AudioBus>) -> Result<(P, AudioBus)> + Send + 'static,
+    {
+        let input_open = inputs
+            .iter()
+            .map(|bus| vec![true; bus.channels.len()])
+            .collect();
+        let mut supervisor = Supervisor {
+            id: id.to_string(),
+            build: Box::new(build),
+            backoff: Backoff::new(policy),
+            inputs,
+            input_open,
+            outputs: vec![],
+            output_spec: None,
+            first: None,
+            child: None,
+            restart_at: None,
+            phantom: PhantomData,
+        };
+        let first = supervisor.build()?;
+        let (output, outputs) =
+            AudioBus::from_spec(first.output.spec, first.output.expected_total_samples);
+        supervisor.output_spec = Some(first.output.spec);
+        supervisor.outputs = outputs;
+        supervisor.first = Some(first);
+        Ok((supervisor, output))
+    }
+
+    fn build(&mut self) -> Result<Built<P>> {
+        let mut inputs = vec![];
+        let mut feeds = vec![];
+        for (bus, open) in self.inputs.iter().zip(&self.input_open) {
+            let (input, senders) = AudioBus::from_spec(bus.spec, bus.expected_total_samples);
+            inputs.push(input);
+            feeds.push(
+                senders
+                    .into_iter()
+                    .zip(open)
+                    .map(|(sender, open)| if *open { Some(sender) } else { None })
+                    .collect(),
+            );
+        }
+        let (processor, output) = (self.build)(inputs)?;
+        if let Some(spec) = self.output_spec {
+            if output.spec != spec {
+                bail!("output changed from {:?} to {:?}", spec, output.spec);
+            }
+        }
+        Ok(Built {
+            processor,
+            feeds,
+            output,
+        })
+    }
+
+    /// Schedule a restart, or return an error if it's time to give up
+    fn failed(&mut self, cause: impl Display, ran_for: Duration) -> Result<()> {
+        match self.backoff.failed(ran_for) {
+            Some(wait) => {
+                error!(
+                    "Signal graph node {:?} stopped: {}. Restarting in {:?}",
+                    self.id, cause, wait
+                );
+                self.restart_at = Some(Instant::now() + wait);
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "gave up after {} restarts: {}",
+                self.backoff.failures - 1,
+                cause
+            )),
+        }
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.restart_at = None;
+        match self.build() {
+            Ok(built) => {
+                info!("Restarted signal graph node {:?}", self.id);
+                self.child = Some(Child::start(built));
+                Ok(())
+            }
+            Err(e) => self.failed(format!("failed to rebuild: {}", e), Duration::ZERO),
+        }
+    }
+
+    /// Block until audio arrives on any open input or output, or a short timeout
+    fn wait(&self) {
+        let mut select = Select::new();
+        let mut waiting = false;
+        for (bus, open) in self.inputs.iter().zip(&self.input_open) {
+            for (channel, _) in bus.channels.iter().zip(open).filter(|(_, open)| **open) {
+                select.recv(channel);
+                waiting = true;
+            }
+        }
+        if let Some(child) = &self.child {
+            for (channel, _) in child
+                .output
+                .channels
+                .iter()
+                .zip(&child.output_open)
+                .filter(|(_, open)| **open)
+            {
+                select.recv(channel);
+                waiting = true;
+            }
+        }
+        if waiting {
+            let _ = select.ready_timeout(POLL);
+        } else {
+            thread::sleep(POLL);
+        }
+    }
+
+    /// Pass input on to the running processor, or drop it if none is running
+    fn feed_input(&mut self) {
+        for (b, bus) in self.inputs.iter().enumerate() {
+            for (c, channel) in bus.channels.iter().enumerate() {
+                if !self.input_open[b][c] {
+                    continue;
+                }
+                loop {
+                    match channel.try_recv() {
+                        Ok(chunk) => {
+                            if let Some(feed) = self
+                                .child
+                                .as_ref()
+                                .and_then(|child| child.feeds[b][c].as_ref())
+                            {
+                                let _ = feed.send(chunk);
+                            }
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            self.input_open[b][c] = false;
+                            if let Some(child) = &mut self.child {
+                                child.feeds[b][c] = None;
+                            }
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pass the running processor's output on, returning false if nothing downstream is listening
+    fn forward_output(&mut self) -> bool {
+        let child = match &mut self.child {
+            Some(child) => child,
+            None => return true,
+        };
+        for (i, channel) in child.output.channels.iter().enumerate() {
+            if !child.output_open[i] {
+                continue;
+            }
+            loop {
+                match channel.try_recv() {
+                    Ok(chunk) => {
+                        if self.outputs[i].send(chunk).is_err() {
+                            return false;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        child.output_open[i] = false;
+                        break;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Collect a processor that has exited, returning whether the supervisor is finished
+    fn reap(&mut self) -> Result<bool> {
+        // The processor's senders are gone, so this takes whatever it sent before exiting
+        self.forward_output();
+        let child = self.child.take().unwrap();
+        let ran_for = child.started.elapsed();
+        match child.node.join() {
+            Ok(()) => Ok(true),
+            Err(e) => self.failed(e, ran_for).map(|_| false),
+        }
+    }
+
+    /// Shut down the running processor and relay its output until it exits
+    fn stop_child(&mut self, message: M) {
+        let child = match &self.child {
+            Some(child) => child,
+            None => return,
+        };
+        if child.node.send_control_message(message).is_err() {
+            debug!("supervised node {:?} had already stopped", self.id);
+        }
+        while !self.child.as_ref().unwrap().node.has_exited() {
+            self.wait();
+            self.forward_output();
+        }
+        self.forward_output();
+        if let Err(e) = self.child.take().unwrap().node.join() {
+            warn!("signal graph node {:?} stopped: {}", self.id, e);
+        }
+    }
+}
+
+impl<P, M> Processor<SupervisorControlMessage<M>> for Supervisor<P, M>
+where
+    P: Processor<M>,
+    M: ControlMessage,
+{
+    fn start(
+        mut self,
+        finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
+    ) -> (Sender<SupervisorControlMessage<M>>, JoinHandle<()>) {
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        let handle = thread::spawn(move || {
+            self.child = self.first.take().map(Child::start);
+            loop {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                if self.restart_at.is_some_and(|at| Instant::now() >= at) {
+                    if let Err(e) = self.restart() {
+                        let _ = errors.send(NodeError::Failed(e));
+                        break;
+                    }
+                }
+                self.wait();
+                self.feed_input();
+                if !self.forward_output() {
+                    info!(
+                        "supervised node {:?} output disconnected, stopping",
+                        self.id
+                    );
+                    self.stop_child(M::immediate_shutdown_msg());
+                    break;
+                }
+                if self
+                    .child
+                    .as_ref()
+                    .is_some_and(|child| child.node.has_exited())
+                {
+                    match self.reap() {
+                        Ok(true) => break,
+                        Ok(false) => {}
+                        Err(e) => {
+                            let _ = errors.send(NodeError::Failed(e));
+                            break;
+                        }
+                    }
+                }
+            }
+            finished.store(true, Ordering::Relaxed);
+        });
+        (ctrl_tx, handle)
+    }
+
+    fn handle_control_messages(
+        &mut self,
+        rx: &Receiver<SupervisorControlMessage<M>>,
+    ) -> Result<ProcessorState> {
+        match rx.try_recv() {
+            Ok(msg) => match msg {
+                SupervisorControlMessage::Shutdown => {
+                    self.stop_child(M::shutdown_msg());
+                    Ok(ProcessorState::Finished)
+                }
+                SupervisorControlMessage::ImmediateShutdown => {
+                    self.stop_child(M::immediate_shutdown_msg());
+                    Ok(ProcessorState::Finished)
+                }
+                SupervisorControlMessage::Forward(message) => {
+                    match &self.child {
+                        Some(child) => child.node.send_control_message(message)?,
+                        None => warn!(
+                            "signal graph node {:?} is restarting, dropping {:?}",
+                            self.id, message
+                        ),
+                    }
+                    Ok(ProcessorState::Running)
+                }
+            },
+            Err(TryRecvError::Disconnected) => {
+                self.stop_child(M::immediate_shutdown_msg());
+                Ok(ProcessorState::Finished)
+            }
+            Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
+        }
+    }
+
+    /// The first processor's latency, which restarts are built to match
+    fn latency(&self) -> usize {
+        self.first
+            .as_ref()
+            .map_or(0, |first| first.processor.latency())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::BusPolicy;
+    use crate::effect_processor::EffectProcessor;
+    use crate::effects::TimeDomainEffect;
+    use crate::signal_flow::graph::Graph;
+    use crate::test_utils::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct Gain(f32);
+
+    impl TimeDomainEffect for Gain {
+        fn process(&mut self, _channel: usize, samples: &mut [f32]) {
+            for sample in samples.iter_mut() {
+                *sample *= self.0;
+            }
+        }
+    }
+
+    struct Explode;
+
+    impl TimeDomainEffect for Explode {
+        fn process(&mut self, _channel: usize, _samples: &mut [f32]) {
+            panic!("boom");
+        }
+    }
+
+    fn quick_policy(max_restarts: Option<usize>) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            max_restarts,
+            ..RestartPolicy::default()
+        }
+    }
+
+    /// A graph of a source fed from the returned senders into a supervised
+    /// node that explodes for its first `explosions` builds
+    fn flaky_graph(
+        explosions: usize,
+        policy: RestartPolicy,
+    ) -> (Graph, Vec<Sender<Vec<f32>>>, Arc<AtomicUsize>) {
+        let spec = AudioSpec {
+            channels: 1,
+            sample_rate: 10,
+        };
+        let (source, senders) = AudioBus::from_spec(spec, None);
+        let builds = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&builds);
+        let mut graph = Graph::new();
+        graph
+            .add_node("source", move |_| {
+                Ok(EffectProcessor::new(
+                    source,
+                    Box::new(Gain(1.0)),
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        graph
+            .add_supervised_node("flaky", policy, move |mut inputs| {
+                let effect: Box<dyn TimeDomainEffect> =
+                    if counted.fetch_add(1, Ordering::SeqCst) < explosions {
+                        Box::new(Explode)
+                    } else {
+                        Box::new(Gain(2.0))
+                    };
+                Ok(EffectProcessor::new(
+                    inputs.remove(0),
+                    effect,
+                    BusPolicy::UNBOUNDED,
+                ))
+            })
+            .unwrap();
+        graph.connect("source", "flaky").unwrap();
+        (graph, senders, builds)
+    }
+
+    #[test]
+    fn backoff_doubles_and_resets_after_a_stable_run() {
+        let mut backoff = Backoff::new(RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            stable_after: Duration::from_secs(60),
+            max_restarts: Some(4),
+        });
+        let waits: Vec<Option<Duration>> = (0..4).map(|_| backoff.failed(Duration::ZERO)).collect();
+        assert_eq!(
+            waits,
+            [100, 200, 300, 300].map(|ms| Some(Duration::from_millis(ms)))
+        );
+        assert_eq!(backoff.failed(Duration::ZERO), None);
+        assert_eq!(
+            backoff.failed(Duration::from_secs(60)),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn failed_nodes_are_restarted_without_stopping_the_graph() {
+        let (graph, senders, builds) = flaky_graph(2, quick_policy(None));
+        let mut running = graph.start().unwrap();
+        let output = running.take_output("flaky").unwrap();
+        // Chunks sent while the node is down are lost, so keep sending until one comes through
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let chunk = loop {
+            assert!(Instant::now() < deadline, "node was never restarted");
+            senders[0].send(vec![1.0; 4]).unwrap();
+            if let Ok(chunk) = output.channels[0].recv_timeout(Duration::from_millis(20)) {
+                break chunk;
+            }
+        };
+        assert_almost_eq_by_element(chunk, vec![2.0; 4]);
+        assert_eq!(builds.load(Ordering::SeqCst), 3);
+        assert!(running.try_recv_error().is_none());
+        drop(senders);
+        output.into_audio();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn supervisor_gives_up_after_max_restarts() {
+        let (graph, senders, builds) = flaky_graph(usize::MAX, quick_policy(Some(2)));
+        let running = graph.start().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (id, error) = loop {
+            assert!(Instant::now() < deadline, "supervisor never gave up");
+            if let Some(error) = running.try_recv_error() {
+                break error;
+            }
+            let _ = senders[0].send(vec![1.0; 4]);
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(id, "flaky");
+        assert!(error
+            .to_string()
+            .contains("gave up after 2 restarts: processor panicked: boom"));
+        assert_eq!(builds.load(Ordering::SeqCst), 3);
+        drop(senders);
+        running.shutdown(Duration::ZERO).unwrap();
+    }
+}
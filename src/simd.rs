@@ -0,0 +1,189 @@
+//! Vectorized versions of the stretcher's hot loops
+//!
+//! Each function picks the widest implementation the CPU supports at
+//! runtime, and falls back to plain loops on other architectures.
+
+use rustfft::num_complex::Complex32;
+
+/// Set `out[i]` to `samples[i] * window[i]` as a real complex number
+pub fn window_into_complex(samples: &[f32], window: &[f32], out: &mut [Complex32]) {
+    assert!(samples.len() == window.len() && samples.len() == out.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        // SSE is part of x86_64, so this path needs no detection
+        unsafe { x86::window_into_complex(samples, window, out) }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    scalar::window_into_complex(samples, window, out)
+}
+
+/// Set `out[i]` to the real part of `bins[i]`, times `window[i]` and `scale`
+pub fn windowed_real_parts(bins: &[Complex32], window: &[f32], scale: f32, out: &mut [f32]) {
+    assert!(bins.len() == window.len() && bins.len() == out.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        unsafe { x86::windowed_real_parts(bins, window, scale, out) }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    scalar::windowed_real_parts(bins, window, scale, out)
+}
+
+/// Set `out[i]` to the magnitude of `bins[i]`
+pub fn magnitudes(bins: &[Complex32], out: &mut [f32]) {
+    assert!(bins.len() == out.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse3") {
+            return unsafe { x86::magnitudes(bins, out) };
+        }
+    }
+    scalar::magnitudes(bins, out)
+}
+
+/// View complex numbers as interleaved real and imaginary parts
+fn as_floats(bins: &[Complex32]) -> &[f32] {
+    // Complex is repr(C) with `re` then `im`, so this layout is guaranteed
+    unsafe { std::slice::from_raw_parts(bins.as_ptr() as *const f32, bins.len() * 2) }
+}
+
+fn as_floats_mut(bins: &mut [Complex32]) -> &mut [f32] {
+    unsafe { std::slice::from_raw_parts_mut(bins.as_mut_ptr() as *mut f32, bins.len() * 2) }
+}
+
+mod scalar {
+    use super::*;
+
+    pub fn window_into_complex(samples: &[f32], window: &[f32], out: &mut [Complex32]) {
+        for ((sample, w), bin) in samples.iter().zip(window).zip(out.iter_mut()) {
+            *bin = Complex32::new(sample * w, 0.0);
+        }
+    }
+
+    pub fn windowed_real_parts(bins: &[Complex32], window: &[f32], scale: f32, out: &mut [f32]) {
+        for ((bin, w), sample) in bins.iter().zip(window).zip(out.iter_mut()) {
+            *sample = bin.re * w * scale;
+        }
+    }
+
+    pub fn magnitudes(bins: &[Complex32], out: &mut [f32]) {
+        for (bin, magnitude) in bins.iter().zip(out.iter_mut()) {
+            *magnitude = (bin.re * bin.re + bin.im * bin.im).sqrt();
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    const LANES: usize = 4;
+
+    pub unsafe fn window_into_complex(samples: &[f32], window: &[f32], out: &mut [Complex32]) {
+        let vector_len = samples.len() - samples.len() % LANES;
+        let out_floats = as_floats_mut(out);
+        let zero = _mm_setzero_ps();
+        for i in (0..vector_len).step_by(LANES) {
+            let windowed = _mm_mul_ps(
+                _mm_loadu_ps(samples.as_ptr().add(i)),
+                _mm_loadu_ps(window.as_ptr().add(i)),
+            );
+            // Interleave with zeros for the imaginary parts
+            let out_ptr = out_floats.as_mut_ptr().add(i * 2);
+            _mm_storeu_ps(out_ptr, _mm_unpacklo_ps(windowed, zero));
+            _mm_storeu_ps(out_ptr.add(LANES), _mm_unpackhi_ps(windowed, zero));
+        }
+        scalar::window_into_complex(
+            &samples[vector_len..],
+            &window[vector_len..],
+            &mut out[vector_len..],
+        );
+    }
+
+    pub unsafe fn windowed_real_parts(
+        bins: &[Complex32],
+        window: &[f32],
+        scale: f32,
+        out: &mut [f32],
+    ) {
+        let vector_len = bins.len() - bins.len() % LANES;
+        let bin_floats = as_floats(bins);
+        let scale_vec = _mm_set1_ps(scale);
+        for i in (0..vector_len).step_by(LANES) {
+            let first = _mm_loadu_ps(bin_floats.as_ptr().add(i * 2));
+            let second = _mm_loadu_ps(bin_floats.as_ptr().add(i * 2 + LANES));
+            // Even floats are the real parts
+            let re = _mm_shuffle_ps(first, second, 0b10_00_10_00);
+            let windowed = _mm_mul_ps(
+                _mm_mul_ps(re, _mm_loadu_ps(window.as_ptr().add(i))),
+                scale_vec,
+            );
+            _mm_storeu_ps(out.as_mut_ptr().add(i), windowed);
+        }
+        scalar::windowed_real_parts(
+            &bins[vector_len..],
+            &window[vector_len..],
+            scale,
+            &mut out[vector_len..],
+        );
+    }
+
+    #[target_feature(enable = "sse3")]
+    pub unsafe fn magnitudes(bins: &[Complex32], out: &mut [f32]) {
+        let vector_len = bins.len() - bins.len() % LANES;
+        let bin_floats = as_floats(bins);
+        for i in (0..vector_len).step_by(LANES) {
+            let first = _mm_loadu_ps(bin_floats.as_ptr().add(i * 2));
+            let second = _mm_loadu_ps(bin_floats.as_ptr().add(i * 2 + LANES));
+            // Adding neighbouring squares gives re² + im² for each bin in order
+            let norms_squared = _mm_hadd_ps(_mm_mul_ps(first, first), _mm_mul_ps(second, second));
+            _mm_storeu_ps(out.as_mut_ptr().add(i), _mm_sqrt_ps(norms_squared));
+        }
+        scalar::magnitudes(&bins[vector_len..], &mut out[vector_len..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use rand::Rng;
+
+    fn random_bins(len: usize) -> Vec<Complex32> {
+        let mut rng = rand::thread_rng();
+        (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect()
+    }
+
+    // Odd lengths check the scalar tails after the vector loops
+    #[test]
+    fn matches_the_scalar_versions() {
+        for len in [0, 3, 4, 17, 1024] {
+            let bins = random_bins(len);
+            let samples: Vec<f32> = bins.iter().map(|bin| bin.re).collect();
+            let window: Vec<f32> = bins.iter().map(|bin| bin.im).collect();
+
+            let mut expected = vec![Complex32::new(9.0, 9.0); len];
+            let mut actual = expected.clone();
+            scalar::window_into_complex(&samples, &window, &mut expected);
+            window_into_complex(&samples, &window, &mut actual);
+            assert_eq!(actual, expected);
+
+            let mut expected = vec![0.0; len];
+            let mut actual = vec![0.0; len];
+            scalar::windowed_real_parts(&bins, &window, 0.5, &mut expected);
+            windowed_real_parts(&bins, &window, 0.5, &mut actual);
+            assert_almost_eq_by_element(actual, expected);
+
+            let mut expected = vec![0.0; len];
+            let mut actual = vec![0.0; len];
+            scalar::magnitudes(&bins, &mut expected);
+            magnitudes(&bins, &mut actual);
+            assert_almost_eq_by_element(actual.clone(), expected);
+            for (bin, magnitude) in bins.iter().zip(actual) {
+                assert_almost_eq(magnitude, bin.norm());
+            }
+        }
+    }
+}
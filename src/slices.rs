@@ -1,14 +1,89 @@
-use libc;
-use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+#[cfg(feature = "native")]
+pub use slice_deque::SliceDeque;
+#[cfg(not(feature = "native"))]
+pub type SliceDeque<T> = FlatDeque<T>;
 
 #[inline]
 pub fn zero_slice<T>(slc: &mut [T]) {
     unsafe {
-        libc::memset(
-            slc.as_mut_ptr() as *mut libc::c_void,
-            0,
-            mem::size_of::<T>() * slc.len(),
-        );
+        ptr::write_bytes(slc.as_mut_ptr(), 0, slc.len());
+    }
+}
+
+/// A queue that can always be read as one slice, standing in for
+/// `slice_deque::SliceDeque` where it can't map its mirrored memory, as on
+/// wasm32
+///
+/// Truncating the front only moves an offset, and the space is reclaimed once
+/// it outgrows what's left, so it stays cheap on average.
+#[derive(Debug, Clone)]
+pub struct FlatDeque<T> {
+    buf: Vec<T>,
+    start: usize,
+}
+
+impl<T> FlatDeque<T> {
+    pub fn new() -> FlatDeque<T> {
+        FlatDeque::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> FlatDeque<T> {
+        FlatDeque {
+            buf: Vec::with_capacity(capacity),
+            start: 0,
+        }
+    }
+
+    /// Drop elements from the front until `len` are left
+    pub fn truncate_front(&mut self, len: usize) {
+        self.start = self.buf.len() - len.min(self.len());
+        if self.start > self.len() {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+    }
+
+    pub fn extend_from_slice(&mut self, other: &[T])
+    where
+        T: Clone,
+    {
+        self.buf.extend_from_slice(other);
+    }
+
+    pub fn resize(&mut self, new_len: usize, value: T)
+    where
+        T: Clone,
+    {
+        self.buf.resize(self.start + new_len, value);
+    }
+}
+
+impl<T> Default for FlatDeque<T> {
+    fn default() -> Self {
+        FlatDeque::new()
+    }
+}
+
+impl<T> Deref for FlatDeque<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.buf[self.start..]
+    }
+}
+
+impl<T> DerefMut for FlatDeque<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.buf[self.start..]
+    }
+}
+
+impl<T> Extend<T> for FlatDeque<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.buf.extend(iter);
     }
 }
 
@@ -30,4 +105,20 @@ mod test {
         zero_slice(&mut v[1..3]);
         assert_eq!(v, vec![1, 0, 0]);
     }
+
+    #[test]
+    fn flat_deque_truncates_from_the_front() {
+        let mut deque = FlatDeque::with_capacity(4);
+        deque.extend(0..6);
+        deque.truncate_front(4);
+        assert_eq!(&deque[..], &[2, 3, 4, 5]);
+        deque[0] = 7;
+        deque.truncate_front(1);
+        assert_eq!(&deque[..], &[5]);
+        deque.extend(vec![6, 7]);
+        deque.resize(5, 0);
+        assert_eq!(&deque[..], &[5, 6, 7, 0, 0]);
+        deque.truncate_front(10);
+        assert_eq!(deque.len(), 5);
+    }
 }
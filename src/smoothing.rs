@@ -0,0 +1,103 @@
+//! Moving externally controlled values to new settings gradually, so that
+//! a gain, stretch factor or kernel parameter changed from the keyboard, OSC
+//! or MIDI doesn't click
+
+/// A value that moves linearly toward its target, a step per sample
+#[derive(Debug, Copy, Clone)]
+pub struct SmoothedParam {
+    current: f32,
+    target: f32,
+    /// Change per sample until the target is reached
+    step: f32,
+    /// How many samples `set_target` takes to reach a new target
+    ramp_samples: usize,
+}
+
+impl SmoothedParam {
+    /// Settled at `value`, jumping straight to new targets until given a ramp
+    pub fn new(value: f32) -> Self {
+        SmoothedParam {
+            current: value,
+            target: value,
+            step: 0.0,
+            ramp_samples: 0,
+        }
+    }
+
+    /// Take `ramp_samples` to reach targets set with `set_target`
+    pub fn with_ramp(mut self, ramp_samples: usize) -> Self {
+        self.ramp_samples = ramp_samples;
+        self
+    }
+
+    /// Move toward `target` over the default ramp
+    pub fn set_target(&mut self, target: f32) {
+        self.ramp_to(target, self.ramp_samples);
+    }
+
+    /// Move toward `target` over `ramp_samples`, from wherever the value is now
+    pub fn ramp_to(&mut self, target: f32, ramp_samples: usize) {
+        self.target = target;
+        if ramp_samples == 0 {
+            self.current = target;
+        } else {
+            self.step = (target - self.current) / ramp_samples as f32;
+        }
+    }
+
+    /// The value for the next sample
+    #[inline]
+    pub fn next_value(&mut self) -> f32 {
+        self.advance(1)
+    }
+
+    /// Move on by `samples` at once, e.g. a window's worth, returning the
+    /// value reached
+    pub fn advance(&mut self, samples: usize) -> f32 {
+        if self.current != self.target {
+            self.current += self.step * samples as f32;
+            if (self.step > 0.0 && self.current > self.target)
+                || (self.step < 0.0 && self.current < self.target)
+            {
+                self.current = self.target;
+            }
+        }
+        self.current
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn moves_to_target() {
+        let mut param = SmoothedParam::new(1.0);
+        param.ramp_to(0.5, 2);
+        assert_almost_eq(param.next_value(), 0.75);
+        assert_almost_eq(param.next_value(), 0.5);
+        assert_almost_eq(param.next_value(), 0.5);
+        param.ramp_to(1.0, 0);
+        assert_almost_eq(param.next_value(), 1.0);
+
+        let mut param = SmoothedParam::new(2.0).with_ramp(100);
+        param.set_target(4.0);
+        assert_almost_eq(param.advance(50), 3.0);
+        assert!(!param.is_settled());
+        assert_almost_eq(param.advance(80), 4.0);
+        assert!(param.is_settled());
+    }
+}
@@ -0,0 +1,214 @@
+//! Equalization applied to the stretcher's bin magnitudes
+//!
+//! Each band is a shelf or peaking filter from Robert Bristow-Johnson's
+//! Audio EQ Cookbook. Rather than running the filters over the output,
+//! their magnitude responses are sampled at each bin's frequency once, and
+//! every window's magnitudes are scaled by the result. Since the phases are
+//! randomized anyway, this sounds the same as filtering without adding
+//! another stage after the stretcher.
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustfft::num_complex::Complex64;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// The Q of a Butterworth response, a gentle default for every band
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BandKind {
+    LowShelf,
+    HighShelf,
+    Peak,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EqBand {
+    pub kind: BandKind,
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl FromStr for EqBand {
+    type Err = anyhow::Error;
+
+    /// Parse `<lowshelf|highshelf|peak>:<Hz>:<dB>[:<Q>]`, e.g. `peak:1000:-4:1.4`
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<&str> = s.split(':').collect();
+        if !(3..=4).contains(&fields.len()) {
+            bail!(
+                "expected <lowshelf|highshelf|peak>:<Hz>:<dB>[:<Q>], got {:?}",
+                s
+            );
+        }
+        let kind = match fields[0] {
+            "lowshelf" => BandKind::LowShelf,
+            "highshelf" => BandKind::HighShelf,
+            "peak" => BandKind::Peak,
+            other => {
+                return Err(anyhow!(
+                    "unknown band {:?}, expected lowshelf, highshelf or peak",
+                    other
+                ))
+            }
+        };
+        let frequency: f32 = fields[1].parse().context("invalid frequency")?;
+        let gain_db: f32 = fields[2].parse().context("invalid gain")?;
+        let q: f32 = match fields.get(3) {
+            Some(q) => q.parse().context("invalid Q")?,
+            None => DEFAULT_Q,
+        };
+        if !(frequency > 0.0 && q > 0.0 && frequency.is_finite() && q.is_finite()) {
+            bail!("the frequency and Q must be positive in {:?}", s);
+        }
+        if !gain_db.is_finite() {
+            bail!("invalid gain in {:?}", s);
+        }
+        Ok(EqBand {
+            kind,
+            frequency,
+            gain_db,
+            q,
+        })
+    }
+}
+
+impl EqBand {
+    /// The band's gain as an amplitude multiplier at `frequency` Hz
+    pub fn response(&self, frequency: f32, sample_rate: u32) -> f32 {
+        let a = 10f64.powf(self.gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * (self.frequency as f64 / sample_rate as f64).min(0.499);
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * self.q as f64);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let (b, a) = match self.kind {
+            BandKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            BandKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
+            ),
+            BandKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
+            ),
+        };
+        let w = 2.0 * PI * frequency as f64 / sample_rate as f64;
+        let z1 = Complex64::from_polar(1.0, -w);
+        let z2 = z1 * z1;
+        let numerator = b[0] + z1 * b[1] + z2 * b[2];
+        let denominator = a[0] + z1 * a[1] + z2 * a[2];
+        (numerator.norm() / denominator.norm()) as f32
+    }
+}
+
+/// Several bands applied one after another
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SpectralEq {
+    pub bands: Vec<EqBand>,
+}
+
+impl SpectralEq {
+    pub fn new(bands: Vec<EqBand>) -> SpectralEq {
+        SpectralEq { bands }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bands.is_empty()
+    }
+
+    /// The gain for every bin of a `window_len` spectrum, negative
+    /// frequencies included
+    ///
+    /// `frequency_scale` is how much the bins' frequencies will be shifted
+    /// by afterwards, e.g. 2 if the output is pitched up an octave, so the
+    /// bands land where they're asked for in what's heard.
+    pub fn bin_gains(&self, window_len: usize, sample_rate: u32, frequency_scale: f32) -> Vec<f32> {
+        (0..window_len)
+            .map(|i| {
+                // Bins past the middle are the negative frequencies, mirrored
+                let bin = i.min(window_len - i);
+                let frequency =
+                    bin as f32 * sample_rate as f32 / window_len as f32 * frequency_scale;
+                self.bands
+                    .iter()
+                    .map(|band| band.response(frequency, sample_rate))
+                    .product()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn db(amplitude: f32) -> f32 {
+        crate::power::amplitude_to_decibels(amplitude)
+    }
+
+    #[test]
+    fn bands_parse() {
+        assert_eq!(
+            "peak:1000:-4:1.4".parse::<EqBand>().unwrap(),
+            EqBand {
+                kind: BandKind::Peak,
+                frequency: 1000.0,
+                gain_db: -4.0,
+                q: 1.4
+            }
+        );
+        assert_eq!("lowshelf:200:6".parse::<EqBand>().unwrap().q, DEFAULT_Q);
+        for invalid in [
+            "peak:1000",
+            "notch:1000:3",
+            "peak:0:3",
+            "peak:1000:3:-1",
+            "peak:x:3",
+        ] {
+            assert!(invalid.parse::<EqBand>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn bands_shape_the_response() {
+        let rate = 44100;
+        let peak: EqBand = "peak:1000:-6:2".parse().unwrap();
+        assert!((db(peak.response(1000.0, rate)) - -6.0).abs() < 0.01);
+        assert!(db(peak.response(100.0, rate)).abs() < 0.1);
+        let low: EqBand = "lowshelf:200:6".parse().unwrap();
+        assert!((db(low.response(10.0, rate)) - 6.0).abs() < 0.1);
+        assert!(db(low.response(10000.0, rate)).abs() < 0.1);
+        let high: EqBand = "highshelf:4000:-3".parse().unwrap();
+        assert!(db(high.response(100.0, rate)).abs() < 0.1);
+        assert!((db(high.response(20000.0, rate)) - -3.0).abs() < 0.1);
+
+        let eq = SpectralEq::new(vec![low, high]);
+        let gains = eq.bin_gains(8, rate, 1.0);
+        assert_eq!(gains.len(), 8);
+        assert_eq!(gains[1], gains[7]);
+        assert!(gains[0] > 1.9 && gains[4] < 0.75);
+        // Pitched down an octave, the Nyquist bin is heard at a quarter of the rate
+        let gains = eq.bin_gains(8, rate, 0.5);
+        assert_eq!(gains[4], eq.bin_gains(8, rate, 1.0)[2]);
+    }
+}
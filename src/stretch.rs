@@ -0,0 +1,229 @@
+//! Stretching a whole piece of audio in one call, for programs embedding
+//! rocoder that have no use for processors and buses
+//!
+//! ```no_run
+//! use rocoder::{stretch, StretchOptions};
+//! # fn example(audio: rocoder::audio::Audio) -> anyhow::Result<()> {
+//! let slowed = stretch(&audio, &StretchOptions {
+//!     factor: 8.0,
+//!     ..StretchOptions::default()
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::audio::{Audio, AudioSlice, AudioSpec};
+use crate::granular::{GrainParams, GranularStretcher};
+use crate::stretch_engine::{EngineKind, StretchEngine};
+use crate::stretcher::Stretcher;
+use crate::windows;
+use crate::wsola::WsolaStretcher;
+use anyhow::{bail, Result};
+use crossbeam_channel::{unbounded, Sender};
+use std::time::Duration;
+
+/// How `stretch` stretches, with the same defaults as the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct StretchOptions {
+    /// How many times longer the output is, where below 1 compresses
+    pub factor: f32,
+    /// A non-zero integer pitch multiplier, where negative numbers divide
+    pub pitch_multiple: i8,
+    pub amplitude: f32,
+    pub engine: EngineKind,
+    /// The vocoder's window length
+    pub window_len: usize,
+    /// How long each of the granular engine's grains is
+    pub grain: Duration,
+    pub grain_jitter: f32,
+    pub grain_density: f32,
+    /// Makes the vocoder's random phases and the grain positions the same
+    /// on every run
+    pub seed: Option<u64>,
+}
+
+impl Default for StretchOptions {
+    fn default() -> Self {
+        StretchOptions {
+            factor: 1.0,
+            pitch_multiple: 1,
+            amplitude: 1.0,
+            engine: EngineKind::Vocoder,
+            window_len: 16384,
+            grain: Duration::from_millis(100),
+            grain_jitter: 0.5,
+            grain_density: 4.0,
+            seed: None,
+        }
+    }
+}
+
+impl StretchOptions {
+    /// Whether every option is in range, as `stretch` needs
+    pub fn check(&self) -> Result<()> {
+        if !(self.factor > 0.0 && self.factor.is_finite()) {
+            bail!("the stretch factor must be above 0, got {}", self.factor);
+        }
+        if self.pitch_multiple == 0 {
+            bail!("the pitch multiple can't be 0");
+        }
+        if self.window_len < 2 {
+            bail!(
+                "the window must be at least 2 samples, got {}",
+                self.window_len
+            );
+        }
+        if self.grain.is_zero() {
+            bail!("grains must be longer than 0");
+        }
+        if !(0.0..=1.0).contains(&self.grain_jitter) {
+            bail!(
+                "grain jitter must be from 0 to 1, got {}",
+                self.grain_jitter
+            );
+        }
+        if !(1.0..).contains(&self.grain_density) {
+            bail!(
+                "grain density must be at least 1, got {}",
+                self.grain_density
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Stretch every channel of `audio`, returning exactly `factor` times as
+/// many samples, with its markers moved to match
+///
+/// `audio` can be a whole `Audio` or an `AudioSlice` of part of one. Each
+/// channel of it is copied into its engine in turn, so stretching a slice
+/// only ever copies one channel of its range at a time.
+///
+/// This blocks until the whole stretch is done, and fails if
+/// `options.check()` does.
+pub fn stretch<'a>(audio: impl Into<AudioSlice<'a>>, options: &StretchOptions) -> Result<Audio> {
+    options.check()?;
+    let audio = audio.into();
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let output_len = (audio.len() as f64 * options.factor as f64).round() as usize;
+    let mut stretched = Audio::from_spec(&audio.spec);
+    for (i, channel) in audio.channels().enumerate() {
+        // Nothing is buffered between threads here
+        let (mut engine, input) = build_engine(
+            audio.spec,
+            options,
+            seed.wrapping_add(i as u64),
+            Duration::from_secs(1),
+        );
+        input.send(channel.to_vec()).unwrap();
+        drop(input);
+        let mut output = Vec::with_capacity(output_len);
+        while output.len() < output_len {
+            output.extend(engine.next_window());
+            if engine.is_done() {
+                break;
+            }
+        }
+        output.resize(output_len, 0.0);
+        stretched.data[i] = output;
+    }
+    stretched.markers = audio
+        .markers()
+        .iter()
+        .map(|marker| marker.scaled(options.factor))
+        .collect();
+    Ok(stretched)
+}
+
+/// An engine for one channel of audio, and where to send it the channel
+pub(crate) fn build_engine(
+    spec: AudioSpec,
+    options: &StretchOptions,
+    seed: u64,
+    buffer_dur: Duration,
+) -> (Box<dyn StretchEngine>, Sender<Vec<f32>>) {
+    let (tx, rx) = unbounded();
+    let engine: Box<dyn StretchEngine> = match options.engine.for_factor(options.factor) {
+        EngineKind::Granular => {
+            let grain = GrainParams {
+                len: ((options.grain.as_secs_f32() * spec.sample_rate as f32) as usize).max(2),
+                jitter: options.grain_jitter,
+                density: options.grain_density,
+            };
+            let mut stretcher = GranularStretcher::new(
+                spec,
+                rx,
+                options.factor,
+                options.amplitude,
+                options.pitch_multiple,
+                grain,
+                buffer_dur,
+            );
+            stretcher.set_seed(seed);
+            Box::new(stretcher)
+        }
+        EngineKind::Wsola => Box::new(WsolaStretcher::new(
+            spec,
+            rx,
+            options.factor,
+            options.amplitude,
+            options.pitch_multiple,
+            buffer_dur,
+        )),
+        EngineKind::Vocoder | EngineKind::Auto => {
+            let mut stretcher = Stretcher::new(
+                spec,
+                rx,
+                options.factor,
+                options.amplitude,
+                options.pitch_multiple,
+                windows::hanning(options.window_len),
+                buffer_dur,
+                vec![],
+            );
+            stretcher.set_seed(seed);
+            Box::new(stretcher)
+        }
+    };
+    (engine, tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::markers::Marker;
+    use crate::test_utils::*;
+
+    #[test]
+    fn stretches_to_exactly_the_factor() {
+        let mut audio = generate_audio(0.5, 8000, 2, 8000);
+        audio.markers = vec![Marker::point("middle", 4000)];
+        for engine in [EngineKind::Vocoder, EngineKind::Granular, EngineKind::Wsola] {
+            let options = StretchOptions {
+                factor: 1.5,
+                engine,
+                window_len: 1024,
+                seed: Some(1),
+                ..StretchOptions::default()
+            };
+            let stretched = stretch(&audio, &options).unwrap();
+            assert_eq!(stretched.spec, audio.spec);
+            assert_eq!(stretched.data.len(), 2);
+            assert!(stretched.data.iter().all(|channel| channel.len() == 12000));
+            assert!(stretched.data[0].iter().any(|sample| *sample != 0.0));
+            assert_eq!(stretched.markers, vec![Marker::point("middle", 6000)]);
+            // Seeded stretches come out the same every time
+            assert_eq!(stretch(&audio, &options).unwrap().data, stretched.data);
+        }
+    }
+
+    #[test]
+    fn invalid_options_are_an_error() {
+        let audio = generate_audio(0.5, 8000, 1, 8000);
+        let options = StretchOptions {
+            pitch_multiple: 0,
+            ..StretchOptions::default()
+        };
+        assert!(stretch(&audio, &options).is_err());
+    }
+}
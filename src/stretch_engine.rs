@@ -0,0 +1,209 @@
+//! The interface between the stretcher processor and whatever stretches each
+//! channel, so different algorithms can be swapped in per run
+
+use crate::audio::AudioSpec;
+use crate::fft::SpectrumTap;
+use crate::slices::SliceDeque;
+use crate::wsola;
+use anyhow::{bail, Result};
+use crossbeam_channel::Receiver;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Stretches one channel of audio, a window at a time
+///
+/// The kernel and spectrum methods only mean something to engines that work
+/// on a spectrum, so the rest can leave them be.
+pub trait StretchEngine: Send + 'static {
+    fn spec(&self) -> AudioSpec;
+
+    /// The next window of stretched output
+    fn next_window(&mut self) -> Vec<f32>;
+
+    /// Whether the input has run out, so the last window has been produced
+    fn is_done(&self) -> bool;
+
+    /// How many windows to let the output channel hold
+    fn channel_bound(&self) -> usize;
+
+    /// Roughly how many frames of input the engine reads before its first
+    /// window is ready, which is how far a stretch of live input lags it
+    fn latency(&self) -> usize;
+
+    /// The stretch factor last set
+    fn factor(&self) -> f32;
+
+    /// Change the stretch factor, taking effect from the next window
+    fn set_factor(&mut self, factor: f32);
+
+    /// Hold the input position, so the current sound goes on indefinitely
+    fn set_frozen(&mut self, frozen: bool);
+
+    /// Set the channel index this engine's frequency kernels see
+    fn set_channel(&mut self, _channel: u32) {}
+
+    /// Set a named parameter passed to the frequency kernels
+    fn set_kernel_param(&mut self, _name: &str, _value: f32) {}
+
+    /// Enable or bypass one stage of the frequency kernel chain
+    fn set_kernel_stage_enabled(&mut self, stage: usize, _enabled: bool) -> Result<()> {
+        bail!("there is no kernel stage {} in this stretch engine", stage)
+    }
+
+    /// Publish the magnitudes of each window this engine analyzes to `tap`
+    fn set_spectrum_tap(&mut self, _tap: SpectrumTap) {}
+}
+
+/// The input of a stretch engine, received in chunks and buffered until the
+/// engine has read past it
+///
+/// Engines read a little beyond their position, so once the channel closes
+/// the buffer is padded with silence and `samples_left` says how much of it
+/// is real.
+pub struct EngineInput {
+    receiver: Receiver<Vec<f32>>,
+    buf: SliceDeque<f32>,
+    /// How many of the samples in `buf` are real once the input has closed,
+    /// the rest being padding
+    samples_left: Option<usize>,
+    buffer_dur: Duration,
+}
+
+impl EngineInput {
+    pub fn new(receiver: Receiver<Vec<f32>>, buffer_dur: Duration) -> EngineInput {
+        EngineInput {
+            receiver,
+            buf: SliceDeque::new(),
+            samples_left: None,
+            buffer_dur,
+        }
+    }
+
+    /// Wait until at least `n` samples are buffered, padding with silence
+    /// if the input closes first
+    pub fn ensure_available(&mut self, n: usize) {
+        while self.buf.len() < n {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.buf.extend(chunk);
+                }
+                Err(_) => {
+                    self.samples_left.get_or_insert(self.buf.len());
+                    self.buf.resize(n, 0.0);
+                }
+            }
+        }
+    }
+
+    /// Drop the first `n` samples, which may be more than are buffered yet
+    pub fn consume(&mut self, n: usize) {
+        self.ensure_available(n);
+        self.buf.truncate_front(self.buf.len() - n);
+        if let Some(left) = &mut self.samples_left {
+            *left = left.saturating_sub(n);
+        }
+    }
+
+    /// How many real samples are buffered, once the input has closed
+    pub fn samples_left(&self) -> Option<usize> {
+        self.samples_left
+    }
+
+    /// How many windows of `window_len` samples make up the buffer duration
+    pub fn channel_bound(&self, window_len: usize, sample_rate: u32) -> usize {
+        ((window_len as f32 / sample_rate as f32) / self.buffer_dur.as_secs_f32()).ceil() as usize
+    }
+}
+
+impl Deref for EngineInput {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.buf
+    }
+}
+
+/// The stretching algorithms to choose from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EngineKind {
+    /// The phase vocoder in `Stretcher`
+    Vocoder,
+    /// Overlapping grains in `GranularStretcher`
+    Granular,
+    /// Waveform similarity overlap-add in `WsolaStretcher`
+    Wsola,
+    /// WSOLA for the factors it suits, and the vocoder for the rest
+    Auto,
+}
+
+impl EngineKind {
+    /// The engine to stretch by `factor` with, which only differs for `Auto`
+    pub fn for_factor(self, factor: f32) -> EngineKind {
+        match self {
+            EngineKind::Auto if wsola::suits_factor(factor) => EngineKind::Wsola,
+            EngineKind::Auto => EngineKind::Vocoder,
+            engine => engine,
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<EngineKind> {
+        match name {
+            "vocoder" => Ok(EngineKind::Vocoder),
+            "granular" => Ok(EngineKind::Granular),
+            "wsola" => Ok(EngineKind::Wsola),
+            "auto" => Ok(EngineKind::Auto),
+            _ => bail!(
+                "unknown stretch engine {:?}, expected vocoder, granular, wsola or auto",
+                name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn ensure_available_when_channel_closed_fills_with_zeros() {
+        let (tx, rx) = unbounded();
+        let mut input = EngineInput::new(rx, Duration::from_secs(1));
+        drop(tx);
+        input.ensure_available(4);
+        assert_eq!(input.samples_left(), Some(0));
+        assert_almost_eq_by_element(input.to_vec(), vec![0.0; 4]);
+    }
+
+    #[test]
+    fn ensure_available_loading_multiple_chunks() {
+        let (tx, rx) = unbounded();
+        let mut input = EngineInput::new(rx, Duration::from_secs(1));
+        tx.send(vec![1.0, 2.0, 3.0]).unwrap();
+        tx.send(vec![4.0, 5.0]).unwrap();
+        input.ensure_available(4);
+        assert_eq!(input.samples_left(), None);
+        assert_almost_eq_by_element(input.to_vec(), vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn consume_counts_down_the_real_samples() {
+        let (tx, rx) = unbounded();
+        let mut input = EngineInput::new(rx, Duration::from_secs(1));
+        tx.send(vec![1.0, 2.0, 3.0]).unwrap();
+        drop(tx);
+        input.ensure_available(5);
+        assert_eq!(input.samples_left(), Some(3));
+        input.consume(2);
+        assert_eq!(input.samples_left(), Some(1));
+        assert_almost_eq_by_element(input.to_vec(), vec![3.0, 0.0, 0.0]);
+        input.consume(4);
+        assert_eq!(input.samples_left(), Some(0));
+    }
+}
@@ -1,20 +1,24 @@
 use crate::audio::AudioSpec;
 use crate::crossfade;
-use crate::fft::ReFFT;
+use crate::fft::{ReFFT, SpectrumTap};
+use crate::kernel::KernelSource;
 use crate::resampler;
+use crate::slices::SliceDeque;
+use crate::spectral_eq::SpectralEq;
+use crate::stretch_engine::{EngineInput, StretchEngine};
+use anyhow::Result;
 use crossbeam_channel::Receiver;
-use slice_deque::SliceDeque;
-use std::path::PathBuf;
 use std::time::Duration;
 // use stopwatch::Stopwatch;
 
 /// concurrent vocoder for one channel of audio
 pub struct Stretcher {
     pub spec: AudioSpec,
-    input: Receiver<Vec<f32>>,
-    input_buf: SliceDeque<f32>,
+    input: EngineInput,
     output_buf: SliceDeque<f32>,
     corrected_amp_factor: f32,
+    amplitude: f32,
+    factor: f32,
     pitch_multiple: i8,
     amp_correction_envelope: Vec<f32>,
     re_fft: ReFFT,
@@ -22,8 +26,8 @@ pub struct Stretcher {
     half_window_len: usize,
     samples_needed_per_window: usize,
     sample_step_len: usize,
-    done: bool,
-    buffer_dur: Duration,
+    /// While frozen the input stops advancing, so the current window sounds indefinitely
+    frozen: bool,
 }
 
 impl Stretcher {
@@ -35,64 +39,137 @@ impl Stretcher {
         pitch_multiple: i8,
         window: Vec<f32>,
         buffer_dur: Duration,
-        frequency_kernel_src: Option<PathBuf>,
+        frequency_kernel_srcs: Vec<KernelSource>,
     ) -> Stretcher {
         assert!(pitch_multiple != 0);
         let window_len = window.len();
-        let pitch_shifted_factor = if pitch_multiple < 0 {
-            factor / pitch_multiple.abs() as f32
-        } else {
-            factor * pitch_multiple.abs() as f32
-        };
         let samples_needed_per_window = if pitch_multiple < 0 {
             (window_len as f32 / pitch_multiple.abs() as f32).ceil() as usize
         } else {
             window_len * pitch_multiple.abs() as usize
         };
-        // correct for power lost in resynth - correction curve approx by trial and error
-        let corrected_amp_factor = (4f32).max(pitch_shifted_factor / 4.0) * amplitude;
         let half_window_len = window_len / 2;
-        let sample_step_len = (window_len as f32 / (pitch_shifted_factor * 2.0)) as usize;
         let amp_correction_envelope = crossfade::hanning_crossfade_compensation(window.len() / 2);
-        let re_fft = ReFFT::new(window, frequency_kernel_src);
+        let re_fft = ReFFT::new(window, spec.sample_rate, frequency_kernel_srcs);
         let mut output_buf = SliceDeque::with_capacity(samples_needed_per_window + half_window_len);
         output_buf.extend(vec![0.0; half_window_len]);
-        Stretcher {
+        let mut stretcher = Stretcher {
             spec,
-            input,
-            corrected_amp_factor,
+            input: EngineInput::new(input, buffer_dur),
+            corrected_amp_factor: 0.0,
+            amplitude,
+            factor,
             pitch_multiple,
             amp_correction_envelope,
             re_fft,
             window_len,
             half_window_len,
             samples_needed_per_window,
-            sample_step_len,
-            buffer_dur,
+            sample_step_len: 0,
+            frozen: false,
             output_buf,
-            input_buf: SliceDeque::new(),
-            done: false,
-        }
+        };
+        stretcher.set_factor(factor);
+        stretcher
+    }
+
+    /// Set how long a hot-swapped frequency kernel takes to fade in over the previous one
+    pub fn set_kernel_crossfade(&mut self, crossfade: Duration) {
+        self.re_fft.set_kernel_crossfade(crossfade);
+    }
+
+    /// Shape the stretched spectrum with `eq`, whose bands are placed
+    /// after any pitch shift
+    pub fn set_spectral_eq(&mut self, eq: &SpectralEq) {
+        let frequency_scale = if self.pitch_multiple < 0 {
+            1.0 / self.pitch_multiple.abs() as f32
+        } else {
+            self.pitch_multiple as f32
+        };
+        self.re_fft.set_spectral_eq(eq, frequency_scale);
+    }
+
+    /// Make this stretcher's random phases reproducible from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.re_fft.set_seed(seed);
+    }
+
+    /// Offset this stretcher's random phases by up to `intensity`, to set
+    /// it apart from other channels given the same seed
+    pub fn set_decorrelation(&mut self, intensity: f32, offset_seed: u64) {
+        self.re_fft.set_decorrelation(intensity, offset_seed);
+    }
+}
+
+impl StretchEngine for Stretcher {
+    fn spec(&self) -> AudioSpec {
+        self.spec
+    }
+
+    fn latency(&self) -> usize {
+        // A whole window for the first analysis, then a step for each
+        // further half window of output
+        let steps = self
+            .samples_needed_per_window
+            .div_ceil(self.half_window_len)
+            - 1;
+        self.window_len + steps * self.sample_step_len
+    }
+
+    fn factor(&self) -> f32 {
+        self.factor
     }
 
-    pub fn is_done(&self) -> bool {
-        self.done
+    fn set_factor(&mut self, factor: f32) {
+        self.factor = factor;
+        let pitch_shifted_factor = if self.pitch_multiple < 0 {
+            factor / self.pitch_multiple.abs() as f32
+        } else {
+            factor * self.pitch_multiple.abs() as f32
+        };
+        // correct for power lost in resynth - correction curve approx by trial and error
+        self.corrected_amp_factor = (4f32).max(pitch_shifted_factor / 4.0) * self.amplitude;
+        self.sample_step_len = (self.window_len as f32 / (pitch_shifted_factor * 2.0)) as usize;
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    fn set_kernel_param(&mut self, name: &str, value: f32) {
+        self.re_fft.set_kernel_param(name, value);
+    }
+
+    fn set_kernel_stage_enabled(&mut self, stage: usize, enabled: bool) -> Result<()> {
+        self.re_fft.set_kernel_stage_enabled(stage, enabled)
+    }
+
+    fn set_spectrum_tap(&mut self, tap: SpectrumTap) {
+        self.re_fft.set_spectrum_tap(tap);
     }
 
-    pub fn channel_bound(&self) -> usize {
-        ((self.window_len as f32 / self.spec.sample_rate as f32) / self.buffer_dur.as_secs_f32())
-            .ceil() as usize
+    fn set_channel(&mut self, channel: u32) {
+        self.re_fft.set_channel(channel);
     }
 
-    pub fn next_window(&mut self) -> Vec<f32> {
+    fn is_done(&self) -> bool {
+        self.input.samples_left() == Some(0)
+    }
+
+    fn channel_bound(&self) -> usize {
+        self.input
+            .channel_bound(self.window_len, self.spec.sample_rate)
+    }
+
+    fn next_window(&mut self) -> Vec<f32> {
         debug_assert!(self.output_buf.len() == self.half_window_len);
         // let sw = Stopwatch::start_new();
         let mut iter_output_buf_pos = 0;
         while self.output_buf.len() < self.samples_needed_per_window + self.half_window_len {
             // Generate output one half-window at a time, with each step leaving a half window
             // from the fade-out half of the window function for the next iteration to pick up.
-            self.ensure_input_samples_available(self.window_len);
-            let fft_result = self.re_fft.resynth(&self.input_buf[..self.window_len]);
+            self.input.ensure_available(self.window_len);
+            let fft_result = self.re_fft.resynth(&self.input[..self.window_len]);
             for i in 0..self.half_window_len {
                 self.output_buf[iter_output_buf_pos + i] = (fft_result[i]
                     + self.output_buf[iter_output_buf_pos + i])
@@ -102,8 +179,11 @@ impl Stretcher {
             self.output_buf
                 .extend_from_slice(&fft_result[self.half_window_len..]);
             iter_output_buf_pos += self.half_window_len;
-            self.input_buf
-                .truncate_front(self.input_buf.len() - self.sample_step_len);
+            if !self.frozen {
+                // Below half speed a step is longer than a window, skipping
+                // input that's never analyzed
+                self.input.consume(self.sample_step_len);
+            }
         }
         let result = resampler::resample(
             &self.output_buf[..self.samples_needed_per_window],
@@ -119,45 +199,58 @@ impl Stretcher {
         // );
         result
     }
-
-    pub fn ensure_input_samples_available(&mut self, n: usize) {
-        while self.input_buf.len() < n {
-            match self.input.recv() {
-                Ok(chunk) => {
-                    self.input_buf.extend(chunk);
-                }
-                Err(_) => {
-                    self.input_buf.resize(n, 0.0);
-                    self.done = true;
-                }
-            }
-        }
-    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::*;
     use crossbeam_channel::{unbounded, Sender};
 
     #[test]
-    fn ensure_input_samples_available_when_channel_closed_fills_with_zeros() {
-        let (mut stretcher, tx) = basic_stretcher(1000);
-        drop(tx);
-        stretcher.ensure_input_samples_available(4);
-        assert_eq!(stretcher.done, true);
-        assert_almost_eq_by_element(stretcher.input_buf.to_vec(), vec![0.0; 4]);
+    fn frozen_stretcher_holds_its_input_position() {
+        let (mut stretcher, tx) = basic_stretcher(8);
+        tx.send(vec![0.5; 64]).unwrap();
+        stretcher.set_frozen(true);
+        stretcher.next_window();
+        assert_eq!(stretcher.input.len(), 64);
+        stretcher.set_frozen(false);
+        stretcher.next_window();
+        assert!(stretcher.input.len() < 64);
     }
 
     #[test]
-    fn ensure_input_samples_available_loading_multiple_chunks() {
-        let (mut stretcher, tx) = basic_stretcher(1000);
-        tx.send(vec![1.0, 2.0, 3.0]).unwrap();
-        tx.send(vec![4.0, 5.0]).unwrap();
-        stretcher.ensure_input_samples_available(4);
-        assert_eq!(stretcher.done, false);
-        assert_almost_eq_by_element(stretcher.input_buf.to_vec(), vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+    fn output_length_follows_the_factor() {
+        let input_len = 44100;
+        for factor in [0.125, 0.25, 0.5, 0.8, 1.0, 2.0] {
+            let (tx, rx) = unbounded();
+            tx.send(vec![0.5; input_len]).unwrap();
+            drop(tx);
+            let mut stretcher = Stretcher::new(
+                AudioSpec {
+                    channels: 1,
+                    sample_rate: 44100,
+                },
+                rx,
+                factor,
+                1.0,
+                1,
+                vec![1.0; 1024],
+                Duration::from_secs(1),
+                vec![],
+            );
+            let mut output_len = 0;
+            while !stretcher.is_done() {
+                output_len += stretcher.next_window().len();
+            }
+            let expected = input_len as f32 * factor;
+            assert!(
+                (output_len as f32 - expected).abs() <= 1024.0,
+                "{} samples at {}x, expected {}",
+                output_len,
+                factor,
+                expected
+            );
+        }
     }
 
     fn basic_stretcher(window_len: usize) -> (Stretcher, Sender<Vec<f32>>) {
@@ -173,7 +266,7 @@ mod test {
             1,
             vec![1.0; window_len],
             Duration::from_secs(1),
-            None,
+            vec![],
         );
         (stretcher, tx)
     }
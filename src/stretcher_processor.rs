@@ -1,15 +1,48 @@
-use crate::audio::AudioBus;
-use crate::signal_flow::node::{ControlMessage, Processor, ProcessorState};
-use crate::stretcher::Stretcher;
+use crate::audio::{AudioBus, BusPolicy, BusSender, Overflow, Timestamp};
+use crate::fft::SpectrumTap;
+use crate::math;
+use crate::metrics::{self, Counter};
+use crate::runtime_setup;
+use crate::signal_flow::node::{ControlMessage, NodeError, Processor, ProcessorState};
+use crate::smoothing::SmoothedParam;
+use crate::stretch_engine::StretchEngine;
 use anyhow::Result;
-use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
-use std::sync::atomic::{AtomicBool, Ordering};
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+/// How long a new stretch factor or kernel parameter takes to sweep in,
+/// moving a window at a time
+const PARAM_RAMP: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 pub enum StretcherProcessorControlMessage {
     Shutdown,
+    /// Enable or bypass one stage of the frequency kernel chain on every channel
+    SetKernelStageEnabled {
+        stage: usize,
+        enabled: bool,
+    },
+    /// Set a named frequency kernel parameter on every channel
+    SetKernelParam {
+        name: String,
+        value: f32,
+    },
+    /// Change the stretch factor on every channel
+    SetFactor {
+        factor: f32,
+    },
+    /// Hold every channel on its current window, or let it move on again
+    SetFrozen {
+        frozen: bool,
+    },
+    /// Stop producing windows, holding the stretchers where they are
+    Pause,
+    Resume,
 }
 
 impl ControlMessage for StretcherProcessorControlMessage {
@@ -18,55 +51,329 @@ impl ControlMessage for StretcherProcessorControlMessage {
     }
 }
 
+/// How many samples per channel a stretcher processor has produced, and how
+/// many it expects to produce, shared with whoever is showing progress
+#[derive(Debug, Clone, Default)]
+pub struct StretchProgress(Arc<ProgressCounts>);
+
+#[derive(Debug, Default)]
+struct ProgressCounts {
+    produced: AtomicUsize,
+    /// Zero if unknown
+    expected: AtomicUsize,
+}
+
+impl StretchProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn samples_produced(&self) -> usize {
+        self.0.produced.load(Ordering::Relaxed)
+    }
+
+    pub fn expected_samples(&self) -> Option<usize> {
+        match self.0.expected.load(Ordering::Relaxed) {
+            0 => None,
+            expected => Some(expected),
+        }
+    }
+
+    /// How much of the stretch is done, from 0 to 1, if its length is known
+    pub fn fraction(&self) -> Option<f32> {
+        self.expected_samples()
+            .map(|expected| (self.samples_produced() as f32 / expected as f32).min(1.0))
+    }
+}
+
+/// Where a stretch is cut short, and how long it fades out before that
+#[derive(Debug, Clone, Copy)]
+struct OutputLimit {
+    max_samples: usize,
+    fade_samples: usize,
+}
+
 pub struct StretcherProcessor {
-    channels: Vec<(Sender<Vec<f32>>, Stretcher)>,
+    output: BusSender,
+    output_limit: Option<OutputLimit>,
+    stretchers: Vec<Box<dyn StretchEngine>>,
+    factor: SmoothedParam,
+    kernel_params: HashMap<String, SmoothedParam>,
+    /// How many samples factor and kernel parameter changes are spread over
+    param_ramp: usize,
+    workers: usize,
+    progress: StretchProgress,
+    paused: bool,
+    busy: Counter,
 }
 
 impl StretcherProcessor {
     pub fn new(
-        channel_stretchers: Vec<Stretcher>,
+        channel_stretchers: Vec<Box<dyn StretchEngine>>,
         expected_total_samples: Option<usize>,
     ) -> (StretcherProcessor, AudioBus) {
-        let spec = channel_stretchers[0].spec;
-        let mut channels: Vec<(Sender<Vec<f32>>, Stretcher)> = vec![];
-        let mut receivers: Vec<Receiver<Vec<f32>>> = vec![];
-        for stretcher in channel_stretchers.into_iter() {
-            let (tx, rx) = bounded(stretcher.channel_bound());
-            channels.push((tx, stretcher));
-            receivers.push(rx);
+        let spec = channel_stretchers[0].spec();
+        let progress = StretchProgress::new();
+        progress
+            .0
+            .expected
+            .store(expected_total_samples.unwrap_or(0), Ordering::Relaxed);
+        let mut stretchers: Vec<Box<dyn StretchEngine>> = vec![];
+        for (i, mut stretcher) in channel_stretchers.into_iter().enumerate() {
+            stretcher.set_channel(i as u32);
+            stretchers.push(stretcher);
         }
+        let policy = BusPolicy {
+            capacity: stretchers
+                .iter()
+                .map(|stretcher| stretcher.channel_bound())
+                .max(),
+            overflow: Overflow::Block,
+        };
+        let (bus, output) = AudioBus::bounded(spec, expected_total_samples, policy);
+        let param_ramp = (PARAM_RAMP.as_secs_f32() * spec.sample_rate as f32) as usize;
         (
-            StretcherProcessor { channels },
-            AudioBus {
-                spec,
-                channels: receivers,
-                expected_total_samples,
+            StretcherProcessor {
+                output,
+                output_limit: None,
+                factor: SmoothedParam::new(stretchers[0].factor()).with_ramp(param_ramp),
+                kernel_params: HashMap::new(),
+                param_ramp,
+                stretchers,
+                workers: 1,
+                progress,
+                paused: false,
+                busy: metrics::busy_counter("stretcher"),
             },
+            bus,
         )
     }
+
+    /// Compute the channels' windows in parallel on this many threads rather
+    /// than one after another on the processor's own thread
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.min(self.stretchers.len()).max(1);
+        self
+    }
+
+    /// Stop once `max` of audio has been produced on each channel, fading
+    /// out over the last `fade` of it rather than cutting off
+    ///
+    /// The expected length given to `new` should already be no more than
+    /// `max`, since the output bus carries it. Call this before
+    /// `with_progress` so the progress expects the shorter length too.
+    pub fn with_output_limit(mut self, max: Duration, fade: Duration) -> Self {
+        let sample_rate = self.stretchers[0].spec().sample_rate as f32;
+        let max_samples = (max.as_secs_f32() * sample_rate) as usize;
+        let fade_samples = ((fade.as_secs_f32() * sample_rate) as usize).min(max_samples);
+        let _ = self.progress.0.expected.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |expected| (expected > max_samples).then_some(max_samples),
+        );
+        self.output_limit = Some(OutputLimit {
+            max_samples,
+            fade_samples,
+        });
+        self
+    }
+
+    /// Count the samples produced in `progress`, e.g. to show a progress bar
+    ///
+    /// Several processors can share one `progress`, e.g. one for each segment
+    /// of a cue sheet, and it then expects the sum of their samples.
+    pub fn with_progress(mut self, progress: StretchProgress) -> Self {
+        progress.0.expected.fetch_add(
+            self.progress.0.expected.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+        self.progress = progress;
+        self
+    }
+
+    /// Publish the first channel's analysis frames to `tap`, e.g. to draw a
+    /// spectrogram
+    pub fn with_spectrum_tap(mut self, tap: SpectrumTap) -> Self {
+        self.stretchers[0].set_spectrum_tap(tap);
+        self
+    }
+
+    /// Move the factor and kernel parameters on by a window of `samples`
+    fn advance_params(&mut self, samples: usize) {
+        if !self.factor.is_settled() {
+            let factor = self.factor.advance(samples);
+            for stretcher in self.stretchers.iter_mut() {
+                stretcher.set_factor(factor);
+            }
+        }
+        for (name, param) in self.kernel_params.iter_mut() {
+            if !param.is_settled() {
+                let value = param.advance(samples);
+                for stretcher in self.stretchers.iter_mut() {
+                    stretcher.set_kernel_param(name, value);
+                }
+            }
+        }
+    }
+
+    /// Cut `windows` off at the output limit and fade them towards it,
+    /// returning whether the limit has now been reached
+    fn apply_output_limit(&self, windows: &mut [Vec<f32>]) -> bool {
+        let limit = match self.output_limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let start = self.output.frames_sent() as usize;
+        let fade_start = limit.max_samples - limit.fade_samples;
+        for window in windows.iter_mut() {
+            window.truncate(limit.max_samples.saturating_sub(start));
+            for (position, sample) in (start..).zip(window.iter_mut()) {
+                if position >= fade_start {
+                    *sample *= math::sqrt_interp(
+                        1.0,
+                        0.0,
+                        (position - fade_start) as f32 / limit.fade_samples as f32,
+                    );
+                }
+            }
+        }
+        start + windows[0].len() >= limit.max_samples
+    }
+
+    /// The next window of every channel, in channel order
+    fn next_windows(&mut self, pool: Option<&WorkerPool>) -> Vec<Vec<f32>> {
+        match pool {
+            Some(pool) => {
+                let (stretchers, windows, busy) = pool.next_windows(self.stretchers.drain(..));
+                self.stretchers = stretchers;
+                self.busy.add(busy.as_secs_f64());
+                windows
+            }
+            None => self
+                .stretchers
+                .iter_mut()
+                .map(|stretcher| {
+                    let started = Instant::now();
+                    let window = stretcher.next_window();
+                    self.busy.add(started.elapsed().as_secs_f64());
+                    window
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A channel's stretcher handed back by a worker, with its next window and
+/// how long that took
+type WorkerResult = (usize, Box<dyn StretchEngine>, Vec<f32>, Duration);
+
+/// Threads that each compute the next window of whichever channel's
+/// stretcher they're handed, passing it back along with the window
+struct WorkerPool {
+    jobs: Sender<(usize, Box<dyn StretchEngine>)>,
+    results: Receiver<WorkerResult>,
+}
+
+impl WorkerPool {
+    fn new(workers: usize) -> WorkerPool {
+        let (jobs_tx, jobs_rx) = unbounded::<(usize, Box<dyn StretchEngine>)>();
+        let (results_tx, results_rx) = unbounded();
+        for _ in 0..workers {
+            let jobs_rx = jobs_rx.clone();
+            let results_tx = results_tx.clone();
+            // The workers stop once the pool is dropped and the jobs dry up
+            thread::spawn(move || {
+                runtime_setup::pin_processing_thread();
+                for (channel, mut stretcher) in jobs_rx {
+                    let started = Instant::now();
+                    let window = stretcher.next_window();
+                    let busy = started.elapsed();
+                    if results_tx.send((channel, stretcher, window, busy)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        WorkerPool {
+            jobs: jobs_tx,
+            results: results_rx,
+        }
+    }
+
+    /// Hand out every stretcher and wait for them all to come back, returning
+    /// them and their windows in channel order, and the total time spent
+    fn next_windows(
+        &self,
+        stretchers: impl Iterator<Item = Box<dyn StretchEngine>>,
+    ) -> (Vec<Box<dyn StretchEngine>>, Vec<Vec<f32>>, Duration) {
+        let mut len = 0;
+        for job in stretchers.enumerate() {
+            self.jobs.send(job).unwrap();
+            len += 1;
+        }
+        let mut results: Vec<_> = (0..len)
+            .map(|_| self.results.recv().expect("stretcher worker panicked"))
+            .collect();
+        results.sort_by_key(|(channel, ..)| *channel);
+        let mut busy = Duration::ZERO;
+        let (stretchers, windows) = results
+            .into_iter()
+            .map(|(_, stretcher, window, elapsed)| {
+                busy += elapsed;
+                (stretcher, window)
+            })
+            .unzip();
+        (stretchers, windows, busy)
+    }
 }
 
 impl Processor<StretcherProcessorControlMessage> for StretcherProcessor {
     fn start(
         mut self,
         finished: Arc<AtomicBool>,
+        errors: Sender<NodeError>,
     ) -> (Sender<StretcherProcessorControlMessage>, JoinHandle<()>) {
         let (ctrl_tx, ctrl_rx) = unbounded();
         let handle = thread::spawn(move || {
+            runtime_setup::pin_processing_thread();
+            let pool = (self.workers > 1).then(|| WorkerPool::new(self.workers));
             'outer: loop {
-                match self.handle_control_messages(&ctrl_rx).unwrap() {
-                    ProcessorState::Finished => {
+                match self.handle_control_messages(&ctrl_rx) {
+                    Ok(ProcessorState::Running) => {}
+                    Ok(ProcessorState::Finished) => break 'outer,
+                    Err(e) => {
+                        let _ = errors.send(NodeError::Failed(e));
                         break 'outer;
                     }
-                    _ => {}
                 }
-                for (output, stretcher) in self.channels.iter_mut() {
-                    if stretcher.is_done() {
-                        // assuming each stretcher finishes at the same time
-                        info!("stretch process completed");
-                        break 'outer;
-                    }
-                    output.send(stretcher.next_window()).unwrap();
+                if self.paused {
+                    thread::sleep(PAUSE_POLL);
+                    continue;
+                }
+                // assuming each stretcher finishes at the same time
+                if self.stretchers.iter().any(|stretcher| stretcher.is_done()) {
+                    info!("stretch process completed");
+                    break 'outer;
+                }
+                let mut windows = self.next_windows(pool.as_ref());
+                let limit_reached = self.apply_output_limit(&mut windows);
+                self.advance_params(windows[0].len());
+                self.progress
+                    .0
+                    .produced
+                    .fetch_add(windows[0].len(), Ordering::Relaxed);
+                let timestamp = Timestamp {
+                    sample: self.output.frames_sent(),
+                    at: Instant::now(),
+                };
+                self.output.send_timestamp(timestamp);
+                if !self.output.send_frame(windows) {
+                    info!("stretch output disconnected, stopping");
+                    break 'outer;
+                }
+                if limit_reached {
+                    info!("stretch reached its output limit");
+                    break 'outer;
                 }
             }
             finished.store(true, Ordering::Relaxed);
@@ -81,9 +388,206 @@ impl Processor<StretcherProcessorControlMessage> for StretcherProcessor {
         match rx.try_recv() {
             Ok(msg) => match msg {
                 StretcherProcessorControlMessage::Shutdown => Ok(ProcessorState::Finished),
+                StretcherProcessorControlMessage::SetKernelStageEnabled { stage, enabled } => {
+                    for stretcher in self.stretchers.iter_mut() {
+                        if let Err(e) = stretcher.set_kernel_stage_enabled(stage, enabled) {
+                            warn!("Failed to set kernel stage enabled: {}", e);
+                            break;
+                        }
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::SetKernelParam { name, value } => {
+                    match self.kernel_params.get_mut(&name) {
+                        Some(param) => param.set_target(value),
+                        None => {
+                            // Nothing to move from, so the first value applies at once
+                            for stretcher in self.stretchers.iter_mut() {
+                                stretcher.set_kernel_param(&name, value);
+                            }
+                            let param = SmoothedParam::new(value).with_ramp(self.param_ramp);
+                            self.kernel_params.insert(name, param);
+                        }
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::SetFactor { factor } => {
+                    if factor > 0.0 {
+                        self.factor.set_target(factor);
+                    } else {
+                        warn!("Ignoring invalid stretch factor {}", factor);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::SetFrozen { frozen } => {
+                    for stretcher in self.stretchers.iter_mut() {
+                        stretcher.set_frozen(frozen);
+                    }
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::Pause => {
+                    self.paused = true;
+                    Ok(ProcessorState::Running)
+                }
+                StretcherProcessorControlMessage::Resume => {
+                    self.paused = false;
+                    Ok(ProcessorState::Running)
+                }
             },
             Err(TryRecvError::Disconnected) => Ok(ProcessorState::Finished),
             Err(TryRecvError::Empty) => Ok(ProcessorState::Running),
         }
     }
+
+    /// The slowest engine's lag behind live input, at the current factor
+    fn latency(&self) -> usize {
+        self.stretchers
+            .iter()
+            .map(|stretcher| stretcher.latency())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::AudioSpec;
+    use crate::stretcher::Stretcher;
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn worker_pool_keeps_windows_in_channel_order() {
+        // Only the middle channel has any signal
+        let (stretchers, _inputs): (Vec<_>, Vec<Sender<Vec<f32>>>) = [0.0, 0.5, 0.0]
+            .iter()
+            .map(|level| {
+                let (tx, rx) = unbounded();
+                tx.send(vec![*level; 1024]).unwrap();
+                let stretcher = Stretcher::new(
+                    AudioSpec {
+                        channels: 3,
+                        sample_rate: 44100,
+                    },
+                    rx,
+                    1.0,
+                    1.0,
+                    1,
+                    vec![1.0; 8],
+                    Duration::from_secs(1),
+                    vec![],
+                );
+                (Box::new(stretcher) as Box<dyn StretchEngine>, tx)
+            })
+            .unzip();
+        let (mut processor, _bus) = StretcherProcessor::new(stretchers, None);
+        let pool = WorkerPool::new(2);
+        for _ in 0..4 {
+            let windows = processor.next_windows(Some(&pool));
+            assert_eq!(windows.len(), 3);
+            assert!(windows[0].iter().all(|sample| *sample == 0.0));
+            assert!(windows[1].iter().any(|sample| *sample != 0.0));
+            assert!(windows[2].iter().all(|sample| *sample == 0.0));
+        }
+        assert_eq!(processor.stretchers.len(), 3);
+    }
+
+    #[test]
+    fn progress_counts_samples_sent_per_channel() {
+        let (tx, rx) = unbounded();
+        tx.send(vec![0.5; 64]).unwrap();
+        drop(tx);
+        let stretcher = Stretcher::new(
+            AudioSpec {
+                channels: 1,
+                sample_rate: 44100,
+            },
+            rx,
+            1.0,
+            1.0,
+            1,
+            vec![1.0; 8],
+            Duration::from_secs(1),
+            vec![],
+        );
+        let progress = StretchProgress::new();
+        let (processor, bus) = StretcherProcessor::new(vec![Box::new(stretcher)], Some(80));
+        let (errors, _) = unbounded();
+        let (_ctrl, handle) = processor
+            .with_progress(progress.clone())
+            .start(Arc::new(AtomicBool::new(false)), errors);
+        let received: usize = bus.channels[0].iter().map(|window| window.len()).sum();
+        handle.join().unwrap();
+        assert!(received > 0);
+        assert_eq!(progress.samples_produced(), received);
+        assert_eq!(progress.expected_samples(), Some(80));
+        assert!(progress.fraction().unwrap() <= 1.0);
+    }
+
+    #[test]
+    fn output_limit_cuts_the_stretch_short_with_a_fade() {
+        let (tx, rx) = unbounded();
+        tx.send(vec![0.5; 1000]).unwrap();
+        drop(tx);
+        let stretcher = Stretcher::new(
+            AudioSpec {
+                channels: 1,
+                sample_rate: 1000,
+            },
+            rx,
+            4.0,
+            1.0,
+            1,
+            vec![1.0; 8],
+            Duration::from_secs(1),
+            vec![],
+        );
+        let progress = StretchProgress::new();
+        let (processor, bus) = StretcherProcessor::new(vec![Box::new(stretcher)], Some(4000));
+        let (errors, _) = unbounded();
+        let (_ctrl, handle) = processor
+            .with_output_limit(Duration::from_millis(100), Duration::from_millis(20))
+            .with_progress(progress.clone())
+            .start(Arc::new(AtomicBool::new(false)), errors);
+        let output: Vec<f32> = bus.channels[0].iter().flatten().collect();
+        handle.join().unwrap();
+        assert_eq!(output.len(), 100);
+        assert_eq!(progress.expected_samples(), Some(100));
+        let level = output[40..80]
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(level > 0.0);
+        // The fade is well under way by the last sample
+        assert!(output[99].abs() < level * 0.5);
+    }
+
+    #[test]
+    fn factor_changes_sweep_in() {
+        let (_tx, rx) = unbounded();
+        let stretcher = Stretcher::new(
+            AudioSpec {
+                channels: 1,
+                sample_rate: 1000,
+            },
+            rx,
+            1.0,
+            1.0,
+            1,
+            vec![1.0; 8],
+            Duration::from_secs(1),
+            vec![],
+        );
+        let (mut processor, _bus) = StretcherProcessor::new(vec![Box::new(stretcher)], None);
+        let (ctrl_tx, ctrl_rx) = unbounded();
+        ctrl_tx
+            .send(StretcherProcessorControlMessage::SetFactor { factor: 3.0 })
+            .unwrap();
+        processor.handle_control_messages(&ctrl_rx).unwrap();
+        assert_eq!(processor.stretchers[0].factor(), 1.0);
+        // Half of the 500 ms ramp at 1 kHz
+        processor.advance_params(250);
+        assert_eq!(processor.stretchers[0].factor(), 2.0);
+        processor.advance_params(250);
+        assert_eq!(processor.stretchers[0].factor(), 3.0);
+    }
 }
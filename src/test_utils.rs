@@ -1,5 +1,9 @@
 use crate::audio::{Audio, AudioSpec};
+#[cfg(feature = "native")]
+use libloading::Library;
 use std::fmt::Debug;
+#[cfg(feature = "native")]
+use std::io::Write;
 
 const F32_EPSILON: f32 = 1.0e-4;
 
@@ -80,3 +84,51 @@ pub fn generate_audio(fill_val: f32, len: usize, channels: u16, sample_rate: u32
     }
     audio
 }
+
+/// Compile a frequency kernel from `src`, with the ABI version and
+/// `KernelFrame` declarations prepended
+#[cfg(feature = "native")]
+#[allow(unused)]
+pub fn compile_test_kernel(src: &str) -> Library {
+    let preamble = format!(
+        "
+        #[no_mangle]
+        pub static ROCODER_KERNEL_ABI_VERSION: u32 = {};
+
+        #[repr(C)]
+        pub struct KernelFrame {{
+            pub bins: *mut [f32; 2],
+            pub bins_len: usize,
+            pub sample_rate: u32,
+            pub window_len: u32,
+            pub channel: u32,
+            pub elapsed_ms: u64,
+            pub params: *const KernelParam,
+            pub params_len: usize,
+            pub state: *mut u8,
+        }}
+
+        #[repr(C)]
+        pub struct KernelParam {{
+            pub name: *const u8,
+            pub name_len: usize,
+            pub value: f32,
+        }}
+        ",
+        crate::kernel::KERNEL_ABI_VERSION
+    );
+    compile_raw_test_kernel(&(preamble + src))
+}
+
+/// Compile a frequency kernel from `src` alone
+#[cfg(feature = "native")]
+#[allow(unused)]
+pub fn compile_raw_test_kernel(src: &str) -> Library {
+    let mut file = tempfile::Builder::new()
+        .prefix("kernel")
+        .suffix(".rs")
+        .tempfile()
+        .unwrap();
+    file.write_all(src.as_bytes()).unwrap();
+    crate::hotswapper::compile(file.path()).unwrap()
+}
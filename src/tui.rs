@@ -0,0 +1,389 @@
+//! A full-screen terminal dashboard for live playback
+
+use crate::power;
+use anyhow::Result;
+use crossbeam_channel::Sender;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Gauge, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+/// The quietest level the output meter shows, in dBFS
+const METER_FLOOR_DB: f32 = -60.0;
+/// The quietest level the spectrogram shows, in dB below a full scale sine
+const SPECTROGRAM_FLOOR_DB: f32 = -80.0;
+/// Log-spaced frequency bands each spectrogram frame is reduced to
+const SPECTROGRAM_BANDS: usize = 64;
+/// Frames kept for a wide terminal to scroll through
+const SPECTROGRAM_HISTORY: usize = 512;
+/// Characters for each level, from silent to loudest
+const SHADES: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+const HELP: &str =
+    "up/down: stretch more/less   f: freeze   space: pause   b: bypass   q: fade out and quit";
+
+/// Everything the dashboard shows, refreshed on every draw
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dashboard {
+    pub elapsed: Duration,
+    pub factor: f32,
+    pub active_stretchers: usize,
+    /// How much of the stretch is done, from 0 to 1, if its length is known
+    pub progress: Option<f32>,
+    /// Highest output sample level since the last draw
+    pub output_peak: f32,
+    pub frozen: bool,
+    pub paused: bool,
+    pub bypass: bool,
+    pub output_gain_db: f32,
+}
+
+/// A scrolling history of analysis frames, newest last, each reduced to
+/// levels from 0 to 1 across log-spaced bands from low to high frequency
+#[derive(Debug, Clone, Default)]
+pub struct Spectrogram {
+    frames: VecDeque<Vec<f32>>,
+}
+
+impl Spectrogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a frame of positive frequency bin magnitudes
+    pub fn push(&mut self, magnitudes: &[f32]) {
+        if self.frames.len() == SPECTROGRAM_HISTORY {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(band_levels(magnitudes));
+    }
+}
+
+/// The loudest bin in each log-spaced band, relative to the peak a full
+/// scale sine makes under a Hann window
+fn band_levels(magnitudes: &[f32]) -> Vec<f32> {
+    // A full scale sine peaks at a quarter of the window length, which is
+    // half the number of positive frequency bins
+    let full_scale = magnitudes.len() as f32 / 2.0;
+    let len = magnitudes.len() as f32;
+    (0..SPECTROGRAM_BANDS)
+        .map(|band| {
+            // Starting the bands at bin 1 leaves out DC
+            let start = len.powf(band as f32 / SPECTROGRAM_BANDS as f32) as usize;
+            let end = (len.powf((band + 1) as f32 / SPECTROGRAM_BANDS as f32) as usize)
+                .max(start + 1)
+                .min(magnitudes.len());
+            let peak = magnitudes
+                .get(start..end)
+                .unwrap_or_default()
+                .iter()
+                .fold(0.0f32, |peak, magnitude| peak.max(*magnitude));
+            if peak <= 0.0 {
+                return 0.0;
+            }
+            let db = power::amplitude_to_decibels(peak / full_scale);
+            ((db - SPECTROGRAM_FLOOR_DB) / -SPECTROGRAM_FLOOR_DB).clamp(0.0, 1.0)
+        })
+        .collect()
+}
+
+/// What a key press asks for
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeyAction {
+    StretchMore,
+    StretchLess,
+    ToggleFreeze,
+    TogglePause,
+    ToggleBypass,
+    FadeOut,
+}
+
+/// The terminal, taken over for the dashboard until this is dropped
+pub struct Tui {
+    terminal: DefaultTerminal,
+}
+
+impl Tui {
+    pub fn start() -> Result<Tui> {
+        Ok(Tui {
+            terminal: ratatui::try_init()?,
+        })
+    }
+
+    pub fn draw(&mut self, dashboard: &Dashboard, spectrogram: &Spectrogram) -> Result<()> {
+        self.terminal
+            .draw(|frame| render(frame, dashboard, spectrogram))?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Read key presses on a thread of their own, turning each one bound to an
+/// action into an event with `to_event` and sending it on `events`
+pub fn listen_keys<T, F>(events: Sender<T>, to_event: F)
+where
+    T: Send + 'static,
+    F: Fn(KeyAction) -> T + Send + 'static,
+{
+    thread::spawn(move || loop {
+        let action = match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                key_action(key.code, key.modifiers)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Stopped reading keys: {}", e);
+                return;
+            }
+        };
+        if let Some(action) = action {
+            if events.send(to_event(action)).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+fn key_action(code: KeyCode, modifiers: KeyModifiers) -> Option<KeyAction> {
+    match code {
+        KeyCode::Up | KeyCode::Char('+') | KeyCode::Char('=') => Some(KeyAction::StretchMore),
+        KeyCode::Down | KeyCode::Char('-') => Some(KeyAction::StretchLess),
+        KeyCode::Char('f') => Some(KeyAction::ToggleFreeze),
+        KeyCode::Char(' ') => Some(KeyAction::TogglePause),
+        KeyCode::Char('b') => Some(KeyAction::ToggleBypass),
+        // The terminal is raw, so ctrl-c arrives as a key rather than a signal
+        KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => Some(KeyAction::FadeOut),
+        KeyCode::Char('q') | KeyCode::Esc => Some(KeyAction::FadeOut),
+        _ => None,
+    }
+}
+
+fn render(frame: &mut Frame, dashboard: &Dashboard, spectrogram: &Spectrogram) {
+    let [status_area, progress_area, meter_area, spectrogram_area, help_area] = Layout::vertical([
+        Constraint::Length(4),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let elapsed = dashboard.elapsed.as_secs();
+    let mut flags = vec![];
+    if dashboard.frozen {
+        flags.push("FROZEN");
+    }
+    if dashboard.paused {
+        flags.push("PAUSED");
+    }
+    if dashboard.bypass {
+        flags.push("BYPASS");
+    }
+    let status = Paragraph::new(vec![
+        Line::from(format!(
+            "Stretch factor {:.2}   Stretchers {}   Elapsed {}:{:02}:{:02}",
+            dashboard.factor,
+            dashboard.active_stretchers,
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60
+        )),
+        Line::from(format!(
+            "Output gain {:+.1} dB   {}",
+            dashboard.output_gain_db,
+            flags.join(" ")
+        )),
+    ])
+    .block(Block::bordered().title(" rocoder "));
+    frame.render_widget(status, status_area);
+
+    let progress = match dashboard.progress {
+        Some(fraction) => Gauge::default()
+            .ratio(fraction.clamp(0.0, 1.0) as f64)
+            .label(format!("{:.0}%", fraction * 100.0)),
+        None => Gauge::default().ratio(0.0).label("unknown length"),
+    };
+    frame.render_widget(
+        progress
+            .block(Block::bordered().title(" Stretched "))
+            .gauge_style(Style::default().fg(Color::Cyan)),
+        progress_area,
+    );
+
+    let meter_label = if dashboard.output_peak > 0.0 {
+        format!(
+            "{:.1} dBFS",
+            power::amplitude_to_decibels(dashboard.output_peak)
+        )
+    } else {
+        "silent".to_string()
+    };
+    let meter_color = if dashboard.output_peak >= 1.0 {
+        Color::Red
+    } else {
+        Color::Green
+    };
+    frame.render_widget(
+        Gauge::default()
+            .ratio(meter_ratio(dashboard.output_peak))
+            .label(meter_label)
+            .block(Block::bordered().title(" Output level "))
+            .gauge_style(Style::default().fg(meter_color)),
+        meter_area,
+    );
+
+    let block = Block::bordered().title(" Spectrum ");
+    let inner = block.inner(spectrogram_area);
+    frame.render_widget(
+        Paragraph::new(spectrogram_lines(
+            spectrogram,
+            inner.width as usize,
+            inner.height as usize,
+        ))
+        .block(block)
+        .style(Style::default().fg(Color::Yellow)),
+        spectrogram_area,
+    );
+
+    frame.render_widget(Paragraph::new(HELP), help_area);
+}
+
+/// Shade the newest frames that fit into rows of text, with time running
+/// left to right and the highest band on the top row
+fn spectrogram_lines(spectrogram: &Spectrogram, width: usize, height: usize) -> Vec<Line<'static>> {
+    let skip = spectrogram.frames.len().saturating_sub(width);
+    let frames: Vec<&Vec<f32>> = spectrogram.frames.iter().skip(skip).collect();
+    (0..height)
+        .map(|row| {
+            // Each row shows the loudest of the bands it covers
+            let low = (height - 1 - row) * SPECTROGRAM_BANDS / height;
+            let high = ((height - row) * SPECTROGRAM_BANDS / height).max(low + 1);
+            let line: String = frames
+                .iter()
+                .map(|levels| {
+                    let level = levels[low..high].iter().fold(0.0f32, |max, l| max.max(*l));
+                    let shade = (level * (SHADES.len() - 1) as f32).round() as usize;
+                    SHADES[shade]
+                })
+                .collect();
+            Line::from(line)
+        })
+        .collect()
+}
+
+/// Where a peak sample level falls on the meter, from 0 at `METER_FLOOR_DB`
+/// to 1 at full scale
+fn meter_ratio(peak: f32) -> f64 {
+    if peak <= 0.0 {
+        return 0.0;
+    }
+    let db = power::amplitude_to_decibels(peak);
+    ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    #[test]
+    fn meter_ratio_is_logarithmic() {
+        assert_eq!(meter_ratio(0.0), 0.0);
+        assert_eq!(meter_ratio(1.0), 1.0);
+        assert_eq!(meter_ratio(2.0), 1.0);
+        assert!((meter_ratio(0.001) - 0.0).abs() < 1e-6);
+        assert!((meter_ratio(0.1 / 3.162_277_7) - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn keys_map_to_actions() {
+        let none = KeyModifiers::NONE;
+        assert_eq!(key_action(KeyCode::Up, none), Some(KeyAction::StretchMore));
+        assert_eq!(
+            key_action(KeyCode::Char(' '), none),
+            Some(KeyAction::TogglePause)
+        );
+        assert_eq!(
+            key_action(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            Some(KeyAction::FadeOut)
+        );
+        assert_eq!(key_action(KeyCode::Char('c'), none), None);
+    }
+
+    #[test]
+    fn renders_the_dashboard() {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let dashboard = Dashboard {
+            elapsed: Duration::from_secs(3725),
+            factor: 8.0,
+            active_stretchers: 2,
+            progress: Some(0.25),
+            output_peak: 0.5,
+            frozen: true,
+            paused: false,
+            bypass: false,
+            output_gain_db: -3.0,
+        };
+        let mut spectrogram = Spectrogram::new();
+        let mut magnitudes = vec![0.0; 512];
+        magnitudes[1] = 256.0;
+        spectrogram.push(&magnitudes);
+        terminal
+            .draw(|frame| render(frame, &dashboard, &spectrogram))
+            .unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("Stretch factor 8.00"));
+        assert!(screen.contains("Elapsed 1:02:05"));
+        assert!(screen.contains("FROZEN"));
+        assert!(screen.contains("25%"));
+        assert!(screen.contains("-6.0 dBFS"));
+        assert!(screen.contains("Spectrum"));
+        assert!(screen.contains('@'));
+    }
+
+    #[test]
+    fn spectrogram_puts_low_bands_at_the_bottom() {
+        let mut spectrogram = Spectrogram::new();
+        let mut magnitudes = vec![0.0; 1024];
+        // Full scale at the lowest bin above DC, -40 dB at the highest
+        magnitudes[0] = 512.0;
+        magnitudes[1] = 512.0;
+        magnitudes[1023] = 5.12;
+        spectrogram.push(&magnitudes);
+        spectrogram.push(&vec![0.0; 1024]);
+        let lines: Vec<String> = spectrogram_lines(&spectrogram, 3, 4)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(lines, vec!["+ ", "  ", "  ", "@ "]);
+    }
+
+    #[test]
+    fn spectrogram_keeps_a_bounded_history() {
+        let mut spectrogram = Spectrogram::new();
+        for _ in 0..SPECTROGRAM_HISTORY + 10 {
+            spectrogram.push(&[1.0, 1.0]);
+        }
+        assert_eq!(spectrogram.frames.len(), SPECTROGRAM_HISTORY);
+        assert!(spectrogram
+            .frames
+            .iter()
+            .all(|levels| levels.len() == SPECTROGRAM_BANDS));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::audio::{Audio, AudioError, AudioSpec, SharedAudio};
+use anyhow::{anyhow, Result};
+use std::sync::{Arc, Mutex};
+
+/// In-memory stand-ins for the input and output devices, selected with
+/// `Backend::Virtual`
+///
+/// Recording replays scripted input instead of opening a device, and playback
+/// captures what would have been played. Nothing depends on real devices or
+/// their timing, so whole pipelines can run the same way every time, e.g. in
+/// tests. Clones share the same input and capture.
+#[derive(Debug, Clone)]
+pub struct VirtualAudio {
+    input: Option<SharedAudio>,
+    speed: f32,
+    captured: Arc<Mutex<Option<Audio>>>,
+}
+
+impl VirtualAudio {
+    pub fn new() -> Self {
+        VirtualAudio {
+            input: None,
+            speed: 1.0,
+            captured: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Record `audio` from the virtual input
+    pub fn with_input(mut self, audio: impl Into<SharedAudio>) -> Self {
+        self.input = Some(audio.into());
+        self
+    }
+
+    /// Deliver input this many times faster than realtime. Output is always
+    /// captured as fast as it's mixed.
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub(crate) fn input(&self) -> Result<SharedAudio> {
+        self.input
+            .clone()
+            .ok_or_else(|| anyhow!("the virtual backend has no input to record"))
+    }
+
+    pub(crate) fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Append interleaved `samples` to the capture, which must all share a spec
+    pub(crate) fn capture(&self, spec: AudioSpec, samples: &[f32]) -> Result<(), AudioError> {
+        let chunk = Audio::from_interleaved(&spec, samples);
+        let mut captured = self.captured.lock().unwrap();
+        match captured.as_mut() {
+            Some(audio) => audio.append(&chunk),
+            None => {
+                *captured = Some(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    /// Everything played to the virtual output so far, or `None` if nothing
+    /// has been
+    pub fn captured(&self) -> Option<Audio> {
+        self.captured.lock().unwrap().clone()
+    }
+}
+
+impl Default for VirtualAudio {
+    fn default() -> Self {
+        VirtualAudio::new()
+    }
+}
+
+impl PartialEq for VirtualAudio {
+    /// The same virtual devices, capturing to the same place
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.captured, &other.captured)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audio::{AudioBus, BusPolicy};
+    use crate::cpal_utils::Backend;
+    use crate::player_processor::{
+        AudioOutputProcessor, AudioOutputProcessorControlMessage, BusId,
+    };
+    use crate::recorder_processor::RecorderProcessor;
+    use crate::signal_flow::node::Node;
+    use crate::stretch_engine::StretchEngine;
+    use crate::stretcher::Stretcher;
+    use crate::stretcher_processor::StretcherProcessor;
+    use crate::windows;
+    use std::time::Duration;
+
+    const SPEC: AudioSpec = AudioSpec {
+        channels: 2,
+        sample_rate: 44100,
+    };
+
+    fn ramp(len: usize) -> Audio {
+        Audio {
+            data: vec![
+                (0..len).map(|i| 0.5 * i as f32 / len as f32).collect(),
+                (0..len).map(|i| -0.5 * i as f32 / len as f32).collect(),
+            ],
+            spec: SPEC,
+            markers: vec![],
+        }
+    }
+
+    /// Play `bus` to the virtual output until it finishes
+    fn play(bus: AudioBus, backend: Backend) {
+        let player = Node::new(AudioOutputProcessor::new(SPEC).with_backend(backend));
+        player
+            .send_control_message(AudioOutputProcessorControlMessage::ConnectBus {
+                id: BusId::allocate(),
+                bus,
+                fade: None,
+                shutdown_when_finished: true,
+            })
+            .unwrap();
+        player.join().unwrap();
+    }
+
+    #[test]
+    fn recording_plays_back_unchanged() {
+        let input = ramp(20000).shared();
+        let virtual_audio = VirtualAudio::new()
+            .with_input(input.clone())
+            .with_speed(100.0);
+        let backend = Backend::Virtual(virtual_audio.clone());
+        let (recorder, bus) = RecorderProcessor::new(SPEC, BusPolicy::UNBOUNDED);
+        let recorder = Node::new(recorder.with_backend(backend.clone()));
+        play(bus, backend);
+        recorder.join().unwrap();
+        let captured = virtual_audio.captured().unwrap();
+        for (played, recorded) in captured.data.iter().zip(&input.data) {
+            assert_eq!(&played[..recorded.len()], &recorded[..]);
+            // Only the rest of the last block mixed after the recording ended
+            assert!(played[recorded.len()..].iter().all(|sample| *sample == 0.0));
+        }
+    }
+
+    #[test]
+    fn record_stretch_and_play() {
+        let factor = 2.0;
+        let window_len = 1024;
+        let input = ramp(22050).shared();
+        let virtual_audio = VirtualAudio::new()
+            .with_input(input.clone())
+            .with_speed(100.0);
+        let backend = Backend::Virtual(virtual_audio.clone());
+        let (recorder, recorded) = RecorderProcessor::new(SPEC, BusPolicy::UNBOUNDED);
+        let recorder = Node::new(recorder.with_backend(backend.clone()));
+        let stretchers = recorded
+            .channels
+            .into_iter()
+            .map(|channel| {
+                Box::new(Stretcher::new(
+                    SPEC,
+                    channel,
+                    factor,
+                    1.0,
+                    1,
+                    windows::hanning(window_len),
+                    Duration::from_secs(1),
+                    vec![],
+                )) as Box<dyn StretchEngine>
+            })
+            .collect();
+        let (stretcher, stretched) = StretcherProcessor::new(stretchers, None);
+        let stretcher = Node::new(stretcher);
+        play(stretched, backend);
+        recorder.join().unwrap();
+        stretcher.join().unwrap();
+
+        let captured = virtual_audio.captured().unwrap();
+        let expected_len = (input.data[0].len() as f32 * factor) as usize;
+        assert!(captured.data[0].len() >= expected_len - window_len);
+        assert!(captured.data[0].iter().any(|sample| *sample > 0.1));
+        assert!(captured.data[1].iter().any(|sample| *sample < -0.1));
+    }
+
+    #[test]
+    fn recording_without_input_fails() {
+        let (recorder, _bus) = RecorderProcessor::new(SPEC, BusPolicy::UNBOUNDED);
+        let recorder = Node::new(recorder.with_backend(Backend::Virtual(VirtualAudio::new())));
+        assert!(recorder.join().is_err());
+    }
+}
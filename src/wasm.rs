@@ -0,0 +1,145 @@
+//! A wasm-bindgen wrapper around `stretch`, so stretches can run in a browser
+//!
+//! Enabled by the `wasm` feature. Without the default `native` feature none of
+//! the audio device, kernel file or MP3 code is built, so the crate compiles
+//! for `wasm32-unknown-unknown`:
+//!
+//! ```sh
+//! cargo rustc --release --lib --target wasm32-unknown-unknown \
+//!     --no-default-features --features wasm --crate-type cdylib
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/rocoder.wasm
+//! ```
+//!
+//! Samples go in and come out as interleaved `Float32Array`s.
+
+use crate::audio::{Audio, AudioSpec};
+use crate::stretch::{self, StretchOptions};
+use crate::stretch_engine::EngineKind;
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+/// The stretching algorithms, as `EngineKind` has them
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Engine {
+    Vocoder,
+    Granular,
+    Wsola,
+    Auto,
+}
+
+/// `StretchOptions` for JavaScript, constructed with the command line's
+/// defaults
+#[wasm_bindgen(js_name = StretchOptions)]
+#[derive(Debug, Copy, Clone)]
+pub struct WasmStretchOptions {
+    pub factor: f32,
+    #[wasm_bindgen(js_name = pitchMultiple)]
+    pub pitch_multiple: i8,
+    pub amplitude: f32,
+    pub engine: Engine,
+    #[wasm_bindgen(js_name = windowLen)]
+    pub window_len: u32,
+    #[wasm_bindgen(js_name = grainSeconds)]
+    pub grain_seconds: f32,
+    #[wasm_bindgen(js_name = grainJitter)]
+    pub grain_jitter: f32,
+    #[wasm_bindgen(js_name = grainDensity)]
+    pub grain_density: f32,
+    /// Left undefined for different random phases on every run
+    pub seed: Option<u32>,
+}
+
+#[wasm_bindgen(js_class = StretchOptions)]
+impl WasmStretchOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmStretchOptions {
+        let options = StretchOptions::default();
+        WasmStretchOptions {
+            factor: options.factor,
+            pitch_multiple: options.pitch_multiple,
+            amplitude: options.amplitude,
+            engine: match options.engine {
+                EngineKind::Vocoder => Engine::Vocoder,
+                EngineKind::Granular => Engine::Granular,
+                EngineKind::Wsola => Engine::Wsola,
+                EngineKind::Auto => Engine::Auto,
+            },
+            window_len: options.window_len as u32,
+            grain_seconds: options.grain.as_secs_f32(),
+            grain_jitter: options.grain_jitter,
+            grain_density: options.grain_density,
+            seed: None,
+        }
+    }
+}
+
+impl Default for WasmStretchOptions {
+    fn default() -> Self {
+        WasmStretchOptions::new()
+    }
+}
+
+impl WasmStretchOptions {
+    fn to_stretch_options(self) -> Option<StretchOptions> {
+        Some(StretchOptions {
+            factor: self.factor,
+            pitch_multiple: self.pitch_multiple,
+            amplitude: self.amplitude,
+            engine: match self.engine {
+                Engine::Vocoder => EngineKind::Vocoder,
+                Engine::Granular => EngineKind::Granular,
+                Engine::Wsola => EngineKind::Wsola,
+                Engine::Auto => EngineKind::Auto,
+            },
+            window_len: self.window_len as usize,
+            grain: Duration::try_from_secs_f32(self.grain_seconds).ok()?,
+            grain_jitter: self.grain_jitter,
+            grain_density: self.grain_density,
+            seed: self.seed.map(u64::from),
+        })
+    }
+}
+
+/// Stretch interleaved `samples`, returning exactly `factor` times as many
+///
+/// This blocks until the whole stretch is done, so long or extreme stretches
+/// are best run in a worker.
+#[wasm_bindgen]
+pub fn stretch(
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    options: &WasmStretchOptions,
+) -> Result<Vec<f32>, JsError> {
+    if channels == 0 || sample_rate == 0 {
+        return Err(JsError::new("channels and sample rate must be above 0"));
+    }
+    let options = options
+        .to_stretch_options()
+        .ok_or_else(|| JsError::new("grains must be longer than 0"))?;
+    let spec = AudioSpec {
+        channels,
+        sample_rate,
+    };
+    let stretched = stretch::stretch(&Audio::from_interleaved(&spec, samples), &options)
+        .map_err(|err| JsError::new(&err.to_string()))?;
+    Ok(stretched.interleaved())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stretches_interleaved_audio() {
+        let samples: Vec<f32> = (0..8000).flat_map(|_| [0.5, -0.5]).collect();
+        let mut options = WasmStretchOptions::new();
+        options.factor = 1.5;
+        options.engine = Engine::Wsola;
+        let stretched = stretch(&samples, 2, 8000, &options).unwrap();
+        assert_eq!(stretched.len(), 24000);
+        assert!((stretched[4000] - 0.5).abs() < 0.01);
+        assert!((stretched[4001] + 0.5).abs() < 0.01);
+    }
+}